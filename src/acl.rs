@@ -0,0 +1,96 @@
+//! who may touch which keys through the server. a database's deebee.toml
+//! can give it roles, each with the token its clients authenticate with and
+//! the key patterns it may use:
+//!
+//! ```toml
+//! [[databases.roles]]
+//! name = "app1"
+//! token = "..."
+//! keys = ["app1:*"]
+//! ```
+//!
+//! once a database has roles, the server answers nothing but `AUTH` to a
+//! client that hasn't authenticated, and refuses the keys outside its
+//! role's patterns: a command on one key fails, a command on several fails
+//! whole if one of them is out of bounds, and `KEYS` and scans only list
+//! what the role may read. the stats, the status page and the client list
+//! cover every namespace, only roles allowed `*` see them. a database
+//! without roles is open to every client, as before
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::key_matches;
+use crate::error::DeebeeError;
+use crate::http::same_token;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) struct Role {
+    pub(crate) name: String,
+    /// what its clients send, as `AUTH <token>` or `Authorization: Bearer
+    /// <token>`
+    pub(crate) token: String,
+    /// patterns (`*` wildcard) of the keys it may read and write
+    #[serde(default)]
+    pub(crate) keys: Vec<String>,
+    /// reads only, whatever the patterns
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) read_only: bool,
+}
+
+/// what a command does with a key
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Access {
+    Read,
+    /// sets, deletes and the reads that burn their key
+    Write,
+}
+
+impl Role {
+    pub(crate) fn allows(&self, key: &str, access: Access) -> bool {
+        (access == Access::Read || !self.read_only)
+            && self.keys.iter().any(|pattern| key_matches(pattern, key))
+    }
+
+    /// `Denied` unless the role may do that to the key
+    pub(crate) fn check(&self, key: &str, access: Access) -> Result<(), DeebeeError> {
+        match self.allows(key, access) {
+            true => Ok(()),
+            false => Err(DeebeeError::Denied(format!(
+                "role {} may not {} {key}",
+                self.name,
+                match access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                }
+            ))),
+        }
+    }
+
+    /// `Denied` unless the role may use every key, for what covers the
+    /// whole database
+    pub(crate) fn check_unrestricted(&self) -> Result<(), DeebeeError> {
+        match self.keys.iter().any(|pattern| pattern == "*") {
+            true => Ok(()),
+            false => Err(DeebeeError::Denied(format!(
+                "role {} only sees some of the keys",
+                self.name
+            ))),
+        }
+    }
+}
+
+/// the role the token belongs to. every token is compared in full, how long
+/// it takes doesn't tell how close a guess was
+pub(crate) fn find<'a>(roles: &'a [Role], token: &str) -> Option<&'a Role> {
+    roles
+        .iter()
+        .fold(None, |found, role| match same_token(token, &role.token) {
+            true => found.or(Some(role)),
+            false => found,
+        })
+}
+
+/// the error of a client that hasn't authenticated to a database with roles
+pub(crate) fn unauthenticated() -> DeebeeError {
+    DeebeeError::Denied("authenticate first, the database has roles".to_string())
+}
@@ -0,0 +1,104 @@
+use std::fmt;
+
+use crate::config::CompactionPolicy;
+use crate::stats::Stats;
+
+// below this many writes the history says more about the test run than the workload
+const MIN_WRITES: u64 = 100;
+// segments around this size keep rotation rare without making compaction slow
+const TARGET_SEGMENT_BYTES: u64 = 1 << 20;
+// values past this would shrink noticeably with compression
+const LARGE_VALUE_BYTES: u64 = 4 << 10;
+
+/// one recommendation from `Database::advise`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Advice {
+    pub reason: String,
+    /// the setting change that follows it, `None` when there is nothing to
+    /// set in this build
+    pub change: Option<Tuning>,
+}
+
+/// a deebee.toml setting `Database::apply_advice` can change
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tuning {
+    SegmentSize(usize),
+    CompactAtDeadRatio(f64),
+}
+
+impl fmt::Display for Tuning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tuning::SegmentSize(records) => write!(f, "segment_size = {records}"),
+            Tuning::CompactAtDeadRatio(ratio) => write!(f, "compaction.dead_ratio = {ratio}"),
+        }
+    }
+}
+
+/// what the advisor looks at, gathered by the database
+pub(crate) struct Workload<'a> {
+    pub(crate) stats: &'a Stats,
+    pub(crate) segment_size: usize,
+    pub(crate) records: usize,
+    pub(crate) live_keys: usize,
+    pub(crate) compaction: Option<&'a CompactionPolicy>,
+}
+
+pub(crate) fn advise(workload: &Workload) -> Vec<Advice> {
+    let stats = workload.stats;
+    if stats.total_writes < MIN_WRITES {
+        return vec![Advice {
+            reason: format!(
+                "only {} writes recorded, ask again once the database has seen real traffic",
+                stats.total_writes
+            ),
+            change: None,
+        }];
+    }
+
+    let mut advice = Vec::new();
+    let avg_record = (stats.bytes_written / stats.total_writes).max(1);
+
+    let suggested = (TARGET_SEGMENT_BYTES / avg_record).clamp(100, 1_000_000) as usize;
+    if workload.segment_size < suggested / 4 || workload.segment_size > suggested * 4 {
+        advice.push(Advice {
+            reason: format!(
+                "records average {avg_record} bytes, {} records per segment makes segments of about {} bytes",
+                workload.segment_size,
+                workload.segment_size as u64 * avg_record
+            ),
+            change: Some(Tuning::SegmentSize(suggested)),
+        });
+    }
+
+    let dead = workload.records.saturating_sub(workload.live_keys);
+    let dead_ratio = dead as f64 / workload.records.max(1) as f64;
+    let compacts_on_dead = workload.compaction.is_some_and(|p| p.dead_ratio.is_some());
+    if dead_ratio >= 0.3 && !compacts_on_dead {
+        // write-heavy databases pay for every compaction with more writes, let
+        // them build up more garbage first
+        let write_heavy = stats.total_writes > stats.total_reads.saturating_mul(10);
+        let threshold = if write_heavy { 0.7 } else { 0.5 };
+        advice.push(Advice {
+            reason: format!(
+                "{:.0}% of the records on disk are overwritten or deleted and nothing compacts them, {} writes to {} reads",
+                dead_ratio * 100.0,
+                stats.total_writes,
+                stats.total_reads
+            ),
+            change: Some(Tuning::CompactAtDeadRatio(threshold)),
+        });
+    }
+
+    if avg_record >= LARGE_VALUE_BYTES {
+        advice.push(Advice {
+            reason: format!(
+                "records average {} KiB, they would benefit from compression once deebee supports it",
+                avg_record >> 10
+            ),
+            change: None,
+        });
+    }
+
+    advice
+}
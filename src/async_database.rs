@@ -0,0 +1,149 @@
+//! an async face on the database for async servers. the blocking work runs on
+//! a few threads of the database's own and wakes the task when it's done, so
+//! no runtime thread ever waits on the disk and however many operations are in
+//! flight, the threads stay the same. nothing here depends on a particular
+//! runtime
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, mpsc};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use crate::config::DatabaseOptions;
+use crate::error::DeebeeError;
+use crate::shared::SharedDatabase;
+
+/// a `SharedDatabase` whose operations are futures. clones are handles on
+/// the same database
+#[derive(Clone)]
+pub struct AsyncDatabase {
+    db: SharedDatabase,
+    workers: Workers,
+}
+
+impl AsyncDatabase {
+    pub fn new(db: SharedDatabase) -> Self {
+        Self {
+            db,
+            workers: Workers::spawn(),
+        }
+    }
+
+    pub async fn open(db_name: &str, options: &DatabaseOptions) -> Result<Self, DeebeeError> {
+        let (db_name, options) = (db_name.to_string(), options.clone());
+        let workers = Workers::spawn();
+        let db = workers
+            .run(move || SharedDatabase::open(&db_name, &options))
+            .await?;
+        Ok(Self { db, workers })
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>, DeebeeError> {
+        let (db, key) = (self.db.clone(), key.to_string());
+        self.workers.run(move || db.get(&key)).await
+    }
+
+    pub async fn set(&self, key: &str, value: &str) -> Result<(), DeebeeError> {
+        let (db, key, value) = (self.db.clone(), key.to_string(), value.to_string());
+        self.workers.run(move || db.set(&key, &value)).await
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<bool, DeebeeError> {
+        let (db, key) = (self.db.clone(), key.to_string());
+        self.workers.run(move || db.delete(&key)).await
+    }
+
+    /// the blocking handle underneath, for everything else
+    pub fn shared(&self) -> &SharedDatabase {
+        &self.db
+    }
+}
+
+/// how many blocking operations run at once, the rest wait their turn
+const WORKERS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// the threads running a database's blocking work, `WORKERS` of them fed
+/// through one queue. they exit once every handle on the database is gone
+#[derive(Clone)]
+struct Workers {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Workers {
+    fn spawn() -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..WORKERS {
+            let queue = Arc::clone(&queue);
+            thread::Builder::new()
+                .name(format!("deebee-async-{i}"))
+                .spawn(move || {
+                    loop {
+                        // the queue is only held while waiting for the next job
+                        let job = queue.lock().expect("jobs never panic holding it").recv();
+                        match job {
+                            Ok(job) => job(),
+                            Err(_) => return,
+                        }
+                    }
+                })
+                .expect("spawning an async worker");
+        }
+        Self { jobs }
+    }
+
+    /// resolves to what `f` returns once a worker has run it
+    fn run<T: Send + 'static>(&self, f: impl FnOnce() -> T + Send + 'static) -> OffThread<T> {
+        let state = Arc::new(Mutex::new(State {
+            result: None,
+            waker: None,
+        }));
+        let done = Arc::clone(&state);
+        let job = Box::new(move || {
+            // a panic is handed to the awaiting task instead of leaving it
+            // hanging, the worker carries on with the next job
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let mut state = done.lock().expect("polling never panics holding it");
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+        self.jobs
+            .send(job)
+            .expect("the workers only exit once their handles are gone");
+        OffThread { state }
+    }
+}
+
+struct State<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// resolves to what a job returns once a worker has run it
+struct OffThread<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+impl<T> Future for OffThread<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self
+            .state
+            .lock()
+            .expect("the worker never panics holding it");
+        match state.result.take() {
+            Some(Ok(value)) => Poll::Ready(value),
+            Some(Err(panic)) => panic::resume_unwind(panic),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! the threads a database does its housekeeping on. every background task
+//! runs on one small pool instead of a thread of its own, can be switched off
+//! in deebee.toml and reports how it's doing in `stats`. compaction and cache
+//! warmup are low priority, they pause while the handle's gets and sets are
+//! busy. flush isn't, the writes waiting on it are foreground work too
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::error::DeebeeError;
+
+/// merges sealed segments when the compaction policy calls for it
+pub(crate) const COMPACTION: &str = "compaction";
+/// fsyncs what `everysec` writes left unsynced, about once a second, also
+/// when no write comes along to do it
+pub(crate) const FLUSH: &str = "flush";
+/// reads the keys that were hot at the last close back into the cache
+pub(crate) const WARMUP: &str = "warmup";
+const TASKS: [&str; 3] = [COMPACTION, FLUSH, WARMUP];
+
+// how often the periodic tasks run
+const TICK: Duration = Duration::from_secs(1);
+const DEFAULT_THREADS: usize = 2;
+const DEFAULT_YIELD: Duration = Duration::from_millis(1);
+
+/// the `[databases.background]` table
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub(crate) struct BackgroundConfig {
+    /// threads the tasks share, 2 unless set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) threads: Option<usize>,
+    /// tasks that never run, by name
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) disabled: Vec<String>,
+    /// how long the low priority tasks pause for when a get or set ran
+    /// that recently, 1 unless set. 0 never pauses them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) yield_ms: Option<u64>,
+}
+
+impl BackgroundConfig {
+    pub(crate) fn validate(&self) -> Result<(), DeebeeError> {
+        if self.threads == Some(0) {
+            return Err(DeebeeError::Config(
+                "background threads must be at least 1".to_string(),
+            ));
+        }
+        if let Some(task) = self
+            .disabled
+            .iter()
+            .find(|task| !TASKS.contains(&task.as_str()))
+        {
+            return Err(DeebeeError::Config(format!(
+                "unknown background task {task}, expected {}",
+                TASKS.join(" or ")
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// how a background task has done since the database was opened
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
+pub struct TaskStatus {
+    pub name: String,
+    /// false when deebee.toml switched it off
+    pub enabled: bool,
+    pub running: bool,
+    /// finished runs, failed ones included
+    pub runs: u64,
+    pub failures: u64,
+    /// times it paused for the handle's gets and sets
+    #[serde(default)]
+    pub pauses: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+/// returns whether there was anything to do, idle runs aren't counted
+pub(crate) type Periodic = Box<dyn Fn() -> Result<bool, String> + Send + Sync>;
+
+/// the pool, owned by the database handle. dropping it lets the queued jobs
+/// finish and waits for the threads
+pub(crate) struct Background {
+    jobs: Option<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+    status: Arc<Mutex<BTreeMap<&'static str, TaskStatus>>>,
+    foreground: Foreground,
+}
+
+/// when the handle's gets and sets last ran, for the low priority tasks to
+/// pause behind. clones share it
+#[derive(Clone)]
+pub(crate) struct Foreground {
+    since: Instant,
+    /// microseconds from `since` to the start of the last get or set, 0
+    /// before the first
+    last: Arc<AtomicU64>,
+    pause: Duration,
+    status: Arc<Mutex<BTreeMap<&'static str, TaskStatus>>>,
+}
+
+impl Foreground {
+    /// a get or set is starting
+    pub(crate) fn touch(&self) {
+        let now = self.since.elapsed().as_micros() as u64;
+        self.last.store(now.max(1), Ordering::Relaxed);
+    }
+
+    /// a step of the task's work is done, pause once if a get or set ran
+    /// within the pause. only once, so the task gets on under steady load,
+    /// slower
+    pub(crate) fn yield_to(&self, task: &'static str) {
+        let last = self.last.load(Ordering::Relaxed);
+        if self.pause.is_zero() || last == 0 {
+            return;
+        }
+        let last = Duration::from_micros(last);
+        if self.since.elapsed().saturating_sub(last) >= self.pause {
+            return;
+        }
+        thread::sleep(self.pause);
+        if let Some(status) = self
+            .status
+            .lock()
+            .expect("tasks never panic holding it")
+            .get_mut(task)
+        {
+            status.pauses += 1;
+        }
+    }
+}
+
+impl Background {
+    /// start the threads. `periodic` tasks run every second on a thread that
+    /// found nothing else to do, as long as they're enabled
+    pub(crate) fn spawn(
+        db_name: &str,
+        config: &BackgroundConfig,
+        periodic: Vec<(&'static str, Periodic)>,
+    ) -> Self {
+        let status: BTreeMap<&'static str, TaskStatus> = TASKS
+            .iter()
+            .map(|&name| {
+                let status = TaskStatus {
+                    name: name.to_string(),
+                    enabled: !config.disabled.iter().any(|task| task == name),
+                    ..Default::default()
+                };
+                (name, status)
+            })
+            .collect();
+        let status = Arc::new(Mutex::new(status));
+        let periodic: Arc<Vec<_>> = Arc::new(
+            periodic
+                .into_iter()
+                .filter(|(name, _)| !config.disabled.iter().any(|task| task == name))
+                .collect(),
+        );
+
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        let threads = (0..config.threads.unwrap_or(DEFAULT_THREADS))
+            .map(|i| {
+                let (queue, periodic, status) = (queue.clone(), periodic.clone(), status.clone());
+                thread::Builder::new()
+                    .name(format!("deebee-{db_name}-{i}"))
+                    .spawn(move || {
+                        loop {
+                            // the queue is only held while waiting for the next job
+                            let job = queue
+                                .lock()
+                                .expect("jobs never panic holding it")
+                                .recv_timeout(TICK);
+                            match job {
+                                Ok(job) => job(),
+                                Err(RecvTimeoutError::Timeout) => {
+                                    for (name, task) in periodic.iter() {
+                                        run_tracked(&status, name, task);
+                                    }
+                                }
+                                Err(RecvTimeoutError::Disconnected) => return,
+                            }
+                        }
+                    })
+                    .expect("spawning a background thread")
+            })
+            .collect();
+
+        let foreground = Foreground {
+            since: Instant::now(),
+            last: Arc::new(AtomicU64::new(0)),
+            pause: config.yield_ms.map_or(DEFAULT_YIELD, Duration::from_millis),
+            status: status.clone(),
+        };
+        Self {
+            jobs: Some(jobs),
+            threads,
+            status,
+            foreground,
+        }
+    }
+
+    /// what the low priority tasks pause behind
+    pub(crate) fn foreground(&self) -> &Foreground {
+        &self.foreground
+    }
+
+    /// queue `f` as a run of the task, unless it's disabled. returns whether
+    /// it was queued
+    pub(crate) fn run(
+        &self,
+        task: &'static str,
+        f: impl FnOnce() -> Result<(), String> + Send + 'static,
+    ) -> bool {
+        if !self.enabled(task) {
+            return false;
+        }
+        let status = self.status.clone();
+        let job: Job = Box::new(move || run_tracked(&status, task, || f().map(|()| true)));
+        self.jobs
+            .as_ref()
+            .is_some_and(|jobs| jobs.send(job).is_ok())
+    }
+
+    pub(crate) fn enabled(&self, task: &'static str) -> bool {
+        self.lock_status()
+            .get(task)
+            .is_some_and(|status| status.enabled)
+    }
+
+    /// every task, by name
+    pub(crate) fn status(&self) -> Vec<TaskStatus> {
+        self.lock_status().values().cloned().collect()
+    }
+
+    fn lock_status(&self) -> std::sync::MutexGuard<'_, BTreeMap<&'static str, TaskStatus>> {
+        self.status.lock().expect("tasks never panic holding it")
+    }
+}
+
+/// run the task and record how it went. a panicking task counts as failed,
+/// the thread carries on
+fn run_tracked(
+    status: &Mutex<BTreeMap<&'static str, TaskStatus>>,
+    task: &'static str,
+    f: impl FnOnce() -> Result<bool, String>,
+) {
+    let lock = || status.lock().expect("tasks never panic holding it");
+    if let Some(status) = lock().get_mut(task) {
+        status.running = true;
+    }
+    let result = panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err(format!("the {task} task panicked")));
+    if let Some(status) = lock().get_mut(task) {
+        status.running = false;
+        match result {
+            Ok(false) => {}
+            Ok(true) => status.runs += 1,
+            Err(e) => {
+                status.runs += 1;
+                status.failures += 1;
+                status.last_error = Some(e);
+            }
+        }
+    }
+}
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        // closing the queue ends the thread loops once it's empty
+        self.jobs.take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! the shared blob area: values compaction found under several keys, stored
+//! once in `<db-dir>/blobs/<blake3 of the value>`. the merged records only
+//! hold a reference, the hash flagged as `RecordFlags::BLOB`, or `BLOB_REF`
+//! followed by the hash before format version 5. `blobs/REFS` counts the
+//! references to every blob, a blob nothing refers to any more goes with the
+//! compaction or restore that dropped its last reference
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::compress;
+use crate::error::DeebeeError;
+use crate::segment::{RecordEncoding, RecordFlags, segment_records};
+
+const BLOBS: &str = "blobs";
+const REFS: &str = "REFS";
+
+fn blob_dir(db_dir: &Path) -> PathBuf {
+    db_dir.join(BLOBS)
+}
+
+/// the value a record stands for, read from the blob area when its flags
+/// say it only refers to it
+pub(crate) fn resolve<'a>(
+    db_dir: &Path,
+    value: Cow<'a, [u8]>,
+    flags: RecordFlags,
+) -> Result<Cow<'a, [u8]>, DeebeeError> {
+    if !flags.contains(RecordFlags::BLOB) {
+        return Ok(value);
+    }
+    let path = blob_dir(db_dir).join(String::from_utf8_lossy(&value).as_ref());
+    match fs::read(&path) {
+        Ok(shared) => Ok(Cow::Owned(shared)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(DeebeeError::Corruption(format!(
+            "shared value {} is missing",
+            path.display()
+        ))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// keep one copy of the value in the blob area, returning the hash a
+/// record holds instead of it. the blob is complete on disk before any
+/// record refers to it
+pub(crate) fn store(db_dir: &Path, value: &[u8]) -> Result<String, DeebeeError> {
+    let hash = blake3::hash(value).to_hex().to_string();
+    let dir = blob_dir(db_dir);
+    let path = dir.join(&hash);
+    if !path.exists() {
+        fs::create_dir_all(&dir)?;
+        let tmp_path = dir.join(format!("{hash}.tmp"));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(value)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+    }
+    Ok(hash)
+}
+
+/// how many records of the segments refer to each blob, overwritten ones
+/// included
+pub(crate) fn count_refs(
+    segments: &[String],
+    encoding: RecordEncoding,
+) -> Result<BTreeMap<String, usize>, DeebeeError> {
+    let mut refs = BTreeMap::new();
+    for path in segments {
+        let content = match compress::read_segment(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for (_, record) in segment_records(&content, encoding) {
+            if record.flags.contains(RecordFlags::BLOB) {
+                let hash = String::from_utf8_lossy(&record.value).into_owned();
+                *refs.entry(hash).or_default() += 1;
+            }
+        }
+    }
+    Ok(refs)
+}
+
+/// record the reference counts in `REFS` and remove the blobs without any.
+/// nothing to do for databases that never shared a value
+pub(crate) fn collect(db_dir: &Path, refs: &BTreeMap<String, usize>) -> Result<(), DeebeeError> {
+    let dir = blob_dir(db_dir);
+    if !dir.exists() {
+        return Ok(());
+    }
+    let tmp_path = dir.join(format!("{REFS}.tmp"));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(toml::to_string(refs)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, dir.join(REFS))?;
+
+    for entry in fs::read_dir(&dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        // leftovers of a crashed store are garbage too
+        if name != REFS && !refs.contains_key(name.as_ref()) {
+            fs::remove_file(dir.join(name.as_ref()))?;
+        }
+    }
+    Ok(())
+}
+
+/// link the blobs into `to`'s blob area, copying where links don't work.
+/// blobs never change, so the two areas can share the files
+pub(crate) fn link_all(from: &Path, to: &Path) -> Result<(), DeebeeError> {
+    let (from, to) = (blob_dir(from), blob_dir(to));
+    if !from.exists() {
+        return Ok(());
+    }
+    fs::create_dir_all(&to)?;
+    for entry in fs::read_dir(&from)? {
+        let name = entry?.file_name();
+        let (source, target) = (from.join(&name), to.join(&name));
+        if name == REFS || target.exists() {
+            continue;
+        }
+        if fs::hard_link(&source, &target).is_err() {
+            fs::copy(&source, &target)?;
+        }
+    }
+    Ok(())
+}
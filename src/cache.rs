@@ -0,0 +1,141 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::error::DeebeeError;
+
+/// keys saved for the next open to warm the cache with, at most
+pub(crate) const HOT_KEYS: usize = 10_000;
+
+/// recently read values up to a byte budget, the least recently used go first
+/// when it's full. keys and values both count against the budget. values
+/// are shared, so `get_ref` can lend one out without copying it
+pub(crate) struct ValueCache {
+    max_bytes: usize,
+    bytes: usize,
+    entries: HashMap<String, (Arc<[u8]>, u64)>,
+    /// keys by when they were last used, oldest first
+    by_use: BTreeMap<u64, String>,
+    clock: u64,
+    /// keys a warmup is still reading, dropped when they're written meanwhile
+    warming: HashSet<String>,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+}
+
+impl ValueCache {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            bytes: 0,
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+            warming: HashSet::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// the cached value, counted as a hit or a miss. it stays valid after
+    /// it's evicted
+    pub(crate) fn get(&mut self, key: &str) -> Option<Arc<[u8]>> {
+        self.clock += 1;
+        let Some((value, used)) = self.entries.get_mut(key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.by_use.remove(used);
+        *used = self.clock;
+        self.by_use.insert(self.clock, key.to_string());
+        self.hits += 1;
+        Some(value.clone())
+    }
+
+    /// values too big for the whole budget aren't kept
+    pub(crate) fn insert(&mut self, key: &str, value: &[u8]) {
+        self.insert_shared(key, value.into());
+    }
+
+    /// `insert` of a value that's shared already
+    pub(crate) fn insert_shared(&mut self, key: &str, value: Arc<[u8]>) {
+        self.remove(key);
+        let size = key.len() + value.len();
+        if size > self.max_bytes {
+            return;
+        }
+        while self.bytes + size > self.max_bytes {
+            let Some((_, oldest)) = self.by_use.pop_first() else {
+                break;
+            };
+            if let Some((value, _)) = self.entries.remove(&oldest) {
+                self.bytes -= oldest.len() + value.len();
+            }
+        }
+
+        self.clock += 1;
+        self.entries.insert(key.to_string(), (value, self.clock));
+        self.by_use.insert(self.clock, key.to_string());
+        self.bytes += size;
+    }
+
+    /// the keys a warmup is about to read, most recently used first
+    pub(crate) fn start_warming(&mut self, keys: impl IntoIterator<Item = String>) {
+        self.warming.extend(keys);
+    }
+
+    /// a value a warmup read, kept unless the key was read or written since
+    pub(crate) fn insert_warm(&mut self, key: &str, value: &[u8]) {
+        if self.warming.remove(key) && !self.entries.contains_key(key) {
+            self.insert(key, value);
+        }
+    }
+
+    /// the cached keys, most recently used first, then the ones a warmup
+    /// didn't get to
+    pub(crate) fn hot_keys(&self, limit: usize) -> Vec<String> {
+        self.by_use
+            .values()
+            .rev()
+            .chain(&self.warming)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        self.warming.remove(key);
+        if let Some((value, used)) = self.entries.remove(key) {
+            self.by_use.remove(&used);
+            self.bytes -= key.len() + value.len();
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.by_use.clear();
+        self.warming.clear();
+        self.bytes = 0;
+    }
+}
+
+/// the keys the cache held when the database was last closed, most recently
+/// used first
+pub(crate) fn hot_keys_path(dir: &Path) -> PathBuf {
+    dir.join("HOT")
+}
+
+/// a missing or unreadable file warms nothing
+pub(crate) fn load_hot_keys(dir: &Path) -> Vec<String> {
+    fs::read_to_string(hot_keys_path(dir))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+pub(crate) fn save_hot_keys(dir: &Path, keys: &[String]) -> Result<(), DeebeeError> {
+    let keys = serde_json::to_string(keys).expect("keys always serialize");
+    fs::write(hot_keys_path(dir), keys)?;
+    Ok(())
+}
@@ -0,0 +1,124 @@
+//! bounds on one operation: a deadline, a token another thread cancels it
+//! with, or both. they're checked between the steps of the operation, before
+//! each record a scan or compaction reads, so a step already running (one
+//! read off a slow disk, one fsync) finishes first. an operation stopped
+//! this way fails with `DeebeeError::Cancelled` and changed nothing
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::error::DeebeeError;
+
+/// cancels the operations it was handed to, from any thread. clones share
+/// one flag
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the operations holding the token stop at their next check, and the
+    /// ones handed it later don't start
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Limits {
+    deadline: Option<Instant>,
+    cancel: Option<CancelToken>,
+}
+
+impl Limits {
+    fn check(&self) -> Result<(), DeebeeError> {
+        if self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            return Err(DeebeeError::Cancelled(
+                "the operation was cancelled".to_string(),
+            ));
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(DeebeeError::Cancelled(
+                "the operation ran past its deadline".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// bounds on a read or a scan, `Database::get_with` and `scan_prefix_with`.
+/// a read is checked before it starts, a scan before each record. no
+/// deadline and no token by default
+#[derive(Clone, Debug, Default)]
+pub struct GetOptions {
+    limits: Limits,
+}
+
+impl GetOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// give up once it's this late
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.limits.deadline = Some(deadline);
+        self
+    }
+
+    /// give up this long from now
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.deadline(Instant::now() + timeout)
+    }
+
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.limits.cancel = Some(token);
+        self
+    }
+
+    /// `Cancelled` once the deadline passed or the token was cancelled
+    pub fn check(&self) -> Result<(), DeebeeError> {
+        self.limits.check()
+    }
+}
+
+/// bounds on a write or a compaction, `Database::set_with`, `delete_with`
+/// and `compact_segments_with`. a write is checked before it starts, a
+/// compaction throughout. no deadline and no token by default
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+    limits: Limits,
+}
+
+impl WriteOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// give up once it's this late
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.limits.deadline = Some(deadline);
+        self
+    }
+
+    /// give up this long from now
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.deadline(Instant::now() + timeout)
+    }
+
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.limits.cancel = Some(token);
+        self
+    }
+
+    /// `Cancelled` once the deadline passed or the token was cancelled
+    pub fn check(&self) -> Result<(), DeebeeError> {
+        self.limits.check()
+    }
+}
@@ -0,0 +1,87 @@
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::ChaosConfig;
+use crate::error::DeebeeError;
+
+/// misbehaves on purpose before reads and writes, as configured in the
+/// database's `[databases.chaos]` table. meant for staging, never production
+pub(crate) struct Chaos {
+    config: ChaosConfig,
+    rng: AtomicU64,
+    // earliest time the next operation may start when throttling
+    next_slot: Mutex<Instant>,
+}
+
+impl Chaos {
+    pub(crate) fn new(config: ChaosConfig) -> Self {
+        let seed = config.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default()
+        });
+        Self {
+            config,
+            // xorshift gets stuck on zero
+            rng: AtomicU64::new(seed | 1),
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// throttle, delay and maybe fail the operation, in that order
+    pub(crate) fn before(&self, op: &str) -> Result<(), DeebeeError> {
+        if let Some(rate) = self.config.max_ops_per_sec.filter(|&rate| rate > 0) {
+            let now = Instant::now();
+            // threads take the slots in turn, then wait for theirs unlocked
+            let slot = {
+                let mut next_slot = self
+                    .next_slot
+                    .lock()
+                    .expect("chaos never panics holding it");
+                let slot = (*next_slot).max(now);
+                *next_slot = slot + Duration::from_secs_f64(1.0 / rate as f64);
+                slot
+            };
+            thread::sleep(slot - now);
+        }
+
+        let jitter = match self.config.latency_jitter_ms {
+            Some(jitter) if jitter > 0 => self.next_random() % (jitter + 1),
+            _ => 0,
+        };
+        let latency = self.config.latency_ms.unwrap_or(0) + jitter;
+        if latency > 0 {
+            thread::sleep(Duration::from_millis(latency));
+        }
+
+        if let Some(rate) = self.config.error_rate
+            && self.next_fraction() < rate
+        {
+            return Err(io::Error::other(format!("chaos: injected {op} failure")).into());
+        }
+        Ok(())
+    }
+
+    fn next_random(&self) -> u64 {
+        let step = |mut x: u64| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        let previous = self
+            .rng
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(step(x)))
+            .expect("the update always succeeds");
+        step(previous)
+    }
+
+    /// uniform in [0, 1)
+    fn next_fraction(&self) -> f64 {
+        (self.next_random() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
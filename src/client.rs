@@ -0,0 +1,210 @@
+//! clients of a running server
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::error::{DeebeeError, KeyError};
+
+/// talks to a `Protocol::Line` server in tracking mode and keeps the values
+/// it read, misses included. the server says when one of them changes and
+/// the cached copy is dropped, so a `get` of a key read before only goes to
+/// the server again once someone wrote it. a write that happened but whose
+/// invalidation is still on the way can be missed for that long. values go
+/// both ways with their CRC32, a set the server got mangled isn't written
+/// and a read that doesn't match isn't cached
+pub struct CachedClient {
+    out: BufWriter<TcpStream>,
+    replies: Receiver<io::Result<String>>,
+    shared: Arc<Mutex<Shared>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    cache: HashMap<String, Option<String>>,
+    /// the commands waiting for their reply, oldest first. a `GET` names its
+    /// key, its reply goes into the cache before anything after it is read
+    pending: VecDeque<Option<String>>,
+    hits: u64,
+}
+
+impl CachedClient {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self, DeebeeError> {
+        let stream = TcpStream::connect(addr)?;
+        let read_half = stream.try_clone()?;
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let (reply_tx, replies) = mpsc::channel();
+
+        let reader_shared = shared.clone();
+        let reader = thread::spawn(move || {
+            let mut lines = BufReader::new(read_half).lines();
+            while let Some(line) = lines.next() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        let _ = reply_tx.send(Err(e));
+                        return;
+                    }
+                };
+                let mut shared = reader_shared.lock().expect("nothing panics holding it");
+                if let Some(key) = line.strip_prefix("INVALIDATE ") {
+                    shared.cache.remove(key);
+                    continue;
+                }
+                let get = shared.pending.pop_front().flatten();
+                let mut reply = line;
+                if let Some(count) = reply.strip_prefix("ARRAY ") {
+                    // the client doesn't send KEYS, but the lines still belong together
+                    let count: usize = count.parse().unwrap_or_default();
+                    for line in lines.by_ref().take(count) {
+                        reply.push('\n');
+                        reply.push_str(&line.unwrap_or_default());
+                    }
+                }
+                if let Some(key) = get {
+                    if let Some(Ok(value)) = Self::checked(&key, &reply) {
+                        shared.cache.insert(key, Some(value));
+                    } else if reply == "NIL" {
+                        shared.cache.insert(key, None);
+                    }
+                }
+                drop(shared);
+                if reply_tx.send(Ok(reply)).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut client = Self {
+            out: BufWriter::new(stream),
+            replies,
+            shared,
+            reader: Some(reader),
+        };
+        match client.command("TRACKING ON".to_string(), None)?.as_str() {
+            "OK" => Ok(client),
+            reply => Err(Self::refused(reply)),
+        }
+    }
+
+    /// the cached value when there is one, otherwise the server's
+    pub fn get(&mut self, key: &str) -> Result<Option<String>, DeebeeError> {
+        Self::check_key(key)?;
+        {
+            let mut shared = self.lock();
+            if let Some(value) = shared.cache.get(key).cloned() {
+                shared.hits += 1;
+                return Ok(value);
+            }
+        }
+        let reply = self.command(format!("GETCRC {key}"), Some(key))?;
+        match reply.as_str() {
+            "NIL" => Ok(None),
+            _ => match Self::checked(key, &reply) {
+                Some(value) => value.map(Some),
+                None => Err(Self::refused(&reply)),
+            },
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), DeebeeError> {
+        Self::check_key(key)?;
+        if value.contains(['\n', '\r']) {
+            return Err(DeebeeError::InvalidValue(
+                "values sent over the line protocol can't have line breaks".to_string(),
+            ));
+        }
+        // the server's invalidation would drop it too, this way it's gone
+        // before the reply
+        self.lock().cache.remove(key);
+        let checksum = crc32fast::hash(value.as_bytes());
+        let line = format!("SETCRC {key} {checksum:08x} {value}");
+        match self.command(line, None)?.as_str() {
+            "OK" => Ok(()),
+            reply => Err(Self::refused(reply)),
+        }
+    }
+
+    /// returns whether the key existed
+    pub fn delete(&mut self, key: &str) -> Result<bool, DeebeeError> {
+        Self::check_key(key)?;
+        self.lock().cache.remove(key);
+        match self.command(format!("DEL {key}"), None)?.as_str() {
+            "OK" => Ok(true),
+            "NIL" => Ok(false),
+            reply => Err(Self::refused(reply)),
+        }
+    }
+
+    /// keys with a cached value or miss
+    pub fn cached(&self) -> usize {
+        self.lock().cache.len()
+    }
+
+    /// gets answered from the cache
+    pub fn hits(&self) -> u64 {
+        self.lock().hits
+    }
+
+    /// send the line and wait for its reply
+    fn command(&mut self, line: String, get: Option<&str>) -> Result<String, DeebeeError> {
+        self.lock().pending.push_back(get.map(str::to_string));
+        writeln!(self.out, "{line}")?;
+        self.out.flush()?;
+        match self.replies.recv() {
+            Ok(reply) => Ok(reply?),
+            Err(_) => Err(DeebeeError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the server hung up",
+            ))),
+        }
+    }
+
+    /// the value of a `CHECKED <crc> <value>` reply, `None` for any other
+    /// reply and `Corruption` when the value doesn't match its checksum
+    fn checked(key: &str, reply: &str) -> Option<Result<String, DeebeeError>> {
+        let (checksum, value) = reply.strip_prefix("CHECKED ")?.split_once(' ')?;
+        let checksum = crate::server::parse_checksum(checksum).ok()?;
+        if crc32fast::hash(value.as_bytes()) != checksum {
+            return Some(Err(DeebeeError::Corruption(format!(
+                "the value of {key} doesn't match its checksum {checksum:08x}, it changed on the way"
+            ))));
+        }
+        Some(Ok(value.to_string()))
+    }
+
+    // the line protocol splits a command at its spaces
+    fn check_key(key: &str) -> Result<(), DeebeeError> {
+        if key.is_empty() {
+            return Err(DeebeeError::InvalidArgument("the key is empty".to_string()));
+        }
+        match key.chars().find(|c| [' ', '\n', '\r'].contains(c)) {
+            Some(c) => Err(KeyError::InvalidChar(c).into()),
+            None => Ok(()),
+        }
+    }
+
+    fn refused(reply: &str) -> DeebeeError {
+        let message = reply.strip_prefix("ERR ").unwrap_or(reply);
+        DeebeeError::InvalidArgument(format!("the server refused: {message}"))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Shared> {
+        self.shared.lock().expect("nothing panics holding it")
+    }
+}
+
+impl Drop for CachedClient {
+    fn drop(&mut self) {
+        let _ = writeln!(self.out, "QUIT").and_then(|()| self.out.flush());
+        // wakes the reader up if the server doesn't hang up first
+        let _ = self.out.get_ref().shutdown(Shutdown::Both);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
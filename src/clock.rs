@@ -0,0 +1,62 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// where the engine gets wall-clock time from, for timestamps it records.
+/// swap it out to freeze time in tests or to plug in a hybrid logical clock.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// seconds since the unix epoch
+    fn unix_secs(&self) -> u64 {
+        self.now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+}
+
+/// the default clock, reads the system time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// a clock that only moves when told to. clones share the same time, so a test
+/// can keep one and hand another to the database.
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock {
+    // milliseconds since the unix epoch
+    now_ms: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    /// frozen at the given time
+    pub fn new(now: SystemTime) -> Self {
+        let clock = Self::default();
+        clock.set(now);
+        clock
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        let ms = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        self.now_ms.store(ms, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now_ms
+            .fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.now_ms.load(Ordering::SeqCst))
+    }
+}
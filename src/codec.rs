@@ -0,0 +1,92 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+/// an embedder's own ordering of keys, e.g. semver keys with `1.10.0` after
+/// `1.9.0`, along with which keys it can order at all. registered with
+/// `DatabaseOptions::key_codec` and recorded by name in the database's
+/// MANIFEST, so the database never opens again under a different ordering.
+/// the index stays in byte order, so a listing under a codec collects the
+/// keys it covers and sorts them, O(n log n) each time with a `&str` kept
+/// per key, where byte order walks the index as it is
+pub trait KeyCodec: Send + Sync {
+    /// what the MANIFEST records, it has to stay the same across releases.
+    /// a built-in `Collation`'s name stands for that collation
+    fn name(&self) -> &str;
+
+    /// the order exports, scans, key listings and compacted segments come
+    /// back in
+    fn compare(&self, a: &str, b: &str) -> Ordering;
+
+    /// refuse keys the ordering has no place for, checked on every write and
+    /// against the existing keys when the codec is first registered
+    fn check(&self, _key: &str) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// the orderings deebee knows by itself, chosen for a database with
+/// `DatabaseOptions::collation` when it's created. the MANIFEST records them
+/// like any key codec, later opens pick them up without registering anything.
+/// another ordering is a `KeyCodec` of the embedder's
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Collation {
+    /// by key bytes, what a database without a codec does too
+    #[default]
+    Bytewise,
+    /// `A` and `a` side by side, keys equal but for ASCII case ordered by
+    /// their bytes. they're still different keys
+    AsciiCaseInsensitive,
+}
+
+impl Collation {
+    /// the built-in collation a MANIFEST names, if it names one
+    pub(crate) fn by_name(name: &str) -> Option<Self> {
+        name.parse().ok()
+    }
+}
+
+impl std::str::FromStr for Collation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bytewise" => Ok(Collation::Bytewise),
+            "ascii-case-insensitive" => Ok(Collation::AsciiCaseInsensitive),
+            _ => Err(format!(
+                "unknown collation {s:?}, expected bytewise or ascii-case-insensitive"
+            )),
+        }
+    }
+}
+
+impl KeyCodec for Collation {
+    fn name(&self) -> &str {
+        match self {
+            Collation::Bytewise => "bytewise",
+            Collation::AsciiCaseInsensitive => "ascii-case-insensitive",
+        }
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        match self {
+            Collation::Bytewise => a.cmp(b),
+            Collation::AsciiCaseInsensitive => {
+                let lower = u8::to_ascii_lowercase;
+                a.bytes()
+                    .map(|byte| lower(&byte))
+                    .cmp(b.bytes().map(|byte| lower(&byte)))
+                    .then_with(|| a.cmp(b))
+            }
+        }
+    }
+}
+
+/// a registered codec, shows up by name in debug output
+#[derive(Clone)]
+pub(crate) struct RegisteredCodec(pub(crate) Arc<dyn KeyCodec>);
+
+impl std::fmt::Debug for RegisteredCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "KeyCodec({})", self.0.name())
+    }
+}
@@ -0,0 +1,405 @@
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::background::{Background, COMPACTION, Foreground};
+use crate::blob;
+use crate::cancel::WriteOptions;
+use crate::codec::KeyCodec;
+use crate::compress::{self, Compression};
+use crate::error::DeebeeError;
+use crate::merge::{self, MergeOperator};
+use crate::segment::{self, Record, RecordEncoding, RecordFlags, segment_records};
+
+pub(crate) type MergeResult = Result<MergedSegments, DeebeeError>;
+
+/// how often a bounded wait for a background merge looks at its bounds,
+/// a cancel token can't wake it up
+const BOUNDED_WAIT_STEP: Duration = Duration::from_millis(10);
+
+/// records a background merge handles between its pauses for foreground
+/// gets and sets
+const YIELD_EVERY: usize = 256;
+
+/// what happens to a record a compaction carries over
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterDecision {
+    Keep,
+    /// gone from the database, as if deleted
+    Drop,
+    /// kept under the same key with this value instead
+    Rewrite(String),
+}
+
+/// an embedder's say over what compactions keep, e.g. to strip fields from
+/// old values. registered with `DatabaseOptions::compaction_filter`, it sees
+/// the latest value of every key in the merged segments, on the compaction
+/// thread. records in the active segment wait for a later compaction
+pub trait CompactionFilter: Send + Sync {
+    fn filter(&self, key: &str, value: &str) -> FilterDecision;
+
+    /// what compactions call, for values that aren't UTF-8 as well. those
+    /// are kept unless the filter sees to them here
+    fn filter_bytes(&self, key: &str, value: &[u8]) -> FilterDecision {
+        match std::str::from_utf8(value) {
+            Ok(value) => self.filter(key, value),
+            Err(_) => FilterDecision::Keep,
+        }
+    }
+}
+
+/// a registered filter, debug output can't show more than that it's there
+#[derive(Clone)]
+pub(crate) struct RegisteredFilter(pub(crate) Arc<dyn CompactionFilter>);
+
+impl std::fmt::Debug for RegisteredFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CompactionFilter")
+    }
+}
+
+/// what a merge does besides keeping the latest record of every key
+#[derive(Clone, Default)]
+pub(crate) struct MergeSettings {
+    pub(crate) filter: Option<Arc<dyn CompactionFilter>>,
+    /// values at least this long that the merge finds under several keys
+    /// are stored once in the blob area, `None` keeps every value inline
+    pub(crate) dedup_min_bytes: Option<usize>,
+    /// checked before each segment and record the merge reads and before
+    /// the output is written, a merge that stops leaves no output behind
+    pub(crate) bounds: WriteOptions,
+    /// the order the output is written in, key bytes without one
+    pub(crate) key_codec: Option<Arc<dyn KeyCodec>>,
+    /// folds merge operands into the values they apply to. without one a
+    /// key's value and its operands are written out as they are, and the
+    /// filter doesn't see them
+    pub(crate) operator: Option<Arc<dyn MergeOperator>>,
+    /// the gets and sets a background merge pauses behind, `None` for a
+    /// merge someone is waiting on
+    pub(crate) foreground: Option<Foreground>,
+    /// how the output is written, records as they are by default
+    pub(crate) compression: Compression,
+}
+
+/// sealed segments merged into a temp file, waiting to be swapped in for them
+pub(crate) struct MergedSegments {
+    /// the segments the merge replaces, oldest first
+    pub(crate) sealed: Vec<String>,
+    pub(crate) tmp_path: String,
+    pub(crate) records_kept: usize,
+    pub(crate) records_dropped: usize,
+    pub(crate) records_rewritten: usize,
+    /// references to the blob area in the output, by blob
+    pub(crate) blob_refs: BTreeMap<String, usize>,
+    pub(crate) bytes_before: u64,
+    pub(crate) bytes_after: u64,
+    pub(crate) duration_ms: u64,
+}
+
+/// write the latest record of every key in the sealed segments of the
+/// database in `dir` to `tmp_path`, in the database's key order. keys whose
+/// latest record is a tombstone are dropped, every older record of them is in
+/// the merge too, which is also why the filter can drop keys and merge operands
+/// can be folded into the value they apply to. sealed segments are never
+/// written again, so this can run next to writes to the active segment.
+pub(crate) fn merge_segments(
+    dir: &Path,
+    sealed: Vec<String>,
+    tmp_path: String,
+    encoding: RecordEncoding,
+    settings: &MergeSettings,
+) -> MergeResult {
+    let started = Instant::now();
+    let steps = Cell::new(0usize);
+    let check = || {
+        if let Some(foreground) = &settings.foreground
+            && steps.get().is_multiple_of(YIELD_EVERY)
+        {
+            foreground.yield_to(COMPACTION);
+        }
+        steps.set(steps.get() + 1);
+        settings.bounds.check()
+    };
+    let contents = sealed
+        .iter()
+        .map(|path| check().and_then(|()| Ok(compress::read_segment(path)?)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut latest = BTreeMap::new();
+    // the merge operands after each key's latest value, oldest first
+    let mut operands: BTreeMap<Cow<str>, Vec<Cow<[u8]>>> = BTreeMap::new();
+    for content in &contents {
+        for (_, record) in segment_records(content, encoding) {
+            check()?;
+            if record.is_tombstone() {
+                latest.remove(&record.key);
+                operands.remove(&record.key);
+            } else if record.flags.contains(RecordFlags::OPERAND) {
+                operands.entry(record.key).or_default().push(record.value);
+            } else {
+                operands.remove(&record.key);
+                latest.insert(record.key, (record.value, record.flags));
+            }
+        }
+    }
+    // values shared by an earlier merge, the filter sees them like any
+    // other and they're only shared again if they still are. whether the key
+    // burns after read and the checksum a client sent for the value, checked
+    // here, carry over to its record in the output
+    let mut latest: BTreeMap<Cow<str>, (Cow<[u8]>, RecordFlags)> = latest
+        .into_iter()
+        .map(|(key, (value, flags))| {
+            check()?;
+            let value = match flags.contains(RecordFlags::CHECKSUM) {
+                true => segment::checked_value(&key, value)?,
+                false => blob::resolve(dir, value, flags)?,
+            };
+            Ok((key, (value, flags.without(RecordFlags::BLOB))))
+        })
+        .collect::<Result<_, DeebeeError>>()?;
+
+    // a merge writes the key, it doesn't burn after read any more
+    let mut unfolded = BTreeMap::new();
+    for (key, operands) in operands {
+        check()?;
+        let base = latest.remove(&key).map(|(value, _)| value);
+        match &settings.operator {
+            Some(operator) => {
+                let operands = operands.into_iter().map(Cow::into_owned).collect();
+                let base = base.map(Cow::into_owned);
+                if let Some(value) = merge::fold(Some(&**operator), &key, base, operands)? {
+                    latest.insert(key, (Cow::Owned(value), RecordFlags::NONE));
+                }
+            }
+            None => {
+                unfolded.insert(key, (base, operands));
+            }
+        }
+    }
+
+    let (mut dropped, mut rewritten) = (0, 0);
+    if let Some(filter) = &settings.filter {
+        let mut kept = BTreeMap::new();
+        for (key, (value, flags)) in latest {
+            check()?;
+            match filter.filter_bytes(&key, &value) {
+                FilterDecision::Keep => {
+                    kept.insert(key, (value, flags));
+                }
+                FilterDecision::Drop => dropped += 1,
+                FilterDecision::Rewrite(value) => {
+                    if !encoding.can_encode(&key, &value) {
+                        return Err(DeebeeError::InvalidValue(format!(
+                            "the compaction filter rewrote {key} to a value the segments can't hold"
+                        )));
+                    }
+                    rewritten += 1;
+                    // the client's checksum was for the value it sent
+                    let flags = flags.without(RecordFlags::CHECKSUM);
+                    kept.insert(key, (Cow::Owned(value.into_bytes()), flags));
+                }
+            }
+        }
+        latest = kept;
+    }
+
+    // blobs stored from here on are referred to by the output, so the last
+    // chance to stop with nothing left behind
+    check()?;
+    let mut blob_refs = BTreeMap::new();
+    let mut shared_bytes = 0;
+    // the hash of every value stored in the blob area
+    let mut shared: HashMap<&[u8], String> = HashMap::new();
+    if let Some(min_bytes) = settings.dedup_min_bytes {
+        let mut keys_per_value: HashMap<&[u8], usize> = HashMap::new();
+        // the client's checksum stays next to a value, those aren't shared
+        for (value, _) in latest.values().filter(|(value, flags)| {
+            value.len() >= min_bytes && !flags.contains(RecordFlags::CHECKSUM)
+        }) {
+            *keys_per_value.entry(value).or_default() += 1;
+        }
+        // stored before the output is written, a record never refers to a
+        // blob that isn't there yet
+        for (&value, &keys) in &keys_per_value {
+            if keys > 1 {
+                let hash = blob::store(dir, value)?;
+                blob_refs.insert(hash.clone(), keys);
+                shared.insert(value, hash);
+                shared_bytes += value.len() as u64;
+            }
+        }
+    }
+
+    let mut records: Vec<Record> = latest
+        .iter()
+        .map(|(key, (value, flags))| {
+            if flags.contains(RecordFlags::CHECKSUM) {
+                let checksummed = Record::checksummed(key, value, crc32fast::hash(value));
+                return Record {
+                    flags: *flags,
+                    ..checksummed
+                };
+            }
+            match shared.get(value.as_ref()) {
+                Some(hash) => Record {
+                    key: Cow::Borrowed(key),
+                    value: Cow::Borrowed(hash.as_bytes()),
+                    flags: RecordFlags::BLOB | *flags,
+                },
+                None => Record {
+                    flags: *flags,
+                    ..Record::new(key, value)
+                },
+            }
+        })
+        .collect();
+    for (key, (base, operands)) in &unfolded {
+        records.extend(base.iter().map(|value| Record::new(key, value)));
+        records.extend(operands.iter().map(|operand| Record {
+            flags: RecordFlags::OPERAND,
+            ..Record::new(key, operand)
+        }));
+    }
+    // stable, a key's operands stay after its value
+    match &settings.key_codec {
+        Some(codec) => records.sort_by(|a, b| codec.compare(&a.key, &b.key)),
+        None => records.sort_by(|a, b| a.key.cmp(&b.key)),
+    }
+    let mut merged = Vec::new();
+    for record in &records {
+        merged.extend_from_slice(&encoding.encode_record(record));
+    }
+
+    let written = match settings.compression {
+        Compression::None => merged,
+        Compression::Deflate => compress::compress(&merged, encoding),
+    };
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(&written)?;
+    tmp.sync_all()?;
+    // what the files take up, compressed or not
+    let bytes_before = sealed
+        .iter()
+        .map(|path| Ok(fs::metadata(path)?.len()))
+        .sum::<Result<u64, DeebeeError>>()?;
+
+    Ok(MergedSegments {
+        records_kept: latest.len() + unfolded.len(),
+        records_dropped: dropped,
+        records_rewritten: rewritten,
+        blob_refs,
+        bytes_before,
+        bytes_after: written.len() as u64 + shared_bytes,
+        duration_ms: started.elapsed().as_millis() as u64,
+        sealed,
+        tmp_path,
+    })
+}
+
+/// merges running on the background pool, one at a time
+pub(crate) struct Compactor {
+    dir: PathBuf,
+    tmp_path: String,
+    // the running merge's result, a merge that panicked drops its sender.
+    // behind a mutex only so the database handle can be shared between
+    // threads, it's always reached through `&mut self`
+    pending: Mutex<Option<Receiver<MergeResult>>>,
+}
+
+impl Compactor {
+    /// merges the sealed segments of the database in `dir` into `tmp_path`
+    pub(crate) fn new(dir: PathBuf, tmp_path: String) -> Self {
+        Self {
+            dir,
+            tmp_path,
+            pending: Mutex::new(None),
+        }
+    }
+
+    /// start merging the given sealed segments, ignored while a merge is
+    /// running or when the compaction task is switched off. returns whether
+    /// it started
+    pub(crate) fn submit(
+        &mut self,
+        background: &Background,
+        sealed: Vec<String>,
+        encoding: RecordEncoding,
+        settings: MergeSettings,
+    ) -> bool {
+        if self.pending().is_some() {
+            return false;
+        }
+        let settings = MergeSettings {
+            foreground: Some(background.foreground().clone()),
+            ..settings
+        };
+        let (dir, tmp_path) = (self.dir.clone(), self.tmp_path.clone());
+        let (result_tx, result) = mpsc::channel();
+        let started = background.run(COMPACTION, move || {
+            let merged = merge_segments(&dir, sealed, tmp_path, encoding, &settings);
+            let outcome = merged.as_ref().map(|_| ()).map_err(|e| e.to_string());
+            // the database handle may be gone, nobody to hand the merge to then
+            let _ = result_tx.send(merged);
+            outcome
+        });
+        if started {
+            *self.pending() = Some(result);
+        }
+        started
+    }
+
+    /// the finished merge, if there is one, without waiting
+    pub(crate) fn try_finished(&mut self) -> Option<MergeResult> {
+        let pending = self.pending();
+        let result = match pending.as_ref()?.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => None,
+        };
+        *pending = None;
+        result
+    }
+
+    /// wait for the running merge, if there is one
+    pub(crate) fn wait(&mut self) -> Option<MergeResult> {
+        self.pending().take()?.recv().ok()
+    }
+
+    /// `wait` within the bounds. a merge still running when they're crossed
+    /// keeps going and is swapped in later, like one nobody waited for
+    pub(crate) fn wait_within(
+        &mut self,
+        bounds: &WriteOptions,
+    ) -> Result<Option<MergeResult>, DeebeeError> {
+        loop {
+            bounds.check()?;
+            let pending = self.pending();
+            let Some(result) = pending.as_ref() else {
+                return Ok(None);
+            };
+            match result.recv_timeout(BOUNDED_WAIT_STEP) {
+                Ok(result) => {
+                    *pending = None;
+                    return Ok(Some(result));
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    *pending = None;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn pending(&mut self) -> &mut Option<Receiver<MergeResult>> {
+        self.pending
+            .get_mut()
+            .expect("nothing panics holding the results")
+    }
+}
@@ -0,0 +1,202 @@
+//! sealed segments compressed in DEFLATE blocks, written by compactions of
+//! a database with `compression = "deflate"` in its deebee.toml. the file
+//! starts with `MAGIC`, then come the blocks, each one holding whole records
+//! and about `BLOCK_BYTES` of them, then a table of how long every block is
+//! before and after compression, the number of blocks and a CRC32 of the
+//! table. offsets in the index and the hints are into the records as they
+//! were before compression, a read inflates the one block its record is in.
+//! the active segment is never compressed, and neither is anything a
+//! database at a format version before 6 writes
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use serde::{Deserialize, Serialize};
+
+use crate::gzip;
+use crate::segment::{RecordEncoding, sized_records};
+
+/// no segment of records starts like this, the first would need a key over
+/// a gigabyte long
+const MAGIC: &[u8; 8] = b"\xffDEEBEEZ";
+
+/// records a block holds before it's closed, a longer record gets a block of
+/// its own
+const BLOCK_BYTES: usize = 16 << 10;
+
+// block count u32, CRC32 of the table u32
+const TRAILER: usize = 8;
+
+// bytes of records u64, bytes compressed u64
+const TABLE_ENTRY: usize = 16;
+
+/// how compactions write their output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Compression {
+    /// records as they are
+    #[default]
+    None,
+    /// DEFLATE blocks, see the module docs
+    Deflate,
+}
+
+/// the segment records as a compressed segment
+pub(crate) fn compress(records: &[u8], encoding: RecordEncoding) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    let mut table = Vec::new();
+    let mut push = |block: &[u8]| {
+        let deflated = gzip::deflate(block);
+        table.extend_from_slice(&(block.len() as u64).to_le_bytes());
+        table.extend_from_slice(&(deflated.len() as u64).to_le_bytes());
+        out.extend_from_slice(&deflated);
+    };
+    let mut start = 0;
+    for (offset, len, _) in sized_records(records, encoding) {
+        let end = offset as usize + len;
+        if end - start >= BLOCK_BYTES {
+            push(&records[start..end]);
+            start = end;
+        }
+    }
+    // a damaged record and whatever follows it stay in, like they do in a
+    // segment that isn't compressed
+    if start < records.len() {
+        push(&records[start..]);
+    }
+
+    let blocks = (table.len() / TABLE_ENTRY) as u32;
+    out.extend_from_slice(&table);
+    out.extend_from_slice(&blocks.to_le_bytes());
+    out.extend_from_slice(&crc32fast::hash(&table).to_le_bytes());
+    out
+}
+
+/// where the blocks of a compressed segment are
+#[derive(Debug)]
+pub(crate) struct Blocks {
+    /// the offset into the records and into the file each block starts at,
+    /// then the ones the last block ends at
+    starts: Vec<(u64, u64)>,
+}
+
+impl Blocks {
+    /// the blocks of the segment the reader is on, `None` when it isn't
+    /// compressed
+    pub(crate) fn load<R: Read + Seek>(reader: &mut R) -> io::Result<Option<Self>> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        if len < (MAGIC.len() + TRAILER) as u64 {
+            return Ok(None);
+        }
+        let mut magic = [0; MAGIC.len()];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Ok(None);
+        }
+
+        let mut trailer = [0; TRAILER];
+        reader.seek(SeekFrom::End(-(TRAILER as i64)))?;
+        reader.read_exact(&mut trailer)?;
+        let blocks = u32::from_le_bytes(trailer[..4].try_into().unwrap()) as u64;
+        let crc = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+        let table_start = (len - TRAILER as u64)
+            .checked_sub(blocks * TABLE_ENTRY as u64)
+            .filter(|&start| start >= MAGIC.len() as u64)
+            .ok_or_else(|| damaged("its block table is longer than the segment"))?;
+        let mut table = vec![0; (blocks as usize) * TABLE_ENTRY];
+        reader.seek(SeekFrom::Start(table_start))?;
+        reader.read_exact(&mut table)?;
+        if crc32fast::hash(&table) != crc {
+            return Err(damaged("its block table fails its checksum"));
+        }
+
+        let mut starts = Vec::with_capacity(blocks as usize + 1);
+        let (mut records, mut at) = (0, MAGIC.len() as u64);
+        for entry in table.chunks_exact(TABLE_ENTRY) {
+            starts.push((records, at));
+            records += u64::from_le_bytes(entry[..8].try_into().unwrap());
+            at += u64::from_le_bytes(entry[8..].try_into().unwrap());
+        }
+        if at != table_start {
+            return Err(damaged("its blocks don't add up to the segment"));
+        }
+        starts.push((records, at));
+        Ok(Some(Self { starts }))
+    }
+
+    /// bytes of records the segment holds before compression
+    pub(crate) fn records_len(&self) -> u64 {
+        self.starts.last().map_or(0, |&(records, _)| records)
+    }
+
+    /// the records of the block holding the offset, and the offset the block
+    /// starts at. `None` past the last record
+    fn block_at<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        offset: u64,
+    ) -> io::Result<Option<(Vec<u8>, u64)>> {
+        let next = self
+            .starts
+            .partition_point(|&(records, _)| records <= offset);
+        if next == 0 || next == self.starts.len() {
+            return Ok(None);
+        }
+        self.block(reader, next - 1).map(Some)
+    }
+
+    fn block<R: Read + Seek>(&self, reader: &mut R, i: usize) -> io::Result<(Vec<u8>, u64)> {
+        let ((records, at), (records_end, end)) = (self.starts[i], self.starts[i + 1]);
+        let mut deflated = vec![0; (end - at) as usize];
+        reader.seek(SeekFrom::Start(at))?;
+        reader.read_exact(&mut deflated)?;
+        Ok((
+            gzip::inflate(&deflated, (records_end - records) as usize)?,
+            records,
+        ))
+    }
+
+    /// every record of the segment, as it was before compression
+    pub(crate) fn read_all<R: Read + Seek>(&self, reader: &mut R) -> io::Result<Vec<u8>> {
+        let mut records = Vec::with_capacity(self.records_len() as usize);
+        for i in 0..self.starts.len() - 1 {
+            records.extend_from_slice(&self.block(reader, i)?.0);
+        }
+        Ok(records)
+    }
+}
+
+/// the records of a segment, compressed or not
+pub(crate) fn read_segment(path: &str) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    if let Some(blocks) = Blocks::load(&mut file)? {
+        return blocks.read_all(&mut file);
+    }
+    let mut content = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// the raw bytes of the record at the offset into a compressed segment's
+/// records, empty past the last one like `RecordEncoding::read_record`
+pub(crate) fn read_record<R: Read + Seek>(
+    reader: &mut R,
+    blocks: &Blocks,
+    offset: u64,
+    encoding: RecordEncoding,
+) -> io::Result<Vec<u8>> {
+    let Some((block, start)) = blocks.block_at(reader, offset)? else {
+        return Ok(Vec::new());
+    };
+    let mut rest = &block[(offset - start) as usize..];
+    encoding.read_record(&mut rest)
+}
+
+fn damaged(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("compressed segment can't be read, {reason}"),
+    )
+}
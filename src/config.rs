@@ -0,0 +1,724 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::acl::Role;
+use crate::background::BackgroundConfig;
+use crate::codec::{Collation, KeyCodec, RegisteredCodec};
+use crate::compaction::{CompactionFilter, RegisteredFilter};
+use crate::compress::Compression;
+use crate::error::{DeebeeError, KeyError};
+use crate::maintenance::MaintenanceWindow;
+use crate::manifest::Manifest;
+use crate::merge::{MergeOperator, RegisteredOperator};
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub(crate) struct ConfigFile {
+    /// where every database gets its `<db-name>/` directory, the current
+    /// directory unless set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) data_dir: Option<String>,
+    /// defaults for how databases are opened, CLI flags can tighten them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) open_options: Option<DatabaseOptions>,
+    /// settings for every database that doesn't set its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) defaults: Option<DatabaseDefaults>,
+    #[serde(default)]
+    pub(crate) databases: Vec<DatabaseConfig>,
+}
+
+/// the `[defaults]` table, same meaning as the database settings of the
+/// same name
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub(crate) struct DatabaseDefaults {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) segment_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) cache_bytes: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) compaction: Option<CompactionPolicy>,
+}
+
+/// how a database gets opened, checked before any file is touched
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DatabaseOptions {
+    pub(crate) create_if_missing: bool,
+    pub(crate) read_only: bool,
+    pub(crate) sync: SyncPolicy,
+    pub(crate) epoch: Option<u64>,
+    pub(crate) verify: VerifyLevel,
+    pub(crate) lock: bool,
+    #[serde(skip)]
+    pub(crate) key_codec: Option<RegisteredCodec>,
+    #[serde(skip)]
+    pub(crate) compaction_filter: Option<RegisteredFilter>,
+    #[serde(skip)]
+    pub(crate) merge_operator: Option<RegisteredOperator>,
+    #[serde(skip)]
+    pub(crate) root: PathBuf,
+    // win over the deebee.toml settings of the same name for this handle,
+    // and are never saved
+    #[serde(skip)]
+    pub(crate) segment_size: Option<usize>,
+    #[serde(skip)]
+    pub(crate) cache_bytes: Option<usize>,
+    #[serde(skip)]
+    pub(crate) data_dir: Option<String>,
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> Self {
+        Self {
+            create_if_missing: true,
+            read_only: false,
+            sync: SyncPolicy::EverySec,
+            epoch: None,
+            verify: VerifyLevel::None,
+            lock: true,
+            key_codec: None,
+            compaction_filter: None,
+            merge_operator: None,
+            root: PathBuf::new(),
+            segment_size: None,
+            cache_bytes: None,
+            data_dir: None,
+        }
+    }
+}
+
+/// when writes get fsynced to the segment file
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncPolicy {
+    /// after every write, nothing acknowledged is lost in a crash
+    Always,
+    /// on the first write a second or more after the last fsync, on rotation
+    /// and on close. a crash loses about a second of writes
+    EverySec,
+    /// leave it to the OS
+    Never,
+}
+
+impl std::str::FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(SyncPolicy::Always),
+            "everysec" => Ok(SyncPolicy::EverySec),
+            "never" => Ok(SyncPolicy::Never),
+            _ => Err(format!(
+                "unknown sync policy {s:?}, expected always, everysec or never"
+            )),
+        }
+    }
+}
+
+/// how much of the segments gets checked against their checksums on open
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum VerifyLevel {
+    /// trust the files, damage shows up when a damaged record is read
+    None,
+    /// the last record of every segment, found through the hint files
+    FootersOnly,
+    /// every record of every segment
+    FullChecksum,
+}
+
+impl std::str::FromStr for VerifyLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(VerifyLevel::None),
+            "footers-only" => Ok(VerifyLevel::FootersOnly),
+            "full-checksum" => Ok(VerifyLevel::FullChecksum),
+            _ => Err(format!(
+                "unknown verify level {s:?}, expected none, footers-only or full-checksum"
+            )),
+        }
+    }
+}
+
+impl DatabaseOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// create the database (config entry and first segment) when it doesn't exist
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// reject writes and never create or modify files
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// how often writes are fsynced, `everysec` by default
+    pub fn sync(mut self, sync: SyncPolicy) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// tag writes with this epoch, they are rejected once the database is
+    /// fenced at a newer one
+    pub fn epoch(mut self, epoch: u64) -> Self {
+        self.epoch = Some(epoch);
+        self
+    }
+
+    /// check the segments before opening, `none` by default
+    pub fn verify(mut self, verify: VerifyLevel) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// hold the database's lock file while open, exclusive for writers and
+    /// shared for read-only handles, so a second writer fails fast instead of
+    /// corrupting the segments. on by default, turn it off only when
+    /// something else keeps writers apart, like fencing epochs on storage
+    /// where locks don't work
+    pub fn lock(mut self, lock: bool) -> Self {
+        self.lock = lock;
+        self
+    }
+
+    /// order and check keys with the codec. the database records its name
+    /// and won't open without a codec of that name afterwards
+    pub fn key_codec(mut self, codec: impl KeyCodec + 'static) -> Self {
+        self.key_codec = Some(RegisteredCodec(Arc::new(codec)));
+        self
+    }
+
+    /// order keys with one of the built-in collations, a key codec that
+    /// doesn't need registering again once the database recorded it
+    pub fn collation(self, collation: Collation) -> Self {
+        self.key_codec(collation)
+    }
+
+    /// run every compaction's records through the filter. it isn't recorded
+    /// anywhere, handles opened without it compact as usual
+    pub fn compaction_filter(mut self, filter: impl CompactionFilter + 'static) -> Self {
+        self.compaction_filter = Some(RegisteredFilter(Arc::new(filter)));
+        self
+    }
+
+    /// fold `Database::merge` operands with the operator. like the filter it
+    /// isn't recorded, a handle opened without it can't read keys that have
+    /// operands and compacts them as they are
+    pub fn merge_operator(mut self, operator: impl MergeOperator + 'static) -> Self {
+        self.merge_operator = Some(RegisteredOperator(Arc::new(operator)));
+        self
+    }
+
+    /// the directory holding deebee.toml, the current directory by default.
+    /// the database directories and every path deebee.toml records are
+    /// relative to it, so nothing depends on where the process happens to be
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    /// records per segment instead of the configured `segment_size`
+    pub fn segment_size(mut self, records: usize) -> Self {
+        self.segment_size = Some(records);
+        self
+    }
+
+    /// size of the value cache instead of the configured `cache_bytes`
+    pub fn cache_bytes(mut self, bytes: usize) -> Self {
+        self.cache_bytes = Some(bytes);
+        self
+    }
+
+    /// look for the database's directory here instead of the configured
+    /// `data_dir`, relative to the root
+    pub fn data_dir(mut self, data_dir: &str) -> Self {
+        self.data_dir = Some(data_dir.to_string());
+        self
+    }
+
+    /// the `[open_options]` table of the current directory's deebee.toml, or
+    /// the defaults when it has none
+    pub fn from_config() -> Result<Self, DeebeeError> {
+        Ok(Config::load(Path::new(""))?
+            .inner
+            .open_options
+            .unwrap_or_default())
+    }
+
+    /// override with the `DEEBEE_SYNC`, `DEEBEE_VERIFY`, `DEEBEE_READ_ONLY`,
+    /// `DEEBEE_EPOCH`, `DEEBEE_SEGMENT_SIZE`, `DEEBEE_CACHE_BYTES` and
+    /// `DEEBEE_DATA_DIR` environment variables, where set
+    pub fn with_env(mut self) -> Result<Self, DeebeeError> {
+        fn var<T: std::str::FromStr>(name: &str) -> Result<Option<T>, DeebeeError>
+        where
+            T::Err: std::fmt::Display,
+        {
+            match std::env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|e| DeebeeError::Config(format!("{name}: {e}"))),
+                Err(std::env::VarError::NotPresent) => Ok(None),
+                Err(e) => Err(DeebeeError::Config(format!("{name}: {e}"))),
+            }
+        }
+
+        if let Some(sync) = var("DEEBEE_SYNC")? {
+            self.sync = sync;
+        }
+        if let Some(verify) = var("DEEBEE_VERIFY")? {
+            self.verify = verify;
+        }
+        if let Some(read_only) = var("DEEBEE_READ_ONLY")? {
+            self.read_only = read_only;
+        }
+        if let Some(epoch) = var("DEEBEE_EPOCH")? {
+            self.epoch = Some(epoch);
+        }
+        if let Some(records) = var("DEEBEE_SEGMENT_SIZE")? {
+            self.segment_size = Some(records);
+        }
+        if let Some(bytes) = var("DEEBEE_CACHE_BYTES")? {
+            self.cache_bytes = Some(bytes);
+        }
+        if let Some(data_dir) = var("DEEBEE_DATA_DIR")? {
+            self.data_dir = Some(data_dir);
+        }
+        Ok(self)
+    }
+
+    /// the directory of the database, in the options' data dir when they set one
+    pub(crate) fn database_dir(&self, config: &Config, db_name: &str) -> PathBuf {
+        match &self.data_dir {
+            Some(data_dir) => self.root.join(data_dir).join(db_name),
+            None => config.database_dir(db_name),
+        }
+    }
+
+    /// the settings the options stand in for, replaced in the database's
+    /// configuration
+    pub(crate) fn override_settings(&self, mut db_config: DatabaseConfig) -> DatabaseConfig {
+        db_config.segment_size = self.segment_size.or(db_config.segment_size);
+        db_config.cache_bytes = self.cache_bytes.or(db_config.cache_bytes);
+        db_config
+    }
+
+    /// make sure the options can be honored for this database, `manifest`
+    /// being what its directory holds
+    pub(crate) fn validate(
+        &self,
+        db_name: &str,
+        existing: Option<&DatabaseConfig>,
+        dir: &Path,
+        manifest: Option<&Manifest>,
+    ) -> Result<(), DeebeeError> {
+        let Some(db_config) = existing else {
+            if self.read_only {
+                return Err(DeebeeError::Config(format!(
+                    "database {db_name} doesn't exist and can't be created read-only"
+                )));
+            }
+            if !self.create_if_missing {
+                return Err(DeebeeError::Config(format!(
+                    "database {db_name} doesn't exist and create_if_missing is off"
+                )));
+            }
+            return Ok(());
+        };
+        if let Some(background) = &db_config.background {
+            background.validate()?;
+        }
+
+        let registered = self.key_codec.as_ref().map(|codec| codec.0.name());
+        let recorded = manifest
+            .and_then(|manifest| manifest.key_codec.as_deref())
+            .or(db_config.key_codec.as_deref());
+        match (recorded, registered) {
+            (Some(recorded), None) if Collation::by_name(recorded).is_none() => {
+                return Err(DeebeeError::Config(format!(
+                    "database {db_name} orders its keys with the {recorded} key codec, register it with DatabaseOptions::key_codec"
+                )));
+            }
+            (Some(recorded), Some(registered)) if recorded != registered => {
+                return Err(DeebeeError::Config(format!(
+                    "database {db_name} orders its keys with the {recorded} key codec, not {registered}"
+                )));
+            }
+            _ => {}
+        }
+
+        let Some(manifest) = manifest else {
+            if db_config.segments_files_paths.is_empty() {
+                return Err(DeebeeError::Corruption(format!(
+                    "database {db_name} has no MANIFEST in {}",
+                    dir.display()
+                )));
+            }
+            if self.read_only {
+                return Err(DeebeeError::Config(format!(
+                    "database {db_name} still keeps its segments next to deebee.toml, open it writable once to move them into {}",
+                    dir.display()
+                )));
+            }
+            return Ok(());
+        };
+
+        if self.read_only {
+            for path in &manifest.segment_paths(dir) {
+                if !Path::new(path).exists() {
+                    return Err(DeebeeError::Corruption(format!(
+                        "segment file {path} of {db_name} is missing"
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub(crate) struct DatabaseConfig {
+    pub(crate) name: String,
+    /// where the segments were listed before every database got its own
+    /// directory and MANIFEST, only read to move them there
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) segments_files_paths: Vec<String>,
+    /// format version of those segments, the MANIFEST has it since
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) format_version: Option<u32>,
+    /// name of the embedder's key codec from before the MANIFEST recorded
+    /// it, moved there on the next writable open
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) key_codec: Option<String>,
+    /// key patterns (`*` wildcard) whose values must never show up in logs or errors
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) sensitive_keys: Vec<String>,
+    /// constraints every key has to satisfy before it is written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) key_rules: Option<KeyRules>,
+    /// path to a JSON Schema every value has to match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) json_schema: Option<String>,
+    /// write-once: existing keys can never be overwritten
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) immutable: bool,
+    /// named copies of the segments, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) snapshots: Vec<Snapshot>,
+    /// thresholds that trigger warnings, writes keep working past them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) soft_limits: Option<SoftLimits>,
+    /// records per segment before writes roll over to a new one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) segment_size: Option<usize>,
+    /// bytes of recently read keys and values kept in memory, off unless set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) cache_bytes: Option<usize>,
+    /// read the keys the cache held at the last close back into it after
+    /// opening, in the background
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) warm_cache: bool,
+    /// when to compact in the background, off unless set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) compaction: Option<CompactionPolicy>,
+    /// inject latency, errors and throttling, for resilience testing in staging
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) chaos: Option<ChaosConfig>,
+    /// keys whose values are kept in memory for as long as the database is open
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) pinned_keys: Vec<String>,
+    /// keys deleted by the first get that returns them, from before their
+    /// records were flagged so. a writer at format version 5 moves them into
+    /// the records and leaves this empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) burn_after_read: Vec<String>,
+    /// writes from handles tagged with an older epoch, or none, are rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) fence_epoch: Option<u64>,
+    /// how many threads do the background work, which tasks they skip and
+    /// how long the low priority ones pause for gets and sets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) background: Option<BackgroundConfig>,
+    /// values at least this many bytes long that compaction finds under
+    /// several keys are stored once and shared by them, off unless set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) dedup_min_bytes: Option<usize>,
+    /// `deflate` has compactions compress the segments they write, which
+    /// needs format version 6. off unless set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) compression: Option<Compression>,
+    /// when background compactions, compact and snapshots may run, any time
+    /// unless set
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) maintenance_windows: Vec<MaintenanceWindow>,
+    /// the tokens server clients authenticate with and the keys each may
+    /// use, the server is open to everyone unless set
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) roles: Vec<Role>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub(crate) struct SoftLimits {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_size_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_keys: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_segments: Option<usize>,
+}
+
+/// background compaction starts once either threshold is reached
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub(crate) struct CompactionPolicy {
+    /// number of sealed (full, no longer written) segments
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sealed_segments: Option<usize>,
+    /// share of records on disk that were overwritten or deleted, 0.0 to 1.0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) dead_ratio: Option<f64>,
+}
+
+/// every field is off unless set
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub(crate) struct ChaosConfig {
+    /// added to every read and write
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) latency_ms: Option<u64>,
+    /// up to this much more, picked at random per operation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) latency_jitter_ms: Option<u64>,
+    /// share of reads and writes that fail with an I/O error, 0.0 to 1.0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error_rate: Option<f64>,
+    /// operations past this rate wait for their turn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_ops_per_sec: Option<u32>,
+    /// makes the injected errors reproducible
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) seed: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    /// unix timestamp, seconds
+    pub created_at: u64,
+    /// how the copied records are laid out, restoring brings it back. `None`
+    /// for snapshots taken before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format_version: Option<u32>,
+    pub files: Vec<SnapshotFile>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    /// where the segment lives in the database, like every path deebee.toml
+    /// records relative to the directory holding it
+    pub segment: String,
+    /// where the snapshot keeps its copy
+    pub copy: String,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Charset {
+    #[default]
+    Any,
+    /// printable ASCII, no spaces or control characters
+    Printable,
+    /// ASCII letters and digits only
+    Alphanumeric,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub(crate) struct KeyRules {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_length: Option<usize>,
+    #[serde(default)]
+    pub(crate) charset: Charset,
+    /// characters allowed on top of the charset, e.g. ":_-"
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub(crate) extra_chars: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) required_prefix: Option<String>,
+}
+
+impl KeyRules {
+    /// check a key against the rules, returning the first violation found
+    pub(crate) fn validate(&self, key: &str) -> Result<(), KeyError> {
+        if let Some(max) = self.max_length
+            && key.len() > max
+        {
+            return Err(KeyError::TooLong {
+                len: key.len(),
+                max,
+            });
+        }
+
+        if let Some(c) = key.chars().find(|c| !self.allows(*c)) {
+            return Err(KeyError::InvalidChar(c));
+        }
+
+        if let Some(prefix) = &self.required_prefix
+            && !key.starts_with(prefix.as_str())
+        {
+            return Err(KeyError::MissingPrefix(prefix.clone()));
+        }
+
+        Ok(())
+    }
+
+    fn allows(&self, c: char) -> bool {
+        let in_charset = match self.charset {
+            Charset::Any => true,
+            Charset::Printable => c.is_ascii_graphic(),
+            Charset::Alphanumeric => c.is_ascii_alphanumeric(),
+        };
+        in_charset || self.extra_chars.contains(c)
+    }
+}
+
+pub(crate) struct Config {
+    /// the directory holding deebee.toml, empty for the current one
+    pub(crate) root: PathBuf,
+    pub(crate) inner: ConfigFile,
+}
+
+pub(crate) const CONFIG_PATH: &str = "deebee.toml";
+
+impl Config {
+    /// Load configuration from the deebee.toml in `root`
+    pub(crate) fn load(root: &Path) -> Result<Self, DeebeeError> {
+        match Self::read(root)? {
+            Some(inner) => Ok(Config {
+                root: root.to_path_buf(),
+                inner,
+            }),
+            // Create default config if it doesn't exist
+            None => Self::update(root, |_| Ok(())),
+        }
+    }
+
+    fn read(root: &Path) -> Result<Option<ConfigFile>, DeebeeError> {
+        match fs::read_to_string(root.join(CONFIG_PATH)) {
+            Ok(content) => Ok(Some(toml::from_str(&content)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// read deebee.toml, change it and save it back while holding its lock
+    /// file, so handles and processes changing it at the same time don't
+    /// undo each other's changes. returns what was saved
+    pub(crate) fn update(
+        root: &Path,
+        f: impl FnOnce(&mut Config) -> Result<(), DeebeeError>,
+    ) -> Result<Self, DeebeeError> {
+        let lock = File::create(root.join(format!("{CONFIG_PATH}.lock")))?;
+        lock.lock()?;
+        let mut config = Config {
+            root: root.to_path_buf(),
+            inner: Self::read(root)?.unwrap_or_default(),
+        };
+        f(&mut config)?;
+        config.save()?;
+        Ok(config)
+    }
+
+    /// Save configuration to deebee.toml. the new file is written aside and
+    /// renamed over the old one, a crash leaves one or the other. only
+    /// `update` calls it, with the lock held
+    fn save(&self) -> Result<(), DeebeeError> {
+        static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+
+        let toml_string = toml::to_string_pretty(&self.inner)?;
+        // unique, a crashed writer's leftover is never written through
+        let tmp_path = self.root.join(format!(
+            "{CONFIG_PATH}.{}.{}.tmp",
+            std::process::id(),
+            NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(toml_string.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, self.root.join(CONFIG_PATH))?;
+        Ok(())
+    }
+
+    /// the directory the database's segments and MANIFEST live in
+    pub(crate) fn database_dir(&self, db_name: &str) -> PathBuf {
+        match &self.inner.data_dir {
+            Some(data_dir) => self.root.join(data_dir).join(db_name),
+            None => self.root.join(db_name),
+        }
+    }
+
+    /// a path deebee.toml records, which is relative to the root
+    pub(crate) fn resolve(&self, path: &str) -> String {
+        resolve_path(&self.root, path)
+    }
+
+    /// a path the way deebee.toml records it
+    pub(crate) fn relative(&self, path: &str) -> String {
+        relative_path(&self.root, path)
+    }
+
+    /// Get database configuration by name
+    pub(crate) fn get_database(&self, db_name: &str) -> Option<&DatabaseConfig> {
+        self.inner.databases.iter().find(|db| db.name == db_name)
+    }
+
+    /// the database's configuration with `[defaults]` filling in what it
+    /// leaves unset. only for opening, what gets saved back is `get_database`
+    pub(crate) fn resolved_database(&self, db_name: &str) -> Option<DatabaseConfig> {
+        let mut db_config = self.get_database(db_name)?.clone();
+        if let Some(defaults) = &self.inner.defaults {
+            db_config.segment_size = db_config.segment_size.or(defaults.segment_size);
+            db_config.cache_bytes = db_config.cache_bytes.or(defaults.cache_bytes);
+            db_config.compaction = db_config.compaction.or(defaults.compaction.clone());
+        }
+        Some(db_config)
+    }
+
+    /// Add or update a database configuration
+    pub(crate) fn upsert_database(&mut self, db_config: DatabaseConfig) {
+        if let Some(pos) = self
+            .inner
+            .databases
+            .iter()
+            .position(|db| db.name == db_config.name)
+        {
+            self.inner.databases[pos] = db_config;
+        } else {
+            self.inner.databases.push(db_config);
+        }
+    }
+}
+
+/// `path` as recorded in the deebee.toml in `root`
+pub(crate) fn resolve_path(root: &Path, path: &str) -> String {
+    root.join(path).to_string_lossy().into_owned()
+}
+
+/// `path` the way the deebee.toml in `root` records it
+pub(crate) fn relative_path(root: &Path, path: &str) -> String {
+    match Path::new(path).strip_prefix(root) {
+        Ok(rest) => rest.to_string_lossy().into_owned(),
+        Err(_) => path.to_string(),
+    }
+}
+
+// databases registered before format versions existed only ever wrote version 1
+pub(crate) const LEGACY_FORMAT_VERSION: u32 = 1;
@@ -0,0 +1,3844 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::acl::Role;
+use crate::advise::{self, Advice, Tuning, Workload};
+use crate::background::{self, Background, Periodic};
+use crate::blob;
+use crate::cache::{self, ValueCache};
+use crate::cancel::{GetOptions, WriteOptions};
+use crate::chaos::Chaos;
+use crate::clock::{Clock, SystemClock};
+use crate::codec::{Collation, KeyCodec};
+use crate::compaction::{
+    CompactionFilter, Compactor, MergeResult, MergeSettings, MergedSegments, merge_segments,
+};
+use crate::compress::{self, Blocks, Compression};
+use crate::config::{
+    CONFIG_PATH, CompactionPolicy, Config, DatabaseConfig, DatabaseOptions, KeyRules,
+    LEGACY_FORMAT_VERSION, Snapshot, SnapshotFile, SoftLimits, SyncPolicy, VerifyLevel,
+    relative_path, resolve_path,
+};
+use crate::durable::{Durable, Unsynced};
+use crate::error::{DeebeeError, KeyError, WriteError};
+use crate::hint::{Hint, hint_path};
+use crate::index::Index;
+use crate::maintenance::MaintenanceWindow;
+use crate::manifest::{Manifest, segment_name};
+use crate::merge::{self, MergeOperator};
+use crate::metrics::{MetricsSink, NoopMetrics};
+#[cfg(feature = "mmap")]
+use crate::mmap::Mmap;
+use crate::patch::{self, Journal, PatchOp};
+use crate::segment::{
+    self, FORMAT_VERSION, Record, RecordEncoding, RecordFlags, SEGMENT_SIZE, is_reserved,
+    segment_records, sized_records, torn_tail,
+};
+use crate::stats::{
+    self, CompactionReport, OpStats, RECENT_COMPACTIONS, RecoveryProgress, RecoveryReport,
+    RestoreReport, SegmentInfo, Stats,
+};
+
+/// match a key against a glob pattern where `*` stands for any run of characters
+pub(crate) fn key_matches(pattern: &str, key: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = key.strip_prefix(first) else {
+        return false;
+    };
+
+    // no `*` at all means an exact match
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// what a record's value reads back as: a shared value looked up in the blob
+/// area, a client's checksum checked against the value and taken off
+fn resolve_value<'a>(
+    dir: &Path,
+    key: &str,
+    value: Cow<'a, [u8]>,
+    flags: RecordFlags,
+) -> Result<Cow<'a, [u8]>, DeebeeError> {
+    match flags.contains(RecordFlags::CHECKSUM) {
+        true => segment::checked_value(key, value),
+        false => blob::resolve(dir, value, flags),
+    }
+}
+
+/// a value handed out by the methods that return text
+fn into_text(key: &str, value: Vec<u8>) -> Result<String, DeebeeError> {
+    String::from_utf8(value).map_err(|_| {
+        DeebeeError::InvalidValue(format!(
+            "the value of {key} isn't UTF-8, read it with get_bytes"
+        ))
+    })
+}
+
+/// 64-bit FNV-1a over the given byte slices, stable across builds and machines
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// every file in `dir` and the directories below it, relative to `dir`
+fn files_under(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = PathBuf::from(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            files.extend(
+                files_under(&entry.path())?
+                    .into_iter()
+                    .map(|file| name.join(file)),
+            );
+        } else {
+            files.push(name);
+        }
+    }
+    Ok(files)
+}
+
+/// when a conditional set is allowed to write
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SetCondition {
+    Always,
+    /// only if the key doesn't exist (`--nx`)
+    IfAbsent,
+    /// only if the key already exists (`--xx`)
+    IfPresent,
+}
+
+/// a handle on one database: its segment files, in-memory index and settings.
+/// `SharedDatabase` hands it to several threads at once
+pub struct Database {
+    db_name: String,
+    /// the directory holding deebee.toml
+    root: PathBuf,
+    idx: Index,
+    /// oldest first, the last one is the active segment new records go to
+    segment_files_paths: Vec<String>,
+    /// `<data-dir>/<db-name>/`, holding the segments, MANIFEST, lock file,
+    /// stats sidecar and snapshots
+    dir: PathBuf,
+    /// number of the next segment file, recorded in the MANIFEST
+    next_segment: u64,
+    /// records in the active segment, it rotates once this reaches `segment_size`
+    active_records: usize,
+    segment_size: usize,
+    /// records in all segments, live or not
+    records: usize,
+    compaction_policy: Option<CompactionPolicy>,
+    /// runs background compactions, only there when a policy is configured
+    compactor: Option<Compactor>,
+    /// the threads compaction and flushing run on, none for read-only handles
+    background: Option<Background>,
+    /// why the running background merge was started
+    compaction_trigger: Option<String>,
+    /// a `set_with` or `delete_with` is running, background merges wait
+    /// for the next write to be swapped in
+    bounded_write: bool,
+    /// segment written by the last compaction in this process
+    last_compacted: Option<String>,
+    /// injected faults, only there when `[databases.chaos]` is configured
+    chaos: Option<Chaos>,
+    sensitive_keys: Vec<String>,
+    /// who the server lets in, see `acl`
+    roles: Vec<Role>,
+    key_rules: KeyRules,
+    json_schema: Option<String>,
+    soft_limits: SoftLimits,
+    /// counters accumulated by earlier processes, read from the stats sidecar
+    lifetime_stats: Stats,
+    /// counters for this process only
+    session_stats: Stats,
+    opened_at: Instant,
+    /// gets in this process, counted through `&self`
+    reads: AtomicU64,
+    metrics: Box<dyn MetricsSink>,
+    clock: Box<dyn Clock>,
+    read_only: bool,
+    sync: SyncPolicy,
+    last_sync: Instant,
+    /// a server's commit window is open, `always` leaves the fsync of its
+    /// writes to `commit_deferred`
+    deferred_sync: bool,
+    /// the writes that weren't fsynced, shared with the flush task
+    unsynced: Arc<Mutex<Unsynced>>,
+    immutable: bool,
+    format_version: u32,
+    /// epoch the handle's writes are tagged with
+    epoch: Option<u64>,
+    /// last fence epoch seen in deebee.toml, 0 when unfenced
+    fence_epoch: u64,
+    /// when deebee.toml last changed as far as the fence check knows
+    config_modified: Option<SystemTime>,
+    /// values of the pinned keys, `None` for the ones that don't exist. gets
+    /// of these never touch the disk
+    pinned: HashMap<String, Option<Vec<u8>>>,
+    /// keys set with burn-after-read that haven't been read yet, the ones
+    /// whose latest record is flagged to burn and the live `legacy_burns`
+    burn_after_read: HashSet<String>,
+    /// the keys deebee.toml lists to burn after read, from before records
+    /// were flagged. see `settle_legacy_burns`
+    legacy_burns: HashSet<String>,
+    /// the open lock file, released when the handle drops. `None` when
+    /// locking is off or a read-only handle found no lock file
+    lock: Option<File>,
+    /// values read recently, only there when `cache_bytes` is configured.
+    /// pinned keys never go through it
+    cache: Option<Arc<Mutex<ValueCache>>>,
+    /// the embedder's ordering of keys, byte order without one
+    key_codec: Option<Arc<dyn KeyCodec>>,
+    /// decides what compactions keep, the latest value of every key without one
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// folds the operands `merge` writes, they can't be read without one
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// values this long found under several keys are shared by compaction
+    dedup_min_bytes: Option<usize>,
+    /// heavy maintenance waits for one of these, empty means any time
+    maintenance_windows: Vec<MaintenanceWindow>,
+    /// how compactions write the segments they merge
+    compression: Compression,
+    /// the blocks of the sealed segments looked up so far, by path, `None`
+    /// for the ones that aren't compressed. dropped whenever the segment
+    /// list changes
+    blocks: Mutex<HashMap<String, Option<Arc<Blocks>>>>,
+    /// sealed segments mapped so far, by path. dropped whenever the segment
+    /// list changes
+    #[cfg(feature = "mmap")]
+    mapped: Mutex<HashMap<String, Arc<Mmap>>>,
+}
+
+impl Database {
+    /// open the database registered under `db_name` in the deebee.toml of the
+    /// options' root, creating it
+    /// when it doesn't exist and the options allow it. its segments are the
+    /// ones the MANIFEST in its directory lists, the index is rebuilt from
+    /// them before this returns.
+    pub fn open(db_name: &str, options: &DatabaseOptions) -> Result<Self, DeebeeError> {
+        let mut config = Config::load(&options.root)?;
+        let dir = options.database_dir(&config, db_name);
+        let manifest = Manifest::load(&dir)?;
+        options.validate(
+            db_name,
+            config.get_database(db_name),
+            &dir,
+            manifest.as_ref(),
+        )?;
+        if !options.read_only {
+            fs::create_dir_all(&dir)?;
+        }
+        // taken before anything is read, repairs and compactions included
+        let lock = match options.lock {
+            true => Self::acquire_lock(db_name, &dir, options.read_only)?,
+            false => None,
+        };
+
+        // a built-in collation orders the keys without being registered again
+        let recorded_collation = manifest
+            .as_ref()
+            .and_then(|manifest| manifest.key_codec.as_deref())
+            .or_else(|| {
+                config
+                    .get_database(db_name)
+                    .and_then(|db_config| db_config.key_codec.as_deref())
+            })
+            .and_then(Collation::by_name);
+
+        let legacy = config
+            .get_database(db_name)
+            .map(|db_config| !db_config.segments_files_paths.is_empty());
+        let mut codec_in_manifest = false;
+        let mut db = match legacy {
+            Some(legacy) => {
+                // still listed in deebee.toml, or a crash interrupted the move
+                let manifest = match legacy && !options.read_only {
+                    true => Self::move_into_dir(&mut config, db_name, &dir)?,
+                    false => manifest.expect("validate checks there is one"),
+                };
+                codec_in_manifest = manifest.key_codec.is_some();
+                let db_config = config.resolved_database(db_name).expect("it is registered");
+                let db_config = options.override_settings(db_config);
+                Self::load_from_config(
+                    db_config,
+                    dir,
+                    manifest,
+                    !options.read_only,
+                    options.verify,
+                )?
+            }
+            None => {
+                let manifest = Self::create_new(db_name, &dir)?;
+                config = Config::update(&options.root, |config| {
+                    config.upsert_database(DatabaseConfig {
+                        name: db_name.to_string(),
+                        ..Default::default()
+                    });
+                    Ok(())
+                })?;
+
+                let db_config = config
+                    .resolved_database(db_name)
+                    .expect("it was just added");
+                let db_config = options.override_settings(db_config);
+                Self::with_state(db_config, dir, manifest, Index::new(), HashSet::new(), 0)
+            }
+        };
+        db.root = options.root.clone();
+        db.read_only = options.read_only;
+        db.sync = options.sync;
+        db.epoch = options.epoch;
+        db.lock = lock;
+        db.compaction_filter = options.compaction_filter.as_ref().map(|f| f.0.clone());
+        db.merge_operator = options.merge_operator.as_ref().map(|o| o.0.clone());
+        let codec = match &options.key_codec {
+            Some(codec) => Some(codec.0.clone()),
+            None => recorded_collation.map(|collation| Arc::new(collation) as Arc<dyn KeyCodec>),
+        };
+        if let Some(codec) = codec {
+            // where databases kept it before the MANIFEST did
+            let codec_in_config = config
+                .get_database(db_name)
+                .is_some_and(|db_config| db_config.key_codec.is_some());
+            db.register_key_codec(codec, codec_in_manifest, codec_in_config)?;
+        }
+
+        // a batch the last writer journaled may not have reached the segments
+        if !db.read_only
+            && let Some(journal) = Journal::load(&db.dir)?
+        {
+            db.replay_journal(journal)?;
+        }
+        db.settle_legacy_burns()?;
+
+        if db.chaos.is_some() {
+            eprintln!(
+                "chaos mode is on for {db_name}, reads and writes may be slowed down or fail"
+            );
+        }
+
+        if !db.read_only {
+            let db_config = config.get_database(db_name);
+            let background_config = db_config
+                .and_then(|db_config| db_config.background.clone())
+                .unwrap_or_default();
+            let warm_cache = db_config.is_some_and(|db_config| db_config.warm_cache);
+            let mut periodic: Vec<(&'static str, Periodic)> = Vec::new();
+            // `never` only flushes for `set_async`
+            if db.sync != SyncPolicy::Always {
+                periodic.push((background::FLUSH, Self::flush_task(&db.unsynced)));
+            }
+            db.background = Some(Background::spawn(db_name, &background_config, periodic));
+            if warm_cache {
+                db.warm_cache();
+            }
+        }
+        if !db.read_only && db.compaction_policy.is_some() {
+            db.compactor = Some(Compactor::new(db.dir.clone(), db.compaction_tmp_path()));
+            db.poll_compaction();
+        }
+
+        Ok(db)
+    }
+
+    /// order keys with the codec from now on. one the database doesn't have
+    /// recorded yet has to accept every existing key first. the MANIFEST
+    /// records it, one still recorded in deebee.toml moves over
+    fn register_key_codec(
+        &mut self,
+        codec: Arc<dyn KeyCodec>,
+        in_manifest: bool,
+        in_config: bool,
+    ) -> Result<(), DeebeeError> {
+        if !in_manifest && !in_config {
+            for key in self.idx.scan_prefix("").map(|(key, _)| key) {
+                if let Err(reason) = codec.check(key) {
+                    return Err(DeebeeError::Config(format!(
+                        "the {} key codec rejects the existing key {key}: {reason}",
+                        codec.name()
+                    )));
+                }
+            }
+        }
+        self.key_codec = Some(codec);
+        // a read-only handle orders with it but leaves the files alone
+        if !in_manifest && !self.read_only {
+            self.save_manifest(&self.segment_files_paths, self.format_version)?;
+            if in_config {
+                self.update_config(|db_config| {
+                    db_config.key_codec = None;
+                    Ok(())
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// start the database's directory with an empty first segment. a
+    /// MANIFEST already there belongs to a database deebee.toml doesn't list,
+    /// and is left alone
+    fn create_new(db_name: &str, dir: &Path) -> Result<Manifest, DeebeeError> {
+        if Manifest::exists(dir) {
+            return Err(DeebeeError::Config(format!(
+                "database {db_name} isn't in deebee.toml but {} holds one, register it or remove the directory",
+                dir.display()
+            )));
+        }
+        let manifest = Manifest {
+            format_version: FORMAT_VERSION,
+            sealed: Vec::new(),
+            active: segment_name(1),
+            next_segment: 2,
+            key_codec: None,
+        };
+        File::create(dir.join(&manifest.active))?;
+        manifest.save(dir)?;
+        Ok(manifest)
+    }
+
+    /// move a database from before the MANIFEST into its directory. the
+    /// segments deebee.toml lists are linked in as `000001.log` and on, their
+    /// hints with them, and the MANIFEST is written before deebee.toml stops
+    /// listing them: a crash before that leaves the old layout in charge, one
+    /// after is finished by the next open. snapshots keep their copies where
+    /// they are and restore into the directory
+    fn move_into_dir(
+        config: &mut Config,
+        db_name: &str,
+        dir: &Path,
+    ) -> Result<Manifest, DeebeeError> {
+        let mut db_config = config
+            .get_database(db_name)
+            .cloned()
+            .expect("callers check it is registered");
+        let legacy = std::mem::take(&mut db_config.segments_files_paths);
+
+        // old path and new path, the listed segments first so they get the
+        // same numbers every time this runs
+        let mut moved: Vec<(String, String)> = Vec::new();
+        let mut move_to_dir = |old: &str| -> String {
+            let old = config.resolve(old);
+            if let Some((_, new)) = moved.iter().find(|(seen, _)| *seen == old) {
+                return new.clone();
+            }
+            let new = dir
+                .join(segment_name(moved.len() as u64 + 1))
+                .to_string_lossy()
+                .into_owned();
+            moved.push((old, new.clone()));
+            new
+        };
+        let segments: Vec<String> = legacy.iter().map(|old| move_to_dir(old)).collect();
+        for snapshot in &mut db_config.snapshots {
+            for file in &mut snapshot.files {
+                file.segment = config.relative(&move_to_dir(&file.segment));
+            }
+        }
+        let legacy: Vec<String> = legacy.iter().map(|old| config.resolve(old)).collect();
+
+        let manifest = match Manifest::load(dir)? {
+            Some(manifest) => manifest,
+            None => {
+                for (old, new) in legacy.iter().zip(&segments) {
+                    let mut links = vec![(PathBuf::from(old), PathBuf::from(new))];
+                    if hint_path(old).exists() {
+                        links.push((hint_path(old), hint_path(new)));
+                    }
+                    for (old, new) in links {
+                        match fs::remove_file(&new) {
+                            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                            _ => {}
+                        }
+                        if !old.exists() {
+                            File::create(&new)?;
+                        } else if fs::hard_link(&old, &new).is_err() {
+                            // the data dir is on another filesystem
+                            fs::copy(&old, &new)?;
+                        }
+                    }
+                }
+                let format_version = db_config.format_version.unwrap_or(LEGACY_FORMAT_VERSION);
+                let manifest = Manifest::new(
+                    dir,
+                    &segments,
+                    format_version,
+                    moved.len() as u64 + 1,
+                    db_config.key_codec.clone(),
+                )?;
+                manifest.save(dir)?;
+                manifest
+            }
+        };
+
+        db_config.format_version = None;
+        // the MANIFEST records it from here on
+        db_config.key_codec = None;
+        *config = Config::update(&config.root, |config| {
+            config.upsert_database(db_config);
+            Ok(())
+        })?;
+
+        // the directory is in charge from here on, what's left is tidying up
+        for old in &legacy {
+            let _ = fs::remove_file(old);
+            let _ = fs::remove_file(hint_path(old));
+        }
+        let _ = fs::rename(
+            config.root.join(format!("{db_name}.stats")),
+            Stats::path(dir),
+        );
+        let _ = fs::remove_file(config.root.join(format!("{db_name}.lock")));
+        eprintln!("moved the segments of {db_name} into {}", dir.display());
+        Ok(manifest)
+    }
+
+    /// build the handle from its configuration and the state loaded from disk
+    fn with_state(
+        db_config: DatabaseConfig,
+        dir: PathBuf,
+        manifest: Manifest,
+        idx: Index,
+        burning: HashSet<String>,
+        active_records: usize,
+    ) -> Self {
+        let stats = Stats::load(&dir);
+
+        Self {
+            db_name: db_config.name,
+            root: PathBuf::new(),
+            idx,
+            segment_files_paths: manifest.segment_paths(&dir),
+            dir,
+            next_segment: manifest.next_segment,
+            active_records,
+            segment_size: db_config.segment_size.unwrap_or(SEGMENT_SIZE),
+            records: 0,
+            compaction_policy: db_config.compaction,
+            compactor: None,
+            background: None,
+            compaction_trigger: None,
+            bounded_write: false,
+            last_compacted: None,
+            chaos: db_config.chaos.map(Chaos::new),
+            sensitive_keys: db_config.sensitive_keys,
+            roles: db_config.roles,
+            key_rules: db_config.key_rules.unwrap_or_default(),
+            json_schema: db_config.json_schema,
+            soft_limits: db_config.soft_limits.unwrap_or_default(),
+            lifetime_stats: stats,
+            session_stats: Stats {
+                opens: 1,
+                ..Default::default()
+            },
+            opened_at: Instant::now(),
+            reads: AtomicU64::new(0),
+            metrics: Box::new(NoopMetrics),
+            clock: Box::new(SystemClock),
+            read_only: false,
+            sync: SyncPolicy::EverySec,
+            last_sync: Instant::now(),
+            deferred_sync: false,
+            unsynced: Default::default(),
+            immutable: db_config.immutable,
+            format_version: manifest.format_version,
+            epoch: None,
+            fence_epoch: db_config.fence_epoch.unwrap_or(0),
+            // unknown, so the first write reads the fence again
+            config_modified: None,
+            pinned: db_config
+                .pinned_keys
+                .into_iter()
+                .map(|key| (key, None))
+                .collect(),
+            burn_after_read: burning,
+            legacy_burns: db_config.burn_after_read.into_iter().collect(),
+            lock: None,
+            cache: db_config
+                .cache_bytes
+                .map(|max_bytes| Arc::new(Mutex::new(ValueCache::new(max_bytes)))),
+            key_codec: None,
+            compaction_filter: None,
+            merge_operator: None,
+            dedup_min_bytes: db_config.dedup_min_bytes,
+            maintenance_windows: db_config.maintenance_windows,
+            compression: db_config.compression.unwrap_or_default(),
+            blocks: Default::default(),
+            #[cfg(feature = "mmap")]
+            mapped: Default::default(),
+        }
+    }
+
+    /// take the `LOCK` file of the database's directory without waiting,
+    /// exclusively for a writer and shared for a reader. readers don't create
+    /// the file, without one no writer has ever had the database open
+    fn acquire_lock(
+        db_name: &str,
+        dir: &Path,
+        read_only: bool,
+    ) -> Result<Option<File>, DeebeeError> {
+        let path = dir.join("LOCK");
+        let file = if read_only {
+            match File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?
+        };
+
+        let locked = match read_only {
+            true => file.try_lock_shared(),
+            false => file.try_lock(),
+        };
+        match locked {
+            Ok(()) => Ok(Some(file)),
+            Err(TryLockError::WouldBlock) => Err(DeebeeError::Locked(match read_only {
+                true => format!(
+                    "database {db_name} is being written by another process ({} is locked)",
+                    path.display()
+                ),
+                false => format!(
+                    "database {db_name} is open in another process ({} is locked), \
+                     close it or open this one read-only",
+                    path.display()
+                ),
+            })),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+
+    /// give the database `from` the name `to`: its directory, with the
+    /// segments, MANIFEST, stats and snapshots in it, is renamed with it.
+    /// only the options' root and data dir matter. fails when the database is
+    /// open anywhere. the files are linked into
+    /// the new directory first and deebee.toml switches over in one rename, a
+    /// crash before that leaves `from` as it was and one after leaves `to`
+    /// complete, only the old directory behind. running it again after a
+    /// crash cleans that up
+    pub fn rename(from: &str, to: &str, options: &DatabaseOptions) -> Result<(), DeebeeError> {
+        if to.is_empty() || to.contains(['/', '\\']) {
+            return Err(DeebeeError::InvalidArgument(format!(
+                "{to:?} can't be a database name"
+            )));
+        }
+        let mut config = Config::load(&options.root)?;
+        let Some(db_config) = config.get_database(from) else {
+            return Err(DeebeeError::Config(format!(
+                "database {from} is not in deebee.toml"
+            )));
+        };
+        let legacy = !db_config.segments_files_paths.is_empty();
+        if config.get_database(to).is_some() {
+            return Err(DeebeeError::InvalidArgument(format!(
+                "database {to} already exists"
+            )));
+        }
+        let (from_dir, to_dir) = (
+            options.database_dir(&config, from),
+            options.database_dir(&config, to),
+        );
+        fs::create_dir_all(&from_dir)?;
+        fs::create_dir_all(&to_dir)?;
+        // held until the config names the database `to`, nothing opens
+        // either name in the meantime
+        let _from_lock = Self::acquire_lock(from, &from_dir, false)?;
+        let _to_lock = Self::acquire_lock(to, &to_dir, false)?;
+        if legacy {
+            Self::move_into_dir(&mut config, from, &from_dir)?;
+        }
+
+        let moved = |path: &str| -> String {
+            match Path::new(&config.resolve(path)).strip_prefix(&from_dir) {
+                Ok(rest) => config.relative(&to_dir.join(rest).to_string_lossy()),
+                Err(_) => path.to_string(),
+            }
+        };
+        let mut renamed_config = config
+            .get_database(from)
+            .cloned()
+            .expect("it was there a moment ago");
+        renamed_config.name = to.to_string();
+        for snapshot in &mut renamed_config.snapshots {
+            for file in &mut snapshot.files {
+                file.segment = moved(&file.segment);
+                file.copy = moved(&file.copy);
+            }
+        }
+
+        // `to` has a lock file of its own
+        for file in files_under(&from_dir)? {
+            if file == Path::new("LOCK") {
+                continue;
+            }
+            let (old, new) = (from_dir.join(&file), to_dir.join(&file));
+            if let Some(dir) = new.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            match fs::remove_file(&new) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            fs::hard_link(old, new)?;
+        }
+
+        Config::update(&config.root, |config| {
+            config.inner.databases.retain(|db| db.name != from);
+            config.upsert_database(renamed_config);
+            Ok(())
+        })?;
+
+        // `to` is complete from here on, what's left is tidying up
+        let _ = fs::remove_dir_all(&from_dir);
+        Ok(())
+    }
+
+    fn load_from_config(
+        db_config: DatabaseConfig,
+        dir: PathBuf,
+        manifest: Manifest,
+        repair: bool,
+        verify: VerifyLevel,
+    ) -> Result<Self, DeebeeError> {
+        let segments = manifest.segment_paths(&dir);
+        let encoding = RecordEncoding::for_format(manifest.format_version);
+        for file_path in &segments {
+            let path = Path::new(file_path);
+            if !path.exists() {
+                File::create_new(path)?;
+            }
+        }
+
+        // only the active segment was being written when a crash could hit
+        let mut truncated_bytes = 0;
+        if repair && let Some(active) = segments.last() {
+            let content = fs::read(active)?;
+            if let Some(end) = torn_tail(&content, encoding) {
+                let file = OpenOptions::new().write(true).open(active)?;
+                file.set_len(end as u64)?;
+                file.sync_data()?;
+                truncated_bytes = (content.len() - end) as u64;
+                eprintln!(
+                    "{}: dropped a torn write, {truncated_bytes} bytes at offset {end} of {active}",
+                    db_config.name
+                );
+            }
+        }
+
+        Self::verify_segments(&segments, encoding, verify)?;
+
+        let (idx, burning, active_records, mut report) =
+            Self::build_index(&segments, encoding, &SystemClock, repair)?;
+        report.truncated_bytes = truncated_bytes;
+
+        let mut db = Self::with_state(db_config, dir, manifest, idx, burning, active_records);
+        db.records = report.records;
+        db.session_stats.last_recovery = Some(report);
+        db.load_pinned()?;
+        Ok(db)
+    }
+
+    /// check the segments' records against their checksums, as deep as the
+    /// level asks. segments without an up to date hint get a full check even
+    /// for `FootersOnly`, there is no way to find their last record without
+    /// reading them
+    fn verify_segments(
+        segment_files_paths: &[String],
+        encoding: RecordEncoding,
+        level: VerifyLevel,
+    ) -> Result<(), DeebeeError> {
+        use std::io::BufReader;
+
+        if level == VerifyLevel::None || !encoding.is_checksummed() {
+            return Ok(());
+        }
+
+        for file_path in segment_files_paths {
+            let hint = match level {
+                VerifyLevel::FootersOnly => Hint::load(file_path),
+                _ => None,
+            };
+            if let Some(hint) = hint {
+                let Some((offset, size)) = hint.last_record() else {
+                    continue;
+                };
+                let mut file = File::open(file_path)?;
+                let (record, records_len) = match Blocks::load(&mut file)? {
+                    Some(blocks) => (
+                        compress::read_record(&mut file, &blocks, offset, encoding)?,
+                        blocks.records_len(),
+                    ),
+                    None => {
+                        let len = file.metadata()?.len();
+                        let mut reader = BufReader::new(file);
+                        reader.seek(SeekFrom::Start(offset))?;
+                        (encoding.read_record(&mut reader)?, len)
+                    }
+                };
+                let ends_segment = offset + size as u64 == records_len;
+                if record.len() != size as usize
+                    || !ends_segment
+                    || encoding.decode(&record).is_none()
+                {
+                    return Err(DeebeeError::Corruption(format!(
+                        "last record of {file_path}, at offset {offset}, fails its checksum"
+                    )));
+                }
+                continue;
+            }
+
+            let content = compress::read_segment(file_path)?;
+            let end = sized_records(&content, encoding)
+                .last()
+                .map_or(0, |(offset, len, _)| offset as usize + len);
+            if end != content.len() {
+                return Err(DeebeeError::Corruption(format!(
+                    "record at offset {end} of {file_path} fails its checksum"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// read the segments oldest to newest and index the latest record of every
+    /// key, also returning the keys whose latest record burns after read and
+    /// how many records the active segment holds. sealed
+    /// segments come from their hint files when those are up to date, the
+    /// missing or stale ones get rewritten when `write_hints` is set
+    fn build_index(
+        segment_files_paths: &[String],
+        encoding: RecordEncoding,
+        clock: &dyn Clock,
+        write_hints: bool,
+    ) -> Result<(Index, HashSet<String>, usize, RecoveryReport), DeebeeError> {
+        // when you connect a databse that is already there
+        // first, index the whole DB into a hashmap so it's easier to navigate in-memory
+        // without many I/O disk operations. only keys and offsets are kept, values
+        // stay on disk until someone asks for them.
+
+        let mut idx = Index::new();
+        let mut burning = HashSet::new();
+        let started = Instant::now();
+        let mut records: usize = 0;
+        let mut bytes: u64 = 0;
+        let mut active_records: usize = 0;
+
+        let sealed = segment_files_paths.len().saturating_sub(1);
+        for (segment, file_path) in segment_files_paths[..sealed].iter().enumerate() {
+            let hint = match Hint::load(file_path) {
+                Some(hint) => hint,
+                None => {
+                    let hint = Hint::from_file(file_path, encoding)?;
+                    if write_hints && let Err(e) = hint.save(file_path) {
+                        eprintln!("couldn't write the hint file of {file_path}: {e}");
+                    }
+                    hint
+                }
+            };
+            hint.apply(&mut idx, &mut burning, segment);
+            records += hint.records;
+            bytes += fs::metadata(file_path)?.len();
+        }
+
+        // the active segment is still growing, it never has a hint
+        for (segment, file_path) in segment_files_paths.iter().enumerate().skip(sealed) {
+            let file_content = fs::read(file_path)?;
+            let mut progress = RecoveryProgress::new(file_path, file_content.len() as u64);
+            active_records = 0;
+
+            // later records override earlier ones, so the index ends up pointing at
+            // the latest value of every key, and tombstones drop the key again
+            for (offset, record) in segment_records(&file_content, encoding) {
+                if record.is_tombstone() {
+                    idx.remove(&record.key);
+                } else if record.flags.contains(RecordFlags::OPERAND) {
+                    idx.push_operand(&record.key, segment, offset);
+                } else {
+                    idx.insert(&record.key, segment, offset);
+                }
+                if record.flags.contains(RecordFlags::BURN) {
+                    burning.insert(record.key.into_owned());
+                } else {
+                    burning.remove(record.key.as_ref());
+                }
+                active_records += 1;
+                progress.advance(offset);
+            }
+            progress.advance(file_content.len() as u64);
+
+            records += active_records;
+            bytes += file_content.len() as u64;
+        }
+
+        let report = RecoveryReport {
+            segments: segment_files_paths.len(),
+            records,
+            bytes,
+            duration_ms: started.elapsed().as_millis() as u64,
+            finished_at: clock.unix_secs(),
+            truncated_bytes: 0,
+        };
+
+        Ok((idx, burning, active_records, report))
+    }
+
+    /// apply a change to this database's entry in deebee.toml and save it,
+    /// nothing is saved when the change fails
+    fn update_config(
+        &self,
+        f: impl FnOnce(&mut DatabaseConfig) -> Result<(), DeebeeError>,
+    ) -> Result<(), DeebeeError> {
+        Config::update(&self.root, |config| {
+            let mut db_config = config.get_database(&self.db_name).cloned().ok_or_else(|| {
+                DeebeeError::Config(format!("database {} is not in deebee.toml", self.db_name))
+            })?;
+            f(&mut db_config)?;
+            config.upsert_database(db_config);
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// record the segments, the active one last, the format version and the
+    /// key codec in the MANIFEST
+    fn save_manifest(&self, segments: &[String], format_version: u32) -> Result<(), DeebeeError> {
+        let key_codec = self
+            .key_codec
+            .as_ref()
+            .map(|codec| codec.name().to_string());
+        Manifest::new(
+            &self.dir,
+            segments,
+            format_version,
+            self.next_segment,
+            key_codec,
+        )?
+        .save(&self.dir)
+    }
+
+    /// the directory holding the database's segments and MANIFEST
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// the name it's registered under in deebee.toml
+    pub fn db_name(&self) -> &str {
+        &self.db_name
+    }
+
+    /// allow writing newer format features, once every reader understands them
+    pub fn upgrade_format(&mut self, version: u32) -> Result<(), DeebeeError> {
+        if version > FORMAT_VERSION {
+            return Err(DeebeeError::InvalidArgument(format!(
+                "this build only supports format versions up to {FORMAT_VERSION}"
+            )));
+        }
+        if version < self.format_version {
+            return Err(DeebeeError::InvalidArgument(format!(
+                "database is already at format version {}, downgrades aren't supported",
+                self.format_version
+            )));
+        }
+
+        let from = self.encoding();
+        let to = RecordEncoding::for_format(version);
+        if from == to {
+            self.save_manifest(&self.segment_files_paths, version)?;
+            self.format_version = version;
+            return Ok(());
+        }
+
+        // the records are laid out differently from this version on, rewrite
+        // every segment so nothing is read back with the wrong encoding
+        self.finish_compaction()?;
+        let mut segments = Vec::with_capacity(self.segment_files_paths.len());
+        for path in self.segment_files_paths.clone() {
+            let content = compress::read_segment(&path)?;
+            let rewritten: Vec<u8> = segment_records(&content, from)
+                .flat_map(|(_, record)| to.encode_record(&record))
+                .collect();
+            let new_path = self.next_segment_file()?;
+            let mut file = File::create(&new_path)?;
+            file.write_all(&rewritten)?;
+            file.sync_all()?;
+            segments.push(new_path);
+        }
+
+        // the old segments only go once the MANIFEST lists the new ones
+        self.save_manifest(&segments, version)?;
+        let obsolete = std::mem::replace(&mut self.segment_files_paths, segments);
+        self.format_version = version;
+        for path in &obsolete {
+            fs::remove_file(path)?;
+            let _ = fs::remove_file(hint_path(path));
+        }
+
+        let (idx, burning, active_records, recovery) =
+            Self::build_index(&self.segment_files_paths, to, &*self.clock, !self.read_only)?;
+        self.idx = idx;
+        self.burn_after_read = burning;
+        self.active_records = active_records;
+        self.records = recovery.records;
+        self.last_compacted = None;
+        self.settle_legacy_burns()
+    }
+
+    /// copy the current segments aside under a name recorded in deebee.toml
+    pub fn create_snapshot(&self, name: &str) -> Result<Snapshot, DeebeeError> {
+        if self.list_snapshots()?.iter().any(|s| s.name == name) {
+            return Err(DeebeeError::InvalidArgument(format!(
+                "snapshot {name} already exists"
+            )));
+        }
+
+        let dir = self.dir.join("snapshots").join(name);
+        fs::create_dir_all(&dir)?;
+
+        let mut files = Vec::new();
+        for segment in &self.segment_files_paths {
+            let file_name = Path::new(segment).file_name().ok_or_else(|| {
+                DeebeeError::Config(format!("segment path {segment} has no file name"))
+            })?;
+            let copy = dir.join(file_name);
+            fs::copy(segment, &copy)?;
+            files.push(SnapshotFile {
+                segment: relative_path(&self.root, segment),
+                copy: relative_path(&self.root, &copy.to_string_lossy()),
+            });
+        }
+        // the copies may refer to shared values a later compaction drops
+        blob::link_all(&self.dir, &dir)?;
+        patch::copy_applied(&self.dir, &dir)?;
+
+        let snapshot = Snapshot {
+            name: name.to_string(),
+            created_at: self.clock.unix_secs(),
+            format_version: Some(self.format_version),
+            files,
+        };
+        self.update_config(|db_config| {
+            // checked again, another handle may have taken the name since
+            if db_config.snapshots.iter().any(|s| s.name == name) {
+                return Err(DeebeeError::InvalidArgument(format!(
+                    "snapshot {name} already exists"
+                )));
+            }
+            db_config.snapshots.push(snapshot.clone());
+            Ok(())
+        })?;
+
+        Ok(snapshot)
+    }
+
+    pub fn list_snapshots(&self) -> Result<Vec<Snapshot>, DeebeeError> {
+        let config = Config::load(&self.root)?;
+        Ok(config
+            .get_database(&self.db_name)
+            .map(|db_config| db_config.snapshots.clone())
+            .unwrap_or_default())
+    }
+
+    /// put the segments back the way they were when the snapshot was taken,
+    /// format version included. segments written since are removed. sealed
+    /// segments never change again, so they come back as hard links to the
+    /// snapshot's copies and a restore costs next to nothing however big they
+    /// are. the active segment is appended to and gets a copy of its own
+    pub fn restore_snapshot(&mut self, name: &str) -> Result<RestoreReport, DeebeeError> {
+        if self.read_only {
+            return Err(WriteError::ReadOnly {
+                db_name: self.db_name.clone(),
+            }
+            .into());
+        }
+
+        let snapshot = self
+            .list_snapshots()?
+            .into_iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| DeebeeError::InvalidArgument(format!("no snapshot named {name}")))?;
+
+        // a merge still reading the segments must not be swapped in over the restore
+        self.finish_compaction()?;
+
+        let files: Vec<SnapshotFile> = snapshot
+            .files
+            .iter()
+            .map(|file| SnapshotFile {
+                segment: resolve_path(&self.root, &file.segment),
+                copy: resolve_path(&self.root, &file.copy),
+            })
+            .collect();
+        let mut report = RestoreReport::default();
+        let sealed = files.len().saturating_sub(1);
+        for (i, file) in files.iter().enumerate() {
+            // the segment may be a link to a copy from an earlier restore,
+            // writing through it would change that snapshot
+            match fs::remove_file(&file.segment) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            // a restored segment can be the same size as the one it replaces
+            let _ = fs::remove_file(hint_path(&file.segment));
+            // linking fails across filesystems, copying still works there
+            if i < sealed && fs::hard_link(&file.copy, &file.segment).is_ok() {
+                report.linked += 1;
+            } else {
+                fs::copy(&file.copy, &file.segment)?;
+                report.copied += 1;
+            }
+        }
+
+        // an upgrade since rewrote the segments, the copies still have the
+        // layout they were taken with. older snapshots didn't record it, those
+        // are read as they always were
+        let format_version = snapshot.format_version.unwrap_or(self.format_version);
+        if let Some(snapshot_dir) = files.first().and_then(|f| Path::new(&f.copy).parent()) {
+            blob::link_all(snapshot_dir, &self.dir)?;
+            patch::copy_applied(snapshot_dir, &self.dir)?;
+        }
+        let segments: Vec<String> = files.iter().map(|f| f.segment.clone()).collect();
+        self.save_manifest(&segments, format_version)?;
+        let replaced = std::mem::replace(&mut self.segment_files_paths, segments);
+        self.format_version = format_version;
+        self.forget_segment_reads();
+        for path in replaced
+            .iter()
+            .filter(|path| !self.segment_files_paths.contains(path))
+        {
+            fs::remove_file(path)?;
+            let _ = fs::remove_file(hint_path(path));
+        }
+        let blob_refs = blob::count_refs(&self.segment_files_paths, self.encoding())?;
+        blob::collect(&self.dir, &blob_refs)?;
+
+        let (idx, burning, active_records, recovery) = Self::build_index(
+            &self.segment_files_paths,
+            self.encoding(),
+            &*self.clock,
+            !self.read_only,
+        )?;
+        self.idx = idx;
+        self.burn_after_read = burning;
+        self.active_records = active_records;
+        self.records = recovery.records;
+        self.last_compacted = None;
+        self.session_stats.last_recovery = Some(recovery);
+        self.load_pinned()?;
+        self.settle_legacy_burns()?;
+
+        Ok(report)
+    }
+
+    /// keep the key's value in memory from now on, also for later opens.
+    /// returns false when it was pinned already
+    pub fn pin(&mut self, key: &str) -> Result<bool, DeebeeError> {
+        if self.read_only {
+            return Err(WriteError::ReadOnly {
+                db_name: self.db_name.clone(),
+            }
+            .into());
+        }
+        if self.pinned.contains_key(key) {
+            return Ok(false);
+        }
+
+        let value = self.read_value(key)?;
+        self.update_config(|db_config| {
+            // another handle may have pinned it in the meantime
+            if !db_config.pinned_keys.iter().any(|pinned| pinned == key) {
+                db_config.pinned_keys.push(key.to_string());
+            }
+            Ok(())
+        })?;
+        self.pinned.insert(key.to_string(), value);
+        Ok(true)
+    }
+
+    /// stop keeping the key's value in memory, returns false when it wasn't pinned
+    pub fn unpin(&mut self, key: &str) -> Result<bool, DeebeeError> {
+        if self.read_only {
+            return Err(WriteError::ReadOnly {
+                db_name: self.db_name.clone(),
+            }
+            .into());
+        }
+        if !self.pinned.contains_key(key) {
+            return Ok(false);
+        }
+
+        self.update_config(|db_config| {
+            db_config.pinned_keys.retain(|pinned| pinned != key);
+            Ok(())
+        })?;
+        self.pinned.remove(key);
+        Ok(true)
+    }
+
+    /// the pinned keys, sorted
+    pub fn pinned_keys(&self) -> Vec<&str> {
+        let mut keys: Vec<&str> = self.pinned.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// read the values of the pinned keys from the segments
+    fn load_pinned(&mut self) -> Result<(), DeebeeError> {
+        let keys: Vec<String> = self.pinned.keys().cloned().collect();
+        for key in keys {
+            let value = self.read_value(&key)?;
+            self.pinned.insert(key, value);
+        }
+        Ok(())
+    }
+
+    /// pick up writes other processes made since this handle was opened, by
+    /// re-reading deebee.toml and the MANIFEST and rebuilding the index
+    pub fn reload(&mut self) -> Result<(), DeebeeError> {
+        self.finish_compaction()?;
+
+        let config = Config::load(&self.root)?;
+        let db_config = config.get_database(&self.db_name).ok_or_else(|| {
+            DeebeeError::Config(format!("database {} is not in deebee.toml", self.db_name))
+        })?;
+        let manifest = Manifest::load(&self.dir)?.ok_or_else(|| {
+            DeebeeError::Corruption(format!(
+                "database {} has no MANIFEST in {}",
+                self.db_name,
+                self.dir.display()
+            ))
+        })?;
+        let segments = manifest.segment_paths(&self.dir);
+        let encoding = RecordEncoding::for_format(manifest.format_version);
+        let (idx, burning, active_records, report) =
+            Self::build_index(&segments, encoding, &*self.clock, !self.read_only)?;
+
+        self.segment_files_paths = segments;
+        self.next_segment = manifest.next_segment;
+        self.forget_segment_reads();
+        self.format_version = manifest.format_version;
+        self.idx = idx;
+        self.active_records = active_records;
+        self.records = report.records;
+        self.session_stats.last_recovery = Some(report);
+        self.pinned = db_config
+            .pinned_keys
+            .iter()
+            .map(|key| (key.clone(), None))
+            .collect();
+        self.burn_after_read = burning;
+        self.legacy_burns = db_config.burn_after_read.iter().cloned().collect();
+        self.load_pinned()?;
+        self.settle_legacy_burns()
+    }
+
+    fn inject_chaos(&self, op: &str) -> Result<(), DeebeeError> {
+        let Some(chaos) = &self.chaos else {
+            return Ok(());
+        };
+        let result = chaos.before(op);
+        if result.is_err() {
+            self.metrics.counter("deebee.chaos_errors", 1);
+        }
+        result
+    }
+
+    /// how records are laid out in this database's segments
+    fn encoding(&self) -> RecordEncoding {
+        RecordEncoding::for_format(self.format_version)
+    }
+
+    /// the segment new records are written to
+    fn active_segment(&self) -> &str {
+        self.segment_files_paths
+            .last()
+            .expect("opening checks the segment list isn't empty")
+    }
+
+    /// create the segment file the MANIFEST numbers next. it only counts once
+    /// the MANIFEST lists it
+    fn next_segment_file(&mut self) -> Result<String, DeebeeError> {
+        loop {
+            let file_path = self
+                .dir
+                .join(segment_name(self.next_segment))
+                .to_string_lossy()
+                .into_owned();
+            self.next_segment += 1;
+            match File::create_new(&file_path) {
+                Ok(_) => return Ok(file_path),
+                // left behind by a crash before the MANIFEST counted it
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// fsync the writes `everysec` hasn't synced yet
+    fn sync_pending(&mut self) -> Result<(), DeebeeError> {
+        let pending = Self::lock_unsynced(&self.unsynced).take();
+        if let Some((path, covered)) = pending {
+            let result = File::open(&path).and_then(|file| file.sync_data());
+            let outcome = result.as_ref().map(|_| ()).map_err(|e| e.to_string());
+            Self::lock_unsynced(&self.unsynced).finish(path, covered, outcome);
+            result?;
+            self.last_sync = Instant::now();
+            self.metrics.counter("deebee.fsyncs", 1);
+        }
+        Ok(())
+    }
+
+    /// writes under `always` skip their own fsync until `commit_deferred`,
+    /// which has one fsync cover all of them. false under the other
+    /// policies, their writes don't fsync one by one to begin with
+    pub(crate) fn defer_fsyncs(&mut self) -> bool {
+        self.deferred_sync = self.sync == SyncPolicy::Always;
+        self.deferred_sync
+    }
+
+    /// the fsync of the writes since `defer_fsyncs`, later writes get their
+    /// own again
+    pub(crate) fn commit_deferred(&mut self) -> Result<(), DeebeeError> {
+        self.deferred_sync = false;
+        self.sync_pending()
+    }
+
+    /// batches written by the handle so far, a write bumps it
+    pub(crate) fn batches_written(&self) -> u64 {
+        Self::lock_unsynced(&self.unsynced).written()
+    }
+
+    /// `sync_pending` for the background pool, when no write comes along to
+    /// do it
+    fn flush_task(unsynced: &Arc<Mutex<Unsynced>>) -> Periodic {
+        let unsynced = unsynced.clone();
+        Box::new(move || {
+            let Some((path, covered)) = Self::lock_unsynced(&unsynced).take() else {
+                return Ok(false);
+            };
+            let result = File::open(&path)
+                .and_then(|file| file.sync_data())
+                .map_err(|e| format!("couldn't fsync {path}: {e}"));
+            Self::lock_unsynced(&unsynced).finish(path, covered, result.clone());
+            result.map(|()| true)
+        })
+    }
+
+    fn lock_unsynced(unsynced: &Mutex<Unsynced>) -> std::sync::MutexGuard<'_, Unsynced> {
+        unsynced.lock().expect("fsyncs never panic holding it")
+    }
+
+    /// leave the full active segment behind and send new writes to a fresh one
+    fn rotate_segment(&mut self) -> Result<(), DeebeeError> {
+        // nothing syncs the old segment once writes move on
+        self.sync_pending()?;
+        let file_path = self.next_segment_file()?;
+
+        let mut segments = self.segment_files_paths.clone();
+        segments.push(file_path);
+        self.save_manifest(&segments, self.format_version)?;
+        let sealed = std::mem::replace(&mut self.segment_files_paths, segments);
+        self.active_records = 0;
+
+        // the segment just sealed never changes again, so its hint stays valid
+        let sealed = sealed
+            .last()
+            .expect("opening checks the segment list isn't empty");
+        let hint = Hint::from_file(sealed, self.encoding());
+        if let Err(e) = hint
+            .map_err(DeebeeError::from)
+            .and_then(|hint| hint.save(sealed))
+        {
+            eprintln!("couldn't write the hint file of {sealed}: {e}");
+        }
+
+        self.metrics.counter("deebee.segment_rotations", 1);
+        self.metrics
+            .gauge("deebee.segments", self.segment_files_paths.len() as f64);
+        Ok(())
+    }
+
+    /// what the database's merges do, `FormatTooOld` when it compresses
+    /// them and the format doesn't have compressed segments yet
+    fn merge_settings(&self) -> Result<MergeSettings, DeebeeError> {
+        if self.compression != Compression::None && self.format_version < 6 {
+            return Err(WriteError::FormatTooOld {
+                needed: 6,
+                pinned: self.format_version,
+            }
+            .into());
+        }
+        Ok(MergeSettings {
+            filter: self.compaction_filter.clone(),
+            dedup_min_bytes: self.dedup_min_bytes,
+            bounds: WriteOptions::new(),
+            key_codec: self.key_codec.clone(),
+            operator: self.merge_operator.clone(),
+            foreground: None,
+            compression: self.compression,
+        })
+    }
+
+    /// a get, scan or write of the handle's is starting, background merges
+    /// and warmup pause behind it
+    fn touch_foreground(&self) {
+        if let Some(background) = &self.background {
+            background.foreground().touch();
+        }
+    }
+
+    fn compaction_tmp_path(&self) -> String {
+        self.dir.join("compact.tmp").to_string_lossy().into_owned()
+    }
+
+    /// merge every segment but the active one into a single segment holding only
+    /// the latest value of each key. overwritten records and tombstones are
+    /// dropped, the active segment is left alone.
+    pub fn compact_segments(&mut self) -> Result<CompactionReport, DeebeeError> {
+        self.compact_segments_with(&WriteOptions::new())
+    }
+
+    /// `compact_segments` within the options' deadline and cancel token,
+    /// checked while it waits for a background merge and between the records
+    /// it merges. one that stops in time changes no segment
+    pub fn compact_segments_with(
+        &mut self,
+        options: &WriteOptions,
+    ) -> Result<CompactionReport, DeebeeError> {
+        if self.read_only {
+            return Err(WriteError::ReadOnly {
+                db_name: self.db_name.clone(),
+            }
+            .into());
+        }
+        if let Some(compactor) = &mut self.compactor
+            && let Some(result) = compactor.wait_within(options)?
+        {
+            self.install_merge_result(result)?;
+        }
+
+        let sealed = self.segment_files_paths.len() - 1;
+        if sealed == 0 {
+            // nothing but the active segment yet
+            return Ok(CompactionReport::default());
+        }
+
+        let merged = merge_segments(
+            &self.dir,
+            self.segment_files_paths[..sealed].to_vec(),
+            self.compaction_tmp_path(),
+            self.encoding(),
+            &MergeSettings {
+                bounds: options.clone(),
+                ..self.merge_settings()?
+            },
+        )?;
+        self.install_compaction(merged, "manual".to_string())
+    }
+
+    /// which threshold of the compaction policy says the sealed segments are
+    /// due, `None` while they aren't
+    fn compaction_due(&self) -> Option<String> {
+        let policy = self.compaction_policy.as_ref()?;
+        let sealed = self.segment_files_paths.len() - 1;
+        if sealed == 0 || !self.in_maintenance_window() {
+            return None;
+        }
+        // merging a compaction's output with nothing new only finds the dead
+        // records the active segment caused, and those stay dead
+        if sealed == 1 && self.last_compacted.as_ref() == self.segment_files_paths.first() {
+            return None;
+        }
+
+        let dead_ratio = self.records.saturating_sub(self.idx.len()) as f64 / self.records as f64;
+        if let Some(max) = policy.sealed_segments.filter(|&max| sealed >= max) {
+            return Some(format!("{sealed} sealed segments, the policy allows {max}"));
+        }
+        policy
+            .dead_ratio
+            .filter(|&max| dead_ratio >= max)
+            .map(|max| format!("{dead_ratio:.2} of records dead, the policy allows {max:.2}"))
+    }
+
+    /// whether heavy maintenance may run now. outside the configured windows
+    /// background compactions wait, writes and their fsyncs carry on
+    pub fn in_maintenance_window(&self) -> bool {
+        let now = self.clock.unix_secs();
+        self.maintenance_windows.is_empty()
+            || self
+                .maintenance_windows
+                .iter()
+                .any(|window| window.contains(now))
+    }
+
+    /// swap in a finished background merge, then hand the worker a new one if
+    /// the policy calls for it. failures are reported and otherwise ignored,
+    /// a write shouldn't fail because housekeeping did. skipped for a write
+    /// with a deadline, swapping a merge in rebuilds the index
+    fn poll_compaction(&mut self) {
+        if self.bounded_write {
+            return;
+        }
+        let Some(compactor) = &mut self.compactor else {
+            return;
+        };
+        if let Some(result) = compactor.try_finished()
+            && let Err(e) = self.install_merge_result(result)
+        {
+            eprintln!("background compaction of {} failed: {e}", self.db_name);
+        }
+
+        if let Some(trigger) = self.compaction_due() {
+            let sealed = self.segment_files_paths[..self.segment_files_paths.len() - 1].to_vec();
+            let encoding = self.encoding();
+            let settings = match self.merge_settings() {
+                Ok(settings) => settings,
+                Err(e) => {
+                    eprintln!("background compaction of {} can't start: {e}", self.db_name);
+                    return;
+                }
+            };
+            if let (Some(compactor), Some(background)) = (&mut self.compactor, &self.background)
+                && compactor.submit(background, sealed, encoding, settings)
+            {
+                self.compaction_trigger = Some(trigger);
+            }
+        }
+    }
+
+    /// wait for a running background merge and swap it in
+    fn finish_compaction(&mut self) -> Result<(), DeebeeError> {
+        if let Some(result) = self.compactor.as_mut().and_then(|c| c.wait()) {
+            self.install_merge_result(result)?;
+        }
+        Ok(())
+    }
+
+    fn install_merge_result(&mut self, result: MergeResult) -> Result<(), DeebeeError> {
+        let merged = result?;
+        let trigger = self.compaction_trigger.take().unwrap_or_default();
+        self.install_compaction(merged, trigger)?;
+        Ok(())
+    }
+
+    /// replace the merged segments with the merge output and rebuild the index
+    fn install_compaction(
+        &mut self,
+        merged: MergedSegments,
+        trigger: String,
+    ) -> Result<CompactionReport, DeebeeError> {
+        let sealed = merged.sealed.len();
+        // another compaction or a restore changed the segments since the merge
+        // started, its output no longer describes them
+        if sealed >= self.segment_files_paths.len()
+            || !self.segment_files_paths.starts_with(&merged.sealed)
+        {
+            let _ = fs::remove_file(&merged.tmp_path);
+            return Ok(CompactionReport::default());
+        }
+
+        // the merged segment only shows up under a segment name once it is
+        // complete on disk, and the old segments only go once the MANIFEST
+        // stops listing them, so a crash at any point leaves a readable set
+        let compacted = self.next_segment_file()?;
+        fs::rename(&merged.tmp_path, &compacted)?;
+
+        let obsolete = self.segment_files_paths[..sealed].to_vec();
+        let mut segments = vec![compacted.clone()];
+        segments.extend_from_slice(&self.segment_files_paths[sealed..]);
+        self.save_manifest(&segments, self.format_version)?;
+        self.segment_files_paths = segments;
+        self.forget_segment_reads();
+        for path in &obsolete {
+            fs::remove_file(path)?;
+            let _ = fs::remove_file(hint_path(path));
+        }
+        // only merge output refers to shared values, and every merge takes
+        // in the output of the one before
+        blob::collect(&self.dir, &merged.blob_refs)?;
+
+        let (idx, burning, active_records, recovery) = Self::build_index(
+            &self.segment_files_paths,
+            self.encoding(),
+            &*self.clock,
+            !self.read_only,
+        )?;
+        self.idx = idx;
+        self.burn_after_read = burning;
+        self.active_records = active_records;
+        self.records = recovery.records;
+        self.last_compacted = Some(compacted);
+        // the filter may have changed or dropped them
+        if merged.records_dropped + merged.records_rewritten > 0 {
+            self.load_pinned()?;
+        }
+        self.settle_legacy_burns()?;
+
+        let report = CompactionReport {
+            segments: sealed,
+            records_kept: merged.records_kept,
+            records_dropped: merged.records_dropped,
+            records_rewritten: merged.records_rewritten,
+            shared_values: merged.blob_refs.values().sum(),
+            bytes_before: merged.bytes_before,
+            bytes_after: merged.bytes_after,
+            duration_ms: merged.duration_ms,
+            trigger,
+            merged_segments: merged.sealed,
+            finished_at: self.clock.unix_secs(),
+        };
+
+        // legacy segments missing their final newline can grow by a byte
+        let reclaimed = report.bytes_before.saturating_sub(report.bytes_after);
+        self.session_stats.compactions += 1;
+        self.session_stats.bytes_reclaimed += reclaimed;
+        let recent = &mut self.session_stats.recent_compactions;
+        recent.push(report.clone());
+        recent.drain(..recent.len().saturating_sub(RECENT_COMPACTIONS));
+        self.metrics.counter("deebee.compactions", 1);
+        self.metrics
+            .counter("deebee.compaction_bytes_read", report.bytes_before);
+        self.metrics
+            .counter("deebee.compaction_bytes_written", report.bytes_after);
+        self.metrics
+            .histogram("deebee.compaction_duration_ms", report.duration_ms as f64);
+        self.metrics
+            .counter("deebee.compaction_bytes_reclaimed", reclaimed);
+        self.metrics
+            .gauge("deebee.segments", self.segment_files_paths.len() as f64);
+
+        Ok(report)
+    }
+
+    fn is_sensitive(&self, key: &str) -> bool {
+        self.sensitive_keys
+            .iter()
+            .any(|pattern| key_matches(pattern, key))
+    }
+
+    /// hide the value if the key matches one of the sensitive patterns
+    pub fn redact<'a>(&self, key: &str, value: &'a str) -> &'a str {
+        if self.is_sensitive(key) {
+            "<redacted>"
+        } else {
+            value
+        }
+    }
+
+    /// stats for this process, or lifetime stats including earlier runs
+    pub fn stats(&self, since_start: bool) -> Stats {
+        let mut session = self.session_stats.clone();
+        session.uptime_ms = self.opened_at.elapsed().as_millis() as u64;
+        session.total_reads = self.reads.load(Ordering::Relaxed);
+        if let Some(cache) = &self.cache {
+            let cache = Self::lock_cache(cache);
+            session.cache_hits = cache.hits;
+            session.cache_misses = cache.misses;
+        }
+        if let Some(background) = &self.background {
+            session.background_tasks = background.status();
+        }
+
+        if since_start {
+            session
+        } else {
+            self.lifetime_stats.combined(&session)
+        }
+    }
+
+    /// write the lifetime stats so far to the sidecar, which dropping the
+    /// handle does too. for processes that usually end by being killed
+    pub fn save_stats(&self) -> Result<(), DeebeeError> {
+        if self.read_only {
+            return Ok(());
+        }
+        let mut stats = self.stats(false);
+        // only describes this handle
+        stats.background_tasks.clear();
+        stats.save(&self.dir)
+    }
+
+    /// count an operation a server client ran on the key toward its namespace
+    pub(crate) fn count_op(&mut self, key: &str, op: OpStats) {
+        self.session_stats
+            .namespaces
+            .entry(stats::namespace(key).to_string())
+            .or_default()
+            .add(op);
+    }
+
+    /// the segments, oldest first and the active one last
+    pub fn segments(&self) -> Result<Vec<SegmentInfo>, DeebeeError> {
+        let last = self.segment_files_paths.len() - 1;
+        self.segment_files_paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                Ok(SegmentInfo {
+                    name: Path::new(path)
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_else(|| path.clone()),
+                    bytes: fs::metadata(path)?.len(),
+                    active: i == last,
+                })
+            })
+            .collect()
+    }
+
+    /// configuration changes the lifetime stats and the segments call for
+    pub fn advise(&self) -> Vec<Advice> {
+        advise::advise(&Workload {
+            stats: &self.stats(false),
+            segment_size: self.segment_size,
+            records: self.records,
+            live_keys: self.idx.len(),
+            compaction: self.compaction_policy.as_ref(),
+        })
+    }
+
+    /// write the advised settings to deebee.toml and start using them
+    pub fn apply_advice(&mut self, advice: &[Advice]) -> Result<(), DeebeeError> {
+        if self.read_only {
+            return Err(WriteError::ReadOnly {
+                db_name: self.db_name.clone(),
+            }
+            .into());
+        }
+
+        for change in advice.iter().filter_map(|a| a.change) {
+            match change {
+                Tuning::SegmentSize(records) => {
+                    self.update_config(|db_config| {
+                        db_config.segment_size = Some(records);
+                        Ok(())
+                    })?;
+                    self.segment_size = records;
+                }
+                Tuning::CompactAtDeadRatio(ratio) => {
+                    let mut policy = self.compaction_policy.clone().unwrap_or_default();
+                    policy.dead_ratio = Some(ratio);
+                    self.update_config(|db_config| {
+                        db_config.compaction = Some(policy.clone());
+                        Ok(())
+                    })?;
+                    self.compaction_policy = Some(policy);
+                    if self.compactor.is_none() {
+                        self.compactor =
+                            Some(Compactor::new(self.dir.clone(), self.compaction_tmp_path()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// record the epoch in deebee.toml so writes tagged with an older one, or
+    /// none, are rejected from now on, in every process. failover tooling
+    /// fences before promoting a new primary opened with this epoch
+    pub fn fence(&mut self, epoch: u64) -> Result<(), DeebeeError> {
+        if self.read_only {
+            return Err(WriteError::ReadOnly {
+                db_name: self.db_name.clone(),
+            }
+            .into());
+        }
+
+        self.update_config(|db_config| {
+            let current = db_config.fence_epoch.unwrap_or(0);
+            if epoch < current {
+                return Err(DeebeeError::InvalidArgument(format!(
+                    "{} is already fenced at epoch {current}, epochs only move forward",
+                    db_config.name
+                )));
+            }
+            db_config.fence_epoch = Some(epoch);
+            Ok(())
+        })?;
+        self.fence_epoch = epoch;
+        Ok(())
+    }
+
+    /// describe every soft limit the database has reached
+    pub fn soft_limit_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let limits = &self.soft_limits;
+
+        if let Some(max) = limits.max_size_bytes {
+            let size: u64 = self
+                .segment_files_paths
+                .iter()
+                .filter_map(|path| fs::metadata(path).ok())
+                .map(|meta| meta.len())
+                .sum();
+            if size >= max {
+                warnings.push(format!(
+                    "database size is {size} bytes, soft limit is {max}"
+                ));
+            }
+        }
+
+        if let Some(max) = limits.max_keys {
+            let keys = self.idx.len();
+            if keys >= max {
+                warnings.push(format!("database holds {keys} keys, soft limit is {max}"));
+            }
+        }
+
+        if let Some(max) = limits.max_segments {
+            let segments = self.segment_files_paths.len();
+            if segments >= max {
+                warnings.push(format!(
+                    "database has {segments} segments, soft limit is {max}"
+                ));
+            }
+        }
+
+        warnings
+    }
+
+    /// live records whose key passes the filter, sorted by key. values of
+    /// `sensitive_keys` come out as `<redacted>`, `export_raw` has them as
+    /// they are
+    pub fn export(&self, filter: &KeyFilter) -> Result<Vec<ExportRecord>, DeebeeError> {
+        self.export_records(filter, false)
+    }
+
+    /// `export` with the values of `sensitive_keys` left in, for backups and
+    /// moving the data somewhere else
+    pub fn export_raw(&self, filter: &KeyFilter) -> Result<Vec<ExportRecord>, DeebeeError> {
+        self.export_records(filter, true)
+    }
+
+    fn export_records(
+        &self,
+        filter: &KeyFilter,
+        raw: bool,
+    ) -> Result<Vec<ExportRecord>, DeebeeError> {
+        let mut records = Vec::new();
+        self.for_each_live(|key, value| {
+            if filter.matches_ordered(key, self.key_codec.as_deref())
+                && !self.burn_after_read.contains(key)
+            {
+                let value = into_text(key, value.to_vec())?;
+                records.push(ExportRecord {
+                    key: key.to_string(),
+                    value: match raw {
+                        true => value,
+                        false => self.redact(key, &value).to_string(),
+                    },
+                });
+            }
+            Ok(())
+        })?;
+        sort_records(&mut records, self.key_codec.as_deref());
+        Ok(records)
+    }
+
+    /// `export` one record at a time and in the same order, nothing is
+    /// collected. a value is read when the iterator gets to its key
+    pub fn export_iter<'a>(
+        &'a self,
+        filter: &'a KeyFilter,
+    ) -> impl Iterator<Item = Result<ExportRecord, DeebeeError>> + 'a {
+        self.export_records_iter(filter, false)
+    }
+
+    /// `export_iter` with the values of `sensitive_keys` left in
+    pub fn export_iter_raw<'a>(
+        &'a self,
+        filter: &'a KeyFilter,
+    ) -> impl Iterator<Item = Result<ExportRecord, DeebeeError>> + 'a {
+        self.export_records_iter(filter, true)
+    }
+
+    pub(crate) fn export_records_iter<'a>(
+        &'a self,
+        filter: &'a KeyFilter,
+        raw: bool,
+    ) -> impl Iterator<Item = Result<ExportRecord, DeebeeError>> + 'a {
+        self.ordered_keys(filter.prefix.as_deref().unwrap_or(""))
+            .filter(|key| filter.matches_ordered(key, self.key_codec.as_deref()))
+            .filter(|key| !self.burn_after_read.contains(*key))
+            .filter_map(move |key| match self.read_text(key) {
+                Ok(Some(value)) => Some(Ok(ExportRecord {
+                    key: key.to_string(),
+                    value: if raw || !self.is_sensitive(key) {
+                        value
+                    } else {
+                        self.redact(key, &value).to_string()
+                    },
+                })),
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            })
+    }
+
+    /// set every record whose key passes the filter, returning how many were
+    /// written. existing keys are overwritten and the last record of a key wins
+    pub fn import(
+        &mut self,
+        records: impl IntoIterator<Item = ExportRecord>,
+        filter: &KeyFilter,
+    ) -> Result<usize, DeebeeError> {
+        let report = self.import_with(records, filter, &ImportOptions::default())?;
+        Ok(report.imported)
+    }
+
+    /// `import` with a say in which records win. nothing is written when a
+    /// conflict fails the import
+    pub fn import_with(
+        &mut self,
+        records: impl IntoIterator<Item = ExportRecord>,
+        filter: &KeyFilter,
+        options: &ImportOptions,
+    ) -> Result<ImportReport, DeebeeError> {
+        let mut report = ImportReport::default();
+
+        let mut kept: Vec<ExportRecord> = Vec::new();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        let codec = self.key_codec.clone();
+        for record in records
+            .into_iter()
+            .filter(|r| filter.matches_ordered(&r.key, codec.as_deref()))
+        {
+            match positions.get(&record.key) {
+                Some(&at) => {
+                    report.duplicates += 1;
+                    if options.dedup == Dedup::Last {
+                        kept[at].value = record.value;
+                    }
+                }
+                None => {
+                    positions.insert(record.key.clone(), kept.len());
+                    kept.push(record);
+                }
+            }
+        }
+
+        match options.on_conflict {
+            OnConflict::Overwrite => {}
+            OnConflict::Skip => {
+                let before = kept.len();
+                kept.retain(|record| !self.contains_key(&record.key));
+                report.skipped = before - kept.len();
+            }
+            OnConflict::Fail => {
+                if let Some(record) = kept.iter().find(|r| self.contains_key(&r.key)) {
+                    return Err(DeebeeError::InvalidArgument(format!(
+                        "key {} already exists, nothing imported",
+                        record.key
+                    )));
+                }
+            }
+        }
+
+        report.imported = self.set_many(kept.iter().map(|r| (r.key.as_str(), r.value.as_str())))?;
+        Ok(report)
+    }
+
+    /// call `f` with every live key and its value, in the order the
+    /// segments hold them, and collect what it returns. the value is
+    /// borrowed from the buffer the segment is read into, or from its
+    /// mapping with the `mmap` feature, so `f` can decode or filter it
+    /// without copying it first. keys set with burn-after-read are left out
+    pub fn iter_with<T>(
+        &self,
+        mut f: impl FnMut(&str, &[u8]) -> Option<T>,
+    ) -> Result<Vec<T>, DeebeeError> {
+        let mut kept = Vec::new();
+        self.for_each_live(|key, value| {
+            if !self.burn_after_read.contains(key)
+                && let Some(item) = f(key, value)
+            {
+                kept.push(item);
+            }
+            Ok(())
+        })?;
+        Ok(kept)
+    }
+
+    /// latest value of every key, read one segment at a time. a record is live
+    /// when the index points at its segment and offset. stops at the first
+    /// error `f` returns
+    fn for_each_live(
+        &self,
+        mut f: impl FnMut(&str, &[u8]) -> Result<(), DeebeeError>,
+    ) -> Result<(), DeebeeError> {
+        self.touch_foreground();
+        for (segment, path) in self.segment_files_paths.iter().enumerate() {
+            // sealed segments are read through their mapping
+            #[cfg(feature = "mmap")]
+            let mapped = match segment + 1 < self.segment_files_paths.len() {
+                true => self.mapped_segment(segment)?,
+                false => None,
+            };
+            #[cfg(not(feature = "mmap"))]
+            let mapped: Option<Vec<u8>> = None;
+            let read;
+            let content = match &mapped {
+                Some(map) => &map[..],
+                None => {
+                    read = compress::read_segment(path)?;
+                    &read[..]
+                }
+            };
+            for (offset, record) in segment_records(content, self.encoding()) {
+                if self.idx.get(&record.key) != Some((segment, offset)) {
+                    continue;
+                }
+                if record.flags.contains(RecordFlags::OPERAND) {
+                    let value = self.read_folded(&record.key)?.unwrap_or_default();
+                    f(&record.key, &value)?;
+                } else {
+                    f(
+                        &record.key,
+                        &resolve_value(&self.dir, &record.key, record.value, record.flags)?,
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// freeze what the database holds right now, for exports that shouldn't
+    /// see later writes. see `DatabaseManager::view` for several databases
+    /// at one point in time
+    pub fn view(&self) -> Result<View, DeebeeError> {
+        let segments = self
+            .segment_files_paths
+            .iter()
+            .map(|path| {
+                let mut file = File::open(path)?;
+                let blocks = Blocks::load(&mut file)?;
+                let len = file.metadata()?.len();
+                Ok((file, len, blocks))
+            })
+            .collect::<Result<_, DeebeeError>>()?;
+        let mut idx = self.idx.clone();
+        for key in &self.burn_after_read {
+            idx.remove(key);
+        }
+        Ok(View {
+            db_name: self.db_name.clone(),
+            dir: self.dir.clone(),
+            idx,
+            segments,
+            encoding: self.encoding(),
+            key_codec: self.key_codec.clone(),
+            merge_operator: self.merge_operator.clone(),
+            sensitive_keys: self.sensitive_keys.clone(),
+        })
+    }
+
+    /// order-independent digest of all live key/value pairs, so two databases
+    /// can be compared without diffing them record by record
+    pub fn digest(&self) -> Result<(usize, u64), DeebeeError> {
+        let mut keys = 0;
+        let mut digest = 0u64;
+        self.for_each_live(|key, value| {
+            keys += 1;
+            digest = digest.wrapping_add(fnv1a(&[key.as_bytes(), &[0], value]));
+            Ok(())
+        })?;
+
+        Ok((keys, digest))
+    }
+
+    /// compile the configured JSON Schema, if there is one
+    fn schema_validator(&self) -> Result<Option<jsonschema::Validator>, DeebeeError> {
+        let Some(schema_path) = &self.json_schema else {
+            return Ok(None);
+        };
+
+        let schema: serde_json::Value = serde_json::from_str(&fs::read_to_string(schema_path)?)
+            .map_err(|e| DeebeeError::Config(format!("schema {schema_path} isn't JSON: {e}")))?;
+        let validator = jsonschema::validator_for(&schema)
+            .map_err(|e| DeebeeError::Config(format!("invalid schema {schema_path}: {e}")))?;
+
+        Ok(Some(validator))
+    }
+
+    /// check a value against the database's JSON Schema before it gets written
+    pub fn validate_value(&self, key: &str, value: &str) -> Result<(), DeebeeError> {
+        if let Some(validator) = self.schema_validator()?
+            && let Some(reason) = self.schema_violation(&validator, key, value)
+        {
+            return Err(DeebeeError::InvalidValue(reason));
+        }
+        Ok(())
+    }
+
+    /// describe why a value fails the schema, without echoing sensitive values
+    fn schema_violation(
+        &self,
+        validator: &jsonschema::Validator,
+        key: &str,
+        value: &str,
+    ) -> Option<String> {
+        let reason = match serde_json::from_str::<serde_json::Value>(value) {
+            Ok(instance) => validator
+                .validate(&instance)
+                .err()
+                .map(|e| format!("value does not match schema: {e}")),
+            Err(e) => Some(format!("value is not valid JSON: {e}")),
+        }?;
+
+        if self.is_sensitive(key) {
+            Some("value does not match schema: <redacted>".to_string())
+        } else {
+            Some(reason)
+        }
+    }
+
+    /// re-validate all live values against the JSON Schema, returning (key, reason) per violation
+    pub fn verify(&self) -> Result<Vec<(String, String)>, DeebeeError> {
+        let Some(validator) = self.schema_validator()? else {
+            return Ok(Vec::new());
+        };
+
+        let mut violations = Vec::new();
+        self.for_each_live(|key, value| {
+            let reason = match std::str::from_utf8(value) {
+                Ok(value) => self.schema_violation(&validator, key, value),
+                Err(_) => Some("value is not valid JSON: it isn't UTF-8".to_string()),
+            };
+            if let Some(reason) = reason {
+                violations.push((key.to_string(), reason));
+            }
+            Ok(())
+        })?;
+        violations.sort();
+
+        Ok(violations)
+    }
+
+    /// take timestamps from the given clock instead of the system time
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// the time on the database's clock, seconds since the unix epoch
+    pub(crate) fn unix_secs(&self) -> u64 {
+        self.clock.unix_secs()
+    }
+
+    /// report metrics into the given sink, current gauges are reported right away
+    pub fn set_metrics_sink(&mut self, sink: Box<dyn MetricsSink>) {
+        self.metrics = sink;
+        self.metrics.gauge("deebee.keys", self.idx.len() as f64);
+        self.metrics
+            .gauge("deebee.segments", self.segment_files_paths.len() as f64);
+    }
+
+    /// latest value of the key, `None` when it isn't in the index. keys set
+    /// with burn-after-read are refused, `get_and_burn` reads those. a value
+    /// that isn't UTF-8 is `InvalidValue`, `get_bytes` reads it
+    pub fn get(&self, key: &str) -> Result<Option<String>, DeebeeError> {
+        self.get_bytes(key)?
+            .map(|value| into_text(key, value))
+            .transpose()
+    }
+
+    /// `get` for values of any bytes, the ones `set_bytes` wrote included
+    pub fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>, DeebeeError> {
+        Ok(self.get_ref(key)?.map(ValueRef::into_vec))
+    }
+
+    /// `get_bytes` without a copy of the value for every read: it's lent
+    /// out of the value cache, or out of the mapped segment with the `mmap`
+    /// feature and no cache. the cache keeps values it read that way, a
+    /// value neither holds is read from the segment file
+    pub fn get_ref(&self, key: &str) -> Result<Option<ValueRef<'_>>, DeebeeError> {
+        self.check_not_burning(key)?;
+        let started = Instant::now();
+        let result = match self.pinned.get(key) {
+            Some(value) => Ok(value
+                .as_deref()
+                .map(|value| ValueRef(Lent::Borrowed(value)))),
+            None => match self.cached(key) {
+                Some(value) => Ok(Some(ValueRef(Lent::Shared(value)))),
+                None => self.read_ref(key),
+            },
+        };
+
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.metrics.counter("deebee.gets", 1);
+        self.metrics.histogram(
+            "deebee.get_latency_us",
+            started.elapsed().as_micros() as f64,
+        );
+
+        result
+    }
+
+    /// `get` unless the options' deadline passed or their token was
+    /// cancelled before it started. that's the only check, a read that
+    /// started finishes however long the disk takes
+    pub fn get_with(&self, key: &str, options: &GetOptions) -> Result<Option<String>, DeebeeError> {
+        options.check()?;
+        self.get(key)
+    }
+
+    /// the values of several keys, in the order asked for. the lookups are
+    /// grouped by segment so each file is opened once and read front to back
+    pub fn get_many<K: AsRef<str>>(&self, keys: &[K]) -> Result<Vec<Option<String>>, DeebeeError> {
+        use std::io::BufReader;
+
+        for key in keys {
+            self.check_not_burning(key.as_ref())?;
+        }
+        let started = Instant::now();
+        self.inject_chaos("read")?;
+
+        let mut values: Vec<Option<Vec<u8>>> = vec![None; keys.len()];
+        // (segment, offset, position in keys) of every indexed key
+        let mut lookups: Vec<(usize, u64, usize)> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| {
+                let (segment, offset) = self.idx.get(key.as_ref())?;
+                Some((segment, offset, i))
+            })
+            .collect();
+        lookups.sort_unstable();
+
+        let encoding = self.encoding();
+        for group in lookups.chunk_by(|a, b| a.0 == b.0) {
+            let segment = group[0].0;
+            let mut reader = match File::open(&self.segment_files_paths[segment]) {
+                Ok(file) => Some(BufReader::new(file)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+            let blocks = match reader {
+                Some(_) => self.segment_blocks(segment)?,
+                None => None,
+            };
+            for &(_, offset, i) in group {
+                let key = keys[i].as_ref();
+                let found = reader.as_mut().and_then(|reader| {
+                    let record = match &blocks {
+                        Some(blocks) => {
+                            compress::read_record(reader, blocks, offset, encoding).ok()?
+                        }
+                        None => {
+                            reader.seek(SeekFrom::Start(offset)).ok()?;
+                            encoding.read_record(reader).ok()?
+                        }
+                    };
+                    encoding
+                        .decode(&record)
+                        .filter(|record| {
+                            record.key == key && !record.flags.contains(RecordFlags::OPERAND)
+                        })
+                        .map(Record::into_owned)
+                });
+                // anything unexpected goes through the single-key path, which
+                // knows how to recover from a stale index or report damage, and
+                // so do merge operands
+                values[i] = match found {
+                    Some(record) => Some(
+                        resolve_value(&self.dir, &record.key, record.value, record.flags)?
+                            .into_owned(),
+                    ),
+                    None => self.read_value(key)?,
+                };
+            }
+        }
+        let values = keys
+            .iter()
+            .zip(values)
+            .map(|(key, value)| {
+                value
+                    .map(|value| into_text(key.as_ref(), value))
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.reads.fetch_add(keys.len() as u64, Ordering::Relaxed);
+        self.metrics.counter("deebee.gets", keys.len() as u64);
+        self.metrics.histogram(
+            "deebee.get_latency_us",
+            started.elapsed().as_micros() as f64,
+        );
+
+        Ok(values)
+    }
+
+    /// `read_value` for the methods that return text
+    fn read_text(&self, key: &str) -> Result<Option<String>, DeebeeError> {
+        self.read_value(key)?
+            .map(|value| into_text(key, value))
+            .transpose()
+    }
+
+    /// the value of a key the cache doesn't have, kept in the cache
+    fn read_ref(&self, key: &str) -> Result<Option<ValueRef<'static>>, DeebeeError> {
+        self.inject_chaos("read")?;
+        #[cfg(feature = "mmap")]
+        let mapped = self.read_mapped(key)?;
+        #[cfg(not(feature = "mmap"))]
+        let mapped = None;
+        let value = match mapped {
+            Some(value) => value,
+            None => match self.read_value(key)? {
+                Some(value) => ValueRef(Lent::Owned(value)),
+                None => return Ok(None),
+            },
+        };
+        let Some(cache) = &self.cache else {
+            return Ok(Some(value));
+        };
+        let shared: Arc<[u8]> = match value.0 {
+            Lent::Owned(value) => value.into(),
+            _ => (*value).into(),
+        };
+        Self::lock_cache(cache).insert_shared(key, shared.clone());
+        Ok(Some(ValueRef(Lent::Shared(shared))))
+    }
+
+    /// the value straight out of the sealed segment's mapping. `None` when
+    /// the key isn't in a mapped segment or its value is folded from merge
+    /// operands, and for a damaged record, `read_value` says what's wrong
+    /// with it
+    #[cfg(feature = "mmap")]
+    fn read_mapped(&self, key: &str) -> Result<Option<ValueRef<'static>>, DeebeeError> {
+        let Some((segment, offset)) = self.idx.get(key) else {
+            return Ok(None);
+        };
+        if segment + 1 >= self.segment_files_paths.len() {
+            return Ok(None);
+        }
+        let Some(map) = self.mapped_segment(segment)? else {
+            return Ok(None);
+        };
+        self.touch_foreground();
+        let encoding = self.encoding();
+        let Some(rest) = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| map.get(offset..))
+        else {
+            return Ok(None);
+        };
+        let Some(record) = encoding
+            .record_len(rest)
+            .and_then(|len| encoding.decode(rest.get(..len)?))
+        else {
+            return Ok(None);
+        };
+        if record.key != key || record.flags.contains(RecordFlags::OPERAND) {
+            return Ok(None);
+        }
+        let lent = match resolve_value(&self.dir, key, record.value, record.flags)? {
+            Cow::Borrowed(value) => {
+                let start = value.as_ptr() as usize - map.as_ptr() as usize;
+                Lent::Mapped(map.clone(), start..start + value.len())
+            }
+            Cow::Owned(value) => Lent::Owned(value),
+        };
+        Ok(Some(ValueRef(lent)))
+    }
+
+    fn read_value(&self, key: &str) -> Result<Option<Vec<u8>>, DeebeeError> {
+        // Use the index to find the segment and offset
+        let Some((segment, offset)) = self.idx.get(key) else {
+            return Ok(None);
+        };
+
+        match self.read_record_at(segment, offset) {
+            Ok(Some(record))
+                if record.key == key && record.flags.contains(RecordFlags::OPERAND) =>
+            {
+                self.read_folded(key)
+            }
+            Ok(Some(record)) if record.key == key => Ok(Some(
+                resolve_value(&self.dir, &record.key, record.value, record.flags)?.into_owned(),
+            )),
+            // a checksummed record that doesn't decode was damaged on disk,
+            // unless the segment was rewritten and the key lives elsewhere now
+            Ok(None) if self.encoding().is_checksummed() => match self.scan_segments_for(key)? {
+                Some(value) => Ok(Some(value)),
+                None => Err(DeebeeError::Corruption(format!(
+                    "record of {key} at offset {offset} of {} fails its checksum",
+                    self.segment_files_paths[segment]
+                ))),
+            },
+            // the segment went missing or was rewritten behind our back, the
+            // index is stale for this key so look for it the slow way
+            Ok(_) => self.scan_segments_for(key),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => self.scan_segments_for(key),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// the value of a key whose latest record is a merge operand, the records
+    /// of its chain folded together. with any of them gone it's the slow way
+    fn read_folded(&self, key: &str) -> Result<Option<Vec<u8>>, DeebeeError> {
+        let Some(chain) = self.idx.chain(key) else {
+            return self.scan_segments_for(key);
+        };
+        let read = |(segment, offset)| -> Result<Option<Record<'static>>, DeebeeError> {
+            Ok(self
+                .read_record_at(segment, offset)?
+                .filter(|record| record.key == key))
+        };
+        let base = match chain.base {
+            Some(location) => match read(location)? {
+                Some(record) => Some(
+                    resolve_value(&self.dir, &record.key, record.value, record.flags)?.into_owned(),
+                ),
+                None => return self.scan_segments_for(key),
+            },
+            None => None,
+        };
+        let mut operands = Vec::with_capacity(chain.operands.len());
+        for &location in &chain.operands {
+            match read(location)? {
+                Some(record) => operands.push(record.value.into_owned()),
+                None => return self.scan_segments_for(key),
+            }
+        }
+        merge::fold(self.merge_operator.as_deref(), key, base, operands)
+    }
+
+    /// the record starting at the offset, if there is one
+    fn read_record_at(
+        &self,
+        segment: usize,
+        offset: u64,
+    ) -> std::io::Result<Option<Record<'static>>> {
+        use std::io::BufReader;
+
+        self.touch_foreground();
+        let encoding = self.encoding();
+        if let Some(blocks) = self.segment_blocks(segment)? {
+            let mut file = File::open(&self.segment_files_paths[segment])?;
+            let record = compress::read_record(&mut file, &blocks, offset, encoding)?;
+            return Ok(encoding.decode(&record).map(Record::into_owned));
+        }
+        #[cfg(feature = "mmap")]
+        if segment + 1 < self.segment_files_paths.len()
+            && let Some(map) = self.mapped_segment(segment)?
+        {
+            // past the end reads nothing, like seeking past the end of the file
+            let mut rest = usize::try_from(offset)
+                .ok()
+                .and_then(|offset| map.get(offset..))
+                .unwrap_or_default();
+            let record = encoding.read_record(&mut rest)?;
+            return Ok(encoding.decode(&record).map(Record::into_owned));
+        }
+
+        let file = File::open(&self.segment_files_paths[segment])?;
+        let mut reader = BufReader::new(file);
+
+        reader.seek(SeekFrom::Start(offset))?;
+
+        let record = encoding.read_record(&mut reader)?;
+
+        Ok(encoding.decode(&record).map(Record::into_owned))
+    }
+
+    /// the sealed segment's mapping, made on first use. compressed segments
+    /// aren't mapped, their records have to be inflated first
+    #[cfg(feature = "mmap")]
+    fn mapped_segment(&self, segment: usize) -> std::io::Result<Option<Arc<Mmap>>> {
+        if self.segment_blocks(segment)?.is_some() {
+            return Ok(None);
+        }
+        let path = &self.segment_files_paths[segment];
+        let lock = || self.mapped.lock().expect("mapping never panics holding it");
+        if let Some(map) = lock().get(path) {
+            return Ok(Some(map.clone()));
+        }
+        // mapped without the lock, other gets only wait for the lookups. two
+        // threads mapping the same segment at once keep the first mapping
+        let Some(map) = Mmap::map(path)? else {
+            return Ok(None);
+        };
+        Ok(Some(
+            lock()
+                .entry(path.clone())
+                .or_insert_with(|| Arc::new(map))
+                .clone(),
+        ))
+    }
+
+    /// where the blocks of a compressed sealed segment are, looked up on
+    /// first use. `None` for the active segment and the sealed ones that
+    /// aren't compressed
+    fn segment_blocks(&self, segment: usize) -> std::io::Result<Option<Arc<Blocks>>> {
+        if segment + 1 >= self.segment_files_paths.len() {
+            return Ok(None);
+        }
+        let path = &self.segment_files_paths[segment];
+        let lock = || {
+            self.blocks
+                .lock()
+                .expect("block lookups never panic holding it")
+        };
+        if let Some(blocks) = lock().get(path) {
+            return Ok(blocks.clone());
+        }
+        let blocks = Blocks::load(&mut File::open(path)?)?.map(Arc::new);
+        Ok(lock().entry(path.clone()).or_insert(blocks).clone())
+    }
+
+    /// forget mappings, block tables and cached values once the segment
+    /// list changes, a path can name a different file and a key can have
+    /// another value afterwards
+    fn forget_segment_reads(&mut self) {
+        self.blocks
+            .get_mut()
+            .expect("block lookups never panic holding it")
+            .clear();
+        #[cfg(feature = "mmap")]
+        self.mapped
+            .get_mut()
+            .expect("mapping never panics holding it")
+            .clear();
+        if let Some(cache) = &self.cache {
+            Self::lock_cache(cache).clear();
+        }
+    }
+
+    /// read the keys that were hot at the last close back into the cache,
+    /// on the background pool. a key written or read before its turn is
+    /// left alone
+    fn warm_cache(&mut self) {
+        let (Some(cache), Some(background)) = (&self.cache, &self.background) else {
+            return;
+        };
+        let targets: Vec<(String, String, u64)> = cache::load_hot_keys(&self.dir)
+            .into_iter()
+            .filter(|key| !self.pinned.contains_key(key) && !self.burn_after_read.contains(key))
+            .filter_map(|key| {
+                let (segment, offset) = self.idx.get(&key)?;
+                Some((key, self.segment_files_paths[segment].clone(), offset))
+            })
+            .collect();
+        if targets.is_empty() {
+            return;
+        }
+        Self::lock_cache(cache).start_warming(targets.iter().map(|(key, _, _)| key.clone()));
+
+        let (cache, dir, encoding) = (cache.clone(), self.dir.clone(), self.encoding());
+        let foreground = background.foreground().clone();
+        background.run(background::WARMUP, move || {
+            // least recently used first, so the hottest end up the most recent
+            for (key, path, offset) in targets.into_iter().rev() {
+                foreground.yield_to(background::WARMUP);
+                let record =
+                    File::open(&path).and_then(|mut file| match Blocks::load(&mut file)? {
+                        Some(blocks) => compress::read_record(&mut file, &blocks, offset, encoding),
+                        None => {
+                            let mut reader = std::io::BufReader::new(file);
+                            reader.seek(SeekFrom::Start(offset))?;
+                            encoding.read_record(&mut reader)
+                        }
+                    });
+                // the segment may be gone by now, the key gets read the usual way then
+                let Some(record) = record
+                    .ok()
+                    .and_then(|record| Some(encoding.decode(&record)?.into_owned()))
+                else {
+                    continue;
+                };
+                // a merge operand is only part of the value
+                if record.key != key || record.flags.contains(RecordFlags::OPERAND) {
+                    continue;
+                }
+                if let Ok(value) = resolve_value(&dir, &record.key, record.value, record.flags) {
+                    Self::lock_cache(&cache).insert_warm(&key, &value);
+                }
+            }
+            Ok(())
+        });
+    }
+
+    /// the key's value if the cache has it
+    fn cached(&self, key: &str) -> Option<Arc<[u8]>> {
+        Self::lock_cache(self.cache.as_ref()?).get(key)
+    }
+
+    fn lock_cache(cache: &Mutex<ValueCache>) -> std::sync::MutexGuard<'_, ValueCache> {
+        cache.lock().expect("the cache never panics holding it")
+    }
+
+    /// slow path for keys missing from the index: scan the segments newest to
+    /// oldest so a stale index after a crash doesn't turn into a false not-found
+    pub fn find_in_segments(&self, key: &str) -> Result<Option<String>, DeebeeError> {
+        self.check_not_burning(key)?;
+        self.scan_segments_for(key)?
+            .map(|value| into_text(key, value))
+            .transpose()
+    }
+
+    fn scan_segments_for(&self, key: &str) -> Result<Option<Vec<u8>>, DeebeeError> {
+        // merge operands met on the way back, newest first
+        let mut operands = Vec::new();
+        let fold = |base, mut operands: Vec<Vec<u8>>| {
+            operands.reverse();
+            merge::fold(self.merge_operator.as_deref(), key, base, operands)
+        };
+        for path in self.segment_files_paths.iter().rev() {
+            let content = match compress::read_segment(path) {
+                Ok(content) => content,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            // the last record for a key within a segment is the current one,
+            // unless it's an operand, then the ones before it count too. a
+            // tombstone means the key was deleted and older ones don't count
+            let records: Vec<Record> = segment_records(&content, self.encoding())
+                .map(|(_, record)| record)
+                .filter(|record| record.key == key)
+                .collect();
+            for record in records.into_iter().rev() {
+                if record.is_tombstone() {
+                    return fold(None, operands);
+                }
+                if record.flags.contains(RecordFlags::OPERAND) {
+                    operands.push(record.value.into_owned());
+                    continue;
+                }
+                let base =
+                    resolve_value(&self.dir, &record.key, record.value, record.flags)?.into_owned();
+                return fold(Some(base), operands);
+            }
+        }
+        fold(None, operands)
+    }
+
+    fn check_key_codec(&self, key: &str) -> Result<(), KeyError> {
+        match &self.key_codec {
+            Some(codec) => codec.check(key).map_err(|reason| KeyError::Rejected {
+                codec: codec.name().to_string(),
+                reason,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// indexed keys starting with the prefix, in the key codec's order. the
+    /// index is kept in byte order, so under a codec every call collects
+    /// and sorts the prefix's keys, O(n log n) before the first one comes
+    /// out. a byte prefix isn't one run in most codec orders, the index
+    /// couldn't scan it in place anyway
+    fn ordered_keys<'a>(&'a self, prefix: &'a str) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        let keys = self.idx.scan_prefix(prefix).map(|(key, _)| key);
+        match &self.key_codec {
+            // the index is in byte order already
+            None => Box::new(keys),
+            Some(codec) => {
+                let mut keys: Vec<&str> = keys.collect();
+                keys.sort_by(|a, b| codec.compare(a, b));
+                Box::new(keys.into_iter())
+            }
+        }
+    }
+
+    /// the keys matching a glob pattern where `*` stands for any run of
+    /// characters, sorted
+    pub fn keys_matching(&self, pattern: &str) -> Vec<String> {
+        // everything before the first `*` narrows the scan
+        let prefix = pattern.split('*').next().unwrap_or("");
+        self.ordered_keys(prefix)
+            .filter(|key| key_matches(pattern, key))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// borrow every indexed key without copying it, in the key codec's order
+    /// like every other listing. byte order without a codec, under one the
+    /// keys are sorted first, see `KeyCodec`
+    pub fn iter_keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.ordered_keys("").map(str::as_bytes)
+    }
+
+    /// (key, value) of every live key starting with the prefix, in key order
+    pub fn scan_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = Result<(String, String), DeebeeError>> + 'a {
+        self.scan_prefix_with(prefix, &GetOptions::new())
+    }
+
+    /// `scan_prefix` within the options' deadline and cancel token, checked
+    /// before each value is read. once they're crossed the scan yields
+    /// `Cancelled` and ends
+    pub fn scan_prefix_with<'a>(
+        &'a self,
+        prefix: &'a str,
+        options: &GetOptions,
+    ) -> impl Iterator<Item = Result<(String, String), DeebeeError>> + use<'a> {
+        self.scan_filtered_with(prefix, &NO_FILTER, options)
+    }
+
+    /// `scan_prefix` keeping only what passes the filter. keys are matched
+    /// before their values are read, so a glob saves the reads too
+    pub fn scan_filtered<'a>(
+        &'a self,
+        prefix: &'a str,
+        filter: &'a ScanFilter,
+    ) -> impl Iterator<Item = Result<(String, String), DeebeeError>> + 'a {
+        self.scan_filtered_with(prefix, filter, &GetOptions::new())
+    }
+
+    /// `scan_filtered` within the options, like `scan_prefix_with`. values
+    /// the filter turns down count as reads too
+    pub fn scan_filtered_with<'a>(
+        &'a self,
+        prefix: &'a str,
+        filter: &'a ScanFilter,
+        options: &GetOptions,
+    ) -> impl Iterator<Item = Result<(String, String), DeebeeError>> + use<'a> {
+        let options = options.clone();
+        let mut stopped = false;
+        self.ordered_keys(prefix)
+            .filter(|key| !self.burn_after_read.contains(*key) && filter.matches_key(key))
+            .map_while(move |key| {
+                if stopped {
+                    return None;
+                }
+                if let Err(e) = options.check() {
+                    stopped = true;
+                    return Some(Some(Err(e)));
+                }
+                Some(match self.read_text(key) {
+                    Ok(Some(value)) if filter.matches_value(&value) => {
+                        Some(Ok((key.to_string(), value)))
+                    }
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e)),
+                })
+            })
+            .flatten()
+    }
+
+    /// whether the key has a live entry in the index
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.idx.contains_key(key)
+    }
+
+    /// set the key only when the condition holds, returning whether it was written
+    pub fn set_if(
+        &mut self,
+        key: &str,
+        value: &str,
+        condition: SetCondition,
+    ) -> Result<bool, DeebeeError> {
+        let exists = self.contains_key(key);
+        let allowed = match condition {
+            SetCondition::Always => true,
+            SetCondition::IfAbsent => !exists,
+            SetCondition::IfPresent => exists,
+        };
+
+        if allowed {
+            self.set(key, value)?;
+        }
+        Ok(allowed)
+    }
+
+    /// set the key only if it still holds `expected` (`None`: doesn't exist),
+    /// returning whether it was written. compares against what this handle
+    /// sees, `reload` first to account for other processes
+    pub fn compare_and_set(
+        &mut self,
+        key: &str,
+        expected: Option<&str>,
+        value: &str,
+    ) -> Result<bool, DeebeeError> {
+        if self.get(key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// store the value under its BLAKE3 hash and return that key, identical
+    /// values are only written once
+    pub fn put_content_addressed(&mut self, value: &str) -> Result<String, DeebeeError> {
+        let key = blake3::hash(value.as_bytes()).to_hex().to_string();
+        if !self.contains_key(&key) {
+            self.set(&key, value)?;
+        }
+        Ok(key)
+    }
+
+    /// set the key and return the value it held before, if any
+    pub fn put_get_old(&mut self, key: &str, value: &str) -> Result<Option<String>, DeebeeError> {
+        let old = self.get(key)?;
+        self.set(key, value)?;
+        Ok(old)
+    }
+
+    /// `set`, and the first `get_and_burn` that returns the value deletes
+    /// the key. other reads leave it alone: `get` refuses it, scans and
+    /// exports skip it
+    pub fn set_burn_after_read(&mut self, key: &str, value: &str) -> Result<(), DeebeeError> {
+        self.check_writable(key)?;
+        // the read could never delete it
+        if self.immutable {
+            return Err(WriteError::Immutable {
+                key: key.to_string(),
+            }
+            .into());
+        }
+        // the flag is a bit of the record
+        if self.format_version < 5 {
+            return Err(WriteError::FormatTooOld {
+                needed: 5,
+                pinned: self.format_version,
+            }
+            .into());
+        }
+        self.set_record(Record {
+            flags: RecordFlags::BURN,
+            ..Record::new(key, value.as_bytes())
+        })
+    }
+
+    /// whether the next `get_and_burn` of the key deletes it
+    pub fn burns_after_read(&self, key: &str) -> bool {
+        self.burn_after_read.contains(key)
+    }
+
+    /// the roles server clients authenticate as, none when the server is
+    /// open to everyone
+    pub(crate) fn roles(&self) -> &[Role] {
+        &self.roles
+    }
+
+    /// `get`, except a key set with burn-after-read is deleted by the read
+    /// that returns it. holding `&mut self` the read and the delete can't be
+    /// split by another get, only one caller ever sees the value
+    pub fn get_and_burn(&mut self, key: &str) -> Result<Option<String>, DeebeeError> {
+        if !self.burn_after_read.contains(key) {
+            return self.get(key);
+        }
+        self.check_writable(key)?;
+        let value = self.read_text(key)?;
+        if value.is_some() {
+            self.delete(key)?;
+        }
+        Ok(value)
+    }
+
+    /// the key was written again or deleted, whatever its record says now it
+    /// doesn't burn
+    fn stop_burning(&mut self, key: &str) -> Result<(), DeebeeError> {
+        self.burn_after_read.remove(key);
+        if self.legacy_burns.remove(key) {
+            self.update_config(|db_config| {
+                db_config.burn_after_read.retain(|burning| burning != key);
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// before records were flagged deebee.toml listed the keys to burn after
+    /// read. from format version 5 on a writer sets the live ones again as
+    /// flagged records and empties the list, a crash halfway only leaves
+    /// some to set again. older versions keep the list, pruned to the live
+    /// keys so one set again after a compaction dropped it doesn't burn
+    fn settle_legacy_burns(&mut self) -> Result<(), DeebeeError> {
+        if self.legacy_burns.is_empty() {
+            return Ok(());
+        }
+        let live: HashSet<String> = self
+            .legacy_burns
+            .iter()
+            .filter(|key| self.contains_key(key))
+            .cloned()
+            .collect();
+        self.burn_after_read.extend(live.iter().cloned());
+        if self.read_only {
+            return Ok(());
+        }
+
+        if self.format_version >= 5 {
+            self.legacy_burns.clear();
+            for key in &live {
+                let value = self.read_value(key)?.unwrap_or_default();
+                let (segment, offset) = self.write_record(Record {
+                    flags: RecordFlags::BURN,
+                    ..Record::new(key, &value)
+                })?;
+                self.idx.insert(key, segment, offset);
+            }
+            self.update_config(|db_config| {
+                db_config.burn_after_read.clear();
+                Ok(())
+            })?;
+        } else if live.len() < self.legacy_burns.len() {
+            self.update_config(|db_config| {
+                db_config.burn_after_read.retain(|key| live.contains(key));
+                Ok(())
+            })?;
+            self.legacy_burns = live;
+        }
+        Ok(())
+    }
+
+    /// reads that can't delete the key mustn't show a burn-after-read value
+    fn check_not_burning(&self, key: &str) -> Result<(), DeebeeError> {
+        if self.burn_after_read.contains(key) {
+            return Err(DeebeeError::InvalidArgument(format!(
+                "{key} is deleted by the read that returns it, get it with get_and_burn"
+            )));
+        }
+        Ok(())
+    }
+
+    /// write the value under the key, validated against the key rules first
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), DeebeeError> {
+        self.set_bytes(key, value.as_bytes())
+    }
+
+    /// `set` for values of any bytes. ones that aren't UTF-8 need format
+    /// version 5, and only `get_bytes` reads them back
+    pub fn set_bytes(&mut self, key: &str, value: &[u8]) -> Result<(), DeebeeError> {
+        self.set_record(Record::new(key, value))
+    }
+
+    fn set_record(&mut self, record: Record) -> Result<(), DeebeeError> {
+        let started = Instant::now();
+        let key = record.key.as_ref();
+        self.check_writable(key)?;
+        self.key_rules.validate(key)?;
+        self.check_key_codec(key)?;
+        self.check_not_reserved(record.plain_value())?;
+
+        let (segment, offset) = self.write_record(record.clone())?;
+        // point the index at the new record so the write is visible to this
+        // process right away
+        self.idx.insert(key, segment, offset);
+        if let Some(pinned) = self.pinned.get_mut(key) {
+            *pinned = Some(record.plain_value().to_vec());
+        }
+        if record.flags.contains(RecordFlags::BURN) {
+            self.burn_after_read.insert(key.to_string());
+        } else {
+            self.stop_burning(key)?;
+        }
+
+        self.metrics.counter("deebee.sets", 1);
+        self.metrics.histogram(
+            "deebee.set_latency_us",
+            started.elapsed().as_micros() as f64,
+        );
+
+        Ok(())
+    }
+
+    /// `set` with the CRC32 the client computed for the value, as
+    /// `crc32fast::hash` does. a value that doesn't match it was damaged on
+    /// the way and isn't written. from format version 5 on the checksum is
+    /// stored with the record and every read checks the value against it
+    /// again, before that the record's own checksum has to do
+    pub fn set_checked(
+        &mut self,
+        key: &str,
+        value: &str,
+        checksum: u32,
+    ) -> Result<(), DeebeeError> {
+        if crc32fast::hash(value.as_bytes()) != checksum {
+            return Err(DeebeeError::InvalidValue(format!(
+                "the value of {key} doesn't match its checksum {checksum:08x}, it changed on the way"
+            )));
+        }
+        match self.format_version >= 5 {
+            true => self.set_record(Record::checksummed(key, value.as_bytes(), checksum)),
+            false => self.set(key, value),
+        }
+    }
+
+    /// `set` unless the options' deadline passed or their token was
+    /// cancelled before it started. that's the only check, a write that
+    /// started goes through, fsync included. it doesn't swap in a finished
+    /// background compaction the way `set` does, rebuilding the index isn't
+    /// bounded, the next write without a deadline or `compact_segments`
+    /// does that
+    pub fn set_with(
+        &mut self,
+        key: &str,
+        value: &str,
+        options: &WriteOptions,
+    ) -> Result<(), DeebeeError> {
+        options.check()?;
+        self.bounded_write = true;
+        let result = self.set(key, value);
+        self.bounded_write = false;
+        result
+    }
+
+    /// `set`, answering as soon as the write is applied. the future resolves
+    /// once an fsync covering the write completes, so writes can go out one
+    /// after another while the ones that matter are awaited. under `always`
+    /// it's ready right away, otherwise the flush task fsyncs within about a
+    /// second, even under `never`. with the flush task disabled it takes the
+    /// next fsync the sync policy calls for, or closing the handle
+    pub fn set_async(
+        &mut self,
+        key: &str,
+        value: &str,
+    ) -> Result<impl Future<Output = Durable> + Send + 'static, DeebeeError> {
+        self.set(key, value)?;
+        let active = self.active_segment().to_string();
+        let mut unsynced = Self::lock_unsynced(&self.unsynced);
+        let batch = unsynced.written();
+        Ok(unsynced.wait(batch, &active))
+    }
+
+    /// append an operand to the key instead of writing its whole value, the
+    /// `MergeOperator` registered with `DatabaseOptions::merge_operator`
+    /// folds it into the value on reads and compactions. reads get slower
+    /// with every operand until a compaction folds them. needs format
+    /// version 5
+    pub fn merge(&mut self, key: &str, operand: &[u8]) -> Result<(), DeebeeError> {
+        let started = Instant::now();
+        self.check_writable(key)?;
+        if self.merge_operator.is_none() {
+            return Err(DeebeeError::Config(format!(
+                "{} has no merge operator, register one with DatabaseOptions::merge_operator",
+                self.db_name
+            )));
+        }
+        // the operand is told apart by a bit of the record
+        if self.format_version < 5 {
+            return Err(WriteError::FormatTooOld {
+                needed: 5,
+                pinned: self.format_version,
+            }
+            .into());
+        }
+        self.key_rules.validate(key)?;
+        self.check_key_codec(key)?;
+
+        let (segment, offset) = self.write_record(Record {
+            flags: RecordFlags::OPERAND,
+            ..Record::new(key, operand)
+        })?;
+        self.idx.push_operand(key, segment, offset);
+        if self.pinned.contains_key(key) {
+            let value = self.read_value(key)?;
+            self.pinned.insert(key.to_string(), value);
+        }
+        self.stop_burning(key)?;
+
+        self.metrics.counter("deebee.merges", 1);
+        self.metrics.histogram(
+            "deebee.merge_latency_us",
+            started.elapsed().as_micros() as f64,
+        );
+        Ok(())
+    }
+
+    /// set many keys at once, each segment written in one go and the index
+    /// updated at the end. every pair is checked before anything is written.
+    /// returns how many were set
+    pub fn set_many<K: AsRef<str>, V: AsRef<str>>(
+        &mut self,
+        pairs: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<usize, DeebeeError> {
+        let started = Instant::now();
+        let pairs: Vec<(K, V)> = pairs.into_iter().collect();
+        let records: Vec<Record> = pairs
+            .iter()
+            .map(|(key, value)| Record::new(key.as_ref(), value.as_ref().as_bytes()))
+            .collect();
+
+        // in an immutable database the batch can't repeat a key either
+        let mut batch_keys = HashSet::new();
+        for (key, _) in &pairs {
+            let key = key.as_ref();
+            self.check_writable(key)?;
+            self.key_rules.validate(key)?;
+            self.check_key_codec(key)?;
+            if self.immutable && !batch_keys.insert(key) {
+                return Err(WriteError::Immutable {
+                    key: key.to_string(),
+                }
+                .into());
+            }
+        }
+
+        for record in &records {
+            self.check_not_reserved(&record.value)?;
+        }
+
+        let written = self.write_records(&records)?;
+        for (record, &(segment, offset)) in records.iter().zip(&written) {
+            self.idx.insert(&record.key, segment, offset);
+            if let Some(pinned) = self.pinned.get_mut(record.key.as_ref()) {
+                *pinned = Some(record.value.to_vec());
+            }
+            self.stop_burning(&record.key)?;
+        }
+
+        self.metrics.counter("deebee.sets", records.len() as u64);
+        self.metrics.histogram(
+            "deebee.set_many_latency_us",
+            started.elapsed().as_micros() as f64,
+        );
+        Ok(records.len())
+    }
+
+    /// delete a key by appending a tombstone, returning whether it existed
+    pub fn delete(&mut self, key: &str) -> Result<bool, DeebeeError> {
+        self.check_writable(key)?;
+        if self.format_version < 2 {
+            return Err(WriteError::FormatTooOld {
+                needed: 2,
+                pinned: self.format_version,
+            }
+            .into());
+        }
+        if !self.contains_key(key) {
+            return Ok(false);
+        }
+
+        self.write_record(Record::tombstone(key))?;
+        self.idx.remove(key);
+        if let Some(pinned) = self.pinned.get_mut(key) {
+            *pinned = None;
+        }
+        self.stop_burning(key)?;
+
+        self.metrics.counter("deebee.deletes", 1);
+        Ok(true)
+    }
+
+    /// `delete` within the options, checked once before it starts and
+    /// leaving background compactions alone, like `set_with`
+    pub fn delete_with(&mut self, key: &str, options: &WriteOptions) -> Result<bool, DeebeeError> {
+        options.check()?;
+        self.bounded_write = true;
+        let result = self.delete(key);
+        self.bounded_write = false;
+        result
+    }
+
+    /// how many batches of the patch from `source` were applied
+    pub fn applied_batches(&self, source: &str) -> Result<u64, DeebeeError> {
+        Ok(patch::load_applied(&self.dir)?
+            .get(source)
+            .copied()
+            .unwrap_or(0))
+    }
+
+    /// apply batch number `batch` of the patch from `source`, all of it or
+    /// none of it, even across a crash. batches go in order from 1 and one
+    /// that already landed is skipped, so a patch can be replayed from the
+    /// start. every op is checked before anything is written. returns
+    /// whether the batch was applied
+    pub fn apply_batch(
+        &mut self,
+        source: &str,
+        batch: u64,
+        ops: Vec<PatchOp>,
+    ) -> Result<bool, DeebeeError> {
+        let applied = self.applied_batches(source)?;
+        if batch <= applied {
+            return Ok(false);
+        }
+        if batch != applied + 1 {
+            return Err(DeebeeError::InvalidArgument(format!(
+                "batch {batch} of {source} can't be applied before batch {}",
+                applied + 1
+            )));
+        }
+
+        let mut batch_keys = HashSet::new();
+        for op in &ops {
+            self.check_writable(op.key())?;
+            match op {
+                PatchOp::Set { key, value } => {
+                    self.key_rules.validate(key)?;
+                    self.check_key_codec(key)?;
+                    self.check_not_reserved(value.as_bytes())?;
+                }
+                PatchOp::Delete { .. } if self.format_version < 2 => {
+                    return Err(WriteError::FormatTooOld {
+                        needed: 2,
+                        pinned: self.format_version,
+                    }
+                    .into());
+                }
+                PatchOp::Delete { .. } => {}
+            }
+            if self.immutable && !batch_keys.insert(op.key()) {
+                return Err(WriteError::Immutable {
+                    key: op.key().to_string(),
+                }
+                .into());
+            }
+        }
+
+        let journal = Journal {
+            source: source.to_string(),
+            batch,
+            ops,
+        };
+        journal.save(&self.dir)?;
+        self.replay_journal(journal)?;
+        Ok(true)
+    }
+
+    /// write a journaled batch to the segments and count it as applied.
+    /// running it twice only writes the records again
+    fn replay_journal(&mut self, journal: Journal) -> Result<(), DeebeeError> {
+        let records: Vec<Record> = journal
+            .ops
+            .iter()
+            .map(|op| match op {
+                PatchOp::Set { key, value } => Record::new(key, value.as_bytes()),
+                PatchOp::Delete { key } => Record::tombstone(key),
+            })
+            .collect();
+        let written = self.write_records(&records)?;
+        // the count says the batch is on disk, whatever the sync policy
+        let segments: HashSet<usize> = written.iter().map(|&(segment, _)| segment).collect();
+        for segment in segments {
+            File::open(&self.segment_files_paths[segment])?.sync_data()?;
+        }
+        Self::lock_unsynced(&self.unsynced).all_synced();
+
+        let (mut sets, mut deletes) = (0, 0);
+        for (op, &(segment, offset)) in journal.ops.iter().zip(&written) {
+            match op {
+                PatchOp::Set { key, value } => {
+                    self.idx.insert(key, segment, offset);
+                    if let Some(pinned) = self.pinned.get_mut(key) {
+                        *pinned = Some(value.clone().into_bytes());
+                    }
+                    self.stop_burning(key)?;
+                    sets += 1;
+                }
+                PatchOp::Delete { key } => {
+                    self.idx.remove(key);
+                    if let Some(pinned) = self.pinned.get_mut(key) {
+                        *pinned = None;
+                    }
+                    self.stop_burning(key)?;
+                    deletes += 1;
+                }
+            }
+        }
+
+        let mut applied = patch::load_applied(&self.dir)?;
+        applied.insert(journal.source, journal.batch);
+        patch::save_applied(&self.dir, &applied)?;
+        Journal::remove(&self.dir)?;
+
+        self.metrics.counter("deebee.sets", sets);
+        self.metrics.counter("deebee.deletes", deletes);
+        Ok(())
+    }
+
+    /// delete the key and return the value it held, if any
+    pub fn remove_get_old(&mut self, key: &str) -> Result<Option<String>, DeebeeError> {
+        let old = self.get(key)?;
+        self.delete(key)?;
+        Ok(old)
+    }
+
+    /// refuse writes to read-only handles, and overwrites or deletes of
+    /// existing keys in immutable databases
+    fn check_writable(&self, key: &str) -> Result<(), WriteError> {
+        if self.read_only {
+            return Err(WriteError::ReadOnly {
+                db_name: self.db_name.clone(),
+            });
+        }
+        if self.immutable && self.contains_key(key) {
+            return Err(WriteError::Immutable {
+                key: key.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// before format version 5 the segments spell deletes and shared values
+    /// as values, ones that look like them can't be written
+    fn check_not_reserved(&self, value: &[u8]) -> Result<(), WriteError> {
+        if self.format_version < 5 && is_reserved(value) {
+            return Err(WriteError::ReservedValue);
+        }
+        Ok(())
+    }
+
+    /// reject writes once another process fenced the database past this
+    /// handle's epoch. deebee.toml is only read again when it changed
+    fn check_fence(&mut self) -> Result<(), DeebeeError> {
+        let modified = fs::metadata(self.root.join(CONFIG_PATH))
+            .and_then(|meta| meta.modified())
+            .ok();
+        if modified.is_none() || modified != self.config_modified {
+            if let Some(db_config) = Config::load(&self.root)?.get_database(&self.db_name) {
+                self.fence_epoch = db_config.fence_epoch.unwrap_or(0);
+            }
+            self.config_modified = modified;
+        }
+
+        if self.fence_epoch > 0 && self.epoch.is_none_or(|epoch| epoch < self.fence_epoch) {
+            return Err(WriteError::Fenced {
+                epoch: self.epoch,
+                fence: self.fence_epoch,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// append a record to the active segment, rotating first when
+    /// it is full. returns the segment and offset the record starts at
+    fn write_record(&mut self, record: Record) -> Result<(usize, u64), DeebeeError> {
+        let written = self.write_records(&[record])?;
+        Ok(written[0])
+    }
+
+    /// append the records in order, as few writes per segment as rotation
+    /// allows. returns where each one starts
+    fn write_records(&mut self, records: &[Record]) -> Result<Vec<(usize, u64)>, DeebeeError> {
+        self.touch_foreground();
+        let encoding = self.encoding();
+        if let Some(record) = records
+            .iter()
+            .find(|record| !encoding.can_encode_record(record))
+        {
+            return Err(WriteError::FormatTooOld {
+                needed: RecordEncoding::format_needed(record),
+                pinned: self.format_version,
+            }
+            .into());
+        }
+
+        self.check_fence()?;
+        self.inject_chaos("write")?;
+        if let Some(cache) = &self.cache {
+            let mut cache = Self::lock_cache(cache);
+            records.iter().for_each(|record| cache.remove(&record.key));
+        }
+
+        // segment positions can shift here, before the caller learns the new ones
+        self.poll_compaction();
+
+        let mut written = Vec::with_capacity(records.len());
+        let mut rest = records;
+        while !rest.is_empty() {
+            if self.active_records >= self.segment_size {
+                self.rotate_segment()?;
+            }
+            // a segment_size of 0 still takes one record per segment
+            let room = self.segment_size.saturating_sub(self.active_records).max(1);
+            let (chunk, later) = rest.split_at(rest.len().min(room));
+            rest = later;
+
+            let mut file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .open(self.active_segment())?;
+            let mut offset = file.metadata()?.len();
+
+            // segments from before records were newline-terminated end without one,
+            // finish that last line so the new record starts on its own
+            if offset > 0 && !encoding.is_checksummed() {
+                let mut last = [0u8; 1];
+                file.seek(SeekFrom::End(-1))?;
+                file.read_exact(&mut last)?;
+                if last[0] != b'\n' {
+                    file.write_all(b"\n")?;
+                    offset += 1;
+                }
+            }
+
+            let segment = self.segment_files_paths.len() - 1;
+            let mut buffer = Vec::new();
+            for record in chunk {
+                written.push((segment, offset + buffer.len() as u64));
+                buffer.extend_from_slice(&encoding.encode_record(record));
+            }
+            file.write_all(&buffer)?;
+
+            let due = match self.sync {
+                SyncPolicy::Always => !self.deferred_sync,
+                SyncPolicy::EverySec => self.last_sync.elapsed() >= Duration::from_secs(1),
+                SyncPolicy::Never => false,
+            };
+            if due {
+                file.sync_data()?;
+                self.last_sync = Instant::now();
+                self.metrics.counter("deebee.fsyncs", 1);
+            }
+            let path = (self.sync != SyncPolicy::Never).then(|| self.active_segment());
+            Self::lock_unsynced(&self.unsynced).wrote(path, due);
+            self.active_records += chunk.len();
+            self.records += chunk.len();
+        }
+
+        let bytes: u64 = records
+            .iter()
+            .map(|record| (record.key.len() + record.value.len()) as u64)
+            .sum();
+        self.session_stats.total_writes += records.len() as u64;
+        self.session_stats.bytes_written += bytes;
+        self.metrics.counter("deebee.bytes_written", bytes);
+
+        Ok(written)
+    }
+}
+
+impl Drop for Database {
+    // fold this session's counters into the sidecar so the next process sees them
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+        if let Err(e) = self.sync_pending() {
+            eprintln!("couldn't fsync {}: {e}", self.db_name);
+        }
+        Self::lock_unsynced(&self.unsynced).close();
+        if let Some(cache) = &self.cache {
+            let hot = Self::lock_cache(cache).hot_keys(cache::HOT_KEYS);
+            if let Err(e) = cache::save_hot_keys(&self.dir, &hot) {
+                eprintln!("couldn't save the hot keys of {}: {e}", self.db_name);
+            }
+        }
+        if let Err(e) = self.finish_compaction() {
+            eprintln!("background compaction of {} failed: {e}", self.db_name);
+        }
+        if let Err(e) = self.save_stats() {
+            eprintln!("couldn't save stats for {}: {e}", self.db_name);
+        }
+    }
+}
+
+/// one line of an export file
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ExportRecord {
+    pub key: String,
+    pub value: String,
+}
+
+/// restricts export/import to a prefix and/or a `[from, to)` key range
+#[derive(Clone, Debug, Default)]
+pub struct KeyFilter {
+    pub prefix: Option<String>,
+    /// inclusive lower bound
+    pub from: Option<String>,
+    /// exclusive upper bound
+    pub to: Option<String>,
+}
+
+/// a value `Database::get_ref` lends out, it derefs to the value's bytes.
+/// it points into the value cache, the mapped segment or the pinned values,
+/// and only holds a copy of its own when the value was in none of them
+pub struct ValueRef<'a>(Lent<'a>);
+
+enum Lent<'a> {
+    Borrowed(&'a [u8]),
+    Shared(Arc<[u8]>),
+    #[cfg(feature = "mmap")]
+    Mapped(Arc<Mmap>, std::ops::Range<usize>),
+    Owned(Vec<u8>),
+}
+
+impl ValueRef<'_> {
+    /// the value as text, `InvalidValue` when it isn't UTF-8
+    pub fn as_str(&self) -> Result<&str, DeebeeError> {
+        std::str::from_utf8(self)
+            .map_err(|_| DeebeeError::InvalidValue("the value isn't UTF-8".to_string()))
+    }
+
+    /// the value, copied unless the `ValueRef` held a copy already
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.0 {
+            Lent::Owned(value) => value,
+            _ => self.to_vec(),
+        }
+    }
+}
+
+impl std::ops::Deref for ValueRef<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            Lent::Borrowed(value) => value,
+            Lent::Shared(value) => value,
+            #[cfg(feature = "mmap")]
+            Lent::Mapped(map, range) => &map[range.clone()],
+            Lent::Owned(value) => value,
+        }
+    }
+}
+
+impl std::fmt::Debug for ValueRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ValueRef").field(&&**self).finish()
+    }
+}
+
+/// a database as it was when `Database::view` was called. writes,
+/// rotations and compactions after that don't show through: segments only
+/// ever grow, and the open handles keep compacted ones readable after they
+/// are deleted. restoring a snapshot rewrites segments in place, so it isn't
+/// covered
+pub struct View {
+    db_name: String,
+    /// where its shared values are
+    dir: PathBuf,
+    idx: Index,
+    /// every segment, how long it was and where its blocks are when it's
+    /// compressed
+    segments: Vec<(File, u64, Option<Blocks>)>,
+    encoding: RecordEncoding,
+    key_codec: Option<Arc<dyn KeyCodec>>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    sensitive_keys: Vec<String>,
+}
+
+impl View {
+    pub fn db_name(&self) -> &str {
+        &self.db_name
+    }
+
+    /// live key/value pairs passing the filter, sorted by key, like
+    /// `Database::export`. values of `sensitive_keys` come out as `<redacted>`
+    pub fn export(&mut self, filter: &KeyFilter) -> Result<Vec<ExportRecord>, DeebeeError> {
+        let mut records = self.export_raw(filter)?;
+        for record in &mut records {
+            if self
+                .sensitive_keys
+                .iter()
+                .any(|pattern| key_matches(pattern, &record.key))
+            {
+                record.value = "<redacted>".to_string();
+            }
+        }
+        Ok(records)
+    }
+
+    /// `export` with the values of `sensitive_keys` left in
+    pub fn export_raw(&mut self, filter: &KeyFilter) -> Result<Vec<ExportRecord>, DeebeeError> {
+        let mut contents = Vec::with_capacity(self.segments.len());
+        for (file, len, blocks) in &mut self.segments {
+            let content = match blocks {
+                Some(blocks) => blocks.read_all(file)?,
+                None => {
+                    let mut content = Vec::new();
+                    file.seek(SeekFrom::Start(0))?;
+                    file.take(*len).read_to_end(&mut content)?;
+                    content
+                }
+            };
+            contents.push(content);
+        }
+
+        let mut records = Vec::new();
+        for (segment, content) in contents.iter().enumerate() {
+            for (offset, record) in segment_records(content, self.encoding) {
+                if self.idx.get(&record.key) == Some((segment, offset))
+                    && filter.matches_ordered(&record.key, self.key_codec.as_deref())
+                {
+                    let value = match record.flags.contains(RecordFlags::OPERAND) {
+                        true => self.fold(&contents, &record.key)?,
+                        false => resolve_value(&self.dir, &record.key, record.value, record.flags)?
+                            .into_owned(),
+                    };
+                    records.push(ExportRecord {
+                        value: into_text(&record.key, value)?,
+                        key: record.key.into_owned(),
+                    });
+                }
+            }
+        }
+        sort_records(&mut records, self.key_codec.as_deref());
+        Ok(records)
+    }
+
+    /// the values of the keys when the view was taken, in the order asked
+    /// for. the keys can be in any namespaces, they're all read as of the
+    /// same moment. keys set with burn-after-read are `None`, like they
+    /// are in exports
+    pub fn get_many<K: AsRef<str>>(
+        &mut self,
+        keys: &[K],
+    ) -> Result<Vec<Option<String>>, DeebeeError> {
+        keys.iter()
+            .map(|key| {
+                let key = key.as_ref();
+                self.get_bytes(key)?
+                    .map(|value| into_text(key, value))
+                    .transpose()
+            })
+            .collect()
+    }
+
+    /// the key's value when the view was taken
+    pub fn get_bytes(&mut self, key: &str) -> Result<Option<Vec<u8>>, DeebeeError> {
+        let Some(location) = self.idx.get(key) else {
+            return Ok(None);
+        };
+        let Some(chain) = self.idx.chain(key).cloned() else {
+            let record = self.read_at(key, location)?;
+            let value = resolve_value(&self.dir, key, record.value, record.flags)?;
+            return Ok(Some(value.into_owned()));
+        };
+        let base = match chain.base {
+            Some(location) => {
+                let record = self.read_at(key, location)?;
+                Some(resolve_value(&self.dir, key, record.value, record.flags)?.into_owned())
+            }
+            None => None,
+        };
+        let operands = chain
+            .operands
+            .iter()
+            .map(|&location| Ok(self.read_at(key, location)?.value.into_owned()))
+            .collect::<Result<_, DeebeeError>>()?;
+        merge::fold(self.merge_operator.as_deref(), key, base, operands)
+    }
+
+    /// the key's record at the location, within the part of the segment
+    /// the view covers
+    fn read_at(
+        &mut self,
+        key: &str,
+        (segment, offset): (usize, u64),
+    ) -> Result<Record<'static>, DeebeeError> {
+        let damaged = || DeebeeError::Corruption(format!("the record of {key} can't be read back"));
+        let encoding = self.encoding;
+        let (file, len, blocks) = self.segments.get_mut(segment).ok_or_else(damaged)?;
+        let record = match blocks {
+            Some(blocks) => compress::read_record(file, blocks, offset, encoding)?,
+            None => {
+                file.seek(SeekFrom::Start(offset))?;
+                let mut reader = io::BufReader::new((&*file).take(len.saturating_sub(offset)));
+                encoding.read_record(&mut reader)?
+            }
+        };
+        let record = encoding.decode(&record).ok_or_else(damaged)?.into_owned();
+        match record.key == key {
+            true => Ok(record),
+            false => Err(damaged()),
+        }
+    }
+
+    /// the key's value folded from the records of its merge chain
+    fn fold(&self, contents: &[Vec<u8>], key: &str) -> Result<Vec<u8>, DeebeeError> {
+        let damaged =
+            || DeebeeError::Corruption(format!("a merge operand of {key} can't be read back"));
+        let chain = self.idx.chain(key).ok_or_else(damaged)?;
+        let read = |(segment, offset): (usize, u64)| {
+            let mut rest = contents
+                .get(segment)?
+                .get(usize::try_from(offset).ok()?..)?;
+            let record = self.encoding.read_record(&mut rest).ok()?;
+            Some(self.encoding.decode(&record)?.into_owned())
+        };
+        let base = match chain.base {
+            Some(location) => {
+                let record = read(location).ok_or_else(damaged)?;
+                Some(
+                    resolve_value(&self.dir, &record.key, record.value, record.flags)?.into_owned(),
+                )
+            }
+            None => None,
+        };
+        let operands = chain
+            .operands
+            .iter()
+            .map(|&location| Some(read(location)?.value.into_owned()))
+            .collect::<Option<_>>()
+            .ok_or_else(damaged)?;
+        Ok(merge::fold(self.merge_operator.as_deref(), key, base, operands)?.unwrap_or_default())
+    }
+}
+
+/// in the key codec's order, or by key bytes without one
+fn sort_records(records: &mut [ExportRecord], key_codec: Option<&dyn KeyCodec>) {
+    match key_codec {
+        Some(codec) => records.sort_by(|a, b| codec.compare(&a.key, &b.key)),
+        None => records.sort_by(|a, b| a.key.cmp(&b.key)),
+    }
+}
+
+/// what `import_with` does with keys the database already has
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OnConflict {
+    #[default]
+    Overwrite,
+    /// keep the value in the database
+    Skip,
+    /// import nothing
+    Fail,
+}
+
+impl std::str::FromStr for OnConflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(OnConflict::Overwrite),
+            "skip" => Ok(OnConflict::Skip),
+            "fail" => Ok(OnConflict::Fail),
+            _ => Err(format!(
+                "unknown conflict policy {s:?}, expected skip, overwrite or fail"
+            )),
+        }
+    }
+}
+
+/// which record of a key repeated within the import wins
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Dedup {
+    #[default]
+    Last,
+    First,
+}
+
+impl std::str::FromStr for Dedup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "last" => Ok(Dedup::Last),
+            "first" => Ok(Dedup::First),
+            _ => Err(format!(
+                "unknown dedup policy {s:?}, expected last or first"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ImportOptions {
+    pub on_conflict: OnConflict,
+    pub dedup: Dedup,
+}
+
+/// what an import did
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// records left out because the key already existed
+    pub skipped: usize,
+    /// records left out because the import had their key more than once
+    pub duplicates: usize,
+}
+
+// what `scan_prefix` keeps, everything
+static NO_FILTER: ScanFilter = ScanFilter {
+    glob: None,
+    contains: None,
+    field: None,
+};
+
+/// what a scan keeps of the keys under its prefix, checked where the
+/// database runs
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScanFilter {
+    /// matched against the whole key, `*` standing for any run of characters
+    pub glob: Option<String>,
+    /// a substring of the value
+    pub contains: Option<String>,
+    /// the value is a JSON object and the field, `a.b` for a nested one,
+    /// equals this. compared as JSON when it parses as JSON, as a string
+    /// otherwise, so `3` is a number and `"3"` or `x` a string
+    pub field: Option<(String, String)>,
+}
+
+impl ScanFilter {
+    /// nothing to filter
+    pub fn is_empty(&self) -> bool {
+        self.glob.is_none() && self.contains.is_none() && self.field.is_none()
+    }
+
+    pub fn matches_key(&self, key: &str) -> bool {
+        self.glob
+            .as_ref()
+            .is_none_or(|pattern| key_matches(pattern, key))
+    }
+
+    pub fn matches_value(&self, value: &str) -> bool {
+        if self
+            .contains
+            .as_ref()
+            .is_some_and(|text| !value.contains(text.as_str()))
+        {
+            return false;
+        }
+        let Some((path, expected)) = &self.field else {
+            return true;
+        };
+        let expected = serde_json::from_str(expected)
+            .unwrap_or_else(|_| serde_json::Value::String(expected.clone()));
+        let Ok(value) = serde_json::from_str::<serde_json::Value>(value) else {
+            return false;
+        };
+        path.split('.')
+            .try_fold(&value, |value, name| value.get(name))
+            .is_some_and(|field| *field == expected)
+    }
+}
+
+impl KeyFilter {
+    /// whether the key passes, with `from` and `to` compared by key bytes
+    pub fn matches(&self, key: &str) -> bool {
+        self.matches_ordered(key, None)
+    }
+
+    /// the same with `from` and `to` compared in the codec's order, the one
+    /// the database lists its keys in
+    pub fn matches_ordered(&self, key: &str, key_codec: Option<&dyn KeyCodec>) -> bool {
+        let compare = |a: &str, b: &str| match key_codec {
+            Some(codec) => codec.compare(a, b),
+            None => a.cmp(b),
+        };
+        self.prefix
+            .as_ref()
+            .is_none_or(|p| key.starts_with(p.as_str()))
+            && self
+                .from
+                .as_ref()
+                .is_none_or(|from| compare(key, from) != std::cmp::Ordering::Less)
+            && self
+                .to
+                .as_ref()
+                .is_none_or(|to| compare(key, to) == std::cmp::Ordering::Less)
+    }
+}
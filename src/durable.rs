@@ -0,0 +1,146 @@
+//! what the fsyncs covered so far: the handle's writes are numbered in
+//! batches, an fsync of the active segment covers every batch written before
+//! it. `Database::set_async` waits on that
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// how a write of `Database::set_async` ended up
+#[derive(Clone, Debug, PartialEq)]
+pub enum Durable {
+    /// an fsync covering it completed, it survives a crash
+    Synced,
+    /// the fsync failed or the database was closed before one ran, the
+    /// write may be lost in a crash
+    Failed(String),
+}
+
+impl Durable {
+    pub fn is_synced(&self) -> bool {
+        *self == Durable::Synced
+    }
+}
+
+/// the writes no fsync covered yet, shared with the flush task
+#[derive(Default)]
+pub(crate) struct Unsynced {
+    /// the active segment while it has writes that weren't fsynced
+    path: Option<String>,
+    /// batches written so far, and the last one an fsync covered
+    written: u64,
+    synced: u64,
+    /// futures of `set_async` and the batch each waits for
+    waiting: Vec<(u64, Arc<Mutex<Slot>>)>,
+}
+
+#[derive(Default)]
+struct Slot {
+    outcome: Option<Durable>,
+    waker: Option<Waker>,
+}
+
+impl Unsynced {
+    /// a batch went into the segment, `synced` when it was fsynced along
+    /// with everything before it. `path` is left out when nothing should
+    /// fsync it
+    pub(crate) fn wrote(&mut self, path: Option<&str>, synced: bool) {
+        self.written += 1;
+        if synced {
+            self.all_synced();
+        } else if let Some(path) = path {
+            self.path = Some(path.to_string());
+        }
+    }
+
+    /// the last batch written
+    pub(crate) fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// the segment to fsync and the last batch the fsync covers
+    pub(crate) fn take(&mut self) -> Option<(String, u64)> {
+        self.path.take().map(|path| (path, self.written))
+    }
+
+    /// the fsync of what `take` handed out is done. a failed one is tried
+    /// again by the next flush, the writes waiting on this one hear of it
+    pub(crate) fn finish(&mut self, path: String, covered: u64, result: Result<(), String>) {
+        match result {
+            Ok(()) => {
+                self.synced = self.synced.max(covered);
+                self.resolve(covered, Durable::Synced);
+            }
+            Err(e) => {
+                self.path.get_or_insert(path);
+                self.resolve(covered, Durable::Failed(e));
+            }
+        }
+    }
+
+    /// everything written so far was fsynced some other way
+    pub(crate) fn all_synced(&mut self) {
+        self.path = None;
+        self.synced = self.written;
+        self.resolve(self.written, Durable::Synced);
+    }
+
+    /// resolves once an fsync covers the batch, which went into the
+    /// segment. the next flush fsyncs it whatever the sync policy
+    pub(crate) fn wait(&mut self, batch: u64, path: &str) -> WaitDurable {
+        let slot = Arc::new(Mutex::new(Slot::default()));
+        if batch <= self.synced {
+            lock(&slot).outcome = Some(Durable::Synced);
+        } else {
+            self.path.get_or_insert_with(|| path.to_string());
+            self.waiting.push((batch, slot.clone()));
+        }
+        WaitDurable { slot }
+    }
+
+    /// the handle is going away, no fsync is coming for what's still waiting
+    pub(crate) fn close(&mut self) {
+        let closed = Durable::Failed("the database was closed before an fsync".to_string());
+        self.resolve(u64::MAX, closed);
+    }
+
+    fn resolve(&mut self, covered: u64, outcome: Durable) {
+        self.waiting.retain(|(batch, slot)| {
+            if *batch > covered {
+                return true;
+            }
+            let mut slot = lock(slot);
+            slot.outcome = Some(outcome.clone());
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+            false
+        });
+    }
+}
+
+fn lock(slot: &Mutex<Slot>) -> std::sync::MutexGuard<'_, Slot> {
+    slot.lock()
+        .expect("nothing panics holding a durability slot")
+}
+
+/// the future `set_async` hands back
+pub(crate) struct WaitDurable {
+    slot: Arc<Mutex<Slot>>,
+}
+
+impl Future for WaitDurable {
+    type Output = Durable;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Durable> {
+        let mut slot = lock(&self.slot);
+        match slot.outcome.take() {
+            Some(outcome) => Poll::Ready(outcome),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,175 @@
+/// why a key was rejected by the database's key rules
+#[derive(Debug)]
+pub enum KeyError {
+    TooLong {
+        len: usize,
+        max: usize,
+    },
+    InvalidChar(char),
+    MissingPrefix(String),
+    /// the database's key codec has no place for it
+    Rejected {
+        codec: String,
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::TooLong { len, max } => {
+                write!(f, "key is {len} bytes long, the limit is {max}")
+            }
+            KeyError::InvalidChar(c) => write!(f, "key contains disallowed character {c:?}"),
+            KeyError::MissingPrefix(prefix) => write!(f, "key must start with {prefix:?}"),
+            KeyError::Rejected { codec, reason } => {
+                write!(f, "key rejected by the {codec} key codec: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// why a write was refused even though the key itself is valid
+#[derive(Debug)]
+pub enum WriteError {
+    ReadOnly {
+        db_name: String,
+    },
+    /// the database is write-once and the key already has a value
+    Immutable {
+        key: String,
+    },
+    /// the value is the tombstone marker or looks like a reference to a
+    /// shared value, it would read back as a delete or as that value. only
+    /// before format version 5, later records flag those
+    ReservedValue,
+    /// the write needs a newer format than the database is pinned to
+    FormatTooOld {
+        needed: u32,
+        pinned: u32,
+    },
+    /// the database was fenced at a newer epoch than the handle's, it belongs
+    /// to a primary that has been replaced
+    Fenced {
+        epoch: Option<u64>,
+        fence: u64,
+    },
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteError::ReadOnly { db_name } => write!(f, "database {db_name} is open read-only"),
+            WriteError::Immutable { key } => {
+                write!(f, "key {key} already exists and the database is immutable")
+            }
+            WriteError::ReservedValue => {
+                write!(f, "value is reserved for tombstones and shared values")
+            }
+            WriteError::FormatTooOld { needed, pinned } => write!(
+                f,
+                "needs format version {needed} but the database is pinned to {pinned}, run `upgrade --format-version {needed}`"
+            ),
+            WriteError::Fenced {
+                epoch: Some(epoch),
+                fence,
+            } => write!(
+                f,
+                "writes are fenced at epoch {fence}, this handle is at epoch {epoch}"
+            ),
+            WriteError::Fenced { epoch: None, fence } => write!(
+                f,
+                "writes are fenced at epoch {fence}, this handle has no epoch"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+/// everything a database operation can fail with
+#[derive(Debug)]
+pub enum DeebeeError {
+    /// a file couldn't be read or written
+    Io(std::io::Error),
+    KeyNotFound(String),
+    /// a segment or description holds something that doesn't parse or line up
+    Corruption(String),
+    InvalidKey(KeyError),
+    /// the value was rejected, e.g. by the JSON Schema
+    InvalidValue(String),
+    /// the key and value are fine but the database refuses the write
+    Write(WriteError),
+    /// deebee.toml, a schema file or the open options are unusable
+    Config(String),
+    /// the request can't be honored, e.g. an unknown snapshot or format version
+    InvalidArgument(String),
+    /// another process holds the database's lock file
+    Locked(String),
+    /// the operation's deadline passed or its cancel token was cancelled
+    /// before it finished
+    Cancelled(String),
+    /// the server's client isn't allowed to do that, see `DatabaseConfig::roles`
+    Denied(String),
+}
+
+impl std::fmt::Display for DeebeeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeebeeError::Io(e) => write!(f, "{e}"),
+            DeebeeError::KeyNotFound(key) => write!(f, "key {key} not found"),
+            DeebeeError::Corruption(reason) => write!(f, "corrupt data: {reason}"),
+            DeebeeError::InvalidKey(e) => write!(f, "{e}"),
+            DeebeeError::InvalidValue(reason) => write!(f, "{reason}"),
+            DeebeeError::Write(e) => write!(f, "{e}"),
+            DeebeeError::Config(reason) => write!(f, "{reason}"),
+            DeebeeError::InvalidArgument(reason) => write!(f, "{reason}"),
+            DeebeeError::Locked(reason) => write!(f, "{reason}"),
+            DeebeeError::Cancelled(reason) => write!(f, "{reason}"),
+            DeebeeError::Denied(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl std::error::Error for DeebeeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DeebeeError::Io(e) => Some(e),
+            DeebeeError::InvalidKey(e) => Some(e),
+            DeebeeError::Write(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DeebeeError {
+    fn from(e: std::io::Error) -> Self {
+        DeebeeError::Io(e)
+    }
+}
+
+impl From<KeyError> for DeebeeError {
+    fn from(e: KeyError) -> Self {
+        DeebeeError::InvalidKey(e)
+    }
+}
+
+impl From<WriteError> for DeebeeError {
+    fn from(e: WriteError) -> Self {
+        DeebeeError::Write(e)
+    }
+}
+
+impl From<toml::de::Error> for DeebeeError {
+    fn from(e: toml::de::Error) -> Self {
+        DeebeeError::Config(format!("couldn't parse deebee.toml: {e}"))
+    }
+}
+
+impl From<toml::ser::Error> for DeebeeError {
+    fn from(e: toml::ser::Error) -> Self {
+        DeebeeError::Config(format!("couldn't serialize config: {e}"))
+    }
+}
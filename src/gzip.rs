@@ -0,0 +1,457 @@
+//! a small gzip encoder for HTTP responses: LZ77 over a 32 KiB window and
+//! DEFLATE's fixed Huffman codes, which gets most of the way on JSON and
+//! text without a dictionary of its own. written as it goes, so a streamed
+//! response is compressed a chunk at a time. the blocks of compressed
+//! segments are the same DEFLATE without the gzip framing, read back by
+//! `inflate`
+
+use std::io::{self, Write};
+
+// input compressed into one block, and how far back a match can reach
+const CHUNK: usize = 64 << 10;
+const WINDOW: usize = 32 << 10;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// earlier positions with the same hash tried before settling for the best so far
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// compresses what's written to it into a gzip stream on `out`. `finish`
+/// ends the stream, dropping the writer without it leaves a truncated one
+pub(crate) struct GzipWriter<W: Write> {
+    out: W,
+    pending: Vec<u8>,
+    bits: Bits,
+    crc: crc32fast::Hasher,
+    size: u32,
+}
+
+impl<W: Write> GzipWriter<W> {
+    pub(crate) fn new(mut out: W) -> io::Result<Self> {
+        // no name, no timestamp, unknown OS
+        out.write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff])?;
+        Ok(Self {
+            out,
+            pending: Vec::with_capacity(CHUNK),
+            bits: Bits::default(),
+            crc: crc32fast::Hasher::new(),
+            size: 0,
+        })
+    }
+
+    /// compress what's left, write the trailer and hand back the stream
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.compress_pending()?;
+        // an empty last block, then the bits padded out to a byte
+        self.bits.put(1, 1);
+        self.bits.put(1, 2);
+        self.bits.literal(256);
+        self.bits.pad();
+        self.out.write_all(&self.bits.bytes)?;
+        self.out
+            .write_all(&self.crc.clone().finalize().to_le_bytes())?;
+        self.out.write_all(&self.size.to_le_bytes())?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn compress_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.bits.block(&self.pending);
+        self.pending.clear();
+        self.out.write_all(&self.bits.bytes)?;
+        self.bits.bytes.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for GzipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let taken = buf.len().min(CHUNK - self.pending.len());
+        self.pending.extend_from_slice(&buf[..taken]);
+        self.crc.update(&buf[..taken]);
+        self.size = self.size.wrapping_add(taken as u32);
+        if self.pending.len() == CHUNK {
+            self.compress_pending()?;
+        }
+        Ok(taken)
+    }
+
+    /// compresses what's buffered so far, a few bits of it stay behind until
+    /// the next block fills the byte
+    fn flush(&mut self) -> io::Result<()> {
+        self.compress_pending()?;
+        self.out.flush()
+    }
+}
+
+/// `data` as one raw DEFLATE stream, no gzip header or trailer
+pub(crate) fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut bits = Bits::default();
+    for chunk in data.chunks(CHUNK) {
+        bits.block(chunk);
+    }
+    bits.put(1, 1);
+    bits.put(1, 2);
+    bits.literal(256);
+    bits.pad();
+    bits.bytes
+}
+
+/// the `len` bytes the raw DEFLATE stream holds. takes every block type,
+/// not only the fixed codes `deflate` writes, and fails on a stream that
+/// holds more or doesn't parse
+pub(crate) fn inflate(data: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let mut bits = BitReader {
+        data,
+        pos: 0,
+        acc: 0,
+        len: 0,
+    };
+    let mut out = Vec::with_capacity(len);
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => {
+                bits.align();
+                let stored = bits.take(16)?;
+                if stored != !bits.take(16)? & 0xffff {
+                    return Err(damaged(
+                        "a stored block's length doesn't match its complement",
+                    ));
+                }
+                for _ in 0..stored {
+                    out.push(bits.take(8)? as u8);
+                }
+            }
+            1 => {
+                let (literals, distances) = fixed_codes()?;
+                inflate_block(&mut bits, &mut out, len, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut out, len, &literals, &distances)?;
+            }
+            _ => return Err(damaged("a block of the reserved type")),
+        }
+        if out.len() > len {
+            return Err(damaged("more data than expected"));
+        }
+        if last {
+            break;
+        }
+    }
+    match out.len() == len {
+        true => Ok(out),
+        false => Err(damaged("less data than expected")),
+    }
+}
+
+fn damaged(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad DEFLATE stream: {reason}"),
+    )
+}
+
+/// the literals and lengths up to end of block, and the distances after them
+fn inflate_block(
+    bits: &mut BitReader,
+    out: &mut Vec<u8>,
+    len: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> io::Result<()> {
+    loop {
+        let symbol = literals.decode(bits)? as usize;
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+        let i = symbol - 257;
+        if i >= LENGTH_BASE.len() {
+            return Err(damaged("a length code past the table"));
+        }
+        let length = LENGTH_BASE[i] as usize + bits.take(LENGTH_EXTRA[i] as u32)? as usize;
+        let i = distances.decode(bits)? as usize;
+        if i >= DISTANCE_BASE.len() {
+            return Err(damaged("a distance code past the table"));
+        }
+        let distance = DISTANCE_BASE[i] as usize + bits.take(DISTANCE_EXTRA[i] as u32)? as usize;
+        if distance > out.len() {
+            return Err(damaged("a distance back past the start"));
+        }
+        if out.len() + length > len {
+            return Err(damaged("more data than expected"));
+        }
+        // a match can overlap what it copies
+        let start = out.len() - distance;
+        for k in start..start + length {
+            out.push(out[k]);
+        }
+    }
+}
+
+/// the codes of blocks of type 1
+fn fixed_codes() -> io::Result<(Huffman, Huffman)> {
+    let mut lengths = [8; 288];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+/// the codes a block of type 2 starts with, themselves Huffman coded
+fn dynamic_codes(bits: &mut BitReader) -> io::Result<(Huffman, Huffman)> {
+    const ORDER: [usize; 19] = [
+        16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+    ];
+    let literals = bits.take(5)? as usize + 257;
+    let distances = bits.take(5)? as usize + 1;
+    let mut code_lengths = [0; 19];
+    for &i in &ORDER[..bits.take(4)? as usize + 4] {
+        code_lengths[i] = bits.take(3)? as u8;
+    }
+    let code = Huffman::new(&code_lengths)?;
+
+    let mut lengths = vec![0; literals + distances];
+    let mut i = 0;
+    while i < lengths.len() {
+        let (length, repeat) = match code.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => match i.checked_sub(1) {
+                Some(previous) => (lengths[previous], 3 + bits.take(2)? as usize),
+                None => return Err(damaged("a repeat with nothing before it")),
+            },
+            17 => (0, 3 + bits.take(3)? as usize),
+            _ => (0, 11 + bits.take(7)? as usize),
+        };
+        let Some(run) = lengths.get_mut(i..i + repeat) else {
+            return Err(damaged("code lengths past the table"));
+        };
+        run.fill(length);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(damaged("no end of block code"));
+    }
+    Ok((
+        Huffman::new(&lengths[..literals])?,
+        Huffman::new(&lengths[literals..])?,
+    ))
+}
+
+/// a canonical Huffman code: how many codes there are of each length, and
+/// the symbols in code order
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Self> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(damaged("more codes than their lengths allow"));
+            }
+        }
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    /// the next symbol, a bit at a time
+    fn decode(&self, bits: &mut BitReader) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.take(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(damaged("a code that isn't in the table"))
+    }
+}
+
+/// DEFLATE's bit stream read back, least significant bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u64,
+    len: u32,
+}
+
+impl BitReader<'_> {
+    fn take(&mut self, count: u32) -> io::Result<u32> {
+        while self.len < count {
+            let Some(&byte) = self.data.get(self.pos) else {
+                return Err(damaged("the stream ends early"));
+            };
+            self.pos += 1;
+            self.acc |= (byte as u64) << self.len;
+            self.len += 8;
+        }
+        let value = (self.acc & ((1 << count) - 1)) as u32;
+        self.acc >>= count;
+        self.len -= count;
+        Ok(value)
+    }
+
+    /// skip to the next byte, stored blocks start on one
+    fn align(&mut self) {
+        let partial = self.len % 8;
+        self.acc >>= partial;
+        self.len -= partial;
+    }
+}
+
+/// DEFLATE's bit stream, least significant bit first
+#[derive(Default)]
+struct Bits {
+    bytes: Vec<u8>,
+    acc: u64,
+    len: u32,
+}
+
+impl Bits {
+    fn put(&mut self, value: u32, count: u32) {
+        self.acc |= (value as u64) << self.len;
+        self.len += count;
+        while self.len >= 8 {
+            self.bytes.push(self.acc as u8);
+            self.acc >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// Huffman codes go most significant bit first
+    fn put_code(&mut self, code: u32, len: u32) {
+        self.put(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn pad(&mut self) {
+        if self.len > 0 {
+            self.bytes.push(self.acc as u8);
+            self.acc = 0;
+            self.len = 0;
+        }
+    }
+
+    /// a literal byte, the end of block or a length code, in the fixed codes
+    fn literal(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.put_code(0x30 + symbol, 8),
+            144..=255 => self.put_code(0x190 + symbol - 144, 9),
+            256..=279 => self.put_code(symbol - 256, 7),
+            _ => self.put_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn back_reference(&mut self, len: usize, distance: usize) {
+        let i = LENGTH_BASE.partition_point(|&base| base as usize <= len) - 1;
+        self.literal(257 + i as u32);
+        self.put(
+            (len - LENGTH_BASE[i] as usize) as u32,
+            LENGTH_EXTRA[i] as u32,
+        );
+        let i = DISTANCE_BASE.partition_point(|&base| base as usize <= distance) - 1;
+        self.put_code(i as u32, 5);
+        self.put(
+            (distance - DISTANCE_BASE[i] as usize) as u32,
+            DISTANCE_EXTRA[i] as u32,
+        );
+    }
+
+    /// one block that isn't the last, matching only within `data`
+    fn block(&mut self, data: &[u8]) {
+        self.put(0, 1);
+        self.put(1, 2);
+
+        let hash = |i: usize| {
+            let three = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], 0]);
+            (three.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+        };
+        let mut head = vec![usize::MAX; 1 << HASH_BITS];
+        let mut prev = vec![usize::MAX; data.len()];
+        let mut i = 0;
+        while i < data.len() {
+            let (mut best_len, mut best_distance) = (0, 0);
+            if i + MIN_MATCH <= data.len() {
+                let longest = (data.len() - i).min(MAX_MATCH);
+                let mut candidate = head[hash(i)];
+                let mut tries = 0;
+                while candidate != usize::MAX && i - candidate <= WINDOW && tries < MAX_CHAIN {
+                    let len = data[candidate..]
+                        .iter()
+                        .zip(&data[i..i + longest])
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    if len > best_len {
+                        (best_len, best_distance) = (len, i - candidate);
+                        if len == longest {
+                            break;
+                        }
+                    }
+                    candidate = prev[candidate];
+                    tries += 1;
+                }
+            }
+
+            let step = if best_len >= MIN_MATCH {
+                self.back_reference(best_len, best_distance);
+                best_len
+            } else {
+                self.literal(data[i] as u32);
+                1
+            };
+            let hashed = (i + step).min(data.len().saturating_sub(MIN_MATCH - 1));
+            for (j, link) in (i..hashed).zip(&mut prev[i.min(hashed)..hashed]) {
+                let h = hash(j);
+                *link = head[h];
+                head[h] = j;
+            }
+            i += step;
+        }
+        self.literal(256);
+    }
+}
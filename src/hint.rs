@@ -0,0 +1,178 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::compress;
+use crate::error::DeebeeError;
+use crate::index::Index;
+use crate::segment::{RecordEncoding, RecordFlags, sized_records};
+
+const MAGIC: &[u8; 8] = b"DBHINT01";
+// magic, segment_len u64, records u64
+const HEADER: usize = 24;
+// key_len u32, offset u64, size u32, the record's flags u8. hints from when
+// records had no flags hold 1 for a tombstone there, what the flag is too
+const ENTRY_HEADER: usize = 17;
+
+/// the latest record of every key in one sealed segment, and the merge
+/// operands after it, so opening can build the index without reading the
+/// segment itself
+pub(crate) struct Hint {
+    /// size of the segment the hint was written for, a different size means
+    /// the hint is stale
+    segment_len: u64,
+    /// records in the segment, live or not
+    pub(crate) records: usize,
+    entries: Vec<HintEntry>,
+}
+
+struct HintEntry {
+    key: String,
+    offset: u64,
+    /// bytes the record takes up in the segment
+    size: u32,
+    flags: RecordFlags,
+}
+
+/// `db3.log` keeps its hint in `db3.hint`
+pub(crate) fn hint_path(segment: &str) -> PathBuf {
+    Path::new(segment).with_extension("hint")
+}
+
+impl Hint {
+    /// scan a segment for the latest record of each key, and the merge
+    /// operands after it
+    pub(crate) fn from_segment(content: &[u8], encoding: RecordEncoding) -> Self {
+        let mut latest: HashMap<String, usize> = HashMap::new();
+        let mut keys: Vec<Vec<HintEntry>> = Vec::new();
+        let mut records = 0;
+        for (offset, size, record) in sized_records(content, encoding) {
+            let entry = HintEntry {
+                key: record.key.to_string(),
+                offset,
+                size: size as u32,
+                flags: record.flags,
+            };
+            match latest.get(entry.key.as_str()) {
+                Some(&i) if entry.flags.contains(RecordFlags::OPERAND) => keys[i].push(entry),
+                Some(&i) => keys[i] = vec![entry],
+                None => {
+                    latest.insert(entry.key.clone(), keys.len());
+                    keys.push(vec![entry]);
+                }
+            }
+            records += 1;
+        }
+
+        Self {
+            segment_len: content.len() as u64,
+            records,
+            entries: keys.into_iter().flatten().collect(),
+        }
+    }
+
+    /// `from_segment` of the segment file, compressed or not. it goes stale
+    /// once the file's size changes, not the size of its records
+    pub(crate) fn from_file(segment: &str, encoding: RecordEncoding) -> io::Result<Self> {
+        let mut hint = Self::from_segment(&compress::read_segment(segment)?, encoding);
+        hint.segment_len = fs::metadata(segment)?.len();
+        Ok(hint)
+    }
+
+    /// the hint for the segment, `None` when there is none or it doesn't
+    /// describe the segment as it is now
+    pub(crate) fn load(segment: &str) -> Option<Self> {
+        let bytes = fs::read(hint_path(segment)).ok()?;
+        let hint = Self::decode(&bytes)?;
+        let segment_len = fs::metadata(segment).ok()?.len();
+        (hint.segment_len == segment_len).then_some(hint)
+    }
+
+    /// write the hint next to the segment, replacing any older one
+    pub(crate) fn save(&self, segment: &str) -> Result<(), DeebeeError> {
+        let path = hint_path(segment);
+        let tmp_path = path.with_extension("hint.tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&self.encode())?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// offset and size of the segment's last record, `None` for an empty segment
+    pub(crate) fn last_record(&self) -> Option<(u64, u32)> {
+        self.entries
+            .iter()
+            .map(|entry| (entry.offset, entry.size))
+            .max()
+    }
+
+    /// point the index at the segment's records, `segment` being its
+    /// position, and keep `burning` to the keys whose latest record burns
+    pub(crate) fn apply(&self, idx: &mut Index, burning: &mut HashSet<String>, segment: usize) {
+        for entry in &self.entries {
+            if entry.flags.contains(RecordFlags::TOMBSTONE) {
+                idx.remove(&entry.key);
+            } else if entry.flags.contains(RecordFlags::OPERAND) {
+                idx.push_operand(&entry.key, segment, entry.offset);
+            } else {
+                idx.insert(&entry.key, segment, entry.offset);
+            }
+            if entry.flags.contains(RecordFlags::BURN) {
+                burning.insert(entry.key.clone());
+            } else {
+                burning.remove(&entry.key);
+            }
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER + self.entries.len() * (ENTRY_HEADER + 16));
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&self.segment_len.to_le_bytes());
+        bytes.extend_from_slice(&(self.records as u64).to_le_bytes());
+        for entry in &self.entries {
+            bytes.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&entry.offset.to_le_bytes());
+            bytes.extend_from_slice(&entry.size.to_le_bytes());
+            bytes.push(entry.flags.bits());
+            bytes.extend_from_slice(entry.key.as_bytes());
+        }
+        let crc = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (body, crc) = bytes.split_at_checked(bytes.len().checked_sub(4)?)?;
+        if crc32fast::hash(body).to_le_bytes() != crc || body.get(..8)? != MAGIC {
+            return None;
+        }
+        let u64_at = |at: usize| Some(u64::from_le_bytes(body.get(at..at + 8)?.try_into().ok()?));
+        let u32_at = |at: usize| Some(u32::from_le_bytes(body.get(at..at + 4)?.try_into().ok()?));
+
+        let segment_len = u64_at(8)?;
+        let records = u64_at(16)? as usize;
+        let mut entries = Vec::new();
+        let mut at = HEADER;
+        while at < body.len() {
+            let key_len = u32_at(at)? as usize;
+            let key_start = at + ENTRY_HEADER;
+            let key = body.get(key_start..key_start + key_len)?;
+            entries.push(HintEntry {
+                key: String::from_utf8(key.to_vec()).ok()?,
+                offset: u64_at(at + 4)?,
+                size: u32_at(at + 12)?,
+                flags: RecordFlags::from_bits(*body.get(at + 16)?)?,
+            });
+            at = key_start + key_len;
+        }
+
+        Some(Self {
+            segment_len,
+            records,
+            entries,
+        })
+    }
+}
@@ -0,0 +1,321 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::database::{Database, ExportRecord, KeyFilter};
+use crate::error::DeebeeError;
+use crate::gzip::GzipWriter;
+
+// a request head bigger than this is not a client we want to talk to
+const MAX_HEAD_BYTES: usize = 8 << 10;
+// body bytes gathered into one chunk of a chunked response
+const CHUNK_BYTES: usize = 16 << 10;
+
+/// serves `GET /export.jsonl` over plain HTTP, for batch jobs that pull data
+/// without shell access. every request needs `Authorization: Bearer <token>`.
+/// `prefix`, `from` and `to` query parameters narrow the export like the
+/// flags of `deebee export`, and like it values of sensitive keys come out
+/// as `<redacted>` unless the request asks for `raw=true`. connections are handled one at a time and see
+/// what the handle sees, writes other processes made since it was opened
+/// show up once it's reloaded. records go out as they are read, in a chunked
+/// response: a failure halfway ends the connection without the last chunk,
+/// so clients see a broken transfer rather than a shorter export. gzipped
+/// for clients that accept it
+pub struct ExportServer {
+    listener: TcpListener,
+    token: String,
+}
+
+impl ExportServer {
+    pub fn bind(addr: impl ToSocketAddrs, token: &str) -> Result<Self, DeebeeError> {
+        if token.is_empty() {
+            return Err(DeebeeError::InvalidArgument(
+                "the export server needs a non-empty token".to_string(),
+            ));
+        }
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            token: token.to_string(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, DeebeeError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// answer requests until the listener fails. a failed request is
+    /// reported and the next one served
+    pub fn serve(&self, db: &Database) -> Result<(), DeebeeError> {
+        loop {
+            if let Err(e) = self.serve_one(db) {
+                eprintln!("export request failed: {e}");
+            }
+        }
+    }
+
+    /// wait for the next connection and answer its request
+    pub fn serve_one(&self, db: &Database) -> Result<(), DeebeeError> {
+        let (stream, _) = self.listener.accept()?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut out = BufWriter::new(stream);
+
+        let head = match read_head(&mut reader) {
+            Ok(head) => head,
+            Err(e) => return respond(&mut out, "400 Bad Request", &e.to_string()),
+        };
+        let Some((method, target)) = head.first().and_then(|line| {
+            let mut parts = line.split(' ');
+            Some((parts.next()?, parts.next()?))
+        }) else {
+            return respond(&mut out, "400 Bad Request", "malformed request line");
+        };
+
+        let authorized = head[1..].iter().any(|header| {
+            header.split_once(':').is_some_and(|(name, value)| {
+                name.trim().eq_ignore_ascii_case("authorization")
+                    && value
+                        .trim()
+                        .strip_prefix("Bearer ")
+                        .is_some_and(|token| same_token(token.trim(), &self.token))
+            })
+        });
+        if !authorized {
+            return respond(
+                &mut out,
+                "401 Unauthorized",
+                "missing or wrong bearer token",
+            );
+        }
+
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        if path != "/export.jsonl" {
+            return respond(&mut out, "404 Not Found", "only /export.jsonl is served");
+        }
+        if method != "GET" {
+            return respond(&mut out, "405 Method Not Allowed", "export is read-only");
+        }
+
+        let mut filter = KeyFilter::default();
+        let mut raw = false;
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let (Some(name), Some(value)) =
+                (percent_decode_query(name), percent_decode_query(value))
+            else {
+                return respond(&mut out, "400 Bad Request", "malformed query string");
+            };
+            match name.as_str() {
+                "prefix" => filter.prefix = Some(value),
+                "from" => filter.from = Some(value),
+                "to" => filter.to = Some(value),
+                "raw" => match value.as_str() {
+                    "true" => raw = true,
+                    "false" => raw = false,
+                    _ => {
+                        return respond(&mut out, "400 Bad Request", "raw is either true or false");
+                    }
+                },
+                _ => {
+                    return respond(
+                        &mut out,
+                        "400 Bad Request",
+                        &format!("unknown parameter {name}"),
+                    );
+                }
+            }
+        }
+
+        // the handle is borrowed for the whole response, nothing in this
+        // process writes to it until the last record is out
+        let mut records = db.export_records_iter(&filter, raw).peekable();
+        if let Some(Err(e)) = records.peek() {
+            return respond(&mut out, "500 Internal Server Error", &e.to_string());
+        }
+        let gzip = head[1..].iter().any(|header| {
+            header.split_once(':').is_some_and(|(name, value)| {
+                name.trim().eq_ignore_ascii_case("accept-encoding") && accepts_gzip(value)
+            })
+        });
+        out.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\n")?;
+        if gzip {
+            out.write_all(b"Content-Encoding: gzip\r\nVary: Accept-Encoding\r\n")?;
+        }
+        out.write_all(b"Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n")?;
+        // an error returns before `finish`, the terminating chunk never goes out
+        let out = ChunkedWriter::new(out);
+        let out = if gzip {
+            let mut out = GzipWriter::new(out)?;
+            write_records(&mut out, records)?;
+            out.finish()?
+        } else {
+            let mut out = out;
+            write_records(&mut out, records)?;
+            out
+        };
+        out.finish()?;
+        Ok(())
+    }
+}
+
+/// the body of a `Transfer-Encoding: chunked` response. `finish` sends the
+/// terminating chunk, without it the client knows the body is incomplete
+struct ChunkedWriter<W: Write> {
+    out: W,
+    pending: Vec<u8>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    fn new(out: W) -> Self {
+        Self {
+            out,
+            pending: Vec::with_capacity(CHUNK_BYTES),
+        }
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.write_chunk()?;
+        self.out.write_all(b"0\r\n\r\n")?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        // an empty chunk would be the terminating one
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        write!(self.out, "{:x}\r\n", self.pending.len())?;
+        self.out.write_all(&self.pending)?;
+        self.out.write_all(b"\r\n")?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        if self.pending.len() >= CHUNK_BYTES {
+            self.write_chunk()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.out.flush()
+    }
+}
+
+fn write_records(
+    out: &mut impl Write,
+    records: impl Iterator<Item = Result<ExportRecord, DeebeeError>>,
+) -> Result<(), DeebeeError> {
+    for record in records {
+        let line = serde_json::to_string(&record?).expect("export records always serialize");
+        out.write_all(line.as_bytes())?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// whether an `Accept-Encoding` header takes gzip, `gzip;q=0` turning it down
+pub(crate) fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let refused = parts.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    })
+}
+
+/// the request line and headers, without their line endings
+pub(crate) fn read_head(reader: &mut impl BufRead) -> io::Result<Vec<String>> {
+    let mut head = Vec::new();
+    let mut total = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed mid-request",
+            ));
+        }
+        total += line.len();
+        if total > MAX_HEAD_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head too large",
+            ));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            return Ok(head);
+        }
+        head.push(line.to_string());
+    }
+}
+
+fn respond(out: &mut impl Write, status: &str, message: &str) -> Result<(), DeebeeError> {
+    let auth = if status.starts_with("401") {
+        "WWW-Authenticate: Bearer\r\n"
+    } else {
+        ""
+    };
+    write!(
+        out,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\n{auth}Content-Length: {}\r\nConnection: close\r\n\r\n{message}\n",
+        message.len() + 1
+    )?;
+    out.flush()?;
+    Ok(())
+}
+
+// compares every byte so the time taken doesn't give away how much matched
+pub(crate) fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// `%XX` escapes of a URL path, where `+` is just a `+`
+pub(crate) fn percent_decode_path(s: &str) -> Option<String> {
+    percent_decode(s, false)
+}
+
+/// a query string name or value, where `+` also stands for a space
+pub(crate) fn percent_decode_query(s: &str) -> Option<String> {
+    percent_decode(s, true)
+}
+
+fn percent_decode(s: &str, plus_is_space: bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        match b {
+            b'%' => {
+                let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &tail[2..];
+            }
+            b'+' if plus_is_space => {
+                bytes.push(b' ');
+                rest = tail;
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
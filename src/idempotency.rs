@@ -0,0 +1,71 @@
+//! idempotency keys for server mode: a client sends a token of its own with
+//! a write, and a retry with the same token gets the first reply back
+//! instead of applying the write again. only the last `IDEMPOTENCY_WINDOW`
+//! tokens are remembered, a retry after that many other tokens runs again
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// tokens remembered per server
+pub(crate) const IDEMPOTENCY_WINDOW: usize = 10_000;
+
+/// a reply worth remembering. failed writes weren't applied, their retries
+/// run again
+pub(crate) trait Outcome: Clone {
+    fn succeeded(&self) -> bool;
+}
+
+/// the replies of the latest tokens. only touched from the database
+/// thread, so checking a token and running its write can't interleave with
+/// another request
+pub(crate) struct Idempotency<T> {
+    window: usize,
+    /// oldest first
+    order: VecDeque<String>,
+    replies: HashMap<String, (blake3::Hash, T)>,
+}
+
+pub(crate) type Tokens<T> = Arc<Mutex<Idempotency<T>>>;
+
+pub(crate) fn tokens<T>() -> Tokens<T> {
+    Arc::new(Mutex::new(Idempotency {
+        window: IDEMPOTENCY_WINDOW,
+        order: VecDeque::new(),
+        replies: HashMap::new(),
+    }))
+}
+
+impl<T: Outcome> Idempotency<T> {
+    /// run the request unless the token already ran it, then answer what it
+    /// answered. `request` is what the client sent, a token sent again with
+    /// a different request is refused with the message
+    pub(crate) fn run(
+        &mut self,
+        token: &str,
+        request: &[u8],
+        f: impl FnOnce() -> T,
+    ) -> Result<T, String> {
+        let fingerprint = blake3::hash(request);
+        if let Some((sent, reply)) = self.replies.get(token) {
+            if *sent != fingerprint {
+                return Err(format!(
+                    "idempotency key {token} was used for a different request"
+                ));
+            }
+            return Ok(reply.clone());
+        }
+
+        let reply = f();
+        if reply.succeeded() {
+            if self.order.len() >= self.window
+                && let Some(oldest) = self.order.pop_front()
+            {
+                self.replies.remove(&oldest);
+            }
+            self.order.push_back(token.to_string());
+            self.replies
+                .insert(token.to_string(), (fingerprint, reply.clone()));
+        }
+        Ok(reply)
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+#[derive(Clone, Debug, Default)]
+// BTreeMap in-memory index buffer-of-start, buffer-of-end, sorted so keys
+// sharing a prefix sit next to each other.
+// key is a string because our key in the DB can be anything, not just a number.
+// keys are boxed so each one is a single exact-size allocation, with no spare capacity
+// values are (segment, offset): the segment's position in the database's segment
+// list, oldest first, and where the record starts in it
+pub struct Index {
+    keys: BTreeMap<Box<str>, (usize, u64)>,
+    // only the keys whose latest records are merge operands, most keys never
+    // get one
+    chains: HashMap<Box<str>, Chain>,
+}
+
+/// the records a key's value is folded from when its latest ones are merge
+/// operands. its entry in the index points at the newest operand
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Chain {
+    /// the last full value the operands apply to, if the key had one
+    pub(crate) base: Option<(usize, u64)>,
+    /// every operand since, oldest first
+    pub(crate) operands: Vec<(usize, u64)>,
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add an item to the index
+    pub fn insert(&mut self, k: &str, segment: usize, offset: u64) {
+        self.keys.insert(k.into(), (segment, offset));
+        self.chains.remove(k);
+    }
+
+    /// point the key at a merge operand, its value is folded from the
+    /// records before it
+    pub(crate) fn push_operand(&mut self, k: &str, segment: usize, offset: u64) {
+        let base = self.keys.insert(k.into(), (segment, offset));
+        self.chains
+            .entry(k.into())
+            .or_insert_with(|| Chain {
+                base,
+                operands: Vec::new(),
+            })
+            .operands
+            .push((segment, offset));
+    }
+
+    pub fn remove(&mut self, k: &str) {
+        self.keys.remove(k);
+        self.chains.remove(k);
+    }
+
+    /// (segment, offset) of the key's latest record, if it is live
+    pub fn get(&self, k: &str) -> Option<(usize, u64)> {
+        self.keys.get(k).copied()
+    }
+
+    /// the records the key's value is folded from, when its latest record
+    /// is a merge operand
+    pub(crate) fn chain(&self, k: &str) -> Option<&Chain> {
+        self.chains.get(k)
+    }
+
+    pub fn contains_key(&self, k: &str) -> bool {
+        self.keys.contains_key(k)
+    }
+
+    /// number of live keys
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// borrow every indexed key, in sorted order
+    pub fn iter_keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.keys.keys().map(|k| k.as_bytes())
+    }
+
+    /// keys starting with the prefix and where they live, in sorted order
+    pub fn scan_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a str, (usize, u64))> + 'a {
+        self.keys
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(move |(k, _)| k.starts_with(prefix))
+            .map(|(k, &location)| (&**k, location))
+    }
+}
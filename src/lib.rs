@@ -0,0 +1,95 @@
+//! deebee is a small bitcask-style key/value store: records are appended to
+//! segment files and an in-memory index maps every key to its latest record.
+//!
+//! Databases are registered in `deebee.toml`, in the current directory unless
+//! `DatabaseOptions::root` names another. Each one keeps its segments, hint
+//! files and `MANIFEST` in a `<name>/` directory of its own next to it, or
+//! under the `data_dir` deebee.toml sets. Values compaction shares between
+//! keys, with `dedup_min_bytes` set, live in its `blobs/` directory,
+//! `APPLIED` counts the patch batches `Database::apply_batch` replayed, and
+//! `HOT` lists the keys the value cache held at the last close.
+//!
+//! ```no_run
+//! use deebee::{Database, DatabaseOptions};
+//!
+//! let mut db = Database::open("app", &DatabaseOptions::new())?;
+//! db.set("greeting", "hello")?;
+//! assert_eq!(db.get("greeting")?.as_deref(), Some("hello"));
+//! db.delete("greeting")?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+mod acl;
+mod advise;
+#[cfg(feature = "async")]
+mod async_database;
+mod background;
+mod blob;
+mod cache;
+mod cancel;
+mod chaos;
+mod client;
+mod clock;
+mod codec;
+mod compaction;
+mod compress;
+mod config;
+mod database;
+mod durable;
+mod error;
+mod gzip;
+mod hint;
+mod http;
+mod idempotency;
+mod index;
+mod maintenance;
+mod manager;
+mod manifest;
+mod merge;
+mod metrics;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod patch;
+mod resp;
+mod rest;
+mod segment;
+mod server;
+mod shared;
+mod stats;
+mod status;
+pub mod testing;
+mod transform;
+
+pub use advise::{Advice, Tuning};
+#[cfg(feature = "async")]
+pub use async_database::AsyncDatabase;
+pub use background::TaskStatus;
+pub use cancel::{CancelToken, GetOptions, WriteOptions};
+pub use client::CachedClient;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use codec::{Collation, KeyCodec};
+pub use compaction::{CompactionFilter, FilterDecision};
+pub use config::{DatabaseOptions, Snapshot, SnapshotFile, SyncPolicy, VerifyLevel};
+pub use database::{
+    Database, Dedup, ExportRecord, ImportOptions, ImportReport, KeyFilter, OnConflict, ScanFilter,
+    SetCondition, ValueRef, View,
+};
+pub use durable::Durable;
+pub use error::{DeebeeError, KeyError, WriteError};
+pub use http::ExportServer;
+pub use index::Index;
+pub use maintenance::MaintenanceWindow;
+pub use manager::DatabaseManager;
+pub use merge::MergeOperator;
+pub use metrics::{MetricsSink, NoopMetrics, StderrMetrics};
+pub use patch::PatchOp;
+pub use segment::{
+    FORMAT_VERSION, Record, RecordDescription, RecordEncoding, RecordFlags, SegmentDescription,
+    segment_records,
+};
+pub use server::{Protocol, Server};
+pub use shared::SharedDatabase;
+pub use stats::{
+    CompactionReport, OpStats, RecoveryReport, RestoreReport, SegmentInfo, Stats, namespace,
+};
+pub use transform::Transform;
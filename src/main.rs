@@ -1,359 +1,1380 @@
-use clap::{Parser, Subcommand};
-use serde::{Deserialize, Serialize};
+use clap::{Args as ClapArgs, Parser, Subcommand};
+use deebee::{
+    Collation, Database, DatabaseManager, DatabaseOptions, Dedup, DeebeeError, ExportRecord,
+    ExportServer, FORMAT_VERSION, ImportOptions, KeyFilter, OnConflict, PatchOp, Protocol,
+    RecordEncoding, SegmentDescription, Server, SetCondition, StderrMetrics, SyncPolicy, Transform,
+    VerifyLevel, WriteOptions,
+};
 use std::fs::{self, File};
-use std::io::Write;
-use std::path::PathBuf;
-use std::{collections::HashMap, path::Path};
-
-// each segment got a number of entries it can afford
-// for here, each segment carry up to 10 entries
-const SEGMENT_SIZE: usize = 10;
-
-#[derive(Clone, Debug, Serialize, Deserialize, Default)]
-struct ConfigFile {
-    #[serde(default)]
-    databases: Vec<DatabaseConfig>,
+use std::io::{BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+/// restricts export/import to a prefix and/or a `[from, to)` key range
+#[derive(ClapArgs, Clone, Debug, Default)]
+struct KeyFilterArgs {
+    /// Only keys starting with this prefix
+    #[arg(long)]
+    prefix: Option<String>,
+    /// Only keys greater than or equal to this one
+    #[arg(long)]
+    from: Option<String>,
+    /// Only keys strictly less than this one
+    #[arg(long)]
+    to: Option<String>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct DatabaseConfig {
-    name: String,
-    segments_files_paths: Vec<String>,
+impl From<KeyFilterArgs> for KeyFilter {
+    fn from(args: KeyFilterArgs) -> Self {
+        KeyFilter {
+            prefix: args.prefix,
+            from: args.from,
+            to: args.to,
+        }
+    }
 }
 
-struct Config {
-    inner: ConfigFile,
+#[derive(Subcommand, Clone, Debug)]
+enum SnapshotAction {
+    /// Copy the current segments aside under a name
+    Create {
+        #[arg(long)]
+        name: String,
+        /// Run even outside the database's maintenance windows
+        #[arg(long)]
+        force: bool,
+    },
+    /// List the database's snapshots, oldest first
+    List,
+    /// Roll the database back to a named snapshot
+    Restore {
+        #[arg(long)]
+        name: String,
+    },
 }
 
-impl Config {
-    /// Load configuration from deebee.toml
-    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
-        let config_path = "deebee.toml";
+#[derive(Subcommand, Clone, Debug)]
+enum FormatAction {
+    /// Print a segment file as canonical JSON
+    Decode {
+        segment: PathBuf,
+        /// format version the segment was written with
+        #[arg(long, default_value_t = FORMAT_VERSION)]
+        format_version: u32,
+    },
+    /// Write a segment file from its JSON description
+    Encode { json: PathBuf, segment: PathBuf },
+}
 
-        if !Path::new(config_path).exists() {
-            // Create default config if it doesn't exist
-            let default_config = Config {
-                inner: ConfigFile::default(),
-            };
-            default_config.save()?;
-            return Ok(default_config);
+/// parse a JSON lines export file, skipping blank lines
+fn read_export_file(path: &Path) -> Result<Vec<ExportRecord>, DeebeeError> {
+    let reader = std::io::BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
         }
-
-        let content = fs::read_to_string(config_path)?;
-        let config_file: ConfigFile = toml::from_str(&content)?;
-        Ok(Config { inner: config_file })
-    }
-
-    /// Save configuration to deebee.toml
-    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let toml_string = toml::to_string_pretty(&self.inner)?;
-        let mut file = File::create("deebee.toml")?;
-        file.write_all(toml_string.as_bytes())?;
-        Ok(())
-    }
-
-    /// Get database configuration by name
-    pub fn get_database(&self, db_name: &str) -> Option<&DatabaseConfig> {
-        self.inner.databases.iter().find(|db| db.name == db_name)
+        let record = serde_json::from_str(&line).map_err(|e| {
+            DeebeeError::InvalidArgument(format!("{}:{}: {e}", path.display(), i + 1))
+        })?;
+        records.push(record);
     }
+    Ok(records)
+}
 
-    /// Add or update a database configuration
-    pub fn upsert_database(&mut self, db_config: DatabaseConfig) {
-        if let Some(pos) = self
-            .inner
-            .databases
-            .iter()
-            .position(|db| db.name == db_config.name)
-        {
-            self.inner.databases[pos] = db_config;
-        } else {
-            self.inner.databases.push(db_config);
+/// parse a JSON lines patch file. lines are `{"op": "set", "key", "value"}`
+/// or `{"op": "delete", "key"}`, export lines without an op are sets
+fn read_patch_file(path: &Path) -> Result<Vec<PatchOp>, DeebeeError> {
+    let reader = std::io::BufReader::new(File::open(path)?);
+    let mut ops = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
         }
+        let op = serde_json::from_str(&line).or_else(|e| {
+            serde_json::from_str(&line)
+                .map(|record: ExportRecord| PatchOp::Set {
+                    key: record.key,
+                    value: record.value,
+                })
+                .map_err(|_| {
+                    DeebeeError::InvalidArgument(format!("{}:{}: {e}", path.display(), i + 1))
+                })
+        })?;
+        ops.push(op);
     }
+    Ok(ops)
 }
 
-#[derive(Clone, Debug)]
-// HashMap in-memory index buffer-of-start, buffer-of-end
-// key is String because our key in the DB can be anything, not just a number
-struct Index(HashMap<String, u64>);
+// pairs held in memory between appends when loading
+const LOAD_BATCH: usize = 10_000;
 
-impl Index {
-    pub fn new() -> Self {
-        Self(HashMap::new())
-    }
+/// set every `key, value` line, split at the first comma and trimmed like
+/// the original text segments. returns how many keys were set
+fn run_load(db: &mut Database, file: &Path) -> Result<usize, DeebeeError> {
+    let reader: Box<dyn BufRead> = if file == Path::new("-") {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(std::io::BufReader::new(File::open(file)?))
+    };
 
-    /// add an item to the index
-    pub fn insert(&mut self, k: &str, v: u64) {
-        self.0.insert(k.to_string(), v);
+    let mut batch = Vec::with_capacity(LOAD_BATCH);
+    let mut written = 0;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some((key, value)) = line.split_once(',') else {
+            return Err(DeebeeError::InvalidArgument(format!(
+                "{}:{}: expected `key, value`",
+                file.display(),
+                i + 1
+            )));
+        };
+        batch.push((key.trim().to_string(), value.trim().to_string()));
+        if batch.len() == LOAD_BATCH {
+            written += db.set_many(batch.drain(..))?;
+        }
     }
+    written += db.set_many(batch)?;
+    Ok(written)
 }
 
-#[derive(Clone)]
-struct Map(Vec<(String, String)>);
-
-impl Map {
-    fn new(value: Option<Vec<(String, String)>>) -> Self {
-        match value {
-            Some(v) => Self(v),
-            _ => Self(Vec::new()),
+/// records to seed a new database with, parsed up front so a broken seed
+/// file doesn't leave a half-created database behind
+fn read_seed(seed: &[PathBuf], template: Option<&Path>) -> Result<Vec<ExportRecord>, DeebeeError> {
+    let mut files = seed.to_vec();
+    if let Some(dir) = template {
+        let mut templated = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension() == Some("jsonl".as_ref()) {
+                templated.push(path);
+            }
         }
-    }
-
-    pub fn get_key(&self, index: usize) -> Result<String, Box<dyn std::error::Error>> {
-        match self.0.get(index) {
-            Some(value) => Ok(value.clone().0),
-            None => Err("index out of bounds".into()),
+        if templated.is_empty() {
+            return Err(DeebeeError::InvalidArgument(format!(
+                "template {} has no .jsonl seed files",
+                dir.display()
+            )));
         }
+        templated.sort();
+        files.extend(templated);
     }
 
-    /// if the database is already there, read the content and convert it to a rust struct
-    pub fn read_database(self, file_content: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut result: Vec<(String, String)> = Vec::new();
+    let mut records = Vec::new();
+    for file in &files {
+        records.extend(read_export_file(file)?);
+    }
+    Ok(records)
+}
 
-        for line in file_content.lines() {
-            if line.trim().is_empty() {
-                continue;
-            }
-            // split by the first comma only
-            if let Some((key, value)) = line.split_once(',') {
-                result.push((key.trim().to_string(), value.trim().to_string()));
-            }
+fn run_format(action: &FormatAction) -> Result<(), DeebeeError> {
+    match action {
+        FormatAction::Decode {
+            segment,
+            format_version,
+        } => {
+            let description = SegmentDescription::decode(
+                &fs::read(segment)?,
+                RecordEncoding::for_format(*format_version),
+            );
+            let json = serde_json::to_string_pretty(&description)
+                .expect("segment descriptions always serialize");
+            println!("{json}");
+        }
+        FormatAction::Encode { json, segment } => {
+            let description: SegmentDescription = serde_json::from_str(&fs::read_to_string(json)?)
+                .map_err(|e| DeebeeError::InvalidArgument(format!("{}: {e}", json.display())))?;
+            fs::write(segment, description.encode()?)?;
         }
-
-        let map = Map::new(Some(result));
-
-        Ok(map)
     }
+    Ok(())
 }
 
-struct Database {
-    db_name: String,
-    map: Map,
-    idx: Index,
-    segment_files_paths: Vec<String>,
+/// a file only the user can read, under a name nobody can guess, removed
+/// when it goes out of scope
+struct EditFile {
+    path: PathBuf,
 }
 
-impl Database {
-    pub fn new(db_name: &str) -> Self {
-        let mut config = Config::load().expect("Failed to load config");
-
-        // Check if database exists in config
-        if let Some(db_config) = config.get_database(db_name) {
-            // Load existing database from config
-            Self::load_from_config(db_name, db_config.clone())
-        } else {
-            // Create new database and save to config
-            let db = Self::create_new(db_name);
+impl EditFile {
+    fn create(extension: &str, content: &str) -> Result<Self, DeebeeError> {
+        use std::collections::hash_map::RandomState;
+        use std::hash::BuildHasher;
 
-            // Save database configuration
-            let db_config = DatabaseConfig {
-                name: db_name.to_string(),
-                segments_files_paths: db.segment_files_paths.clone(),
-            };
-            config.upsert_database(db_config);
-            config.save().expect("Failed to save config");
-
-            db
+        loop {
+            let name = format!(
+                "deebee-edit-{:016x}.{extension}",
+                RandomState::new().hash_one(std::process::id())
+            );
+            let path = std::env::temp_dir().join(name);
+            let mut options = fs::OpenOptions::new();
+            // never follows a symlink someone planted, it fails on anything there
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            match options.open(&path) {
+                Ok(mut file) => {
+                    // removed again if the write fails
+                    let created = Self { path };
+                    file.write_all(content.as_bytes())?;
+                    return Ok(created);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
         }
     }
+}
 
-    fn create_new(db_name: &str) -> Self {
-        let mut idx = Index::new();
-        let map = Map::new(None);
-
-        let mut segment_files_paths = Vec::new();
-
-        let seg_idx: usize = 1;
-        let file_path = Self::create_segement_file(db_name, seg_idx);
-
-        segment_files_paths.push(file_path.to_str().unwrap().to_string());
-
-        Self {
-            db_name: db_name.to_string(),
-            map,
-            idx,
-            segment_files_paths,
-        }
+impl Drop for EditFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
     }
+}
 
-    fn load_from_config(db_name: &str, db_config: DatabaseConfig) -> Self {
-        let mut idx = Index::new();
-        let map = Map::new(None);
-
-        // Use the first segment file path from config
-        let file_path = db_config
-            .segments_files_paths
-            .get(0)
-            .expect("No segment files in config");
+/// edit the value in a temp file, JSON pretty-printed, and write it back with a
+/// compare-and-set against the original. returns false when someone else
+/// changed the key in the meantime, the edit is printed then. the temp file
+/// is gone whatever happens
+fn run_edit(db: &mut Database, key: &str, skip_validation: bool) -> Result<bool, DeebeeError> {
+    let original = db.get(key)?;
+    let json = original
+        .as_deref()
+        .and_then(|value| serde_json::from_str::<serde_json::Value>(value).ok())
+        .filter(|value| value.is_object() || value.is_array());
 
-        let path = Path::new(file_path);
+    let before = match &json {
+        Some(value) => {
+            serde_json::to_string_pretty(value).expect("JSON values always serialize") + "\n"
+        }
+        None => original.clone().unwrap_or_default(),
+    };
+    let extension = if json.is_some() { "json" } else { "txt" };
+    let file = EditFile::create(extension, &before)?;
 
-        if !path.exists() {
-            File::create_new(path).expect("Couldn't create database file");
-            Self {
-                db_name: db_name.to_string(),
-                map,
-                idx,
-                segment_files_paths: db_config.segments_files_paths,
-            }
-        } else {
-            // when you connect a databse that is already there
-            // first, index the whole DB into a hashmap so it's easier to navigate in-memory
-            // without many I/O disk operations.
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // through the shell so editors configured with arguments work
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(&file.path)
+        .status()?;
+    if !status.success() {
+        return Err(DeebeeError::InvalidArgument(format!(
+            "{editor} exited with {status}, nothing saved"
+        )));
+    }
 
-            let file_content = fs::read_to_string(path).unwrap();
+    let after = fs::read_to_string(&file.path)?;
+    drop(file);
+    if after == before {
+        println!("{key} unchanged");
+        return Ok(true);
+    }
+    let value = if json.is_some() {
+        let value: serde_json::Value = serde_json::from_str(&after)
+            .map_err(|e| DeebeeError::InvalidValue(format!("the edit is not valid JSON: {e}")))?;
+        value.to_string()
+    } else {
+        // editors like to end the file with a newline the value didn't have
+        after.strip_suffix('\n').unwrap_or(&after).to_string()
+    };
 
-            if !file_content.is_empty() {
-                let map = map.clone().read_database(&file_content).unwrap();
+    if !skip_validation {
+        db.validate_value(key, &value)?;
+    }
+    db.reload()?;
+    if !db.compare_and_set(key, original.as_deref(), &value)? {
+        eprintln!("{key} changed while it was being edited, your edit was:\n{value}");
+        return Ok(false);
+    }
+    println!("saved {key}");
+    Ok(true)
+}
 
-                // get each key from the database and store it in the index HashMap
-                // We reimplement the loop to calculate offsets correctly matching the lines() iterator
+const HISTORY_FILE: &str = ".deebee_history";
+const HISTORY_LEN: usize = 1000;
 
-                let mut offset: u64 = 0;
-                let mut line_number: usize = 0;
+/// what can be typed at the `shell` prompt
+#[derive(Parser, Debug)]
+#[command(name = "shell", no_binary_name = true, disable_version_flag = true)]
+struct ShellLine {
+    #[command(subcommand)]
+    command: ShellCommand,
+}
 
-                for line in file_content.lines() {
-                    // Check if this line is in our map (skipped empty lines)
-                    if line.trim().is_empty() {
-                        offset += line.len() as u64 + 1; // +1 for the newline
-                        continue;
-                    }
+#[derive(Subcommand, Debug)]
+enum ShellCommand {
+    /// Get value by key
+    Get { key: String },
+    /// Set key and value, quote values with spaces
+    Set {
+        key: String,
+        value: String,
+        /// Skip JSON Schema validation of the value
+        #[arg(long = "unsafe")]
+        skip_validation: bool,
+    },
+    /// Delete key
+    Delete { key: String },
+    /// Print the keys starting with a prefix and their values
+    Scan {
+        prefix: String,
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Print the numbered history, `!N` runs entry N again and `!!` the last one
+    History,
+    /// Leave the shell, end of input does the same
+    #[command(alias = "quit")]
+    Exit,
+}
 
-                    // We trust map was built in order of lines
-                    if let Ok(key) = map.get_key(line_number) {
-                        idx.insert(&key, offset);
-                        line_number += 1;
-                    }
+/// read commands from stdin and run them against the one open database, so
+/// the index is only built once. history is kept in .deebee_history
+fn run_shell(db: &mut Database, db_name: &str) -> Result<(), DeebeeError> {
+    use std::io::{IsTerminal, Write};
 
-                    // Add line length + 1 (for the newline character)
-                    // Note: This assumes unix style \n. Windows \r\n would be +2, but Rust's lines() handles \r\n by stripping both.
-                    // If the file is actually on disk, we need to be careful.
-                    // For now, assuming simple \n or handling by len is enough for this step.
-                    // To be precise:
-                    // lines() parses content.
-                    // If we want exact byte offset, we should probably iterate bytes or assume \n.
-                    // Let's assume \n for now as per env.
+    let mut history: Vec<String> = fs::read_to_string(HISTORY_FILE)
+        .map(|content| content.lines().map(str::to_string).collect())
+        .unwrap_or_default();
+    let interactive = std::io::stdin().is_terminal();
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        if interactive {
+            print!("{db_name}> ");
+            std::io::stdout().flush()?;
+        }
+        let Some(line) = lines.next().transpose()? else {
+            break;
+        };
+        let mut line = line.trim().to_string();
+        if line.is_empty() {
+            continue;
+        }
 
-                    offset += line.len() as u64 + 1;
-                }
+        if let Some(recall) = line.strip_prefix('!') {
+            let entry = if recall == "!" {
+                history.last()
+            } else {
+                recall
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| history.get(n.checked_sub(1)?))
+            };
+            let Some(entry) = entry else {
+                eprintln!("no history entry {line}");
+                continue;
+            };
+            line = entry.clone();
+            println!("{line}");
+        }
+        history.push(line.clone());
 
-                Self {
-                    db_name: db_name.to_string(),
-                    map,
-                    idx,
-                    segment_files_paths: db_config.segments_files_paths,
-                }
+        let Some(words) = shlex::split(&line) else {
+            eprintln!("unbalanced quotes");
+            continue;
+        };
+        let command = match ShellLine::try_parse_from(words) {
+            Ok(parsed) => parsed.command,
+            Err(e) => {
+                let _ = e.print();
+                continue;
+            }
+        };
+        let result = match command {
+            ShellCommand::Get { key } => db.get_and_burn(&key).map(|value| match value {
+                Some(value) => println!("{}", db.redact(&key, &value)),
+                None => println!("(nil)"),
+            }),
+            ShellCommand::Set {
+                key,
+                value,
+                skip_validation,
+            } => (if skip_validation {
+                Ok(())
             } else {
-                Self {
-                    db_name: db_name.to_string(),
-                    map, // Note: map might be empty if file_content was empty
-                    idx,
-                    segment_files_paths: db_config.segments_files_paths,
+                db.validate_value(&key, &value)
+            })
+            .and_then(|()| db.set(&key, &value)),
+            ShellCommand::Delete { key } => db.delete(&key).map(|existed| {
+                if !existed {
+                    println!("{key} didn't exist");
+                }
+            }),
+            ShellCommand::Scan { prefix, limit } => db
+                .scan_prefix(&prefix)
+                .take(limit.unwrap_or(usize::MAX))
+                .try_for_each(|record| {
+                    let (key, value) = record?;
+                    println!("{key}\t{}", db.redact(&key, &value));
+                    Ok(())
+                }),
+            ShellCommand::History => {
+                for (n, entry) in history.iter().enumerate() {
+                    println!("{:>5}  {entry}", n + 1);
                 }
+                Ok(())
             }
+            ShellCommand::Exit => break,
+        };
+        if let Err(e) = result {
+            eprintln!("error: {e}");
         }
     }
 
-    fn create_segement_file(db_name: &str, seg_idx: usize) -> PathBuf {
-        let file_path = format!("{db_name}{seg_idx}.log");
-        let file = File::create_new(&file_path).expect("Couldn't create segment file");
-
-        PathBuf::from(&file_path)
-    }
-
-    fn compact_segments() {
-        todo!()
-    }
-
-    pub fn get_by_key(&self, key: &str) -> Result<String, Box<dyn std::error::Error>> {
-        // Use the index to find the offset
-        if let Some(&offset) = self.idx.0.get(key) {
-            use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
-
-            // TODO: change this to the new data segments approach
-            let file = File::open(&self.db_name)?;
-            let mut reader = BufReader::new(file);
-
-            reader.seek(SeekFrom::Start(offset))?;
-
-            let mut line = String::new();
-            reader.read_line(&mut line)?;
-
-            // Parsed the line to extract value
-            if let Some((_, value)) = line.split_once(',') {
-                return Ok(value.trim().to_string());
-            }
-        }
+    let keep = history.len().saturating_sub(HISTORY_LEN);
+    let mut saved = history[keep..].join("\n");
+    saved.push('\n');
+    fs::write(HISTORY_FILE, saved)?;
+    Ok(())
+}
 
-        Ok("".to_string())
+/// a distinct exit code per kind of failure, so scripts can tell them apart.
+/// 1 is a refused operation (condition not met, verify violations), 2 is a usage error
+fn exit_code(e: &DeebeeError) -> u8 {
+    match e {
+        DeebeeError::KeyNotFound(_) => 3,
+        DeebeeError::InvalidKey(_) | DeebeeError::InvalidValue(_) => 4,
+        DeebeeError::InvalidArgument(_) => 5,
+        DeebeeError::Write(_) => 6,
+        DeebeeError::Config(_) => 7,
+        DeebeeError::Corruption(_) => 8,
+        DeebeeError::Io(_) => 9,
+        DeebeeError::Locked(_) => 10,
+        DeebeeError::Cancelled(_) => 11,
+        DeebeeError::Denied(_) => 12,
     }
+}
 
-    pub fn set_by_key(self, key: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
-        // append to file with "key, value"
-        // TODO: change this to the new data segments approach
-        let content = fs::read_to_string(self.db_name.clone()).expect("couldn't read database");
-
-        let new_line = format!("{}, {}", key, value);
-        let all_content = if content.is_empty() {
-            new_line
-        } else {
-            // Ensure we append on a new line.
-            // If the file ends with newline, just append. If not, add newline.
-            if content.ends_with('\n') {
-                format!("{}{}", content, new_line)
-            } else {
-                format!("{}\n{}", content, new_line)
-            }
-        };
+/// the `[open_options]` of deebee.toml, with DEEBEE_* variables overriding them
+fn configured_options() -> Result<DatabaseOptions, Exit> {
+    DatabaseOptions::from_config()
+        .and_then(DatabaseOptions::with_env)
+        .map_err(|e| fail("loading deebee.toml", e))
+}
 
-        // TODO: change this to the new data segments approach
-        File::create(self.db_name)
-            .unwrap()
-            .write_all(all_content.as_bytes())
-            .expect("Couldn't write");
+/// the exit code a command ends with when it didn't succeed, its message
+/// already printed
+struct Exit(u8);
 
-        Ok(())
-    }
+/// report a failed command, giving the exit code for its kind of failure
+fn fail(action: &str, e: DeebeeError) -> Exit {
+    eprintln!("{action} failed: {e}");
+    Exit(exit_code(&e))
 }
 
 #[derive(Subcommand, Clone, Debug)]
 enum Command {
     /// Get value by key
-    Get { key: String },
+    Get {
+        key: String,
+        /// Print this instead of failing when the key doesn't exist
+        #[arg(long)]
+        default: Option<String>,
+        /// Scan the segment files before reporting a key missing from the index as not found
+        #[arg(long)]
+        accurate_misses: bool,
+    },
+    /// Open the database once and run get/set/delete/scan commands from a prompt
+    Shell,
+    /// Print whether the key exists, exiting non-zero when it doesn't
+    Exists { key: String },
+    /// Get several keys at once, reading each segment file a single time
+    Mget {
+        #[arg(required = true)]
+        keys: Vec<String>,
+        /// Print one JSON object mapping each key to its value, null when missing
+        #[arg(long)]
+        json: bool,
+    },
     /// Set key and value
-    Set { key: String, value: String },
+    Set {
+        key: String,
+        value: String,
+        /// Skip JSON Schema validation of the value
+        #[arg(long = "unsafe")]
+        skip_validation: bool,
+        /// Only set the key if it doesn't exist yet
+        #[arg(long, conflicts_with = "xx")]
+        nx: bool,
+        /// Only set the key if it already exists
+        #[arg(long)]
+        xx: bool,
+        /// Print the value the key held before this write
+        #[arg(long, conflicts_with_all = ["nx", "xx"])]
+        get_old: bool,
+        /// Delete the key when the next get reads it, only one reader ever sees the value
+        #[arg(long, conflicts_with_all = ["nx", "xx", "get_old"])]
+        burn_after_read: bool,
+    },
+    /// Delete a key
+    Delete {
+        key: String,
+        /// Print the value the key held before it was deleted
+        #[arg(long)]
+        get_old: bool,
+    },
+    /// Open a value in $VISUAL or $EDITOR and save it unless it changed meanwhile
+    Edit {
+        key: String,
+        /// Skip JSON Schema validation of the edited value
+        #[arg(long = "unsafe")]
+        skip_validation: bool,
+    },
+    /// Set `key, value` lines from a file, or stdin with `-`, in batches
+    Load { file: PathBuf },
+    /// Store a value under its BLAKE3 hash and print the hash, use `get` to read it back
+    PutCas { value: String },
+    /// Print every key in the index, sorted
+    Keys,
+    /// Print the keys starting with a prefix and their values, in sorted order
+    Scan {
+        prefix: String,
+        /// Print at most this many keys
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Page through the keys in sorted order, optionally with their values
+    List {
+        /// Print each value after its key, separated by a tab
+        #[arg(long)]
+        values: bool,
+        /// Print at most this many keys
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Skip this many keys first
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+    },
     /// Create a new database
-    New,
+    New {
+        /// Pre-populate it from a JSON lines file, as written by `export`. repeatable
+        #[arg(long)]
+        seed: Vec<PathBuf>,
+        /// Pre-populate it from every .jsonl file in the directory, in name order
+        #[arg(long, conflicts_with = "seed")]
+        from_template: Option<PathBuf>,
+        /// Order its keys bytewise or ascii-case-insensitive, for good
+        #[arg(long)]
+        collation: Option<Collation>,
+    },
+    /// Print an order-independent digest of all live key/value pairs
+    Digest,
+    /// Merge the sealed segments, keeping only the latest value of each key
+    Compact {
+        /// Run even outside the database's maintenance windows
+        #[arg(long)]
+        force: bool,
+        /// Give up, leaving the segments as they are, after this many milliseconds
+        #[arg(long)]
+        timeout_ms: Option<u64>,
+    },
+    /// Re-validate stored values against the database's JSON Schema
+    Verify,
+    /// List all databases registered in deebee.toml
+    Databases,
+    /// Export several databases as of one point in time, each to DIR/<name>.jsonl
+    ExportMany {
+        #[arg(long, value_delimiter = ',', required = true)]
+        databases: Vec<String>,
+        #[arg(long)]
+        out_dir: PathBuf,
+        #[command(flatten)]
+        filter: KeyFilterArgs,
+        /// Write the values of sensitive keys as they are instead of `<redacted>`
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Give the database a new name, along with its files and snapshots
+    RenameDb {
+        #[arg(long)]
+        to: String,
+    },
+    /// Write live key/value pairs as JSON lines to stdout, sorted by key
+    Export {
+        #[command(flatten)]
+        filter: KeyFilterArgs,
+        /// Reshape every record on the way out: trim, lowercase-keys,
+        /// add-prefix=P, strip-prefix=P or select=field,field. repeatable, applied in order
+        #[arg(long = "transform")]
+        transforms: Vec<Transform>,
+        /// Write the values of sensitive keys as they are instead of `<redacted>`
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Keep the database open and answer GET/SET/DEL lines from TCP clients
+    Serve {
+        #[arg(long, default_value = "127.0.0.1")]
+        bind: String,
+        #[arg(long, default_value_t = 7878)]
+        port: u16,
+        /// line, resp to talk to redis-cli and Redis client libraries, or http
+        #[arg(long, default_value = "line")]
+        protocol: Protocol,
+        /// Answer commands still waiting after this many milliseconds with an error
+        #[arg(long)]
+        timeout_ms: Option<u64>,
+        /// Under sync = "always", fsync the writes of this many milliseconds together
+        #[arg(long)]
+        commit_window_ms: Option<u64>,
+    },
+    /// Serve `GET /export.jsonl` over HTTP, gated by a bearer token
+    ServeExport {
+        #[arg(long, default_value = "127.0.0.1:8716")]
+        listen: String,
+        /// file holding the token clients send as `Authorization: Bearer <token>`
+        #[arg(long)]
+        token_file: PathBuf,
+    },
+    /// Set key/value pairs from a JSON lines file, as written by `export`
+    Import {
+        file: PathBuf,
+        #[command(flatten)]
+        filter: KeyFilterArgs,
+        /// What to do with keys the database already has: skip, overwrite or fail
+        #[arg(long, default_value = "overwrite")]
+        on_conflict: OnConflict,
+        /// Which record wins when the file has a key more than once: last or first
+        #[arg(long, default_value = "last")]
+        dedup_within_file: Dedup,
+        /// Reshape every record before it is filtered and set, like `export --transform`
+        #[arg(long = "transform")]
+        transforms: Vec<Transform>,
+    },
+    /// Replay the sets and deletes of a JSON lines patch file in batches that
+    /// each land whole. Batches that already landed are skipped, so an
+    /// interrupted apply can just be run again
+    Apply {
+        file: PathBuf,
+        /// Name the batches are counted under, the file name unless set
+        #[arg(long)]
+        source: Option<String>,
+        /// Ops per batch
+        #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+        batch_size: u64,
+    },
+    /// Raise the on-disk format version the database is allowed to write
+    Upgrade {
+        #[arg(long)]
+        format_version: u32,
+    },
+    /// Bookmark the database under a name and roll back to it later
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotAction,
+    },
+    /// Convert segment files to and from canonical JSON
+    Format {
+        #[command(subcommand)]
+        action: FormatAction,
+    },
+    /// Keep the key's value in memory whenever the database is open
+    Pin { key: String },
+    /// Stop keeping the key's value in memory
+    Unpin { key: String },
+    /// List the pinned keys
+    Pinned,
+    /// Reject writes tagged with an older epoch, or none, from now on
+    Fence {
+        #[arg(long)]
+        epoch: u64,
+    },
+    /// Recommend configuration changes based on the database's stats
+    Advise {
+        /// Write the recommended settings to deebee.toml
+        #[arg(long)]
+        apply: bool,
+    },
+    /// Show write counters and uptime accumulated over the database's lifetime
+    Stats {
+        /// Only count what happened in this process
+        #[arg(long)]
+        since_start: bool,
+        /// Show what the last index rebuild on open did
+        #[arg(long)]
+        last_recovery: bool,
+        /// Show why the last few compactions ran and what they did
+        #[arg(long, conflicts_with = "last_recovery")]
+        compactions: bool,
+        /// Show what server clients did to the keys of each namespace, the
+        /// part of a key before its first `:`
+        #[arg(long, conflicts_with_all = ["last_recovery", "compactions"])]
+        by_namespace: bool,
+    },
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Database to operate on, required by every command except `databases`
     #[arg(short, long)]
-    db_name: String,
+    db_name: Option<String>,
+
+    /// Print engine metrics to stderr as they are reported
+    #[arg(long)]
+    metrics: bool,
+
+    /// Open the database without allowing writes
+    #[arg(long)]
+    read_only: bool,
+
+    /// Fail instead of creating the database when it doesn't exist
+    #[arg(long)]
+    no_create: bool,
+
+    /// When to fsync writes: always, everysec or never
+    #[arg(long)]
+    sync: Option<SyncPolicy>,
+
+    /// Check segment checksums before opening: none, footers-only or full-checksum
+    #[arg(long)]
+    verify: Option<VerifyLevel>,
+
+    /// Tag writes with this epoch, see `fence`
+    #[arg(long)]
+    epoch: Option<u64>,
 
     #[command(subcommand)]
     command: Command,
 }
 
-fn main() {
-    let args = Args::parse();
+fn main() -> ExitCode {
+    match run(Args::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Exit(code)) => ExitCode::from(code),
+    }
+}
+
+/// the command's work. every handle it opens is dropped before it returns,
+/// so writes are fsynced and stats saved however the command ends
+fn run(args: Args) -> Result<(), Exit> {
+    let mut manager = DatabaseManager::new();
+
+    // commands that don't operate on a single database
+    match &args.command {
+        Command::Databases => {
+            match manager.list_databases() {
+                Ok(names) => names.iter().for_each(|name| println!("{name}")),
+                Err(e) => return Err(fail("databases", e)),
+            }
+            return Ok(());
+        }
+        Command::Format { action } => {
+            if let Err(e) = run_format(action) {
+                return Err(fail("format", e));
+            }
+            return Ok(());
+        }
+        Command::ExportMany {
+            databases,
+            out_dir,
+            filter,
+            raw,
+        } => {
+            let options = configured_options()?.create_if_missing(false);
+            let names: Vec<&str> = databases.iter().map(String::as_str).collect();
+            let filter = KeyFilter::from(filter.clone());
+            let result = manager.view(&names, &options).and_then(|views| {
+                fs::create_dir_all(out_dir)?;
+                for mut view in views {
+                    let path = out_dir.join(format!("{}.jsonl", view.db_name()));
+                    let mut out = BufWriter::new(File::create(&path)?);
+                    let records = if *raw {
+                        view.export_raw(&filter)?
+                    } else {
+                        view.export(&filter)?
+                    };
+                    for record in &records {
+                        let line =
+                            serde_json::to_string(record).expect("export records always serialize");
+                        writeln!(out, "{line}")?;
+                    }
+                    out.flush()?;
+                    println!(
+                        "exported {} keys of {} to {}",
+                        records.len(),
+                        view.db_name(),
+                        path.display()
+                    );
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                return Err(fail("export-many", e));
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let Some(db_name) = args.db_name else {
+        eprintln!("--db-name is required for this command");
+        return Err(Exit(2));
+    };
+    if let Command::RenameDb { to } = &args.command {
+        match Database::rename(&db_name, to, &configured_options()?) {
+            Ok(()) => println!("renamed {db_name} to {to}"),
+            Err(e) => return Err(fail("rename-db", e)),
+        }
+        return Ok(());
+    }
+    let mut seed = Vec::new();
+    if let Command::New {
+        seed: seed_files,
+        from_template,
+        ..
+    } = &args.command
+    {
+        match manager.list_databases() {
+            Ok(names) if names.contains(&db_name) => {
+                return Err(fail(
+                    "new",
+                    DeebeeError::InvalidArgument(format!("database {db_name} already exists")),
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => return Err(fail("new", e)),
+        }
+        seed = match read_seed(seed_files, from_template.as_deref()) {
+            Ok(records) => records,
+            Err(e) => return Err(fail("new", e)),
+        };
+    }
+
+    // CLI flags override the DEEBEE_* variables and deebee.toml. --no-create
+    // and --read-only can only turn their option on
+    let mut options = configured_options()?;
+    if args.no_create {
+        options = options.create_if_missing(false);
+    }
+    if args.read_only {
+        options = options.read_only(true);
+    }
+    if let Some(sync) = args.sync {
+        options = options.sync(sync);
+    }
+    if let Some(verify) = args.verify {
+        options = options.verify(verify);
+    }
+    if let Some(epoch) = args.epoch {
+        options = options.epoch(epoch);
+    }
+    if let Command::New {
+        collation: Some(collation),
+        ..
+    } = &args.command
+    {
+        options = options.collation(*collation);
+    }
 
-    let db = Database::new(&args.db_name);
+    let db = match manager.open(&db_name, &options) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("couldn't open {db_name}: {e}");
+            return Err(Exit(exit_code(&e)));
+        }
+    };
+    if args.metrics {
+        db.set_metrics_sink(Box::new(StderrMetrics));
+    }
+    let maintenance = matches!(
+        &args.command,
+        Command::Compact { force: false, .. }
+            | Command::Snapshot {
+                action: SnapshotAction::Create { force: false, .. }
+            }
+    );
+    if maintenance && !db.in_maintenance_window() {
+        eprintln!("{db_name} is outside its maintenance windows, pass --force to run anyway");
+        return Err(Exit(1));
+    }
 
     match args.command {
-        Command::New => {
-            todo!();
+        Command::Databases
+        | Command::Format { .. }
+        | Command::RenameDb { .. }
+        | Command::ExportMany { .. } => unreachable!(),
+        // opening it above already created it
+        Command::New { .. } => {
+            println!("created {db_name}");
+            if !seed.is_empty() {
+                match db.import(seed, &KeyFilter::default()) {
+                    Ok(written) => println!("seeded {written} keys"),
+                    Err(e) => return Err(fail("seeding", e)),
+                }
+            }
+        }
+        Command::Get {
+            key,
+            default,
+            accurate_misses,
+        } => {
+            let value = match db.get_and_burn(&key) {
+                Ok(None) if accurate_misses => db.find_in_segments(&key),
+                value => value,
+            };
+            match value.map(|value| value.or(default)) {
+                Ok(Some(value)) => println!("{value}"),
+                Ok(None) => return Err(fail("get", DeebeeError::KeyNotFound(key))),
+                Err(e) => return Err(fail("get", e)),
+            }
+        }
+        Command::Shell => {
+            if let Err(e) = run_shell(db, &db_name) {
+                return Err(fail("shell", e));
+            }
+        }
+        Command::Exists { key } => {
+            let exists = db.contains_key(&key);
+            println!("{exists}");
+            if !exists {
+                return Err(Exit(exit_code(&DeebeeError::KeyNotFound(key))));
+            }
+        }
+        Command::Mget { keys, json } => match db.get_many(&keys) {
+            Ok(values) if json => {
+                let object: serde_json::Map<String, serde_json::Value> = keys
+                    .iter()
+                    .zip(values)
+                    .map(|(key, value)| {
+                        let value = value.map(|value| db.redact(key, &value).to_string());
+                        (key.clone(), value.into())
+                    })
+                    .collect();
+                println!("{}", serde_json::Value::Object(object));
+            }
+            Ok(values) => {
+                for (key, value) in keys.iter().zip(values) {
+                    match value {
+                        Some(value) => println!("{key}\t{}", db.redact(key, &value)),
+                        None => println!("{key}\t(nil)"),
+                    }
+                }
+            }
+            Err(e) => return Err(fail("mget", e)),
+        },
+        Command::Set {
+            key,
+            value,
+            skip_validation,
+            nx,
+            xx,
+            get_old,
+            burn_after_read,
+        } => {
+            for warning in db.soft_limit_warnings() {
+                eprintln!("warning: {warning}");
+            }
+            if !skip_validation && let Err(e) = db.validate_value(&key, &value) {
+                return Err(fail("set", e));
+            }
+            if burn_after_read {
+                if let Err(e) = db.set_burn_after_read(&key, &value) {
+                    return Err(fail("set", e));
+                }
+            } else if get_old {
+                match db.put_get_old(&key, &value) {
+                    Ok(Some(old)) => println!("{}", db.redact(&key, &old)),
+                    Ok(None) => {}
+                    Err(e) => return Err(fail("set", e)),
+                }
+            } else {
+                let condition = if nx {
+                    SetCondition::IfAbsent
+                } else if xx {
+                    SetCondition::IfPresent
+                } else {
+                    SetCondition::Always
+                };
+                match db.set_if(&key, &value, condition) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        eprintln!("not set: condition not met");
+                        return Err(Exit(1));
+                    }
+                    Err(e) => return Err(fail("set", e)),
+                }
+            }
+        }
+        Command::Delete { key, get_old } => {
+            let result = if get_old {
+                db.remove_get_old(&key).map(|old| {
+                    if let Some(old) = old {
+                        println!("{}", db.redact(&key, &old));
+                    }
+                })
+            } else {
+                db.delete(&key).map(|existed| {
+                    if !existed {
+                        eprintln!("{key} not found");
+                    }
+                })
+            };
+            if let Err(e) = result {
+                return Err(fail("delete", e));
+            }
+        }
+        Command::Edit {
+            key,
+            skip_validation,
+        } => match run_edit(db, &key, skip_validation) {
+            Ok(true) => {}
+            Ok(false) => return Err(Exit(1)),
+            Err(e) => return Err(fail("edit", e)),
+        },
+        Command::Load { file } => match run_load(db, &file) {
+            Ok(written) => println!("loaded {written} keys"),
+            Err(e) => return Err(fail("load", e)),
+        },
+        Command::PutCas { value } => match db.put_content_addressed(&value) {
+            Ok(key) => println!("{key}"),
+            Err(e) => return Err(fail("put-cas", e)),
+        },
+        Command::Keys => {
+            for key in db.iter_keys() {
+                println!("{}", String::from_utf8_lossy(key));
+            }
+        }
+        Command::Scan { prefix, limit } => {
+            for record in db.scan_prefix(&prefix).take(limit.unwrap_or(usize::MAX)) {
+                match record {
+                    Ok((key, value)) => println!("{key}\t{}", db.redact(&key, &value)),
+                    Err(e) => return Err(fail("scan", e)),
+                }
+            }
+        }
+        Command::List {
+            values,
+            limit,
+            offset,
+        } => {
+            let page = db
+                .iter_keys()
+                .skip(offset)
+                .take(limit.unwrap_or(usize::MAX));
+            for key in page {
+                let key = String::from_utf8_lossy(key);
+                if !values {
+                    println!("{key}");
+                    continue;
+                }
+                // listing it mustn't use up its one read
+                if db.burns_after_read(&key) {
+                    println!("{key}\t(burn after read)");
+                    continue;
+                }
+                match db.get(&key) {
+                    Ok(value) => {
+                        println!("{key}\t{}", db.redact(&key, &value.unwrap_or_default()))
+                    }
+                    Err(e) => return Err(fail("list", e)),
+                }
+            }
         }
-        Command::Get { key } => {
-            println!("get called, {}", key);
-            let query = db.get_by_key(key.as_ref());
-            println!("{}", query.unwrap())
+        Command::Compact { timeout_ms, .. } => {
+            let mut options = WriteOptions::new();
+            if let Some(timeout_ms) = timeout_ms {
+                options = options.timeout(Duration::from_millis(timeout_ms));
+            }
+            match db.compact_segments_with(&options) {
+                Ok(report) => println!(
+                    "compacted {} segments: {} -> {} bytes, {} records kept",
+                    report.segments, report.bytes_before, report.bytes_after, report.records_kept
+                ),
+                Err(e) => return Err(fail("compact", e)),
+            }
         }
-        Command::Set { key, value } => {
-            println!("set called, {}, {}", key, value);
-            db.set_by_key(&key, &value).unwrap();
+        Command::Digest => match db.digest() {
+            Ok((keys, digest)) => println!("{digest:016x} ({keys} keys)"),
+            Err(e) => return Err(fail("digest", e)),
+        },
+        Command::Pin { key } => match db.pin(&key) {
+            Ok(true) => println!("pinned {key}"),
+            Ok(false) => println!("{key} was already pinned"),
+            Err(e) => return Err(fail("pin", e)),
+        },
+        Command::Unpin { key } => match db.unpin(&key) {
+            Ok(true) => println!("unpinned {key}"),
+            Ok(false) => println!("{key} wasn't pinned"),
+            Err(e) => return Err(fail("unpin", e)),
+        },
+        Command::Pinned => {
+            for key in db.pinned_keys() {
+                println!("{key}");
+            }
+        }
+        Command::Fence { epoch } => match db.fence(epoch) {
+            Ok(()) => println!("{db_name} is fenced at epoch {epoch}"),
+            Err(e) => return Err(fail("fence", e)),
+        },
+        Command::Advise { apply } => {
+            let advice = db.advise();
+            if advice.is_empty() {
+                println!("nothing to recommend");
+            }
+            for item in &advice {
+                println!("- {}", item.reason);
+                if let Some(change) = item.change {
+                    println!("  set {change}");
+                }
+            }
+            if apply && advice.iter().any(|a| a.change.is_some()) {
+                match db.apply_advice(&advice) {
+                    Ok(()) => println!("applied to deebee.toml"),
+                    Err(e) => return Err(fail("advise", e)),
+                }
+            }
         }
+        Command::Stats {
+            since_start,
+            last_recovery,
+            compactions,
+            by_namespace,
+        } => {
+            let stats = db.stats(since_start);
+            if by_namespace {
+                if stats.namespaces.is_empty() {
+                    println!("no namespaces recorded");
+                }
+                for (namespace, op) in &stats.namespaces {
+                    let namespace = if namespace.is_empty() {
+                        "(none)"
+                    } else {
+                        namespace
+                    };
+                    println!(
+                        "{namespace}: {} ops, {} bytes, {} errors",
+                        op.ops, op.bytes, op.errors
+                    );
+                }
+            } else if compactions {
+                if stats.recent_compactions.is_empty() {
+                    println!("no compactions recorded");
+                }
+                for (i, report) in stats.recent_compactions.iter().enumerate() {
+                    if i > 0 {
+                        println!();
+                    }
+                    println!("finished at: {}", report.finished_at);
+                    println!("trigger: {}", report.trigger);
+                    println!("segments: {}", report.merged_segments.join(", "));
+                    println!("records kept: {}", report.records_kept);
+                    println!("bytes read: {}", report.bytes_before);
+                    println!("bytes written: {}", report.bytes_after);
+                    println!(
+                        "reclaimed: {}",
+                        report.bytes_before.saturating_sub(report.bytes_after)
+                    );
+                    println!("duration: {}ms", report.duration_ms);
+                }
+            } else if last_recovery {
+                match stats.last_recovery {
+                    Some(report) => {
+                        println!("segments: {}", report.segments);
+                        println!("records: {}", report.records);
+                        println!("bytes: {}", report.bytes);
+                        println!("duration: {}ms", report.duration_ms);
+                        println!("finished at: {}", report.finished_at);
+                        println!("truncated bytes: {}", report.truncated_bytes);
+                    }
+                    None => println!("no recovery recorded"),
+                }
+            } else {
+                println!("opens: {}", stats.opens);
+                println!("writes: {}", stats.total_writes);
+                println!("bytes written: {}", stats.bytes_written);
+                println!("reads: {}", stats.total_reads);
+                println!("compactions: {}", stats.compactions);
+                println!("bytes reclaimed: {}", stats.bytes_reclaimed);
+                println!("cache hits: {}", stats.cache_hits);
+                println!("cache misses: {}", stats.cache_misses);
+                println!("uptime: {:.3}s", stats.uptime_ms as f64 / 1000.0);
+                for task in &stats.background_tasks {
+                    let state = if !task.enabled {
+                        "disabled"
+                    } else if task.running {
+                        "running"
+                    } else {
+                        "idle"
+                    };
+                    print!(
+                        "task {}: {state}, {} runs, {} failed",
+                        task.name, task.runs, task.failures
+                    );
+                    match &task.last_error {
+                        Some(e) => println!(", last error: {e}"),
+                        None => println!(),
+                    }
+                }
+            }
+        }
+        Command::Export {
+            filter,
+            transforms,
+            raw,
+        } => match if raw {
+            db.export_raw(&filter.into())
+        } else {
+            db.export(&filter.into())
+        } {
+            Ok(records) => {
+                for record in records {
+                    let record = match Transform::apply_all(&transforms, record) {
+                        Ok(record) => record,
+                        Err(e) => return Err(fail("export", e)),
+                    };
+                    let line =
+                        serde_json::to_string(&record).expect("export records always serialize");
+                    println!("{line}");
+                }
+            }
+            Err(e) => return Err(fail("export", e)),
+        },
+        Command::Serve {
+            bind,
+            port,
+            protocol,
+            timeout_ms,
+            commit_window_ms,
+        } => {
+            let result = Server::bind((bind.as_str(), port), protocol).and_then(|server| {
+                server.set_timeout(timeout_ms.map(Duration::from_millis));
+                server.set_commit_window(commit_window_ms.map(Duration::from_millis));
+                eprintln!("serving {db_name} on {}", server.local_addr());
+                server.serve(db)
+            });
+            if let Err(e) = result {
+                return Err(fail("serve", e));
+            }
+        }
+        Command::ServeExport { listen, token_file } => {
+            let result = fs::read_to_string(&token_file)
+                .map_err(DeebeeError::from)
+                .and_then(|token| ExportServer::bind(&listen, token.trim()))
+                .and_then(|server| {
+                    eprintln!(
+                        "serving {db_name} on http://{}/export.jsonl",
+                        server.local_addr()?
+                    );
+                    server.serve(db)
+                });
+            if let Err(e) = result {
+                return Err(fail("serve-export", e));
+            }
+        }
+        Command::Import {
+            file,
+            filter,
+            on_conflict,
+            dedup_within_file,
+            transforms,
+        } => {
+            let options = ImportOptions {
+                on_conflict,
+                dedup: dedup_within_file,
+            };
+            let result = read_export_file(&file)
+                .and_then(|records| {
+                    records
+                        .into_iter()
+                        .map(|record| Transform::apply_all(&transforms, record))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .and_then(|records| db.import_with(records, &filter.into(), &options));
+            match result {
+                Ok(report) => {
+                    println!("imported {} keys", report.imported);
+                    if report.skipped > 0 {
+                        println!("skipped {} keys that already existed", report.skipped);
+                    }
+                    if report.duplicates > 0 {
+                        println!("dropped {} duplicate records", report.duplicates);
+                    }
+                }
+                Err(e) => return Err(fail("import", e)),
+            }
+        }
+        Command::Apply {
+            file,
+            source,
+            batch_size,
+        } => {
+            let source = source.unwrap_or_else(|| {
+                file.file_name()
+                    .unwrap_or(file.as_os_str())
+                    .to_string_lossy()
+                    .into_owned()
+            });
+            let result = read_patch_file(&file).and_then(|ops| {
+                let (mut applied, mut skipped) = (0, 0);
+                for (i, batch) in ops.chunks(batch_size as usize).enumerate() {
+                    if db.apply_batch(&source, i as u64 + 1, batch.to_vec())? {
+                        applied += 1;
+                    } else {
+                        skipped += 1;
+                    }
+                }
+                Ok((applied, skipped))
+            });
+            match result {
+                Ok((applied, skipped)) => {
+                    println!("applied {applied} batches from {source}");
+                    if skipped > 0 {
+                        println!("skipped {skipped} batches that already landed");
+                    }
+                }
+                Err(e) => return Err(fail("apply", e)),
+            }
+        }
+        Command::Upgrade { format_version } => match db.upgrade_format(format_version) {
+            Ok(()) => println!("{db_name} now writes format version {format_version}"),
+            Err(e) => return Err(fail("upgrade", e)),
+        },
+        Command::Snapshot { action } => {
+            let result = match action {
+                SnapshotAction::Create { name, .. } => db.create_snapshot(&name).map(|snapshot| {
+                    println!(
+                        "created {} ({} segments)",
+                        snapshot.name,
+                        snapshot.files.len()
+                    )
+                }),
+                SnapshotAction::List => db.list_snapshots().map(|snapshots| {
+                    for snapshot in snapshots {
+                        println!("{}\t{}", snapshot.name, snapshot.created_at);
+                    }
+                }),
+                SnapshotAction::Restore { name } => db.restore_snapshot(&name).map(|report| {
+                    println!(
+                        "restored {name} ({} segments linked, {} copied)",
+                        report.linked, report.copied
+                    )
+                }),
+            };
+            if let Err(e) = result {
+                return Err(fail("snapshot", e));
+            }
+        }
+        Command::Verify => match db.verify() {
+            Ok(violations) if violations.is_empty() => println!("ok"),
+            Ok(violations) => {
+                for (key, reason) in &violations {
+                    println!("{key}: {reason}");
+                }
+                return Err(Exit(1));
+            }
+            Err(e) => return Err(fail("verify", e)),
+        },
     }
+    Ok(())
 }
-
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// a weekly stretch of time heavy maintenance may run in, written as
+/// `mon-fri 01:00-05:00`, `sat,sun 00:00-24:00` or `daily 22:00-02:00`, in UTC.
+/// a window ending before it starts runs past midnight into the next day
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MaintenanceWindow {
+    /// days the window starts on, bit 0 is Sunday
+    days: u8,
+    /// minutes since midnight
+    start: u16,
+    end: u16,
+}
+
+impl MaintenanceWindow {
+    /// whether the window is open at this many seconds since the unix epoch
+    pub fn contains(&self, unix_secs: u64) -> bool {
+        let day = unix_secs / 86_400;
+        // 1970-01-01 was a Thursday
+        let weekday = ((day + 4) % 7) as u8;
+        let minute = (unix_secs % 86_400 / 60) as u16;
+        let starts_on = |weekday: u8| self.days & (1 << weekday) != 0;
+
+        if self.start <= self.end {
+            starts_on(weekday) && (self.start..self.end).contains(&minute)
+        } else {
+            (starts_on(weekday) && minute >= self.start)
+                || (starts_on((weekday + 6) % 7) && minute < self.end)
+        }
+    }
+}
+
+fn parse_day(name: &str) -> Result<u8, String> {
+    DAYS.iter()
+        .position(|day| name.eq_ignore_ascii_case(day))
+        .map(|day| day as u8)
+        .ok_or_else(|| format!("unknown day {name:?}, expected one of {}", DAYS.join(", ")))
+}
+
+fn parse_days(s: &str) -> Result<u8, String> {
+    if s == "*" || s.eq_ignore_ascii_case("daily") {
+        return Ok(0x7f);
+    }
+    let mut days = 0;
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (mut day, last) = (parse_day(first)?, parse_day(last)?);
+                // mon-fri, or fri-mon wrapping over the weekend
+                loop {
+                    days |= 1 << day;
+                    if day == last {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+            None => days |= 1 << parse_day(part)?,
+        }
+    }
+    Ok(days)
+}
+
+fn parse_time(s: &str) -> Result<u16, String> {
+    let bad = || format!("bad time {s:?}, expected HH:MM");
+    let (hours, minutes) = s.split_once(':').ok_or_else(bad)?;
+    let hours: u16 = hours.parse().map_err(|_| bad())?;
+    let minutes: u16 = minutes.parse().map_err(|_| bad())?;
+    if minutes >= 60 || hours > 24 || (hours == 24 && minutes > 0) {
+        return Err(bad());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+impl std::str::FromStr for MaintenanceWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (days, times) = match s.trim().rsplit_once(' ') {
+            Some((days, times)) => (parse_days(days.trim())?, times),
+            None => (0x7f, s.trim()),
+        };
+        let (start, end) = times.split_once('-').ok_or_else(|| {
+            format!("bad window {s:?}, expected something like mon-fri 01:00-05:00")
+        })?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end {
+            return Err(format!("window {s:?} is empty"));
+        }
+        Ok(Self { days, start, end })
+    }
+}
+
+impl TryFrom<String> for MaintenanceWindow {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<MaintenanceWindow> for String {
+    fn from(window: MaintenanceWindow) -> Self {
+        window.to_string()
+    }
+}
+
+impl std::fmt::Display for MaintenanceWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let days: Vec<&str> = DAYS
+            .iter()
+            .enumerate()
+            .filter(|(day, _)| self.days & (1 << day) != 0)
+            .map(|(_, name)| *name)
+            .collect();
+        let days = match days.len() {
+            7 => "daily".to_string(),
+            _ => days.join(","),
+        };
+        let time = |minutes: u16| format!("{:02}:{:02}", minutes / 60, minutes % 60);
+        write!(f, "{days} {}-{}", time(self.start), time(self.end))
+    }
+}
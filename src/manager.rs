@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::Path;
+
+use crate::config::{Config, DatabaseOptions};
+use crate::database::{Database, View};
+use crate::error::DeebeeError;
+
+/// keeps at most one open handle per database, so a process hosting many
+/// databases never ends up with two writers on the same segment files
+#[derive(Default)]
+pub struct DatabaseManager {
+    open: HashMap<String, Database>,
+}
+
+impl DatabaseManager {
+    pub fn new() -> Self {
+        Self {
+            open: HashMap::new(),
+        }
+    }
+
+    /// open a database, or hand back the handle that is already open
+    pub fn open(
+        &mut self,
+        db_name: &str,
+        options: &DatabaseOptions,
+    ) -> Result<&mut Database, DeebeeError> {
+        match self.open.entry(db_name.to_string()) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(Database::open(db_name, options)?)),
+        }
+    }
+
+    /// views of several databases at the same point in time, opening the ones
+    /// that aren't open yet. nothing in this process writes between the
+    /// views, and the lock files keep other processes' writers out
+    pub fn view(
+        &mut self,
+        db_names: &[&str],
+        options: &DatabaseOptions,
+    ) -> Result<Vec<View>, DeebeeError> {
+        for db_name in db_names {
+            self.open(db_name, options)?;
+        }
+        db_names
+            .iter()
+            .map(|db_name| self.open[*db_name].view())
+            .collect()
+    }
+
+    /// names of all databases registered in the current directory's deebee.toml
+    pub fn list_databases(&self) -> Result<Vec<String>, DeebeeError> {
+        let config = Config::load(Path::new(""))?;
+        Ok(config
+            .inner
+            .databases
+            .iter()
+            .map(|db| db.name.clone())
+            .collect())
+    }
+}
@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::error::DeebeeError;
+use crate::segment::FORMAT_VERSION;
+
+const MANIFEST: &str = "MANIFEST";
+
+/// `<data-dir>/<db-name>/MANIFEST`, the one place that says which files in
+/// the directory are the database's segments. nothing is found by looking
+/// at file names
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) struct Manifest {
+    /// newest format features the segments may use
+    pub(crate) format_version: u32,
+    /// full segments, oldest first
+    #[serde(default)]
+    pub(crate) sealed: Vec<String>,
+    /// the segment new records are appended to
+    pub(crate) active: String,
+    /// number of the next segment file, never handed out twice
+    pub(crate) next_segment: u64,
+    /// name of the key codec the keys are ordered with, the database won't
+    /// open without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) key_codec: Option<String>,
+}
+
+/// the file name of segment number `n`
+pub(crate) fn segment_name(n: u64) -> String {
+    format!("{n:06}.log")
+}
+
+impl Manifest {
+    /// read the directory's manifest, `None` when it has none
+    pub(crate) fn load(dir: &Path) -> Result<Option<Self>, DeebeeError> {
+        let path = dir.join(MANIFEST);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let manifest: Manifest = toml::from_str(&content)
+            .map_err(|e| DeebeeError::Corruption(format!("{}: {e}", path.display())))?;
+
+        if let Some(name) = manifest
+            .sealed
+            .iter()
+            .chain([&manifest.active])
+            .find(|name| name.is_empty() || name.contains(['/', '\\']) || *name == MANIFEST)
+        {
+            return Err(DeebeeError::Corruption(format!(
+                "{} lists {name:?}, which can't be a segment in {}",
+                path.display(),
+                dir.display()
+            )));
+        }
+        if manifest.sealed.contains(&manifest.active) {
+            return Err(DeebeeError::Corruption(format!(
+                "{} lists the active segment {} as sealed too",
+                path.display(),
+                manifest.active
+            )));
+        }
+        if manifest.format_version > FORMAT_VERSION {
+            return Err(DeebeeError::Config(format!(
+                "{} uses format version {}, this build only understands up to {FORMAT_VERSION}",
+                dir.display(),
+                manifest.format_version
+            )));
+        }
+        Ok(Some(manifest))
+    }
+
+    /// that `segments` (paths inside `dir`, the active one last) are the
+    /// database's segments now
+    pub(crate) fn new(
+        dir: &Path,
+        segments: &[String],
+        format_version: u32,
+        next_segment: u64,
+        key_codec: Option<String>,
+    ) -> Result<Self, DeebeeError> {
+        let mut names = Vec::with_capacity(segments.len());
+        for segment in segments {
+            let name = Path::new(segment)
+                .strip_prefix(dir)
+                .ok()
+                .and_then(|name| name.to_str())
+                .filter(|name| !name.is_empty() && !name.contains(['/', '\\']))
+                .ok_or_else(|| {
+                    DeebeeError::Config(format!(
+                        "segment {segment} isn't in the database directory {}",
+                        dir.display()
+                    ))
+                })?;
+            names.push(name.to_string());
+        }
+        let active = names
+            .pop()
+            .ok_or_else(|| DeebeeError::Config(format!("{} has no segments", dir.display())))?;
+        Ok(Self {
+            format_version,
+            sealed: names,
+            active,
+            next_segment,
+            key_codec,
+        })
+    }
+
+    /// paths of the segments, oldest first and the active one last
+    pub(crate) fn segment_paths(&self, dir: &Path) -> Vec<String> {
+        self.sealed
+            .iter()
+            .chain([&self.active])
+            .map(|name| dir.join(name).to_string_lossy().into_owned())
+            .collect()
+    }
+
+    /// write the manifest aside and rename it over the old one, a crash
+    /// leaves one or the other
+    pub(crate) fn save(&self, dir: &Path) -> Result<(), DeebeeError> {
+        let path = dir.join(MANIFEST);
+        let tmp_path = dir.join(format!("{MANIFEST}.tmp"));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(toml::to_string_pretty(self)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    pub(crate) fn exists(dir: &Path) -> bool {
+        dir.join(MANIFEST).exists()
+    }
+}
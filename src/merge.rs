@@ -0,0 +1,51 @@
+//! merge operands, the deltas `Database::merge` appends instead of a whole
+//! value. a key's value is its last full value, if it has one, with every
+//! operand written after it folded in oldest first by the `MergeOperator`
+//! the handle was opened with. reads fold them every time, compactions fold
+//! them once and write the result as an ordinary value
+
+use std::sync::Arc;
+
+use crate::error::DeebeeError;
+
+/// combines a key's value with one operand, e.g. adds to a counter or
+/// appends to a list. registered with `DatabaseOptions::merge_operator`.
+/// every handle of the database needs the same one, reads and compactions
+/// of a handle without one fail on keys that have operands
+pub trait MergeOperator: Send + Sync {
+    /// the value with the operand applied, `existing` is `None` when the key
+    /// had no value before it
+    fn merge(&self, key: &str, existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8>;
+}
+
+/// a registered operator, debug output can't show more than that it's there
+#[derive(Clone)]
+pub(crate) struct RegisteredOperator(pub(crate) Arc<dyn MergeOperator>);
+
+impl std::fmt::Debug for RegisteredOperator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MergeOperator")
+    }
+}
+
+/// the key's value once the operands, oldest first, are applied to `base`
+pub(crate) fn fold(
+    operator: Option<&dyn MergeOperator>,
+    key: &str,
+    base: Option<Vec<u8>>,
+    operands: Vec<Vec<u8>>,
+) -> Result<Option<Vec<u8>>, DeebeeError> {
+    if operands.is_empty() {
+        return Ok(base);
+    }
+    let Some(operator) = operator else {
+        return Err(DeebeeError::Config(format!(
+            "{key} has merge operands, the database needs a merge operator to read it"
+        )));
+    };
+    let mut value = base;
+    for operand in operands {
+        value = Some(operator.merge(key, value.as_deref(), &operand));
+    }
+    Ok(value)
+}
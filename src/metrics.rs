@@ -0,0 +1,30 @@
+/// receives the engine's counters, gauges and histograms, so embedders can
+/// forward them into their own telemetry. every method defaults to a no-op.
+/// gets on a shared handle report from several threads at once
+pub trait MetricsSink: Send + Sync {
+    fn counter(&self, _name: &str, _delta: u64) {}
+    fn gauge(&self, _name: &str, _value: f64) {}
+    fn histogram(&self, _name: &str, _value: f64) {}
+}
+
+/// the default sink, drops everything
+pub struct NoopMetrics;
+
+impl MetricsSink for NoopMetrics {}
+
+/// prints every metric to stderr, used by `--metrics`
+pub struct StderrMetrics;
+
+impl MetricsSink for StderrMetrics {
+    fn counter(&self, name: &str, delta: u64) {
+        eprintln!("metric counter {name} +{delta}");
+    }
+
+    fn gauge(&self, name: &str, value: f64) {
+        eprintln!("metric gauge {name} = {value}");
+    }
+
+    fn histogram(&self, name: &str, value: f64) {
+        eprintln!("metric histogram {name} {value}");
+    }
+}
@@ -0,0 +1,108 @@
+//! replaying patches, streams of sets and deletes from another database.
+//! a patch lands in numbered batches, each one all or nothing: it's written
+//! to `<db-dir>/APPLY` before any of it reaches a segment, and a writer that
+//! finds the journal on open finishes the batch. `<db-dir>/APPLIED` counts
+//! the batches applied from every source, so replaying a patch again skips
+//! what already landed
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::error::DeebeeError;
+
+const JOURNAL: &str = "APPLY";
+const APPLIED: &str = "APPLIED";
+
+/// one line of a patch file
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOp {
+    Set { key: String, value: String },
+    Delete { key: String },
+}
+
+impl PatchOp {
+    pub fn key(&self) -> &str {
+        match self {
+            PatchOp::Set { key, .. } | PatchOp::Delete { key } => key,
+        }
+    }
+}
+
+/// a batch on its way into the segments
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Journal {
+    pub(crate) source: String,
+    pub(crate) batch: u64,
+    pub(crate) ops: Vec<PatchOp>,
+}
+
+impl Journal {
+    /// the batch a writer didn't get to finish, if there is one
+    pub(crate) fn load(db_dir: &Path) -> Result<Option<Self>, DeebeeError> {
+        let path = db_dir.join(JOURNAL);
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_slice(&content)
+            .map(Some)
+            .map_err(|e| DeebeeError::Corruption(format!("{}: {e}", path.display())))
+    }
+
+    /// written aside and renamed into place, a crash leaves the whole batch
+    /// or nothing
+    pub(crate) fn save(&self, db_dir: &Path) -> Result<(), DeebeeError> {
+        let tmp_path = db_dir.join(format!("{JOURNAL}.tmp"));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(self).expect("patch ops always serialize"))?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, db_dir.join(JOURNAL))?;
+        Ok(())
+    }
+
+    pub(crate) fn remove(db_dir: &Path) -> Result<(), DeebeeError> {
+        fs::remove_file(db_dir.join(JOURNAL))?;
+        Ok(())
+    }
+}
+
+/// how many batches of every source landed
+pub(crate) fn load_applied(db_dir: &Path) -> Result<BTreeMap<String, u64>, DeebeeError> {
+    let path = db_dir.join(APPLIED);
+    match fs::read_to_string(&path) {
+        Ok(content) => toml::from_str(&content)
+            .map_err(|e| DeebeeError::Corruption(format!("{}: {e}", path.display()))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(crate) fn save_applied(
+    db_dir: &Path,
+    applied: &BTreeMap<String, u64>,
+) -> Result<(), DeebeeError> {
+    let tmp_path = db_dir.join(format!("{APPLIED}.tmp"));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(toml::to_string(applied)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, db_dir.join(APPLIED))?;
+    Ok(())
+}
+
+/// give `to` the batch counts `from` has, or none when it has none. the
+/// counts go with the data, a restored snapshot takes the patches again
+pub(crate) fn copy_applied(from: &Path, to: &Path) -> Result<(), DeebeeError> {
+    match fs::copy(from.join(APPLIED), to.join(APPLIED)) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => match fs::remove_file(to.join(APPLIED)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        },
+        Err(e) => Err(e.into()),
+    }
+}
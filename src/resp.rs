@@ -0,0 +1,267 @@
+//! just enough of RESP2, the Redis protocol, for `redis-cli` and Redis client
+//! libraries to talk to the server
+
+use std::io::{self, BufRead, Read, Write};
+
+use crate::cancel::GetOptions;
+use crate::database::Database;
+use crate::server::{self, Clients, Reply, Request};
+
+// a client asking for more than this in one command is not one we want
+const MAX_ARGS: usize = 1024;
+const MAX_BULK_LEN: usize = 512 << 20;
+
+/// the arguments of the next command, `None` once the client hung up. takes
+/// arrays of bulk strings as client libraries send them and space-separated
+/// inline commands as typed into telnet
+pub(crate) fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let Some(line) = read_line(reader)? else {
+            return Ok(None);
+        };
+        let Some(count) = line.strip_prefix(b"*") else {
+            let args: Vec<Vec<u8>> = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        };
+
+        let count = parse_len(count, MAX_ARGS)?;
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            let header = read_line(reader)?.ok_or_else(cut_short)?;
+            let len = header
+                .strip_prefix(b"$")
+                .ok_or_else(|| invalid("expected a bulk string"))?;
+            let len = parse_len(len, MAX_BULK_LEN)?;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg)?;
+            if !arg.ends_with(b"\r\n") {
+                return Err(invalid("bulk string doesn't end with CRLF"));
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+        return Ok(Some(args));
+    }
+}
+
+/// what running a command takes: the requests to send to the database, in
+/// order, and how to fold their replies into the one the client expects
+pub(crate) enum Command {
+    /// answered without the database
+    Immediate(Reply),
+    Get(Request),
+    Set(Request),
+    /// several DEL or EXISTS requests, answered with how many found their key
+    Count(Vec<Request>),
+    Keys(Request),
+    /// `ADMIN CLIENTS` and `AUTH`
+    Admin(Request),
+    Quit,
+}
+
+/// the idempotency key of `IDEM <token> <command> <args>...` and what the
+/// command was, and the command's arguments
+#[allow(clippy::type_complexity)]
+pub(crate) fn split_token(
+    mut args: Vec<Vec<u8>>,
+) -> Result<(Option<(String, Vec<u8>)>, Vec<Vec<u8>>), Reply> {
+    if !args
+        .first()
+        .is_some_and(|name| name.eq_ignore_ascii_case(b"IDEM"))
+    {
+        return Ok((None, args));
+    }
+    if args.len() < 3 {
+        return Err(Reply::Error(
+            "wrong number of arguments for 'idem' command".to_string(),
+        ));
+    }
+    let command = args.split_off(2);
+    let token = String::from_utf8(args.pop().expect("there are three"))
+        .map_err(|_| Reply::Error("the idempotency key must be UTF-8".to_string()))?;
+    // lengths first, so the arguments can't run into each other
+    let mut request = Vec::new();
+    for arg in &command {
+        request.extend_from_slice(&(arg.len() as u64).to_le_bytes());
+        request.extend_from_slice(arg);
+    }
+    Ok((Some((token, request)), command))
+}
+
+impl Command {
+    /// run what the command asks of the database for client `id`, all of
+    /// it in one go
+    pub(crate) fn execute(
+        self,
+        db: &mut Database,
+        clients: &Clients,
+        id: u64,
+        bounds: &GetOptions,
+    ) -> Reply {
+        match self {
+            Command::Immediate(reply) => reply,
+            Command::Quit => Reply::Ok,
+            Command::Get(request)
+            | Command::Set(request)
+            | Command::Keys(request)
+            | Command::Admin(request) => request.execute(db, clients, id, bounds),
+            // none of them runs if one is refused
+            Command::Count(requests) => {
+                for request in &requests {
+                    if let Err(e) = request.authorize(db, clients, id) {
+                        server::count(db, clients, id, None, 0, true);
+                        return Reply::Error(e.to_string());
+                    }
+                }
+                let mut found = 0;
+                for request in requests {
+                    match request.execute(db, clients, id, bounds) {
+                        Reply::Ok => found += 1,
+                        Reply::Integer(n) => found += n,
+                        Reply::Error(message) => return Reply::Error(message),
+                        _ => {}
+                    }
+                }
+                Reply::Integer(found)
+            }
+        }
+    }
+}
+
+/// map a command's arguments onto database requests
+pub(crate) fn parse_command(args: Vec<Vec<u8>>) -> Command {
+    let mut strings = Vec::with_capacity(args.len());
+    for arg in args {
+        match String::from_utf8(arg) {
+            Ok(arg) => strings.push(arg),
+            Err(_) => {
+                return Command::Immediate(Reply::Error(
+                    "keys and values must be UTF-8".to_string(),
+                ));
+            }
+        }
+    }
+    let Some((name, rest)) = strings.split_first() else {
+        return Command::Immediate(Reply::Error("empty command".to_string()));
+    };
+
+    let name = name.to_ascii_uppercase();
+    let wrong_args = || {
+        Command::Immediate(Reply::Error(format!(
+            "wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        )))
+    };
+    match (name.as_str(), rest) {
+        ("PING", []) => Command::Immediate(Reply::Status("PONG".to_string())),
+        ("PING", [message]) => Command::Immediate(Reply::Value(message.clone())),
+        // redis-cli asks for the command docs when it starts, it copes
+        // without them
+        ("COMMAND", _) => Command::Immediate(Reply::Array(Vec::new())),
+        ("QUIT", _) => Command::Quit,
+        ("GET", [key]) => Command::Get(Request::Get(key.clone())),
+        ("GETCRC", [key]) => Command::Get(Request::GetChecked(key.clone())),
+        ("MGET", keys) if !keys.is_empty() => Command::Get(Request::MGet(keys.to_vec())),
+        ("SET", [key, value]) => Command::Set(Request::Set(key.clone(), value.clone())),
+        ("SETCRC", [key, checksum, value]) => match server::parse_checksum(checksum) {
+            Ok(checksum) => Command::Set(Request::SetChecked(key.clone(), value.clone(), checksum)),
+            Err(message) => Command::Immediate(Reply::Error(message)),
+        },
+        ("DEL", keys) if !keys.is_empty() => {
+            Command::Count(keys.iter().cloned().map(Request::Del).collect())
+        }
+        ("EXISTS", keys) if !keys.is_empty() => {
+            Command::Count(keys.iter().cloned().map(Request::Exists).collect())
+        }
+        ("KEYS", [pattern]) => Command::Keys(Request::Keys(pattern.clone())),
+        ("AUTH", [token]) => Command::Admin(Request::Auth(token.clone())),
+        ("ADMIN", [sub]) if sub.eq_ignore_ascii_case("CLIENTS") => Command::Admin(Request::Clients),
+        ("ADMIN", [sub]) => Command::Immediate(Reply::Error(format!(
+            "unknown admin command '{}'",
+            sub.to_ascii_lowercase()
+        ))),
+        (
+            "PING" | "AUTH" | "GET" | "GETCRC" | "MGET" | "SET" | "SETCRC" | "DEL" | "EXISTS"
+            | "KEYS" | "ADMIN",
+            _,
+        ) => wrong_args(),
+        _ => Command::Immediate(Reply::Error(format!(
+            "unknown command '{}'",
+            name.to_ascii_lowercase()
+        ))),
+    }
+}
+
+pub(crate) fn write_reply(out: &mut impl Write, reply: &Reply) -> io::Result<()> {
+    match reply {
+        Reply::Ok => out.write_all(b"+OK\r\n"),
+        Reply::Status(status) => write!(out, "+{status}\r\n"),
+        Reply::Nil => out.write_all(b"$-1\r\n"),
+        Reply::Value(value) => write_bulk(out, value),
+        // the value, then its CRC32 in hex
+        Reply::Checked(value, checksum) => {
+            out.write_all(b"*2\r\n")?;
+            write_bulk(out, value)?;
+            write_bulk(out, &format!("{checksum:08x}"))
+        }
+        Reply::Integer(n) => write!(out, ":{n}\r\n"),
+        Reply::Array(items) => {
+            write!(out, "*{}\r\n", items.len())?;
+            items.iter().try_for_each(|item| write_bulk(out, item))
+        }
+        Reply::Values(values) => {
+            write!(out, "*{}\r\n", values.len())?;
+            values.iter().try_for_each(|value| match value {
+                Some(value) => write_bulk(out, value),
+                None => out.write_all(b"$-1\r\n"),
+            })
+        }
+        // a line break would end the error early
+        Reply::Error(message) => write!(out, "-ERR {}\r\n", message.replace(['\r', '\n'], " ")),
+    }
+}
+
+fn write_bulk(out: &mut impl Write, value: &str) -> io::Result<()> {
+    write!(out, "${}\r\n", value.len())?;
+    out.write_all(value.as_bytes())?;
+    out.write_all(b"\r\n")
+}
+
+/// the next line without its CRLF, `None` at the end of the stream
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    // inline commands are short, bulk strings carry anything long
+    if reader.take(64 << 10).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(invalid("line too long"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| invalid("bad length"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn cut_short() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "command cut short")
+}
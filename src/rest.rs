@@ -0,0 +1,530 @@
+//! the HTTP frontend of server mode: `GET/PUT/DELETE /keys/{key}`, prefix
+//! scans on `GET /keys?prefix=&limit=`, narrowed down on the server with
+//! `glob=`, `contains=` and `field=name=value`, and `GET /stats`, answered
+//! in JSON,
+//! and `GET /status`, a page for people. bodies of a few KiB and up are
+//! gzipped for clients that send `Accept-Encoding: gzip`. a request with an
+//! `Idempotency-Key` header runs once, its retries get the first response.
+//! a PUT can send the CRC32 of its body in 8 hex digits as
+//! `X-Checksum-CRC32`, a body that doesn't match isn't written. a GET
+//! answers with the `crc32` of the value next to it. `GET /mget?key=&key=`
+//! reads several keys, from any namespaces, as of the same moment. a
+//! database with roles needs `Authorization: Bearer <token>` on every
+//! request, see `acl`
+
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::acl::{Access, Role};
+use crate::cancel::GetOptions;
+use crate::database::{Database, ScanFilter, SetCondition};
+use crate::error::{DeebeeError, WriteError};
+use crate::gzip::GzipWriter;
+use crate::http::{accepts_gzip, percent_decode_path, percent_decode_query, read_head};
+use crate::idempotency::{Outcome, Tokens};
+use crate::server::{self, Clients, Connection, Job, run, run_once};
+use crate::status;
+
+const MAX_BODY_BYTES: usize = 64 << 20;
+// smaller bodies fit in a packet or two either way
+const GZIP_MIN_BYTES: usize = 1024;
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// the client's idempotency key and everything its retries must repeat
+    fn token(&self) -> Option<(String, Vec<u8>)> {
+        let token = self.header("Idempotency-Key")?;
+        let mut request = format!("{} {}", self.method, self.path);
+        for (name, value) in &self.query {
+            request.push_str(&format!("\0{name}={value}"));
+        }
+        let mut request = request.into_bytes();
+        request.push(0);
+        request.extend_from_slice(&self.body);
+        Some((token.to_string(), request))
+    }
+}
+
+/// what the request asks the database for
+enum Route {
+    Get(String),
+    /// `if_absent` comes from `If-None-Match: *`, `checksum` from
+    /// `X-Checksum-CRC32`
+    Put {
+        key: String,
+        value: String,
+        if_absent: bool,
+        checksum: Option<u32>,
+    },
+    Delete(String),
+    /// the `key=` parameters, in order
+    MGet(Vec<String>),
+    Scan {
+        prefix: String,
+        limit: usize,
+        filter: ScanFilter,
+    },
+    Stats,
+    Status,
+}
+
+#[derive(Clone)]
+pub(crate) struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    /// `None` for 204
+    body: Option<String>,
+}
+
+impl Outcome for Response {
+    fn succeeded(&self) -> bool {
+        self.status.starts_with('2')
+    }
+}
+
+impl Response {
+    fn json(status: &'static str, body: serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: Some(body.to_string() + "\n"),
+        }
+    }
+
+    fn html(body: String) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "text/html; charset=utf-8",
+            body: Some(body),
+        }
+    }
+
+    fn error(status: &'static str, message: impl std::fmt::Display) -> Self {
+        Self::json(status, json!({ "error": message.to_string() }))
+    }
+
+    fn no_content() -> Self {
+        Self {
+            status: "204 No Content",
+            content_type: "",
+            body: None,
+        }
+    }
+
+    fn write(&self, out: &mut impl Write, gzip: bool) -> io::Result<()> {
+        let mut body = self.body.as_deref().unwrap_or_default().as_bytes();
+        write!(out, "HTTP/1.1 {}\r\nConnection: close\r\n", self.status)?;
+        let compressed;
+        if gzip && body.len() >= GZIP_MIN_BYTES {
+            let mut encoder = GzipWriter::new(Vec::new())?;
+            encoder.write_all(body)?;
+            compressed = encoder.finish()?;
+            body = &compressed;
+            write!(out, "Content-Encoding: gzip\r\nVary: Accept-Encoding\r\n")?;
+        }
+        if self.body.is_some() {
+            write!(
+                out,
+                "Content-Type: {}\r\nContent-Length: {}\r\n",
+                self.content_type,
+                body.len()
+            )?;
+        }
+        out.write_all(b"\r\n")?;
+        out.write_all(body)?;
+        out.flush()
+    }
+}
+
+/// answer the connection's one request
+pub(crate) fn serve_client(
+    stream: TcpStream,
+    jobs: Sender<Job>,
+    conn: Connection,
+    tokens: Tokens<Response>,
+) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(30)));
+    let Ok(read_half) = stream.try_clone() else {
+        return;
+    };
+    let mut reader = BufReader::new(read_half);
+    let mut out = BufWriter::new(stream);
+
+    let request = read_request(&mut reader);
+    let gzip = request
+        .as_ref()
+        .is_ok_and(|request| request.header("Accept-Encoding").is_some_and(accepts_gzip));
+    let token = request.as_ref().ok().and_then(HttpRequest::token);
+    let bearer = request.as_ref().ok().and_then(|request| {
+        let bearer = request.header("Authorization")?.strip_prefix("Bearer ")?;
+        Some(bearer.trim().to_string())
+    });
+    let response = match request.map(route) {
+        Ok(Ok(route)) => {
+            conn.command();
+            let (clients, id, bounds) = (conn.clients().clone(), conn.id(), conn.bounds());
+            let job = move |db: &mut Database| {
+                if let Err(e) = server::authenticate_bearer(db, &clients, id, bearer.as_deref()) {
+                    server::count(db, &clients, id, None, 0, true);
+                    return Response::error("401 Unauthorized", e);
+                }
+                let refused = |message| Response::error("422 Unprocessable Entity", message);
+                let token = clients.scope(id, token);
+                run_once(&tokens, token, refused, || {
+                    execute(route, db, &clients, id, &bounds)
+                })
+            };
+            match run(&jobs, job) {
+                Some(response) => response,
+                None => return,
+            }
+        }
+        Ok(Err(response)) => response,
+        Err(e) => Response::error("400 Bad Request", e),
+    };
+    let _ = response.write(&mut out, gzip);
+}
+
+fn read_request(reader: &mut impl BufRead) -> io::Result<HttpRequest> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    let head = read_head(reader)?;
+    let (request_line, header_lines) =
+        head.split_first().ok_or_else(|| invalid("empty request"))?;
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(invalid("malformed request line"));
+    };
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = percent_decode_path(path).ok_or_else(|| invalid("malformed path"))?;
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((percent_decode_query(name)?, percent_decode_query(value)?))
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| invalid("malformed query string"))?;
+    let headers: Vec<(String, String)> = header_lines
+        .iter()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let mut request = HttpRequest {
+        method: method.to_string(),
+        path,
+        query,
+        headers,
+        body: Vec::new(),
+    };
+    if let Some(len) = request.header("Content-Length") {
+        let len: usize = len
+            .parse()
+            .ok()
+            .filter(|&len| len <= MAX_BODY_BYTES)
+            .ok_or_else(|| invalid("bad Content-Length"))?;
+        request.body = vec![0; len];
+        reader.read_exact(&mut request.body)?;
+    }
+    Ok(request)
+}
+
+fn route(request: HttpRequest) -> Result<Route, Response> {
+    let method = request.method.as_str();
+    if request.path == "/stats" || request.path == "/status" {
+        return match method {
+            "GET" if request.path == "/stats" => Ok(Route::Stats),
+            "GET" => Ok(Route::Status),
+            _ => Err(Response::error(
+                "405 Method Not Allowed",
+                format!("{} is read-only", request.path),
+            )),
+        };
+    }
+    if request.path == "/mget" {
+        if method != "GET" {
+            return Err(Response::error(
+                "405 Method Not Allowed",
+                "/mget is read-only",
+            ));
+        }
+        let mut keys = Vec::new();
+        for (name, value) in request.query {
+            match name.as_str() {
+                "key" => keys.push(value),
+                _ => {
+                    return Err(Response::error(
+                        "400 Bad Request",
+                        format!("unknown parameter {name}"),
+                    ));
+                }
+            }
+        }
+        if keys.is_empty() {
+            return Err(Response::error("400 Bad Request", "/mget needs a key="));
+        }
+        return Ok(Route::MGet(keys));
+    }
+    if request.path == "/keys" {
+        if method != "GET" {
+            return Err(Response::error(
+                "405 Method Not Allowed",
+                "use /keys/{key} to change a key",
+            ));
+        }
+        let mut prefix = String::new();
+        let mut limit = usize::MAX;
+        let mut filter = ScanFilter::default();
+        for (name, value) in request.query {
+            match name.as_str() {
+                "prefix" => prefix = value,
+                "glob" => filter.glob = Some(value),
+                "contains" => filter.contains = Some(value),
+                "field" => match value.split_once('=') {
+                    Some((field, expected)) if !field.is_empty() => {
+                        filter.field = Some((field.to_string(), expected.to_string()))
+                    }
+                    _ => {
+                        return Err(Response::error("400 Bad Request", "field takes name=value"));
+                    }
+                },
+                "limit" => {
+                    limit = value
+                        .parse()
+                        .map_err(|_| Response::error("400 Bad Request", "limit must be a number"))?
+                }
+                _ => {
+                    return Err(Response::error(
+                        "400 Bad Request",
+                        format!("unknown parameter {name}"),
+                    ));
+                }
+            }
+        }
+        return Ok(Route::Scan {
+            prefix,
+            limit,
+            filter,
+        });
+    }
+
+    let Some(key) = request.path.strip_prefix("/keys/").map(str::to_string) else {
+        return Err(Response::error("404 Not Found", "no such endpoint"));
+    };
+    match method {
+        "GET" => Ok(Route::Get(key)),
+        "DELETE" => Ok(Route::Delete(key)),
+        "PUT" => {
+            let if_absent = request.header("If-None-Match") == Some("*");
+            let checksum = request
+                .header("X-Checksum-CRC32")
+                .map(server::parse_checksum)
+                .transpose()
+                .map_err(|message| Response::error("400 Bad Request", message))?;
+            let value = String::from_utf8(request.body)
+                .map_err(|_| Response::error("400 Bad Request", "the value must be UTF-8"))?;
+            Ok(Route::Put {
+                key,
+                value,
+                if_absent,
+                checksum,
+            })
+        }
+        _ => Err(Response::error(
+            "405 Method Not Allowed",
+            "keys take GET, PUT and DELETE",
+        )),
+    }
+}
+
+/// `Denied` unless the client's role may run the route, scans leave out
+/// what it may not read instead
+fn authorize(route: &Route, db: &Database, role: &Role) -> Result<(), DeebeeError> {
+    match route {
+        Route::Get(key) if db.burns_after_read(key) => role.check(key, Access::Write),
+        Route::Get(key) => role.check(key, Access::Read),
+        Route::Put { key, .. } | Route::Delete(key) => role.check(key, Access::Write),
+        Route::MGet(keys) => keys
+            .iter()
+            .try_for_each(|key| role.check(key, Access::Read)),
+        Route::Scan { .. } => Ok(()),
+        Route::Stats | Route::Status => role.check_unrestricted(),
+    }
+}
+
+/// answer the route for client `id` within the bounds and its role,
+/// counting key operations like the other protocols do
+fn execute(
+    route: Route,
+    db: &mut Database,
+    clients: &Clients,
+    id: u64,
+    bounds: &GetOptions,
+) -> Response {
+    let role = clients.role(db, id).and_then(|role| {
+        if let Some(role) = &role {
+            authorize(&route, db, role)?;
+        }
+        Ok(role)
+    });
+    let key = match &route {
+        Route::Get(key) | Route::Put { key, .. } | Route::Delete(key) => Some(key.clone()),
+        Route::MGet(_) | Route::Scan { .. } => None,
+        // not database operations
+        Route::Stats | Route::Status => {
+            return match role {
+                Ok(role) => execute_route(route, db, clients, bounds, role.as_ref(), &mut 0),
+                Err(e) => Response::error(status_of(&e), e),
+            };
+        }
+    };
+    let mut bytes = key.as_ref().map_or(0, String::len);
+    let response = match bounds.check().and(role) {
+        Ok(role) => execute_route(route, db, clients, bounds, role.as_ref(), &mut bytes),
+        Err(e) => Response::error(status_of(&e), e),
+    };
+    // a miss isn't an error, a conflict is
+    let failed = !response.succeeded() && response.status != "404 Not Found";
+    server::count(db, clients, id, key.as_deref(), bytes, failed);
+    response
+}
+
+/// run the route as the role, adding the key and value bytes it moved to
+/// `bytes`
+fn execute_route(
+    route: Route,
+    db: &mut Database,
+    clients: &Clients,
+    bounds: &GetOptions,
+    role: Option<&Role>,
+    bytes: &mut usize,
+) -> Response {
+    let result = match route {
+        Route::Get(key) => {
+            let burns = db.burns_after_read(&key);
+            db.get_and_burn(&key).map(|value| match value {
+                Some(value) => {
+                    *bytes += value.len();
+                    if burns {
+                        clients.invalidate(&key);
+                    }
+                    let crc32 = format!("{:08x}", crc32fast::hash(value.as_bytes()));
+                    Response::json(
+                        "200 OK",
+                        json!({ "key": key, "value": value, "crc32": crc32 }),
+                    )
+                }
+                None => Response::error("404 Not Found", DeebeeError::KeyNotFound(key)),
+            })
+        }
+        Route::Put {
+            key,
+            value,
+            if_absent,
+            checksum,
+        } => {
+            let condition = if if_absent {
+                SetCondition::IfAbsent
+            } else {
+                SetCondition::Always
+            };
+            *bytes += value.len();
+            db.validate_value(&key, &value)
+                .and_then(|()| match checksum {
+                    Some(_) if if_absent && db.contains_key(&key) => Ok(false),
+                    Some(checksum) => db.set_checked(&key, &value, checksum).map(|()| true),
+                    None => db.set_if(&key, &value, condition),
+                })
+                .map(|written| match written {
+                    true => {
+                        clients.invalidate(&key);
+                        Response::no_content()
+                    }
+                    false => Response::error("409 Conflict", format!("key {key} already exists")),
+                })
+        }
+        Route::Delete(key) => db.delete(&key).map(|existed| match existed {
+            true => {
+                clients.invalidate(&key);
+                Response::no_content()
+            }
+            false => Response::error("404 Not Found", DeebeeError::KeyNotFound(key)),
+        }),
+        // a missing key is a null, not a 404 for all of them
+        Route::MGet(keys) => server::get_many(db, &keys, bytes).map(|values| {
+            let records: Vec<_> = keys
+                .iter()
+                .zip(values)
+                .map(|(key, value)| json!({ "key": key, "value": value }))
+                .collect();
+            Response::json("200 OK", records.into())
+        }),
+        Route::Scan {
+            prefix,
+            limit,
+            filter,
+        } => db
+            .scan_filtered_with(&prefix, &filter, bounds)
+            .filter(|record| {
+                record.as_ref().map_or(true, |(key, _)| {
+                    role.is_none_or(|role| role.allows(key, Access::Read))
+                })
+            })
+            .take(limit)
+            .map(|record| {
+                record.map(|(key, value)| {
+                    *bytes += key.len() + value.len();
+                    json!({ "key": key, "value": value })
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|records| Response::json("200 OK", records.into())),
+        Route::Stats => Ok(Response::json(
+            "200 OK",
+            serde_json::to_value(db.stats(false)).expect("stats always serialize"),
+        )),
+        Route::Status => status::render(db, &clients.list(), db.unix_secs()).map(Response::html),
+    };
+    result.unwrap_or_else(|e| Response::error(status_of(&e), e))
+}
+
+fn status_of(e: &DeebeeError) -> &'static str {
+    match e {
+        DeebeeError::KeyNotFound(_) => "404 Not Found",
+        DeebeeError::InvalidKey(_)
+        | DeebeeError::InvalidValue(_)
+        | DeebeeError::InvalidArgument(_) => "400 Bad Request",
+        DeebeeError::Write(WriteError::ReadOnly { .. }) => "403 Forbidden",
+        DeebeeError::Write(WriteError::Immutable { .. } | WriteError::Fenced { .. }) => {
+            "409 Conflict"
+        }
+        DeebeeError::Write(_) => "400 Bad Request",
+        DeebeeError::Config(_)
+        | DeebeeError::Corruption(_)
+        | DeebeeError::Io(_)
+        | DeebeeError::Locked(_) => "500 Internal Server Error",
+        DeebeeError::Cancelled(_) => "503 Service Unavailable",
+        DeebeeError::Denied(_) => "403 Forbidden",
+    }
+}
@@ -0,0 +1,708 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io::{self, BufRead, Read};
+use std::ops::BitOr;
+
+use crate::error::DeebeeError;
+
+// each segment got a number of entries it can afford
+// for here, each segment carry up to 10 entries, unless the database sets `segment_size`
+pub(crate) const SEGMENT_SIZE: usize = 10;
+
+/// newest on-disk format this build can write.
+/// 1: `key, value` records
+/// 2: adds tombstone records for deletes
+/// 3: escapes commas, newlines and backslashes, so keys and values round-trip
+/// 4: binary records with length prefixes and a CRC32
+/// 5: a flags byte in every record, and values that aren't UTF-8
+/// 6: sealed segments can be compressed in DEFLATE blocks
+///
+/// keys are UTF-8 in every version. before version 5 values are too, and a
+/// delete or a shared value is spelled as a reserved value, see `TOMBSTONE`
+pub const FORMAT_VERSION: u32 = 6;
+
+// value written in place of the real one when a key is deleted, up to format
+// version 4: those records have no flags, only lengths. the NUL byte keeps
+// it from colliding with anything typed on a command line
+pub(crate) const TOMBSTONE: &str = "\0tombstone";
+
+// what compaction writes in place of a value it moved to the blob area,
+// followed by the blob's hash, up to format version 4
+pub(crate) const BLOB_REF: &str = "\0blob:";
+
+/// values a write can't store before format version 5, they'd read back as
+/// something else
+pub(crate) fn is_reserved(value: &[u8]) -> bool {
+    value == TOMBSTONE.as_bytes() || value.starts_with(BLOB_REF.as_bytes())
+}
+
+/// the value of a `CHECKSUM` record without the checksum in front, once the
+/// two match
+pub(crate) fn checked_value<'a>(
+    key: &str,
+    value: Cow<'a, [u8]>,
+) -> Result<Cow<'a, [u8]>, DeebeeError> {
+    let damaged = || {
+        DeebeeError::Corruption(format!(
+            "the value of {key} doesn't match the checksum it was written with"
+        ))
+    };
+    let checksum = value.get(..4).ok_or_else(damaged)?;
+    let checksum = u32::from_le_bytes(checksum.try_into().expect("four bytes"));
+    let value = match value {
+        Cow::Borrowed(value) => Cow::Borrowed(&value[4..]),
+        Cow::Owned(mut value) => {
+            value.drain(..4);
+            Cow::Owned(value)
+        }
+    };
+    if crc32fast::hash(&value) != checksum {
+        return Err(damaged());
+    }
+    Ok(value)
+}
+
+// key_len and value_len, both u32 little-endian
+const BINARY_HEADER: usize = 8;
+// then the flags byte
+const FLAGGED_HEADER: usize = 9;
+const BINARY_CRC: usize = 4;
+
+/// what a record is besides its key and value. format version 5 keeps them
+/// in a byte of the record, older versions spell them as reserved values
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RecordFlags(u8);
+
+impl RecordFlags {
+    /// an ordinary value
+    pub const NONE: Self = Self(0);
+    /// the key was deleted, the value is empty
+    pub const TOMBSTONE: Self = Self(1);
+    /// the value is the hash of a shared value in the blob area
+    pub const BLOB: Self = Self(1 << 1);
+    /// the first `get_and_burn` that returns the value deletes the key
+    pub const BURN: Self = Self(1 << 2);
+    /// the value is a merge operand, folded into the key's earlier records
+    pub const OPERAND: Self = Self(1 << 3);
+    /// the value starts with the CRC32 a client sent for it, little-endian,
+    /// and every read checks the rest against it
+    pub const CHECKSUM: Self = Self(1 << 4);
+    // every flag this build knows, a record with others isn't one it wrote
+    const KNOWN: u8 =
+        Self::TOMBSTONE.0 | Self::BLOB.0 | Self::BURN.0 | Self::OPERAND.0 | Self::CHECKSUM.0;
+
+    /// `None` when a flag this build doesn't know is set
+    pub(crate) fn from_bits(bits: u8) -> Option<Self> {
+        (bits & !Self::KNOWN == 0).then_some(Self(bits))
+    }
+
+    /// whether every flag of `flags` is set
+    pub fn contains(self, flags: Self) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    /// these flags with the ones of `flags` cleared
+    pub(crate) fn without(self, flags: Self) -> Self {
+        Self(self.0 & !flags.0)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+}
+
+impl BitOr for RecordFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// a record as the segments hold it, the value not resolved from the blob
+/// area yet
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record<'a> {
+    pub key: Cow<'a, str>,
+    pub value: Cow<'a, [u8]>,
+    pub flags: RecordFlags,
+}
+
+impl<'a> Record<'a> {
+    /// the key holding the value
+    pub fn new(key: &'a str, value: &'a [u8]) -> Self {
+        Self {
+            key: Cow::Borrowed(key),
+            value: Cow::Borrowed(value),
+            flags: RecordFlags::NONE,
+        }
+    }
+
+    /// the key deleted
+    pub fn tombstone(key: &'a str) -> Self {
+        Self {
+            key: Cow::Borrowed(key),
+            value: Cow::Borrowed(b""),
+            flags: RecordFlags::TOMBSTONE,
+        }
+    }
+
+    /// the key holding the value, with the checksum the client sent for it
+    pub(crate) fn checksummed(key: &'a str, value: &[u8], checksum: u32) -> Self {
+        let mut stored = checksum.to_le_bytes().to_vec();
+        stored.extend_from_slice(value);
+        Self {
+            key: Cow::Borrowed(key),
+            value: Cow::Owned(stored),
+            flags: RecordFlags::CHECKSUM,
+        }
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.flags.contains(RecordFlags::TOMBSTONE)
+    }
+
+    /// the value without the checksum in front of it, unchecked
+    pub(crate) fn plain_value(&self) -> &[u8] {
+        match self.flags.contains(RecordFlags::CHECKSUM) {
+            true => self.value.get(4..).unwrap_or_default(),
+            false => &self.value,
+        }
+    }
+
+    pub fn into_owned(self) -> Record<'static> {
+        Record {
+            key: Cow::Owned(self.key.into_owned()),
+            value: Cow::Owned(self.value.into_owned()),
+            flags: self.flags,
+        }
+    }
+
+    /// what an older format reads back as this record's value, `None` when
+    /// it can't spell the record at all
+    fn as_text(&self) -> Option<Cow<'_, str>> {
+        if self.is_tombstone() {
+            return Some(Cow::Borrowed(TOMBSTONE));
+        }
+        let value = std::str::from_utf8(&self.value).ok()?;
+        match self.flags {
+            RecordFlags::BLOB => Some(Cow::Owned(format!("{BLOB_REF}{value}"))),
+            RecordFlags::NONE if !is_reserved(value.as_bytes()) => Some(Cow::Borrowed(value)),
+            _ => None,
+        }
+    }
+
+    /// the record an older format's key and value stand for
+    fn from_text(key: Cow<'a, str>, value: Cow<'a, str>) -> Self {
+        let (value, flags) = if value == TOMBSTONE {
+            (Cow::Borrowed(&b""[..]), RecordFlags::TOMBSTONE)
+        } else if value.starts_with(BLOB_REF) {
+            let hash = match value {
+                Cow::Borrowed(value) => Cow::Borrowed(&value.as_bytes()[BLOB_REF.len()..]),
+                Cow::Owned(value) => Cow::Owned(value.as_bytes()[BLOB_REF.len()..].to_vec()),
+            };
+            (hash, RecordFlags::BLOB)
+        } else {
+            let value = match value {
+                Cow::Borrowed(value) => Cow::Borrowed(value.as_bytes()),
+                Cow::Owned(value) => Cow::Owned(value.into_bytes()),
+            };
+            (value, RecordFlags::NONE)
+        };
+        Self { key, value, flags }
+    }
+}
+
+/// how records are laid out inside a segment
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordEncoding {
+    /// `key, value` lines written as-is and trimmed on read, keys can't hold
+    /// commas and nothing can hold a newline. format versions 1 and 2
+    Plain,
+    /// `key, value` lines with `\`, `,`, `\n` and `\r` escaped by a backslash
+    /// and nothing trimmed. format version 3
+    Escaped,
+    /// key_len, value_len, key bytes, value bytes, then a CRC32 of all of
+    /// them. format version 4. keys and values are still UTF-8 and a delete
+    /// is still the tombstone value, what changes is that nothing needs
+    /// escaping and damage is caught by the checksum
+    Binary,
+    /// key_len, value_len, a `RecordFlags` byte, key bytes, value bytes and
+    /// a CRC32 of all of them. format version 5 onwards. values are any
+    /// bytes, deletes and shared values are told apart by their flags
+    Flagged,
+}
+
+impl RecordEncoding {
+    pub fn for_format(version: u32) -> Self {
+        match version {
+            0..=2 => RecordEncoding::Plain,
+            3 => RecordEncoding::Escaped,
+            4 => RecordEncoding::Binary,
+            _ => RecordEncoding::Flagged,
+        }
+    }
+
+    /// the oldest format version that can hold the record. deletes need
+    /// version 2 on top of that
+    pub(crate) fn format_needed(record: &Record) -> u32 {
+        let Some(value) = record.as_text() else {
+            return 5;
+        };
+        if record.key.contains([',', '\n', '\r']) || value.contains(['\n', '\r']) {
+            3
+        } else {
+            1
+        }
+    }
+
+    /// whether the pair can be written without losing anything
+    pub fn can_encode(self, key: &str, value: &str) -> bool {
+        self.can_encode_record(&Record::new(key, value.as_bytes()))
+    }
+
+    /// whether the record can be written without losing anything
+    pub fn can_encode_record(self, record: &Record) -> bool {
+        let fits =
+            u32::try_from(record.key.len()).is_ok() && u32::try_from(record.value.len()).is_ok();
+        if self == RecordEncoding::Flagged {
+            return fits;
+        }
+        let Some(value) = record.as_text() else {
+            return false;
+        };
+        match self {
+            RecordEncoding::Plain => {
+                !record.key.contains([',', '\n', '\r']) && !value.contains(['\n', '\r'])
+            }
+            RecordEncoding::Escaped => true,
+            _ => fits,
+        }
+    }
+
+    /// the full record holding the value, trailing newline or checksum
+    /// included
+    pub fn encode(self, key: &str, value: &str) -> Vec<u8> {
+        self.encode_record(&Record::new(key, value.as_bytes()))
+    }
+
+    /// the full record, trailing newline or checksum included. one
+    /// `can_encode_record` turns down comes out mangled
+    pub fn encode_record(self, record: &Record) -> Vec<u8> {
+        let key = &record.key;
+        if self == RecordEncoding::Flagged {
+            let value = &record.value;
+            let mut bytes =
+                Vec::with_capacity(FLAGGED_HEADER + key.len() + value.len() + BINARY_CRC);
+            bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            bytes.push(record.flags.bits());
+            bytes.extend_from_slice(key.as_bytes());
+            bytes.extend_from_slice(value);
+            let crc = crc32fast::hash(&bytes);
+            bytes.extend_from_slice(&crc.to_le_bytes());
+            return bytes;
+        }
+
+        let value = record
+            .as_text()
+            .unwrap_or_else(|| String::from_utf8_lossy(&record.value));
+        match self {
+            RecordEncoding::Plain => format!("{key}, {value}\n").into_bytes(),
+            RecordEncoding::Escaped => {
+                format!("{}, {}\n", escape(key), escape(&value)).into_bytes()
+            }
+            _ => {
+                let mut bytes =
+                    Vec::with_capacity(BINARY_HEADER + key.len() + value.len() + BINARY_CRC);
+                bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(key.as_bytes());
+                bytes.extend_from_slice(value.as_bytes());
+                let crc = crc32fast::hash(&bytes);
+                bytes.extend_from_slice(&crc.to_le_bytes());
+                bytes
+            }
+        }
+    }
+
+    /// bytes before the key of a checksummed record
+    fn header_len(self) -> usize {
+        match self {
+            RecordEncoding::Flagged => FLAGGED_HEADER,
+            _ => BINARY_HEADER,
+        }
+    }
+
+    /// how many bytes the record at the start of `rest` takes up, which can
+    /// be more than is left when the segment ends early
+    pub(crate) fn record_len(self, rest: &[u8]) -> Option<usize> {
+        if rest.is_empty() {
+            return None;
+        }
+        match self {
+            RecordEncoding::Plain | RecordEncoding::Escaped => Some(
+                rest.iter()
+                    .position(|&b| b == b'\n')
+                    .map_or(rest.len(), |i| i + 1),
+            ),
+            RecordEncoding::Binary | RecordEncoding::Flagged => {
+                let header = rest.get(..BINARY_HEADER)?;
+                let key_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+                let value_len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+                Some(self.header_len() + key_len + value_len + BINARY_CRC)
+            }
+        }
+    }
+
+    /// split one whole record into its key, value and flags, `None` if it
+    /// isn't a record or fails its checksum
+    pub fn decode<'a>(self, record: &'a [u8]) -> Option<Record<'a>> {
+        match self {
+            RecordEncoding::Plain => {
+                // split by the first comma only
+                let line = std::str::from_utf8(record).ok()?;
+                let (key, value) = line.split_once(',')?;
+                Some(Record::from_text(
+                    Cow::Borrowed(key.trim()),
+                    Cow::Borrowed(value.trim()),
+                ))
+            }
+            RecordEncoding::Escaped => {
+                let line = std::str::from_utf8(record).ok()?;
+                let line = line.strip_suffix('\n').unwrap_or(line);
+                let comma = unescaped_comma(line)?;
+                let value = &line[comma + 1..];
+                let value = value.strip_prefix(' ').unwrap_or(value);
+                Some(Record::from_text(unescape(&line[..comma]), unescape(value)))
+            }
+            RecordEncoding::Binary | RecordEncoding::Flagged => {
+                if self.record_len(record)? != record.len() {
+                    return None;
+                }
+                let (body, crc) = record.split_at(record.len() - BINARY_CRC);
+                if crc32fast::hash(body).to_le_bytes() != crc {
+                    return None;
+                }
+                let key_len = u32::from_le_bytes(body[..4].try_into().unwrap()) as usize;
+                let (key, value) = body[self.header_len()..].split_at(key_len);
+                let key = Cow::Borrowed(std::str::from_utf8(key).ok()?);
+                if self == RecordEncoding::Binary {
+                    return Some(Record::from_text(
+                        key,
+                        Cow::Borrowed(std::str::from_utf8(value).ok()?),
+                    ));
+                }
+                Some(Record {
+                    key,
+                    value: Cow::Borrowed(value),
+                    flags: RecordFlags::from_bits(body[BINARY_HEADER])?,
+                })
+            }
+        }
+    }
+
+    /// whether a record that doesn't decode is damaged rather than just not a
+    /// record, in which case nothing after it can be trusted either
+    pub fn is_checksummed(self) -> bool {
+        matches!(self, RecordEncoding::Binary | RecordEncoding::Flagged)
+    }
+
+    /// read the raw bytes of the record the reader is positioned at, empty at
+    /// the end of the segment
+    pub(crate) fn read_record(self, reader: &mut impl BufRead) -> io::Result<Vec<u8>> {
+        let mut record = Vec::new();
+        match self {
+            RecordEncoding::Plain | RecordEncoding::Escaped => {
+                reader.read_until(b'\n', &mut record)?;
+            }
+            RecordEncoding::Binary | RecordEncoding::Flagged => {
+                reader
+                    .by_ref()
+                    .take(BINARY_HEADER as u64)
+                    .read_to_end(&mut record)?;
+                if let Some(len) = self.record_len(&record) {
+                    // a damaged length can claim gigabytes, only read what is there
+                    reader
+                        .by_ref()
+                        .take((len - BINARY_HEADER) as u64)
+                        .read_to_end(&mut record)?;
+                }
+            }
+        }
+        Ok(record)
+    }
+}
+
+fn escape(s: &str) -> Cow<'_, str> {
+    if !s.contains(['\\', ',', '\n', '\r']) {
+        return Cow::Borrowed(s);
+    }
+    let mut escaped = String::with_capacity(s.len() + 8);
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+fn unescape(s: &str) -> Cow<'_, str> {
+    if !s.contains('\\') {
+        return Cow::Borrowed(s);
+    }
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    Cow::Owned(unescaped)
+}
+
+/// byte position of the first comma that isn't escaped
+fn unescaped_comma(line: &str) -> Option<usize> {
+    let bytes = line.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b',' => return Some(i),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// walk the records of a segment, yielding (offset, record). text lines
+/// that aren't records are skipped, the offset is where the record starts.
+/// checksummed segments stop at the first damaged or cut-off record
+pub fn segment_records(
+    content: &[u8],
+    encoding: RecordEncoding,
+) -> impl Iterator<Item = (u64, Record<'_>)> {
+    sized_records(content, encoding).map(|(offset, _, record)| (offset, record))
+}
+
+/// `segment_records` with how many bytes each record takes up
+pub(crate) fn sized_records(
+    content: &[u8],
+    encoding: RecordEncoding,
+) -> impl Iterator<Item = (u64, usize, Record<'_>)> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        loop {
+            let rest = &content[offset..];
+            let len = encoding.record_len(rest)?;
+            let record = rest.get(..len)?;
+            let start = offset as u64;
+            match encoding.decode(record) {
+                Some(record) => {
+                    offset += len;
+                    return Some((start, len, record));
+                }
+                None if encoding.is_checksummed() => return None,
+                None => offset += len,
+            }
+        }
+    })
+}
+
+/// where a record cut short by a crash starts, if the segment ends with one.
+/// a damaged record with more data after it isn't a torn write, it is left
+/// for someone to look at
+pub(crate) fn torn_tail(content: &[u8], encoding: RecordEncoding) -> Option<usize> {
+    if !encoding.is_checksummed() {
+        return None;
+    }
+    let mut offset = 0;
+    while let Some(len) = encoding.record_len(&content[offset..]) {
+        let rest = &content[offset..];
+        match rest.get(..len) {
+            Some(record) if encoding.decode(record).is_some() => offset += len,
+            _ if len >= rest.len() => return Some(offset),
+            _ => return None,
+        }
+    }
+    // too short to even hold a header
+    (offset < content.len()).then_some(offset)
+}
+
+/// canonical JSON description of a segment file, for external tooling and
+/// format round-trip tests
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SegmentDescription {
+    pub format: String,
+    pub records: Vec<RecordDescription>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RecordDescription {
+    /// byte offset of the record, checked on encode when present
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    pub key: String,
+    #[serde(default)]
+    pub value: String,
+    /// the value in hex when it isn't UTF-8, `value` is empty then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_hex: Option<String>,
+    /// the record deletes the key, value is empty
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tombstone: bool,
+    /// the value is the hash of a shared value in the blob area
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub blob: bool,
+    /// the key burns after read
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub burn: bool,
+    /// the value is a merge operand
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub operand: bool,
+    /// the value starts with the checksum the client sent for it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub checksum: bool,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+impl SegmentDescription {
+    fn format_name(encoding: RecordEncoding) -> &'static str {
+        match encoding {
+            RecordEncoding::Plain => "text-v1",
+            RecordEncoding::Escaped => "text-v2",
+            RecordEncoding::Binary => "binary-v1",
+            RecordEncoding::Flagged => "binary-v2",
+        }
+    }
+
+    fn encoding(&self) -> Option<RecordEncoding> {
+        [
+            RecordEncoding::Plain,
+            RecordEncoding::Escaped,
+            RecordEncoding::Binary,
+            RecordEncoding::Flagged,
+        ]
+        .into_iter()
+        .find(|&encoding| Self::format_name(encoding) == self.format)
+    }
+
+    /// describe every record of a segment, with the offset it starts at
+    pub fn decode(content: &[u8], encoding: RecordEncoding) -> Self {
+        let records = segment_records(content, encoding)
+            .map(|(offset, record)| {
+                let (value, value_hex) = match std::str::from_utf8(&record.value) {
+                    Ok(value) => (value.to_string(), None),
+                    Err(_) => (String::new(), Some(to_hex(&record.value))),
+                };
+                RecordDescription {
+                    offset: Some(offset),
+                    key: record.key.to_string(),
+                    value,
+                    value_hex,
+                    tombstone: record.is_tombstone(),
+                    blob: record.flags.contains(RecordFlags::BLOB),
+                    burn: record.flags.contains(RecordFlags::BURN),
+                    operand: record.flags.contains(RecordFlags::OPERAND),
+                    checksum: record.flags.contains(RecordFlags::CHECKSUM),
+                }
+            })
+            .collect();
+
+        Self {
+            format: Self::format_name(encoding).to_string(),
+            records,
+        }
+    }
+
+    /// produce the segment bytes, failing if a record's offset doesn't line up
+    pub fn encode(&self) -> Result<Vec<u8>, DeebeeError> {
+        let Some(encoding) = self.encoding() else {
+            return Err(DeebeeError::Corruption(format!(
+                "unsupported segment format {:?}",
+                self.format
+            )));
+        };
+
+        let mut content = Vec::new();
+        for (i, record) in self.records.iter().enumerate() {
+            if let Some(offset) = record.offset
+                && offset != content.len() as u64
+            {
+                return Err(DeebeeError::Corruption(format!(
+                    "record {i} ({}) claims offset {offset} but starts at {}",
+                    record.key,
+                    content.len()
+                )));
+            }
+            let value = match &record.value_hex {
+                Some(hex) => Cow::Owned(from_hex(hex).ok_or_else(|| {
+                    DeebeeError::Corruption(format!(
+                        "record {i} ({}) has a value_hex that isn't hex",
+                        record.key
+                    ))
+                })?),
+                None => Cow::Borrowed(record.value.as_bytes()),
+            };
+            let mut flags = RecordFlags::NONE;
+            if record.tombstone {
+                flags = RecordFlags::TOMBSTONE;
+            } else {
+                if record.blob {
+                    flags = flags | RecordFlags::BLOB;
+                }
+                if record.burn {
+                    flags = flags | RecordFlags::BURN;
+                }
+                if record.operand {
+                    flags = flags | RecordFlags::OPERAND;
+                }
+                if record.checksum {
+                    flags = flags | RecordFlags::CHECKSUM;
+                }
+            }
+            let encoded = Record {
+                key: Cow::Borrowed(&record.key),
+                value: if record.tombstone {
+                    Cow::Borrowed(b"")
+                } else {
+                    value
+                },
+                flags,
+            };
+            if !encoding.can_encode_record(&encoded) {
+                return Err(DeebeeError::Corruption(format!(
+                    "record {i} ({}) can't be written as {}",
+                    record.key, self.format
+                )));
+            }
+            content.extend_from_slice(&encoding.encode_record(&encoded));
+        }
+
+        Ok(content)
+    }
+}
@@ -0,0 +1,1073 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::acl::{self, Access, Role};
+use crate::cancel::GetOptions;
+use crate::database::{Database, ScanFilter};
+use crate::error::DeebeeError;
+use crate::idempotency::{self, Outcome, Tokens};
+use crate::resp::{self, Command};
+use crate::rest;
+use crate::stats::OpStats;
+
+/// how often `Server::serve` writes the stats sidecar, between commands
+const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// how clients talk to the server
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Protocol {
+    /// one command per line, one reply per line
+    #[default]
+    Line,
+    /// RESP2, for redis-cli and Redis client libraries
+    Resp,
+    /// HTTP with JSON bodies, `/keys/{key}` and `/stats`, and the `/status` page
+    Http,
+}
+
+impl std::str::FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "line" => Ok(Protocol::Line),
+            "resp" => Ok(Protocol::Resp),
+            "http" => Ok(Protocol::Http),
+            _ => Err(format!(
+                "unknown protocol {s:?}, expected line, resp or http"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Protocol::Line => "line",
+            Protocol::Resp => "resp",
+            Protocol::Http => "http",
+        })
+    }
+}
+
+/// someone connected to the server, as `/status` and `ADMIN CLIENTS` list
+/// them
+#[derive(Clone, Debug)]
+pub(crate) struct Client {
+    pub(crate) id: u64,
+    pub(crate) addr: SocketAddr,
+    pub(crate) protocol: Protocol,
+    pub(crate) connected_at: Instant,
+    pub(crate) commands: u64,
+    /// the database operations its commands ran, and the commands refused
+    /// before getting that far as errors
+    pub(crate) usage: OpStats,
+    /// the role it authenticated as, if the database has any
+    pub(crate) role: Option<String>,
+}
+
+impl Client {
+    /// `id=<n> addr=<addr> ...`, one line
+    pub(crate) fn describe(&self) -> String {
+        let mut line = format!(
+            "id={} addr={} protocol={} age={}s commands={} ops={} bytes={} errors={}",
+            self.id,
+            self.addr,
+            self.protocol,
+            self.connected_at.elapsed().as_secs(),
+            self.commands,
+            self.usage.ops,
+            self.usage.bytes,
+            self.usage.errors
+        );
+        if let Some(role) = &self.role {
+            line.push_str(&format!(" role={role}"));
+        }
+        line
+    }
+}
+
+/// the clients connected right now, by connection number
+#[derive(Clone, Default)]
+pub(crate) struct Clients(Arc<Mutex<Registry>>);
+
+#[derive(Default)]
+struct Registry {
+    clients: BTreeMap<u64, Client>,
+    /// connections in tracking mode, see `Clients::track`
+    tracking: HashMap<u64, Tracking>,
+    /// how long a command may take from the moment it's read, `None` waits
+    /// as long as it takes
+    timeout: Option<Duration>,
+    /// how long the writes of one group commit are collected, `None` fsyncs
+    /// each on its own
+    commit_window: Option<Duration>,
+}
+
+/// the keys a client read since they last changed, and where to tell it
+/// they did
+struct Tracking {
+    keys: HashSet<String>,
+    pushes: Sender<String>,
+}
+
+impl Clients {
+    fn connect(&self, id: u64, addr: SocketAddr, protocol: Protocol) -> Connection {
+        let client = Client {
+            id,
+            addr,
+            protocol,
+            connected_at: Instant::now(),
+            commands: 0,
+            usage: OpStats::default(),
+            role: None,
+        };
+        self.lock().clients.insert(id, client);
+        Connection {
+            id,
+            clients: self.clone(),
+        }
+    }
+
+    /// oldest connection first
+    pub(crate) fn list(&self) -> Vec<Client> {
+        self.lock().clients.values().cloned().collect()
+    }
+
+    /// count what one of the connection's operations did
+    pub(crate) fn count(&self, id: u64, op: OpStats) {
+        if let Some(client) = self.lock().clients.get_mut(&id) {
+            client.usage.add(op);
+        }
+    }
+
+    /// the connection authenticated as the role
+    fn authenticate(&self, id: u64, role: &str) {
+        if let Some(client) = self.lock().clients.get_mut(&id) {
+            client.role = Some(role.to_string());
+        }
+    }
+
+    /// the role of the connection, for what the database lets it do. `None`
+    /// when the database has no roles, `Denied` when it has and the
+    /// connection didn't authenticate as one of them
+    pub(crate) fn role(&self, db: &Database, id: u64) -> Result<Option<Role>, DeebeeError> {
+        if db.roles().is_empty() {
+            return Ok(None);
+        }
+        let name = self
+            .lock()
+            .clients
+            .get(&id)
+            .and_then(|client| client.role.clone());
+        db.roles()
+            .iter()
+            .find(|role| Some(&role.name) == name.as_ref())
+            .map(|role| Some(role.clone()))
+            .ok_or_else(acl::unauthenticated)
+    }
+
+    /// the connection's idempotency key with its role in the request, so
+    /// another role's retry is refused as a different request instead of
+    /// getting the response of a command it may not run
+    pub(crate) fn scope(
+        &self,
+        id: u64,
+        token: Option<(String, Vec<u8>)>,
+    ) -> Option<(String, Vec<u8>)> {
+        let (token, request) = token?;
+        let registry = self.lock();
+        let role = registry
+            .clients
+            .get(&id)
+            .and_then(|client| client.role.as_deref());
+        let mut scoped = role.unwrap_or_default().as_bytes().to_vec();
+        scoped.push(0);
+        scoped.extend_from_slice(&request);
+        Some((token, scoped))
+    }
+
+    /// remember that the connection read the key, if it's in tracking mode.
+    /// the next write to the key sends it `INVALIDATE <key>`, once
+    pub(crate) fn track(&self, id: u64, key: &str) {
+        if let Some(tracking) = self.lock().tracking.get_mut(&id) {
+            tracking.keys.insert(key.to_string());
+        }
+    }
+
+    /// the key changed, tell the connections that read it
+    pub(crate) fn invalidate(&self, key: &str) {
+        let mut registry = self.lock();
+        if registry.tracking.is_empty() {
+            return;
+        }
+        for tracking in registry.tracking.values_mut() {
+            if tracking.keys.remove(key) {
+                // a connection going away drops its receiver, nothing to tell
+                let _ = tracking.pushes.send(format!("INVALIDATE {key}"));
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Registry> {
+        self.0.lock().expect("nothing panics holding the clients")
+    }
+}
+
+/// a client's entry in `Clients`, removed when it hangs up
+pub(crate) struct Connection {
+    id: u64,
+    clients: Clients,
+}
+
+impl Connection {
+    /// count a command the client sent
+    pub(crate) fn command(&self) {
+        if let Some(client) = self.clients.lock().clients.get_mut(&self.id) {
+            client.commands += 1;
+        }
+    }
+
+    /// count a command that couldn't be run as an error
+    pub(crate) fn refused(&self) {
+        let op = OpStats {
+            errors: 1,
+            ..OpStats::default()
+        };
+        self.clients.count(self.id, op);
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// start or stop tracking the keys the client reads, invalidations go
+    /// to `pushes`
+    fn set_tracking(&self, pushes: Option<Sender<String>>) {
+        let mut registry = self.clients.lock();
+        match pushes {
+            Some(pushes) => {
+                registry.tracking.entry(self.id).or_insert(Tracking {
+                    keys: HashSet::new(),
+                    pushes,
+                });
+            }
+            None => {
+                registry.tracking.remove(&self.id);
+            }
+        }
+    }
+
+    pub(crate) fn clients(&self) -> &Clients {
+        &self.clients
+    }
+
+    /// the bounds of a command the client just sent, the server's timeout
+    /// counting from now
+    pub(crate) fn bounds(&self) -> GetOptions {
+        match self.clients.lock().timeout {
+            Some(timeout) => GetOptions::new().timeout(timeout),
+            None => GetOptions::new(),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut registry = self.clients.lock();
+        registry.clients.remove(&self.id);
+        registry.tracking.remove(&self.id);
+    }
+}
+
+/// one command from a client
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Request {
+    /// become the role the token belongs to
+    Auth(String),
+    Get(String),
+    /// `Get`, answered with the CRC32 of the value as well
+    GetChecked(String),
+    /// the values of several keys, from any namespaces, read as of one
+    /// moment
+    MGet(Vec<String>),
+    Set(String, String),
+    /// `Set` with the CRC32 the client computed for the value
+    SetChecked(String, String, u32),
+    Del(String),
+    Exists(String),
+    /// every key matching the pattern, `*` standing for any run of characters
+    Keys(String),
+    /// the keys under the prefix that pass the filter, and their values
+    Scan {
+        prefix: String,
+        filter: ScanFilter,
+        limit: usize,
+    },
+    /// `ADMIN CLIENTS`, a line describing each connected client
+    Clients,
+}
+
+/// what a command answers
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Reply {
+    Ok,
+    /// a short status other than OK
+    Status(String),
+    Nil,
+    Value(String),
+    /// a value and its CRC32
+    Checked(String, u32),
+    Integer(i64),
+    Array(Vec<String>),
+    /// values in the order their keys were asked for, `None` for the ones
+    /// that weren't there
+    Values(Vec<Option<String>>),
+    Error(String),
+}
+
+impl Request {
+    /// `GET key`, `MGET key...`, `SET key value`, `DEL key`, `EXISTS key`, `KEYS pattern`,
+    /// `SCAN [PREFIX p] [GLOB p] [CONTAINS text] [FIELD name value] [LIMIT n]`
+    /// `AUTH token` or `ADMIN CLIENTS`, the command in any case. the value is the rest of
+    /// the line, spaces and all. `SETCRC key crc value` and `GETCRC key` are
+    /// `SET` and `GET` with the CRC32 of the value in 8 hex digits, checked
+    /// by the server before the write and sent back with the value
+    pub(crate) fn parse_line(line: &str) -> Result<Self, String> {
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let (key, value) = rest.split_once(' ').unwrap_or((rest, ""));
+        if command.eq_ignore_ascii_case("ADMIN") {
+            return match rest.eq_ignore_ascii_case("CLIENTS") {
+                true => Ok(Request::Clients),
+                false => Err(format!("unknown admin command {rest}")),
+            };
+        }
+        if command.eq_ignore_ascii_case("SCAN") {
+            return Self::parse_scan(rest.split(' ').filter(|word| !word.is_empty()));
+        }
+        if key.is_empty() {
+            return Err(format!("{command} needs a key"));
+        }
+        match command.to_ascii_uppercase().as_str() {
+            "MGET" => Ok(Request::MGet(
+                rest.split(' ')
+                    .filter(|key| !key.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            )),
+            "GET" if value.is_empty() => Ok(Request::Get(key.to_string())),
+            "DEL" if value.is_empty() => Ok(Request::Del(key.to_string())),
+            "EXISTS" if value.is_empty() => Ok(Request::Exists(key.to_string())),
+            "KEYS" if value.is_empty() => Ok(Request::Keys(key.to_string())),
+            "GETCRC" if value.is_empty() => Ok(Request::GetChecked(key.to_string())),
+            "AUTH" if value.is_empty() => Ok(Request::Auth(key.to_string())),
+            "AUTH" => Err("AUTH takes a single token".to_string()),
+            "SET" => Ok(Request::Set(key.to_string(), value.to_string())),
+            "SETCRC" => {
+                let (checksum, value) = value.split_once(' ').unwrap_or((value, ""));
+                let checksum = parse_checksum(checksum)?;
+                Ok(Request::SetChecked(
+                    key.to_string(),
+                    value.to_string(),
+                    checksum,
+                ))
+            }
+            "GET" | "GETCRC" | "DEL" | "EXISTS" | "KEYS" => {
+                Err(format!("{command} takes a single key"))
+            }
+            _ => Err(format!("unknown command {command}")),
+        }
+    }
+
+    /// the options of `SCAN`, a word each
+    pub(crate) fn parse_scan<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        let mut prefix = String::new();
+        let mut filter = ScanFilter::default();
+        let mut limit = usize::MAX;
+        while let Some(option) = words.next() {
+            let option = option.to_ascii_uppercase();
+            let mut arg = || {
+                words
+                    .next()
+                    .map(str::to_string)
+                    .ok_or_else(|| format!("SCAN {option} needs a value"))
+            };
+            match option.as_str() {
+                "PREFIX" => prefix = arg()?,
+                "GLOB" => filter.glob = Some(arg()?),
+                "CONTAINS" => filter.contains = Some(arg()?),
+                "FIELD" => filter.field = Some((arg()?, arg()?)),
+                "LIMIT" => {
+                    limit = arg()?
+                        .parse()
+                        .map_err(|_| "SCAN LIMIT must be a number".to_string())?
+                }
+                _ => return Err(format!("unknown SCAN option {option}")),
+            }
+        }
+        Ok(Request::Scan {
+            prefix,
+            filter,
+            limit,
+        })
+    }
+
+    /// the role client `id` runs the request as, `Denied` if it may not run
+    /// it. `KEYS` and `SCAN` leave out what the role may not read instead
+    pub(crate) fn authorize(
+        &self,
+        db: &Database,
+        clients: &Clients,
+        id: u64,
+    ) -> Result<Option<Role>, DeebeeError> {
+        let Some(role) = clients.role(db, id)? else {
+            return Ok(None);
+        };
+        match self {
+            Request::Auth(_) | Request::Keys(_) | Request::Scan { .. } => Ok(()),
+            Request::Get(key) | Request::GetChecked(key) if db.burns_after_read(key) => {
+                role.check(key, Access::Write)
+            }
+            Request::Get(key) | Request::GetChecked(key) | Request::Exists(key) => {
+                role.check(key, Access::Read)
+            }
+            Request::MGet(keys) => keys
+                .iter()
+                .try_for_each(|key| role.check(key, Access::Read)),
+            Request::Set(key, _) | Request::SetChecked(key, ..) | Request::Del(key) => {
+                role.check(key, Access::Write)
+            }
+            Request::Clients => role.check_unrestricted(),
+        }?;
+        Ok(Some(role))
+    }
+
+    /// run the request for client `id` within the bounds, telling the
+    /// clients tracking its key when it changed. a command that waited past
+    /// its deadline or that the client's role may not run isn't run at all,
+    /// a scan stops at it. counts toward the client and the key's namespace
+    pub(crate) fn execute(
+        self,
+        db: &mut Database,
+        clients: &Clients,
+        id: u64,
+        bounds: &GetOptions,
+    ) -> Reply {
+        // not a database operation, nothing to count
+        if let Request::Auth(token) = &self {
+            return authenticate(db, clients, id, token);
+        }
+        // the read that burns a key changes it too
+        let changes = match &self {
+            Request::Set(key, _) | Request::SetChecked(key, ..) | Request::Del(key) => {
+                Some(key.clone())
+            }
+            Request::Get(key) | Request::GetChecked(key) if db.burns_after_read(key) => {
+                Some(key.clone())
+            }
+            _ => None,
+        };
+        let key = match &self {
+            Request::Get(key)
+            | Request::GetChecked(key)
+            | Request::Set(key, _)
+            | Request::SetChecked(key, ..)
+            | Request::Del(key)
+            | Request::Exists(key) => Some(key.clone()),
+            Request::Auth(_)
+            | Request::MGet(_)
+            | Request::Keys(_)
+            | Request::Scan { .. }
+            | Request::Clients => None,
+        };
+        let mut bytes = key.as_ref().map_or(0, String::len);
+        let role = match bounds
+            .check()
+            .and_then(|()| self.authorize(db, clients, id))
+        {
+            Ok(role) => role,
+            Err(e) => {
+                count(db, clients, id, key.as_deref(), 0, true);
+                return Reply::Error(e.to_string());
+            }
+        };
+        let readable = |key: &str| {
+            role.as_ref()
+                .is_none_or(|role| role.allows(key, Access::Read))
+        };
+        let result = match self {
+            Request::Get(key) => db.get_and_burn(&key).map(|value| match value {
+                Some(value) => {
+                    bytes += value.len();
+                    Reply::Value(value)
+                }
+                None => Reply::Nil,
+            }),
+            Request::GetChecked(key) => db.get_and_burn(&key).map(|value| match value {
+                Some(value) => {
+                    bytes += value.len();
+                    let checksum = crc32fast::hash(value.as_bytes());
+                    Reply::Checked(value, checksum)
+                }
+                None => Reply::Nil,
+            }),
+            // one view for all of them, none of the keys is read after a
+            // write the others missed
+            Request::MGet(keys) => get_many(db, &keys, &mut bytes).map(Reply::Values),
+            Request::Set(key, value) => {
+                bytes += value.len();
+                db.validate_value(&key, &value)
+                    .and_then(|()| db.set(&key, &value))
+                    .map(|()| Reply::Ok)
+            }
+            Request::SetChecked(key, value, checksum) => {
+                bytes += value.len();
+                db.validate_value(&key, &value)
+                    .and_then(|()| db.set_checked(&key, &value, checksum))
+                    .map(|()| Reply::Ok)
+            }
+            Request::Del(key) => db
+                .delete(&key)
+                .map(|existed| if existed { Reply::Ok } else { Reply::Nil }),
+            Request::Exists(key) => Ok(Reply::Integer(db.contains_key(&key) as i64)),
+            Request::Keys(pattern) => {
+                let mut keys = db.keys_matching(&pattern);
+                keys.retain(|key| readable(key));
+                bytes = keys.iter().map(String::len).sum();
+                Ok(Reply::Array(keys))
+            }
+            // keys and values taking turns
+            Request::Scan {
+                prefix,
+                filter,
+                limit,
+            } => db
+                .scan_filtered_with(&prefix, &filter, bounds)
+                .filter(|record| record.as_ref().map_or(true, |(key, _)| readable(key)))
+                .take(limit)
+                .try_fold(Vec::new(), |mut items, record| {
+                    let (key, value) = record?;
+                    bytes += key.len() + value.len();
+                    items.extend([key, value]);
+                    Ok(items)
+                })
+                .map(Reply::Array),
+            // not a database operation, nothing to count
+            Request::Clients => {
+                return Reply::Array(clients.list().iter().map(Client::describe).collect());
+            }
+            Request::Auth(_) => unreachable!("answered before the bounds are checked"),
+        };
+        count(db, clients, id, key.as_deref(), bytes, result.is_err());
+        if let (Ok(_), Some(key)) = (&result, changes) {
+            clients.invalidate(&key);
+        }
+        result.unwrap_or_else(|e| Reply::Error(e.to_string()))
+    }
+}
+
+/// have client `id` run its commands as the role the token belongs to
+fn authenticate(db: &Database, clients: &Clients, id: u64, token: &str) -> Reply {
+    if db.roles().is_empty() {
+        return Reply::Error("AUTH without roles, the database is open to everyone".to_string());
+    }
+    match acl::find(db.roles(), token) {
+        Some(role) => {
+            clients.authenticate(id, &role.name);
+            Reply::Ok
+        }
+        None => {
+            let op = OpStats {
+                errors: 1,
+                ..OpStats::default()
+            };
+            clients.count(id, op);
+            Reply::Error("wrong token".to_string())
+        }
+    }
+}
+
+/// have HTTP client `id` run its request as the role its bearer token
+/// belongs to, `Denied` without a good one when the database has roles
+pub(crate) fn authenticate_bearer(
+    db: &Database,
+    clients: &Clients,
+    id: u64,
+    token: Option<&str>,
+) -> Result<(), DeebeeError> {
+    if db.roles().is_empty() {
+        return Ok(());
+    }
+    let role = token
+        .and_then(|token| acl::find(db.roles(), token))
+        .ok_or_else(acl::unauthenticated)?;
+    clients.authenticate(id, &role.name);
+    Ok(())
+}
+
+/// a CRC32 as clients send it, 8 hex digits
+pub(crate) fn parse_checksum(checksum: &str) -> Result<u32, String> {
+    match checksum.len() == 8 {
+        true => u32::from_str_radix(checksum, 16).ok(),
+        false => None,
+    }
+    .ok_or_else(|| format!("{checksum:?} isn't a CRC32 in 8 hex digits"))
+}
+
+/// the values of the keys, read under one view of the database so none of
+/// them is newer than the others. each key counts toward its own namespace,
+/// the bytes moved are added to `bytes`
+pub(crate) fn get_many(
+    db: &mut Database,
+    keys: &[String],
+    bytes: &mut usize,
+) -> Result<Vec<Option<String>>, DeebeeError> {
+    let values = db.view()?.get_many(keys)?;
+    for (key, value) in keys.iter().zip(&values) {
+        let len = key.len() + value.as_ref().map_or(0, String::len);
+        *bytes += len;
+        let op = OpStats {
+            ops: 1,
+            bytes: len as u64,
+            errors: 0,
+        };
+        db.count_op(key, op);
+    }
+    Ok(values)
+}
+
+/// count an operation of client `id` toward it, and toward the key's
+/// namespace if it was about one key
+pub(crate) fn count(
+    db: &mut Database,
+    clients: &Clients,
+    id: u64,
+    key: Option<&str>,
+    bytes: usize,
+    failed: bool,
+) {
+    let op = OpStats {
+        ops: 1,
+        bytes: bytes as u64,
+        errors: failed as u64,
+    };
+    clients.count(id, op);
+    if let Some(key) = key {
+        db.count_op(key, op);
+    }
+}
+
+impl Outcome for Reply {
+    fn succeeded(&self) -> bool {
+        !matches!(self, Reply::Error(_))
+    }
+}
+
+/// run the request under the client's idempotency key, if it sent one
+pub(crate) fn run_once<T: Outcome>(
+    tokens: &Tokens<T>,
+    token: Option<(String, Vec<u8>)>,
+    refused: impl FnOnce(String) -> T,
+    f: impl FnOnce() -> T,
+) -> T {
+    match token {
+        Some((token, request)) => tokens
+            .lock()
+            .expect("nothing panics holding the tokens")
+            .run(&token, &request, f)
+            .unwrap_or_else(refused),
+        None => f(),
+    }
+}
+
+impl Reply {
+    /// `OK`, `NIL`, `VALUE <value>`, `CHECKED <crc> <value>`, `INTEGER <n>`
+    /// or `ERR <message>`, one line each. arrays are an `ARRAY <n>` line
+    /// followed by a `VALUE` line per item, or `NIL` for a missing value
+    pub(crate) fn to_line(&self) -> String {
+        match self {
+            Reply::Ok => "OK".to_string(),
+            Reply::Status(status) => status.clone(),
+            Reply::Nil => "NIL".to_string(),
+            Reply::Integer(n) => format!("INTEGER {n}"),
+            Reply::Array(items) => {
+                let mut lines = format!("ARRAY {}", items.len());
+                for item in items {
+                    lines.push('\n');
+                    lines.push_str(&Reply::Value(item.clone()).to_line());
+                }
+                lines
+            }
+            Reply::Values(values) => {
+                let mut lines = format!("ARRAY {}", values.len());
+                for value in values {
+                    lines.push('\n');
+                    lines.push_str(&match value {
+                        Some(value) => Reply::Value(value.clone()).to_line(),
+                        None => Reply::Nil.to_line(),
+                    });
+                }
+                lines
+            }
+            // set through another interface, lines can't carry it
+            Reply::Value(value) if value.contains(['\n', '\r']) => {
+                "ERR the value has a line break in it".to_string()
+            }
+            Reply::Value(value) => format!("VALUE {value}"),
+            Reply::Checked(value, _) if value.contains(['\n', '\r']) => {
+                "ERR the value has a line break in it".to_string()
+            }
+            Reply::Checked(value, checksum) => format!("CHECKED {checksum:08x} {value}"),
+            Reply::Error(message) => format!("ERR {message}"),
+        }
+    }
+}
+
+/// work waiting for the database thread, it hands back how to answer its
+/// client
+pub(crate) type Job = Box<dyn FnOnce(&mut Database) -> Answer + Send>;
+
+/// sends a job's result back, on the database thread. `false` when the
+/// fsync its writes waited for failed, the client never hears back then
+pub(crate) type Answer = Box<dyn FnOnce(bool) + Send>;
+
+/// keeps a database open and serves its commands over TCP. every client
+/// gets a thread reading its commands, the commands themselves run one at a
+/// time on the thread calling `serve`, which owns the database
+pub struct Server {
+    addr: SocketAddr,
+    jobs: Receiver<Job>,
+    clients: Clients,
+}
+
+impl Server {
+    /// start accepting clients, their commands wait for `serve`
+    pub fn bind(addr: impl ToSocketAddrs, protocol: Protocol) -> Result<Self, DeebeeError> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let (job_tx, jobs) = mpsc::channel();
+        let clients = Clients::default();
+        let (replies, responses) = (idempotency::tokens(), idempotency::tokens());
+
+        let accepted = clients.clone();
+        thread::spawn(move || {
+            let clients = accepted;
+            for (id, stream) in (0..).zip(listener.incoming()) {
+                match stream {
+                    Ok(stream) => {
+                        let Ok(peer) = stream.peer_addr() else {
+                            continue;
+                        };
+                        let job_tx = job_tx.clone();
+                        let conn = clients.connect(id, peer, protocol);
+                        let (replies, responses) = (replies.clone(), responses.clone());
+                        thread::spawn(move || match protocol {
+                            Protocol::Line => serve_line_client(stream, job_tx, conn, replies),
+                            Protocol::Resp => serve_resp_client(stream, job_tx, conn, replies),
+                            Protocol::Http => rest::serve_client(stream, job_tx, conn, responses),
+                        });
+                    }
+                    Err(e) => eprintln!("couldn't accept a client: {e}"),
+                }
+            }
+        });
+
+        Ok(Self {
+            addr,
+            jobs,
+            clients,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// how long a command may take from the moment the server read it,
+    /// waiting for the commands ahead of it included. one that runs out
+    /// before it starts is answered with an error and changes nothing, a
+    /// scan stops where it ran out. `None`, the default, never gives up
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        self.clients.lock().timeout = timeout;
+    }
+
+    /// under `sync = "always"`, collect the writes clients send for this
+    /// long after the first one and fsync them together, instead of one
+    /// fsync each. their replies wait for that fsync, so a write takes up to
+    /// the window longer. reads are answered right away, a read in the
+    /// window can see a write that isn't acknowledged yet. a client whose
+    /// write the fsync failed for is hung up on without a reply. `None`, the
+    /// default, and the other sync policies answer each command once it ran
+    pub fn set_commit_window(&self, window: Option<Duration>) {
+        self.clients.lock().commit_window = window;
+    }
+
+    /// run commands as clients send them, never returns unless accepting fails
+    pub fn serve(&self, db: &mut Database) -> Result<(), DeebeeError> {
+        // a server is usually stopped by a signal, its stats are saved as it goes
+        let mut saved = Instant::now();
+        loop {
+            self.serve_one(db)?;
+            if saved.elapsed() >= STATS_SAVE_INTERVAL {
+                if let Err(e) = db.save_stats() {
+                    eprintln!("couldn't save stats for {}: {e}", db.db_name());
+                }
+                saved = Instant::now();
+            }
+        }
+    }
+
+    /// wait for the next command from any client and run it. with a commit
+    /// window, run the ones that come in during it too
+    pub fn serve_one(&self, db: &mut Database) -> Result<(), DeebeeError> {
+        let job = self.jobs.recv().map_err(|_| {
+            DeebeeError::Io(std::io::Error::other(
+                "the server stopped accepting clients",
+            ))
+        })?;
+        let window = self.clients.lock().commit_window;
+        match window {
+            Some(window) if db.defer_fsyncs() => self.serve_window(db, job, window),
+            _ => job(db)(true),
+        }
+        Ok(())
+    }
+
+    /// run the job and the ones after it until the window is over, one
+    /// fsync covers all their writes. the answers of the jobs that wrote
+    /// wait for it, in the order the jobs ran
+    fn serve_window(&self, db: &mut Database, first: Job, window: Duration) {
+        let closes = Instant::now() + window;
+        let mut waiting: Vec<Answer> = Vec::new();
+        let mut job = Some(first);
+        while let Some(next) = job.take() {
+            let written = db.batches_written();
+            let answer = next(db);
+            if db.batches_written() == written {
+                answer(true);
+            } else {
+                waiting.push(answer);
+            }
+            let left = closes.saturating_duration_since(Instant::now());
+            job = self.jobs.recv_timeout(left).ok();
+        }
+        let committed = db.commit_deferred();
+        if let Err(e) = &committed {
+            eprintln!("couldn't fsync the commit window of {}: {e}", db.db_name());
+        }
+        for answer in waiting {
+            answer(committed.is_ok());
+        }
+    }
+}
+
+/// have the database thread run `f`, `None` once the server is gone or
+/// the fsync `f`'s writes waited for failed
+pub(crate) fn run<T: Send + 'static>(
+    jobs: &Sender<Job>,
+    f: impl FnOnce(&mut Database) -> T + Send + 'static,
+) -> Option<T> {
+    run_then(jobs, f, |result| result)
+}
+
+/// `run`, handing the result to `answer` on the database thread once
+/// `f`'s writes are committed. what `answer` sends goes out in the order
+/// the database thread committed it
+pub(crate) fn run_then<T: Send + 'static, U: Send + 'static>(
+    jobs: &Sender<Job>,
+    f: impl FnOnce(&mut Database) -> T + Send + 'static,
+    answer: impl FnOnce(T) -> U + Send + 'static,
+) -> Option<U> {
+    let (result_tx, result_rx) = mpsc::channel();
+    jobs.send(Box::new(move |db| {
+        let result = f(db);
+        Box::new(move |committed| {
+            // the client may have hung up in the meantime, nobody to tell then
+            if committed {
+                let _ = result_tx.send(answer(result));
+            }
+        })
+    }))
+    .ok()?;
+    result_rx.recv().ok()
+}
+
+/// read the client's lines until it hangs up or sends QUIT, answering each
+/// `TRACKING ON` has the server send `INVALIDATE <key>` once a key the
+/// client read since changes, until `TRACKING OFF`. `IDEM <token> <command>`
+/// runs the command once however often it's retried
+fn serve_line_client(
+    stream: TcpStream,
+    jobs: Sender<Job>,
+    conn: Connection,
+    tokens: Tokens<Reply>,
+) {
+    let Ok(read_half) = stream.try_clone() else {
+        return;
+    };
+    // replies and invalidations go out in the order the database thread
+    // came up with them, a reply never overtakes the invalidation after it
+    let (out, lines) = mpsc::channel::<String>();
+    let writer = thread::spawn(move || {
+        let mut stream = BufWriter::new(stream);
+        for line in lines {
+            if writeln!(stream, "{line}")
+                .and_then(|()| stream.flush())
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+
+    for line in BufReader::new(read_half).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        if line.eq_ignore_ascii_case("QUIT") {
+            break;
+        }
+        conn.command();
+
+        let reply = if line.eq_ignore_ascii_case("TRACKING ON") {
+            conn.set_tracking(Some(out.clone()));
+            Reply::Ok
+        } else if line.eq_ignore_ascii_case("TRACKING OFF") {
+            conn.set_tracking(None);
+            Reply::Ok
+        } else {
+            let (token, line) = match line.split_once(' ') {
+                Some((idem, rest)) if idem.eq_ignore_ascii_case("IDEM") => {
+                    match rest.split_once(' ') {
+                        Some((token, line)) => {
+                            (Some((token.to_string(), line.as_bytes().to_vec())), line)
+                        }
+                        None => (None, ""),
+                    }
+                }
+                _ => (None, line),
+            };
+            match Request::parse_line(line) {
+                Ok(request) => {
+                    let (clients, id, out) = (conn.clients().clone(), conn.id, out.clone());
+                    let (tokens, bounds) = (tokens.clone(), conn.bounds());
+                    let execute = move |db: &mut Database| {
+                        let read = match &request {
+                            Request::Get(key) | Request::GetChecked(key) => vec![key.clone()],
+                            Request::MGet(keys) => keys.clone(),
+                            _ => Vec::new(),
+                        };
+                        let token = clients.scope(id, token);
+                        let reply = run_once(&tokens, token, Reply::Error, || {
+                            request.execute(db, &clients, id, &bounds)
+                        });
+                        // a key the role may not read is none of its business
+                        if reply.succeeded() {
+                            read.iter().for_each(|key| clients.track(id, key));
+                        }
+                        reply
+                    };
+                    let sent = run_then(&jobs, execute, move |reply| {
+                        out.send(reply.to_line()).is_ok()
+                    });
+                    match sent {
+                        Some(true) => continue,
+                        _ => break,
+                    }
+                }
+                Err(_) if line.is_empty() => {
+                    conn.refused();
+                    Reply::Error("IDEM takes a token and a command".to_string())
+                }
+                Err(message) => {
+                    conn.refused();
+                    Reply::Error(message)
+                }
+            }
+        };
+        if out.send(reply.to_line()).is_err() {
+            break;
+        }
+    }
+
+    // the tracking entry holds on to the queue too
+    drop(conn);
+    drop(out);
+    let _ = writer.join();
+}
+
+/// answer the client's RESP commands until it hangs up or sends QUIT.
+/// `IDEM <token> <command> <args>...` runs the command once however often
+/// it's retried
+fn serve_resp_client(
+    stream: TcpStream,
+    jobs: Sender<Job>,
+    conn: Connection,
+    tokens: Tokens<Reply>,
+) {
+    let Ok(read_half) = stream.try_clone() else {
+        return;
+    };
+    let mut reader = BufReader::new(read_half);
+    let mut out = BufWriter::new(stream);
+    loop {
+        let args = match resp::read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return,
+            Err(e) => {
+                // the stream is out of step now, tell the client and hang up
+                let _ = resp::write_reply(&mut out, &Reply::Error(format!("protocol error: {e}")));
+                let _ = out.flush();
+                return;
+            }
+        };
+        conn.command();
+
+        let (token, command) = match resp::split_token(args) {
+            Ok((token, args)) => (token, resp::parse_command(args)),
+            Err(reply) => (None, Command::Immediate(reply)),
+        };
+        let reply = match command {
+            Command::Immediate(reply) => {
+                if matches!(reply, Reply::Error(_)) {
+                    conn.refused();
+                }
+                reply
+            }
+            Command::Quit => {
+                let _ = resp::write_reply(&mut out, &Reply::Ok);
+                let _ = out.flush();
+                return;
+            }
+            command => {
+                let (clients, id, tokens) = (conn.clients().clone(), conn.id, tokens.clone());
+                let bounds = conn.bounds();
+                let job = move |db: &mut Database| {
+                    let token = clients.scope(id, token);
+                    run_once(&tokens, token, Reply::Error, || {
+                        command.execute(db, &clients, id, &bounds)
+                    })
+                };
+                match run(&jobs, job) {
+                    Some(reply) => reply,
+                    None => return,
+                }
+            }
+        };
+        if resp::write_reply(&mut out, &reply)
+            .and_then(|()| out.flush())
+            .is_err()
+        {
+            return;
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::config::DatabaseOptions;
+use crate::database::Database;
+use crate::error::DeebeeError;
+
+/// a database handle for many threads at once. gets run side by side, writes
+/// take turns and wait for the gets in flight. every get opens its own reader
+/// on the segment, so threads share no file position. with the value cache on,
+/// or segments mapped with the `mmap` feature, gets take turns on a mutex for
+/// the lookup and the insert, never for reading a segment. clones are handles
+/// on the same database
+#[derive(Clone)]
+pub struct SharedDatabase {
+    db: Arc<RwLock<Database>>,
+}
+
+impl SharedDatabase {
+    pub fn new(db: Database) -> Self {
+        Self {
+            db: Arc::new(RwLock::new(db)),
+        }
+    }
+
+    pub fn open(db_name: &str, options: &DatabaseOptions) -> Result<Self, DeebeeError> {
+        Ok(Self::new(Database::open(db_name, options)?))
+    }
+
+    /// burn-after-read keys are read under the write lock, so of two gets
+    /// racing for one only the first sees the value
+    pub fn get(&self, key: &str) -> Result<Option<String>, DeebeeError> {
+        {
+            let db = self.read();
+            if !db.burns_after_read(key) {
+                return db.get(key);
+            }
+        }
+        self.write().get_and_burn(key)
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<(), DeebeeError> {
+        self.write().set(key, value)
+    }
+
+    pub fn delete(&self, key: &str) -> Result<bool, DeebeeError> {
+        self.write().delete(key)
+    }
+
+    /// the rest of the read API, other gets carry on while the guard is held
+    pub fn read(&self) -> RwLockReadGuard<'_, Database> {
+        self.db
+            .read()
+            .expect("a thread panicked in the middle of a write")
+    }
+
+    /// the rest of the write API, everything else waits while the guard is held
+    pub fn write(&self) -> RwLockWriteGuard<'_, Database> {
+        self.db
+            .write()
+            .expect("a thread panicked in the middle of a write")
+    }
+}
@@ -0,0 +1,215 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::background::TaskStatus;
+use crate::error::DeebeeError;
+
+/// cumulative counters, persisted next to the segments so they survive restarts
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct Stats {
+    #[serde(default)]
+    pub opens: u64,
+    #[serde(default)]
+    pub total_writes: u64,
+    #[serde(default)]
+    pub bytes_written: u64,
+    #[serde(default)]
+    pub total_reads: u64,
+    #[serde(default)]
+    pub uptime_ms: u64,
+    #[serde(default)]
+    pub compactions: u64,
+    /// segment bytes freed by compaction
+    #[serde(default)]
+    pub bytes_reclaimed: u64,
+    /// gets answered from the value cache, and the ones it had to pass to disk
+    #[serde(default)]
+    pub cache_hits: u64,
+    #[serde(default)]
+    pub cache_misses: u64,
+    /// the last few compactions, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recent_compactions: Vec<CompactionReport>,
+    /// what the most recent index rebuild on open did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_recovery: Option<RecoveryReport>,
+    /// the handle's background tasks, never saved
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub background_tasks: Vec<TaskStatus>,
+    /// what server clients did to the keys of each namespace, see `namespace`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub namespaces: BTreeMap<String, OpStats>,
+}
+
+/// operations a client or the keys of a namespace went through, the key
+/// and value bytes they moved and how many of them failed
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq)]
+pub struct OpStats {
+    pub ops: u64,
+    pub bytes: u64,
+    pub errors: u64,
+}
+
+impl OpStats {
+    pub(crate) fn add(&mut self, other: OpStats) {
+        self.ops += other.ops;
+        self.bytes += other.bytes;
+        self.errors += other.errors;
+    }
+}
+
+/// the part of the key before its first `:`, `user` for `user:42`. keys
+/// without one are in the namespace `""`
+pub fn namespace(key: &str) -> &str {
+    key.split_once(':').map_or("", |(namespace, _)| namespace)
+}
+
+/// what an index rebuild on open went through
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecoveryReport {
+    pub segments: usize,
+    pub records: usize,
+    pub bytes: u64,
+    pub duration_ms: u64,
+    /// unix timestamp, seconds
+    pub finished_at: u64,
+    /// torn write cut off the end of the active segment before indexing
+    #[serde(default)]
+    pub truncated_bytes: u64,
+}
+
+/// how a snapshot's segments came back
+#[derive(Clone, Debug, Default)]
+pub struct RestoreReport {
+    /// sealed segments hard-linked to the snapshot's copies, no data copied
+    pub linked: usize,
+    /// the active segment, and any segment on another filesystem
+    pub copied: usize,
+}
+
+/// what one compaction did, and why it ran
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct CompactionReport {
+    /// sealed segments merged into one
+    pub segments: usize,
+    pub records_kept: usize,
+    /// what the compaction filter dropped and rewrote
+    #[serde(default)]
+    pub records_dropped: usize,
+    #[serde(default)]
+    pub records_rewritten: usize,
+    /// records of the new segment referring to a value stored once in the
+    /// blob area
+    #[serde(default)]
+    pub shared_values: usize,
+    /// read from the merged segments
+    pub bytes_before: u64,
+    /// written to the new one, the values it shares with other keys counted
+    /// once
+    pub bytes_after: u64,
+    pub duration_ms: u64,
+    /// `manual`, or the policy threshold that was crossed
+    #[serde(default)]
+    pub trigger: String,
+    #[serde(default)]
+    pub merged_segments: Vec<String>,
+    /// unix timestamp, seconds
+    #[serde(default)]
+    pub finished_at: u64,
+}
+
+/// one of the database's segment files
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SegmentInfo {
+    /// the file name in the database directory
+    pub name: String,
+    pub bytes: u64,
+    /// the one new records are appended to
+    pub active: bool,
+}
+
+/// compactions kept in `recent_compactions`
+pub(crate) const RECENT_COMPACTIONS: usize = 10;
+
+// rebuilds smaller than this finish fast enough that progress would just be noise
+const RECOVERY_PROGRESS_MIN_BYTES: u64 = 8 * 1024 * 1024;
+
+/// prints how far an index rebuild got, so a big one doesn't look like a hang
+pub(crate) struct RecoveryProgress {
+    segment: String,
+    total: u64,
+    last_percent: u64,
+}
+
+impl RecoveryProgress {
+    pub(crate) fn new(segment: &str, total: u64) -> Self {
+        Self {
+            segment: segment.to_string(),
+            total,
+            last_percent: 0,
+        }
+    }
+
+    pub(crate) fn advance(&mut self, done: u64) {
+        if self.total < RECOVERY_PROGRESS_MIN_BYTES {
+            return;
+        }
+        let percent = done * 100 / self.total;
+        if percent >= self.last_percent + 10 {
+            eprintln!("recovering {}: {percent}%", self.segment);
+            self.last_percent = percent;
+        }
+    }
+}
+
+impl Stats {
+    /// the sidecar in the database's directory
+    pub(crate) fn path(dir: &Path) -> PathBuf {
+        dir.join("stats")
+    }
+
+    /// read the stats sidecar, a missing or unreadable file starts from zero
+    pub(crate) fn load(dir: &Path) -> Self {
+        fs::read_to_string(Self::path(dir))
+            .ok()
+            .and_then(|content| toml::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn save(&self, dir: &Path) -> Result<(), DeebeeError> {
+        fs::write(Self::path(dir), toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub(crate) fn combined(&self, other: &Stats) -> Stats {
+        Stats {
+            opens: self.opens + other.opens,
+            total_writes: self.total_writes + other.total_writes,
+            bytes_written: self.bytes_written + other.bytes_written,
+            total_reads: self.total_reads + other.total_reads,
+            uptime_ms: self.uptime_ms + other.uptime_ms,
+            compactions: self.compactions + other.compactions,
+            bytes_reclaimed: self.bytes_reclaimed + other.bytes_reclaimed,
+            cache_hits: self.cache_hits + other.cache_hits,
+            cache_misses: self.cache_misses + other.cache_misses,
+            recent_compactions: {
+                let all = [&self.recent_compactions[..], &other.recent_compactions[..]].concat();
+                all[all.len().saturating_sub(RECENT_COMPACTIONS)..].to_vec()
+            },
+            last_recovery: other
+                .last_recovery
+                .clone()
+                .or_else(|| self.last_recovery.clone()),
+            background_tasks: other.background_tasks.clone(),
+            namespaces: {
+                let mut all = self.namespaces.clone();
+                for (namespace, op) in &other.namespaces {
+                    all.entry(namespace.clone()).or_default().add(*op);
+                }
+                all
+            },
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! the `/status` page of the HTTP frontend: the stats, segments, background
+//! work and clients of a running server on one page that refreshes itself
+
+use crate::database::Database;
+use crate::error::DeebeeError;
+use crate::server::Client;
+use std::fmt::Write;
+
+// seconds between reloads of the page
+const REFRESH_SECS: u32 = 5;
+
+/// the page as of `now`, in seconds since the unix epoch on the database's
+/// clock, the one its compactions are stamped with
+pub(crate) fn render(db: &Database, clients: &[Client], now: u64) -> Result<String, DeebeeError> {
+    let stats = db.stats(false);
+    let segments = db.segments()?;
+
+    let mut page = String::new();
+    let name = escape(db.db_name());
+    // writing to a String can't fail
+    let _ = write!(
+        page,
+        "<!doctype html>\n<html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"{REFRESH_SECS}\">\
+         <title>deebee {name}</title>\
+         <style>body{{font-family:sans-serif}}table{{border-collapse:collapse;margin-bottom:1em}}\
+         td,th{{border:1px solid #ccc;padding:2px 8px;text-align:left}}</style>\
+         </head><body>\n<h1>{name}</h1>\n"
+    );
+
+    page.push_str("<h2>stats</h2>\n");
+    table(
+        &mut page,
+        &["", "since created"],
+        [
+            ["opens".to_string(), stats.opens.to_string()],
+            ["writes".to_string(), stats.total_writes.to_string()],
+            ["bytes written".to_string(), stats.bytes_written.to_string()],
+            ["reads".to_string(), stats.total_reads.to_string()],
+            ["cache hits".to_string(), stats.cache_hits.to_string()],
+            ["cache misses".to_string(), stats.cache_misses.to_string()],
+            ["compactions".to_string(), stats.compactions.to_string()],
+            [
+                "bytes reclaimed".to_string(),
+                stats.bytes_reclaimed.to_string(),
+            ],
+            [
+                "uptime".to_string(),
+                format!("{:.1}s", stats.uptime_ms as f64 / 1000.0),
+            ],
+        ],
+    );
+
+    let total: u64 = segments.iter().map(|segment| segment.bytes).sum();
+    let _ = writeln!(page, "<h2>segments ({} bytes)</h2>", total);
+    table(
+        &mut page,
+        &["segment", "bytes", ""],
+        segments.iter().map(|segment| {
+            [
+                segment.name.clone(),
+                segment.bytes.to_string(),
+                if segment.active { "active" } else { "sealed" }.to_string(),
+            ]
+        }),
+    );
+
+    page.push_str("<h2>background tasks</h2>\n");
+    table(
+        &mut page,
+        &["task", "state", "runs", "failures", "last error"],
+        stats.background_tasks.iter().map(|task| {
+            let state = if !task.enabled {
+                "disabled"
+            } else if task.running {
+                "running"
+            } else {
+                "idle"
+            };
+            [
+                task.name.clone(),
+                state.to_string(),
+                task.runs.to_string(),
+                task.failures.to_string(),
+                task.last_error.clone().unwrap_or_default(),
+            ]
+        }),
+    );
+
+    page.push_str("<h2>recent compactions</h2>\n");
+    table(
+        &mut page,
+        &[
+            "finished",
+            "trigger",
+            "segments",
+            "records kept",
+            "reclaimed",
+            "took",
+        ],
+        stats.recent_compactions.iter().rev().map(|report| {
+            [
+                format!("{}s ago", now.saturating_sub(report.finished_at)),
+                report.trigger.clone(),
+                report.segments.to_string(),
+                report.records_kept.to_string(),
+                report
+                    .bytes_before
+                    .saturating_sub(report.bytes_after)
+                    .to_string(),
+                format!("{}ms", report.duration_ms),
+            ]
+        }),
+    );
+
+    page.push_str("<h2>namespaces</h2>\n");
+    table(
+        &mut page,
+        &["namespace", "ops", "bytes", "errors"],
+        stats.namespaces.iter().map(|(namespace, op)| {
+            [
+                namespace.clone(),
+                op.ops.to_string(),
+                op.bytes.to_string(),
+                op.errors.to_string(),
+            ]
+        }),
+    );
+
+    let _ = writeln!(page, "<h2>clients ({})</h2>", clients.len());
+    table(
+        &mut page,
+        &[
+            "address",
+            "protocol",
+            "connected",
+            "commands",
+            "ops",
+            "bytes",
+            "errors",
+        ],
+        clients.iter().map(|client| {
+            [
+                client.addr.to_string(),
+                client.protocol.to_string(),
+                format!("{}s", client.connected_at.elapsed().as_secs()),
+                client.commands.to_string(),
+                client.usage.ops.to_string(),
+                client.usage.bytes.to_string(),
+                client.usage.errors.to_string(),
+            ]
+        }),
+    );
+
+    page.push_str("</body></html>\n");
+    Ok(page)
+}
+
+/// a table with a header row, "none" when there are no rows
+fn table<const N: usize>(
+    page: &mut String,
+    header: &[&str; N],
+    rows: impl IntoIterator<Item = [String; N]>,
+) {
+    let mut rows = rows.into_iter().peekable();
+    if rows.peek().is_none() {
+        page.push_str("<p>none</p>\n");
+        return;
+    }
+    page.push_str("<table><tr>");
+    for cell in header {
+        let _ = write!(page, "<th>{}</th>", escape(cell));
+    }
+    page.push_str("</tr>\n");
+    for row in rows {
+        page.push_str("<tr>");
+        for cell in &row {
+            let _ = write!(page, "<td>{}</td>", escape(cell));
+        }
+        page.push_str("</tr>\n");
+    }
+    page.push_str("</table>\n");
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
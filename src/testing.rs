@@ -0,0 +1,178 @@
+//! throwaway databases for tests, in their own temp directories.
+//!
+//! every `TempDatabase` is opened with a fresh directory as its root, so it
+//! gets a deebee.toml of its own and the current directory is never touched:
+//! tests using them run side by side and relative paths keep meaning what
+//! they meant.
+//!
+//! ```no_run
+//! use deebee::testing::TempDatabase;
+//!
+//! let mut db = TempDatabase::builder().record("greeting", "hello").open()?;
+//! assert_eq!(db.get("greeting")?.as_deref(), Some("hello"));
+//! db.reopen()?;
+//! assert!(db.contains_key("greeting"));
+//! # Ok::<(), deebee::DeebeeError>(())
+//! ```
+
+use std::fs;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::config::{DatabaseOptions, SyncPolicy};
+use crate::database::Database;
+use crate::error::DeebeeError;
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// a fresh temp directory, removed when this is dropped. hand its path to
+/// `DatabaseOptions::root` to keep databases in it
+pub struct ScratchDir {
+    dir: PathBuf,
+}
+
+impl ScratchDir {
+    pub fn new(name: &str) -> Result<Self, DeebeeError> {
+        let dir = std::env::temp_dir().join(format!(
+            "deebee-{name}-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// a database in its own scratch directory, removed with it on drop. derefs
+/// to the `Database`
+pub struct TempDatabase {
+    db: Option<Database>,
+    name: String,
+    options: DatabaseOptions,
+    // declared last so the database is closed before its directory goes
+    scratch: ScratchDir,
+}
+
+impl TempDatabase {
+    /// an empty database with the builder's defaults
+    pub fn new() -> Result<Self, DeebeeError> {
+        Self::builder().open()
+    }
+
+    pub fn builder() -> TempDatabaseBuilder {
+        TempDatabaseBuilder::default()
+    }
+
+    /// the scratch directory holding deebee.toml, the database's root. its
+    /// own directory, with the segments and MANIFEST, is `Database::dir` in it
+    pub fn dir(&self) -> &Path {
+        self.scratch.path()
+    }
+
+    /// close the database and open it again, so everything is read back
+    /// from disk
+    pub fn reopen(&mut self) -> Result<(), DeebeeError> {
+        drop(self.db.take());
+        self.db = Some(Database::open(&self.name, &self.options)?);
+        Ok(())
+    }
+}
+
+impl Deref for TempDatabase {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        self.db
+            .as_ref()
+            .expect("only a failed reopen leaves it closed")
+    }
+}
+
+impl DerefMut for TempDatabase {
+    fn deref_mut(&mut self) -> &mut Database {
+        self.db
+            .as_mut()
+            .expect("only a failed reopen leaves it closed")
+    }
+}
+
+/// what goes into a `TempDatabase`. writes aren't fsynced unless the options
+/// say otherwise, nothing outlives the test anyway
+pub struct TempDatabaseBuilder {
+    name: String,
+    options: DatabaseOptions,
+    records: Vec<(String, String)>,
+}
+
+impl Default for TempDatabaseBuilder {
+    fn default() -> Self {
+        Self {
+            name: "test".to_string(),
+            options: DatabaseOptions::new().sync(SyncPolicy::Never),
+            records: Vec::new(),
+        }
+    }
+}
+
+impl TempDatabaseBuilder {
+    /// name of the database, also used for the scratch directory
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// how the database is opened, the root is always the scratch directory
+    pub fn options(mut self, options: DatabaseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// set this key before handing the database over
+    pub fn record(mut self, key: &str, value: &str) -> Self {
+        self.records.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn records<K: Into<String>, V: Into<String>>(
+        mut self,
+        records: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.records
+            .extend(records.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    pub fn open(self) -> Result<TempDatabase, DeebeeError> {
+        let scratch = ScratchDir::new(&self.name)?;
+        let options = self.options.root(scratch.path());
+        // the fixtures go in through a writable handle even for read-only tests
+        let writable = options.clone().read_only(false);
+        let mut db = Database::open(&self.name, &writable)?;
+        for (key, value) in &self.records {
+            db.set(key, value)?;
+        }
+
+        let mut temp = TempDatabase {
+            db: Some(db),
+            name: self.name,
+            options,
+            scratch,
+        };
+        if temp.options.read_only {
+            temp.reopen()?;
+        }
+        Ok(temp)
+    }
+}
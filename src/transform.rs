@@ -0,0 +1,89 @@
+use crate::database::ExportRecord;
+use crate::error::DeebeeError;
+
+/// a reshaping step applied to every record going through `import` or
+/// `export`, written on the command line as `trim`, `lowercase-keys`,
+/// `add-prefix=P`, `strip-prefix=P` or `select=field,field`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transform {
+    /// whitespace around keys and values
+    Trim,
+    LowercaseKeys,
+    AddPrefix(String),
+    /// keys without the prefix are left alone
+    StripPrefix(String),
+    /// keep only these fields of JSON object values
+    Select(Vec<String>),
+}
+
+impl Transform {
+    pub fn apply(&self, mut record: ExportRecord) -> Result<ExportRecord, DeebeeError> {
+        match self {
+            Transform::Trim => {
+                record.key = record.key.trim().to_string();
+                record.value = record.value.trim().to_string();
+            }
+            Transform::LowercaseKeys => record.key = record.key.to_lowercase(),
+            Transform::AddPrefix(prefix) => record.key.insert_str(0, prefix),
+            Transform::StripPrefix(prefix) => {
+                if let Some(key) = record.key.strip_prefix(prefix.as_str()) {
+                    record.key = key.to_string();
+                }
+            }
+            Transform::Select(fields) => {
+                let value: serde_json::Value = serde_json::from_str(&record.value)
+                    .ok()
+                    .filter(serde_json::Value::is_object)
+                    .ok_or_else(|| {
+                        DeebeeError::InvalidValue(format!(
+                            "value of {} is not a JSON object, there are no fields to select",
+                            record.key
+                        ))
+                    })?;
+                let selected: serde_json::Map<String, serde_json::Value> = fields
+                    .iter()
+                    .filter_map(|field| Some((field.clone(), value.get(field)?.clone())))
+                    .collect();
+                record.value = serde_json::Value::Object(selected).to_string();
+            }
+        }
+        Ok(record)
+    }
+
+    /// run the transforms over the record in order
+    pub fn apply_all(
+        transforms: &[Transform],
+        record: ExportRecord,
+    ) -> Result<ExportRecord, DeebeeError> {
+        transforms
+            .iter()
+            .try_fold(record, |record, transform| transform.apply(record))
+    }
+}
+
+impl std::str::FromStr for Transform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = match s.split_once('=') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        match (name, arg) {
+            ("trim", None) => Ok(Transform::Trim),
+            ("lowercase-keys", None) => Ok(Transform::LowercaseKeys),
+            ("add-prefix", Some(prefix)) => Ok(Transform::AddPrefix(prefix.to_string())),
+            ("strip-prefix", Some(prefix)) => Ok(Transform::StripPrefix(prefix.to_string())),
+            ("select", Some(fields)) if !fields.is_empty() => Ok(Transform::Select(
+                fields.split(',').map(str::to_string).collect(),
+            )),
+            ("trim" | "lowercase-keys", Some(_)) => Err(format!("{name} takes no argument")),
+            ("add-prefix" | "strip-prefix" | "select", _) => {
+                Err(format!("{name} needs an argument, as in {name}=..."))
+            }
+            _ => Err(format!(
+                "unknown transform {name:?}, expected trim, lowercase-keys, add-prefix=, strip-prefix= or select="
+            )),
+        }
+    }
+}
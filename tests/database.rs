@@ -0,0 +1,2799 @@
+use deebee::testing::{ScratchDir, TempDatabase};
+use deebee::{
+    CachedClient, CancelToken, Collation, CompactionFilter, Database, DatabaseManager,
+    DatabaseOptions, Dedup, DeebeeError, Durable, ExportRecord, ExportServer, FORMAT_VERSION,
+    FilterDecision, GetOptions, ImportOptions, KeyCodec, KeyError, KeyFilter, MaintenanceWindow,
+    ManualClock, MergeOperator, MetricsSink, OnConflict, OpStats, PatchOp, Protocol,
+    RecordEncoding, RecordFlags, SegmentDescription, Server, SharedDatabase, SyncPolicy, Transform,
+    Tuning, VerifyLevel, WriteError, WriteOptions, segment_records,
+};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+// these tests cover the default root, the current directory, so each one
+// switches into its own scratch directory, taking turns with the others.
+// TempDatabase tests get a root of their own and run alongside
+static CWD: Mutex<()> = Mutex::new(());
+
+fn in_scratch_dir(name: &str, f: impl FnOnce()) {
+    // a test that panicked still gave its turn back
+    let _turn = CWD.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = ScratchDir::new(name).unwrap();
+    let previous = std::env::current_dir().unwrap();
+    std::env::set_current_dir(dir.path()).unwrap();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    std::env::set_current_dir(previous).unwrap();
+    if let Err(panic) = result {
+        std::panic::resume_unwind(panic);
+    }
+}
+
+// enough of an executor to drive one future to completion
+struct Unpark(std::thread::Thread);
+impl std::task::Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::task::{Context, Poll, Waker};
+    let mut future = std::pin::pin!(future);
+    let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+// pretend the database `db` was created by an older build
+fn pin_format_version(version: u32) {
+    let manifest = fs::read_to_string("db/MANIFEST").unwrap();
+    fs::write(
+        "db/MANIFEST",
+        manifest.replace(
+            &format!("format_version = {FORMAT_VERSION}"),
+            &format!("format_version = {version}"),
+        ),
+    )
+    .unwrap();
+}
+
+#[test]
+fn set_then_get_in_the_same_process() {
+    in_scratch_dir("set-get", || {
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        db.set("name", "deebee").unwrap();
+
+        assert!(db.contains_key("name"));
+        assert_eq!(db.get("name").unwrap().as_deref(), Some("deebee"));
+    });
+}
+
+#[test]
+fn overwrite_then_get_returns_latest_value() {
+    in_scratch_dir("overwrite-get", || {
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        db.set("k", "first").unwrap();
+        db.set("other", "x").unwrap();
+        db.set("k", "second").unwrap();
+
+        assert_eq!(db.get("k").unwrap().as_deref(), Some("second"));
+        assert_eq!(db.get("other").unwrap().as_deref(), Some("x"));
+    });
+}
+
+#[test]
+fn delete_then_get_reports_missing() {
+    in_scratch_dir("delete-get", || {
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        db.set("k", "v").unwrap();
+
+        assert!(db.delete("k").unwrap());
+        assert!(!db.contains_key("k"));
+        assert_eq!(db.get("k").unwrap(), None);
+        assert_eq!(db.find_in_segments("k").unwrap(), None);
+        assert!(!db.delete("k").unwrap());
+    });
+}
+
+#[test]
+fn deletes_survive_reopening() {
+    in_scratch_dir("delete-reopen", || {
+        {
+            let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+            db.set("gone", "v").unwrap();
+            db.set("kept", "v").unwrap();
+            db.delete("gone").unwrap();
+        }
+
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert!(!db.contains_key("gone"));
+        assert_eq!(db.get("kept").unwrap().as_deref(), Some("v"));
+    });
+}
+
+#[test]
+fn writes_survive_reopening() {
+    in_scratch_dir("reopen", || {
+        {
+            let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+            db.set("k", "v1").unwrap();
+            db.set("k", "v2").unwrap();
+        }
+
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert_eq!(db.get("k").unwrap().as_deref(), Some("v2"));
+    });
+}
+
+#[test]
+fn appends_after_a_segment_without_trailing_newline() {
+    in_scratch_dir("legacy-tail", || {
+        drop(Database::open("db", &DatabaseOptions::new()).unwrap());
+        pin_format_version(1);
+        // older builds never terminated the last record
+        fs::write("db/000001.log", "a, 1\nb, 2").unwrap();
+
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        db.set("c", "3").unwrap();
+
+        assert_eq!(db.get("b").unwrap().as_deref(), Some("2"));
+        assert_eq!(db.get("c").unwrap().as_deref(), Some("3"));
+        assert_eq!(
+            fs::read_to_string("db/000001.log").unwrap(),
+            "a, 1\nb, 2\nc, 3\n"
+        );
+    });
+}
+
+#[test]
+fn writes_roll_over_into_new_segments() {
+    in_scratch_dir("rotation", || {
+        {
+            let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+            for i in 0..25 {
+                db.set(&format!("k{i}"), &i.to_string()).unwrap();
+            }
+            db.set("k0", "updated").unwrap();
+            db.delete("k1").unwrap();
+            assert_eq!(db.get("k0").unwrap().as_deref(), Some("updated"));
+        }
+
+        assert!(fs::exists("db/000003.log").unwrap());
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert_eq!(db.get("k0").unwrap().as_deref(), Some("updated"));
+        assert_eq!(db.get("k1").unwrap(), None);
+        assert_eq!(db.get("k24").unwrap().as_deref(), Some("24"));
+        assert_eq!(db.digest().unwrap().0, 24);
+    });
+}
+
+#[test]
+fn snapshots_are_stamped_by_the_database_clock() {
+    in_scratch_dir("clock", || {
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000));
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        db.set_clock(Box::new(clock.clone()));
+
+        assert_eq!(db.create_snapshot("first").unwrap().created_at, 1_000);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(db.create_snapshot("second").unwrap().created_at, 1_060);
+    });
+}
+
+#[test]
+fn compaction_keeps_only_live_values() {
+    in_scratch_dir("compact", || {
+        {
+            let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+            for round in 0..3 {
+                for i in 0..8 {
+                    db.set(&format!("k{i}"), &format!("{round}")).unwrap();
+                }
+            }
+            db.delete("k0").unwrap();
+
+            let report = db.compact_segments().unwrap();
+            assert_eq!(report.segments, 2);
+            assert_eq!(report.trigger, "manual");
+            assert_eq!(report.merged_segments, ["db/000001.log", "db/000002.log"]);
+            assert!(report.bytes_after < report.bytes_before);
+            assert_eq!(db.get("k0").unwrap(), None);
+            assert_eq!(db.get("k7").unwrap().as_deref(), Some("2"));
+            assert_eq!(db.stats(true).compactions, 1);
+        }
+
+        assert!(!fs::exists("db/000001.log").unwrap());
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert_eq!(db.get("k0").unwrap(), None);
+        assert_eq!(db.get("k3").unwrap().as_deref(), Some("2"));
+        assert_eq!(db.digest().unwrap().0, 7);
+    });
+}
+
+#[test]
+fn background_compaction_follows_the_policy() {
+    in_scratch_dir("auto-compact", || {
+        drop(Database::open("db", &DatabaseOptions::new()).unwrap());
+        let mut config = fs::read_to_string("deebee.toml").unwrap();
+        config.push_str("\n[databases.compaction]\nsealed_segments = 2\n");
+        fs::write("deebee.toml", config).unwrap();
+
+        {
+            let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+            for round in 0..5 {
+                for i in 0..10 {
+                    db.set(&format!("k{i}"), &format!("{round}")).unwrap();
+                }
+            }
+            db.delete("k0").unwrap();
+        }
+
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        let stats = db.stats(false);
+        assert!(stats.compactions > 0);
+        let last = stats.recent_compactions.last().unwrap();
+        assert!(
+            last.trigger.contains("the policy allows 2"),
+            "{}",
+            last.trigger
+        );
+        assert!(!last.merged_segments.is_empty());
+        assert_eq!(db.get("k0").unwrap(), None);
+        assert_eq!(db.get("k9").unwrap().as_deref(), Some("4"));
+        assert_eq!(db.digest().unwrap().0, 9);
+
+        let segments = fs::read_dir(".")
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count();
+        assert!(segments < 6, "{segments} segments left");
+    });
+}
+
+#[test]
+fn background_compaction_waits_for_a_maintenance_window() {
+    // 1970-01-01 was a Thursday, the 6th a Tuesday. monday night's window
+    // runs into it
+    let window: MaintenanceWindow = "fri-mon 22:00-02:00".parse().unwrap();
+    assert!(window.contains(5 * 86_400 + 3_600));
+    assert!(!window.contains(6 * 86_400 + 3_600));
+    assert!(!window.contains(3_600));
+    assert!("mon 25:00-26:00".parse::<MaintenanceWindow>().is_err());
+
+    in_scratch_dir("maintenance", || {
+        drop(Database::open("db", &DatabaseOptions::new()).unwrap());
+        let mut config = fs::read_to_string("deebee.toml").unwrap();
+        config.push_str("maintenance_windows = [\"thu 01:00-02:00\"]\n");
+        config.push_str("\n[databases.compaction]\nsealed_segments = 2\n");
+        fs::write("deebee.toml", config).unwrap();
+
+        {
+            let clock = ManualClock::new(UNIX_EPOCH);
+            let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+            db.set_clock(Box::new(clock.clone()));
+            let write_rounds = |db: &mut Database| {
+                for round in 0..5 {
+                    for i in 0..10 {
+                        db.set(&format!("k{i}"), &format!("{round}")).unwrap();
+                    }
+                }
+            };
+            write_rounds(&mut db);
+            assert!(!db.in_maintenance_window());
+            assert_eq!(db.stats(false).compactions, 0);
+
+            clock.advance(Duration::from_secs(3_600));
+            assert!(db.in_maintenance_window());
+            write_rounds(&mut db);
+        }
+
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert!(db.stats(false).compactions > 0);
+    });
+}
+
+#[test]
+fn defaults_apply_to_databases_that_leave_a_setting_unset() {
+    in_scratch_dir("defaults", || {
+        fs::write(
+            "deebee.toml",
+            "[defaults]\nsegment_size = 2\ncache_bytes = 64\n",
+        )
+        .unwrap();
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        for i in 0..5 {
+            db.set(&format!("k{i}"), "v").unwrap();
+        }
+        db.get("k0").unwrap();
+        db.get("k0").unwrap();
+        assert_eq!(db.stats(true).cache_hits, 1);
+        drop(db);
+
+        assert!(fs::exists("db/000003.log").unwrap());
+        let config = fs::read_to_string("deebee.toml").unwrap();
+        // the defaults stay defaults, the database doesn't get its own copy
+        assert_eq!(config.matches("segment_size").count(), 1, "{config}");
+    });
+}
+
+#[test]
+fn stale_index_falls_back_to_scanning_segments() {
+    in_scratch_dir("stale-index", || {
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        db.set("a", "1").unwrap();
+        db.set("b", "2").unwrap();
+
+        // another tool rewrote the segment, offsets in the index no longer line up
+        let encoding = RecordEncoding::for_format(FORMAT_VERSION);
+        let rewritten = [("padding", "xxxxxxxx"), ("b", "2"), ("a", "1")]
+            .into_iter()
+            .flat_map(|(key, value)| encoding.encode(key, value))
+            .collect::<Vec<u8>>();
+        fs::write("db/000001.log", rewritten).unwrap();
+
+        assert_eq!(db.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(db.get("b").unwrap().as_deref(), Some("2"));
+    });
+}
+
+#[test]
+fn commas_newlines_and_backslashes_round_trip() {
+    in_scratch_dir("escaping", || {
+        let pairs = [
+            ("a,b", "1, 2, 3"),
+            ("multi\nline", "first\r\nsecond\n"),
+            ("C:\\dir", "\\n is not a newline\\"),
+            (" padded ", "  spaces  "),
+        ];
+
+        {
+            let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+            for (key, value) in pairs {
+                db.set(key, value).unwrap();
+            }
+            for (key, value) in pairs {
+                assert_eq!(db.get(key).unwrap().as_deref(), Some(value));
+            }
+        }
+
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        for (key, value) in pairs {
+            assert_eq!(db.get(key).unwrap().as_deref(), Some(value));
+        }
+        assert_eq!(db.digest().unwrap().0, pairs.len());
+    });
+}
+
+#[test]
+fn restores_link_sealed_segments_and_leave_the_snapshot_alone() {
+    in_scratch_dir("restore-links", || {
+        drop(Database::open("db", &DatabaseOptions::new()).unwrap());
+        let config = fs::read_to_string("deebee.toml").unwrap();
+        fs::write("deebee.toml", config + "segment_size = 4\n").unwrap();
+
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        for i in 0..10 {
+            db.set(&format!("k{i}"), "old").unwrap();
+        }
+        db.create_snapshot("s").unwrap();
+
+        for _ in 0..2 {
+            let report = db.restore_snapshot("s").unwrap();
+            assert_eq!((report.linked, report.copied), (2, 1));
+            assert_eq!(db.get("k9").unwrap().as_deref(), Some("old"));
+            // appends go to the copied active segment, the linked ones are
+            // only ever replaced
+            for i in 0..10 {
+                db.set(&format!("k{i}"), "new").unwrap();
+            }
+            db.compact_segments().unwrap();
+        }
+        db.restore_snapshot("s").unwrap();
+        for i in 0..10 {
+            assert_eq!(db.get(&format!("k{i}")).unwrap().as_deref(), Some("old"));
+        }
+    });
+}
+
+#[test]
+fn restoring_a_snapshot_from_before_an_upgrade_keeps_its_records() {
+    in_scratch_dir("restore-upgrade", || {
+        drop(Database::open("db", &DatabaseOptions::new()).unwrap());
+        pin_format_version(3);
+
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        db.set("a", "1").unwrap();
+        db.set("b", "2").unwrap();
+        db.create_snapshot("s").unwrap();
+        db.upgrade_format(4).unwrap();
+        db.set("c", "3").unwrap();
+
+        db.restore_snapshot("s").unwrap();
+        assert_eq!(db.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(db.get("b").unwrap().as_deref(), Some("2"));
+        assert_eq!(db.get("c").unwrap(), None);
+        assert_eq!(db.stats(true).last_recovery.unwrap().records, 2);
+        drop(db);
+
+        // the upgraded segments went with the restore
+        let segments = fs::read_dir("db")
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count();
+        assert_eq!(segments, 1);
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert_eq!(db.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(db.stats(true).last_recovery.unwrap().truncated_bytes, 0);
+    });
+}
+
+#[test]
+fn upgrading_to_escaped_records_keeps_legacy_values() {
+    in_scratch_dir("upgrade-escaping", || {
+        drop(Database::open("db", &DatabaseOptions::new()).unwrap());
+        pin_format_version(2);
+        fs::write("db/000001.log", "path, C:\\dir\nname ,  deebee \n").unwrap();
+
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert_eq!(db.get("path").unwrap().as_deref(), Some("C:\\dir"));
+        assert!(db.set("a,b", "1").is_err());
+
+        db.upgrade_format(3).unwrap();
+        assert_eq!(db.get("path").unwrap().as_deref(), Some("C:\\dir"));
+        assert_eq!(db.get("name").unwrap().as_deref(), Some("deebee"));
+        db.set("a,b", "1").unwrap();
+        drop(db);
+
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert_eq!(db.get("path").unwrap().as_deref(), Some("C:\\dir"));
+        assert_eq!(db.get("a,b").unwrap().as_deref(), Some("1"));
+
+        db.upgrade_format(FORMAT_VERSION).unwrap();
+        assert_eq!(db.get("path").unwrap().as_deref(), Some("C:\\dir"));
+        assert_eq!(db.get("a,b").unwrap().as_deref(), Some("1"));
+    });
+}
+
+#[test]
+fn flagged_records_hold_any_bytes_and_values_that_look_reserved() {
+    in_scratch_dir("flagged", || {
+        let mut db = Database::open("db", &DatabaseOptions::new().segment_size(2)).unwrap();
+        let bytes = [0xff, 0x00, 0xfe];
+        db.set_bytes("bin", &bytes).unwrap();
+        db.set("looks-deleted", "\0tombstone").unwrap();
+        db.set("gone", "1").unwrap();
+        db.delete("gone").unwrap();
+        assert_eq!(db.get_bytes("bin").unwrap().as_deref(), Some(&bytes[..]));
+        assert!(matches!(db.get("bin"), Err(DeebeeError::InvalidValue(_))));
+        assert_eq!(
+            db.get_bytes("looks-deleted").unwrap(),
+            Some(b"\0tombstone".to_vec())
+        );
+
+        // the flags are in the records, a description round-trips them
+        let segment = fs::read("db/000001.log").unwrap();
+        let encoding = RecordEncoding::for_format(FORMAT_VERSION);
+        let description = SegmentDescription::decode(&segment, encoding);
+        assert_eq!(description.format, "binary-v2");
+        assert_eq!(description.records[0].value_hex.as_deref(), Some("ff00fe"));
+        assert_eq!(description.encode().unwrap(), segment);
+        let flags: Vec<RecordFlags> =
+            segment_records(&fs::read("db/000002.log").unwrap(), encoding)
+                .map(|(_, record)| record.flags)
+                .collect();
+        assert_eq!(flags, [RecordFlags::NONE, RecordFlags::TOMBSTONE]);
+
+        db.compact_segments().unwrap();
+        drop(db);
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert_eq!(db.get_bytes("bin").unwrap().as_deref(), Some(&bytes[..]));
+        assert_eq!(
+            db.get("looks-deleted").unwrap().as_deref(),
+            Some("\0tombstone")
+        );
+        assert_eq!(db.get("gone").unwrap(), None);
+        drop(db);
+
+        // older formats spell deletes as values, they can't hold these
+        fs::remove_dir_all("db").unwrap();
+        fs::remove_file("deebee.toml").unwrap();
+        drop(Database::open("db", &DatabaseOptions::new()).unwrap());
+        pin_format_version(4);
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert!(matches!(
+            db.set("looks-deleted", "\0tombstone"),
+            Err(DeebeeError::Write(WriteError::ReservedValue))
+        ));
+        assert!(matches!(
+            db.set_bytes("bin", &bytes),
+            Err(DeebeeError::Write(WriteError::FormatTooOld {
+                needed: 5,
+                ..
+            }))
+        ));
+        db.set("a", "1").unwrap();
+        db.upgrade_format(FORMAT_VERSION).unwrap();
+        db.set_bytes("bin", &bytes).unwrap();
+        assert_eq!(db.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(db.get_bytes("bin").unwrap().as_deref(), Some(&bytes[..]));
+    });
+}
+
+#[test]
+fn chaos_mode_injects_configured_failures() {
+    in_scratch_dir("chaos", || {
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        db.set("k", "v").unwrap();
+        drop(db);
+
+        let config = fs::read_to_string("deebee.toml").unwrap();
+        fs::write(
+            "deebee.toml",
+            format!("{config}\n[databases.chaos]\nerror_rate = 1.0\nlatency_ms = 5\n"),
+        )
+        .unwrap();
+
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        let started = std::time::Instant::now();
+        assert!(db.get("k").is_err());
+        assert!(started.elapsed() >= Duration::from_millis(5));
+        assert!(db.set("k", "w").is_err());
+        drop(db);
+
+        fs::write("deebee.toml", config).unwrap();
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert_eq!(db.get("k").unwrap().as_deref(), Some("v"));
+    });
+}
+
+#[test]
+fn damaged_records_are_reported_as_corruption() {
+    in_scratch_dir("checksums", || {
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        db.set("a", "first").unwrap();
+        db.set("b", "second").unwrap();
+
+        // flip a byte of a's value on disk
+        let mut segment = fs::read("db/000001.log").unwrap();
+        let at = segment.iter().position(|&b| b == b'f').unwrap();
+        segment[at] = b'F';
+        fs::write("db/000001.log", segment).unwrap();
+
+        assert!(matches!(db.get("a"), Err(DeebeeError::Corruption(_))));
+        assert_eq!(db.get("b").unwrap().as_deref(), Some("second"));
+    });
+}
+
+#[test]
+fn compare_and_set_sees_writes_from_other_handles_after_reload() {
+    in_scratch_dir("compare-and-set", || {
+        // two writers in one process, which the lock file would refuse
+        let options = DatabaseOptions::new().lock(false);
+        let mut editor = Database::open("db", &options).unwrap();
+        let mut other = Database::open("db", &options).unwrap();
+        editor.set("doc", "v1").unwrap();
+        other.reload().unwrap();
+        other.set("doc", "v2").unwrap();
+
+        editor.reload().unwrap();
+        assert!(!editor.compare_and_set("doc", Some("v1"), "mine").unwrap());
+        assert!(editor.compare_and_set("doc", Some("v2"), "mine").unwrap());
+        assert!(!editor.compare_and_set("new", Some("v1"), "x").unwrap());
+        assert!(editor.compare_and_set("new", None, "x").unwrap());
+        assert_eq!(editor.get("doc").unwrap().as_deref(), Some("mine"));
+    });
+}
+
+#[test]
+fn lock_file_keeps_a_second_writer_out() {
+    in_scratch_dir("lock", || {
+        let writer = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert!(matches!(
+            Database::open("db", &DatabaseOptions::new()),
+            Err(DeebeeError::Locked(_))
+        ));
+        let read_only = DatabaseOptions::new().read_only(true);
+        assert!(matches!(
+            Database::open("db", &read_only),
+            Err(DeebeeError::Locked(_))
+        ));
+        drop(writer);
+
+        let reader = Database::open("db", &read_only).unwrap();
+        let other_reader = Database::open("db", &read_only).unwrap();
+        assert!(matches!(
+            Database::open("db", &DatabaseOptions::new()),
+            Err(DeebeeError::Locked(_))
+        ));
+        drop((reader, other_reader));
+        assert!(Database::open("db", &DatabaseOptions::new()).is_ok());
+    });
+}
+
+#[test]
+fn databases_from_before_the_manifest_move_into_their_directory() {
+    in_scratch_dir("manifest", || {
+        // the layout older builds left behind, segments next to deebee.toml
+        fs::write(
+            "deebee.toml",
+            "data_dir = \"data\"\n\n[[databases]]\nname = \"db\"\n\
+             segments_files_paths = [\"db1.log\", \"db2.log\"]\nformat_version = 1\n",
+        )
+        .unwrap();
+        fs::write("db1.log", "a, 1\nb, 2\n").unwrap();
+        fs::write("db2.log", "a, 3\n").unwrap();
+        let read_only = DatabaseOptions::new().read_only(true);
+        assert!(matches!(
+            Database::open("db", &read_only),
+            Err(DeebeeError::Config(_))
+        ));
+
+        {
+            let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+            assert!(db.dir().ends_with("data/db"));
+            assert_eq!(db.get("a").unwrap().as_deref(), Some("3"));
+            db.set("c", "4").unwrap();
+        }
+        assert!(!fs::exists("db1.log").unwrap());
+        let config = fs::read_to_string("deebee.toml").unwrap();
+        assert!(!config.contains("segments_files_paths"), "{config}");
+        let manifest = fs::read_to_string("data/db/MANIFEST").unwrap();
+        assert!(manifest.contains("active = \"000002.log\""), "{manifest}");
+        assert!(manifest.contains("next_segment = 3"), "{manifest}");
+
+        let db = Database::open("db", &read_only).unwrap();
+        assert_eq!(db.get("b").unwrap().as_deref(), Some("2"));
+        assert_eq!(db.get("c").unwrap().as_deref(), Some("4"));
+    });
+}
+
+#[test]
+fn options_stand_in_for_settings_without_saving_them() {
+    let scratch = TempDatabase::new().unwrap();
+    let options = DatabaseOptions::new()
+        .root(scratch.dir())
+        .data_dir("elsewhere")
+        .segment_size(2)
+        .cache_bytes(1024);
+    let mut db = Database::open("db", &options).unwrap();
+    for key in ["a", "b", "c"] {
+        db.set(key, "v").unwrap();
+    }
+    db.get("a").unwrap();
+    db.get("a").unwrap();
+    assert_eq!(db.stats(true).cache_hits, 1);
+    assert!(db.dir().ends_with("elsewhere/db"));
+    assert!(fs::exists(db.dir().join("000002.log")).unwrap());
+    drop(db);
+
+    let config = fs::read_to_string(scratch.dir().join("deebee.toml")).unwrap();
+    assert!(!config.contains("elsewhere") && !config.contains("segment_size"));
+    Database::rename("db", "moved", &options).unwrap();
+    assert!(fs::exists(scratch.dir().join("elsewhere/moved/MANIFEST")).unwrap());
+}
+
+#[test]
+fn rename_moves_the_database_and_its_snapshots() {
+    in_scratch_dir("rename", || {
+        let mut db = Database::open("old", &DatabaseOptions::new()).unwrap();
+        db.set("k", "v1").unwrap();
+        db.create_snapshot("before").unwrap();
+        db.set("k", "v2").unwrap();
+        assert!(matches!(
+            Database::rename("old", "new", &DatabaseOptions::new()),
+            Err(DeebeeError::Locked(_))
+        ));
+        drop(db);
+
+        Database::rename("old", "new", &DatabaseOptions::new()).unwrap();
+        assert!(!fs::exists("old").unwrap());
+        assert!(fs::exists("new/MANIFEST").unwrap());
+        assert!(Database::rename("old", "other", &DatabaseOptions::new()).is_err());
+
+        let options = DatabaseOptions::new().create_if_missing(false);
+        assert!(Database::open("old", &options).is_err());
+        let mut db = Database::open("new", &options).unwrap();
+        assert_eq!(db.get("k").unwrap().as_deref(), Some("v2"));
+        db.restore_snapshot("before").unwrap();
+        assert_eq!(db.get("k").unwrap().as_deref(), Some("v1"));
+    });
+}
+
+// counts one metric, for checking how often the engine did something
+struct CountMetric(&'static str, Arc<AtomicU64>);
+
+impl MetricsSink for CountMetric {
+    fn counter(&self, name: &str, delta: u64) {
+        if name == self.0 {
+            self.1.fetch_add(delta, Ordering::SeqCst);
+        }
+    }
+}
+
+#[test]
+fn sync_policy_decides_when_writes_are_fsynced() {
+    in_scratch_dir("sync-policy", || {
+        for (policy, expected) in [(SyncPolicy::Always, 3), (SyncPolicy::Never, 0)] {
+            let fsyncs = Arc::new(AtomicU64::new(0));
+            let options = DatabaseOptions::new().sync(policy);
+            let mut db = Database::open("db", &options).unwrap();
+            db.set_metrics_sink(Box::new(CountMetric("deebee.fsyncs", fsyncs.clone())));
+            for i in 0..3 {
+                db.set(&format!("k{i}"), "v").unwrap();
+            }
+            drop(db);
+            assert_eq!(fsyncs.load(Ordering::SeqCst), expected, "{policy:?}");
+        }
+    });
+}
+
+#[test]
+fn a_commit_window_fsyncs_the_writes_sent_during_it_together() {
+    let fsyncs = Arc::new(AtomicU64::new(0));
+    let mut db = TempDatabase::builder()
+        .options(DatabaseOptions::new().sync(SyncPolicy::Always))
+        .open()
+        .unwrap();
+    db.set_metrics_sink(Box::new(CountMetric("deebee.fsyncs", fsyncs.clone())));
+    let server = Server::bind("127.0.0.1:0", Protocol::Line).unwrap();
+    server.set_commit_window(Some(Duration::from_millis(500)));
+    let addr = server.local_addr();
+
+    let clients: Vec<_> = (0..3)
+        .map(|i| {
+            std::thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                stream
+                    .write_all(format!("SET k{i} v\nQUIT\n").as_bytes())
+                    .unwrap();
+                let mut reply = String::new();
+                stream.read_to_string(&mut reply).unwrap();
+                reply
+            })
+        })
+        .collect();
+    server.serve_one(&mut db).unwrap();
+    for client in clients {
+        assert_eq!(client.join().unwrap(), "OK\n");
+    }
+    assert_eq!(fsyncs.load(Ordering::SeqCst), 1);
+    assert_eq!(db.get("k2").unwrap().as_deref(), Some("v"));
+
+    // without one every write gets its own
+    server.set_commit_window(None);
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"SET a 1\nSET b 2\nQUIT\n").unwrap();
+        stream.read_to_string(&mut String::new()).unwrap();
+    });
+    for _ in 0..2 {
+        server.serve_one(&mut db).unwrap();
+    }
+    client.join().unwrap();
+    assert_eq!(fsyncs.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn set_async_resolves_once_an_fsync_covers_the_write() {
+    in_scratch_dir("set-async", || {
+        let options = DatabaseOptions::new().sync(SyncPolicy::Never);
+        let mut db = Database::open("db", &options).unwrap();
+        let first = db.set_async("a", "1").unwrap();
+        db.set("b", "2").unwrap();
+        // the flush task gets to it under `never` too
+        assert_eq!(block_on(first), Durable::Synced);
+        assert_eq!(db.get("a").unwrap().as_deref(), Some("1"));
+
+        // closing fsyncs whatever is still waiting
+        let pending = db.set_async("c", "3").unwrap();
+        drop(db);
+        assert!(block_on(pending).is_synced());
+
+        let options = DatabaseOptions::new().sync(SyncPolicy::Always);
+        let mut db = Database::open("db", &options).unwrap();
+        assert_eq!(block_on(db.set_async("d", "4").unwrap()), Durable::Synced);
+    });
+}
+
+#[test]
+fn torn_writes_are_truncated_on_open() {
+    in_scratch_dir("torn-write", || {
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        db.set("a", "1").unwrap();
+        db.set("b", "2").unwrap();
+        drop(db);
+
+        // the process died halfway through appending a third record
+        let intact = fs::read("db/000001.log").unwrap();
+        let torn = RecordEncoding::for_format(FORMAT_VERSION).encode("c", "3");
+        let mut segment = intact.clone();
+        segment.extend_from_slice(&torn[..torn.len() / 2]);
+        fs::write("db/000001.log", segment).unwrap();
+
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert_eq!(fs::read("db/000001.log").unwrap(), intact);
+        let report = db.stats(true).last_recovery.unwrap();
+        assert_eq!(report.truncated_bytes, (torn.len() / 2) as u64);
+        assert_eq!(report.records, 2);
+
+        db.set("c", "3").unwrap();
+        drop(db);
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert_eq!(db.get("b").unwrap().as_deref(), Some("2"));
+        assert_eq!(db.get("c").unwrap().as_deref(), Some("3"));
+    });
+}
+
+#[test]
+fn temp_databases_come_prepopulated_and_clean_up() {
+    let dir = {
+        let mut db = TempDatabase::builder()
+            .name("fixtures")
+            .record("a", "1")
+            .records([("b", "2"), ("c", "3")])
+            .open()
+            .unwrap();
+        assert_eq!(db.get("b").unwrap().as_deref(), Some("2"));
+
+        db.set("d", "4").unwrap();
+        db.reopen().unwrap();
+        assert_eq!(db.digest().unwrap().0, 4);
+        db.dir().to_path_buf()
+    };
+    assert!(!dir.exists());
+}
+
+#[test]
+fn temp_databases_keep_out_of_the_current_directory() {
+    let cwd = std::env::current_dir().unwrap();
+    let mut outer = TempDatabase::builder().record("a", "1").open().unwrap();
+    // a second one while the first is open, both named "test"
+    let inner = TempDatabase::builder().record("a", "2").open().unwrap();
+    assert_eq!(std::env::current_dir().unwrap(), cwd);
+    assert_eq!(inner.get("a").unwrap().as_deref(), Some("2"));
+
+    outer.create_snapshot("before").unwrap();
+    outer.set("a", "changed").unwrap();
+    // recorded relative to the root, the directory can move
+    let config = fs::read_to_string(outer.dir().join("deebee.toml")).unwrap();
+    assert!(config.contains("segment = \"test/000001.log\""));
+    assert!(config.contains("copy = \"test/snapshots/before/000001.log\""));
+    outer.restore_snapshot("before").unwrap();
+    assert_eq!(outer.get("a").unwrap().as_deref(), Some("1"));
+    assert!(!cwd.join("test").exists());
+}
+
+#[test]
+fn concurrent_config_changes_are_all_kept() {
+    let db = TempDatabase::new().unwrap();
+    let options = DatabaseOptions::new().root(db.dir());
+    let writers: Vec<_> = (0..4)
+        .map(|i| {
+            let options = options.clone();
+            std::thread::spawn(move || {
+                let mut db = Database::open(&format!("db{i}"), &options).unwrap();
+                for k in 0..10 {
+                    db.set(&format!("k{k}"), "v").unwrap();
+                    db.pin(&format!("k{k}")).unwrap();
+                }
+            })
+        })
+        .collect();
+    writers
+        .into_iter()
+        .for_each(|writer| writer.join().unwrap());
+
+    for i in 0..4 {
+        let db = Database::open(&format!("db{i}"), &options).unwrap();
+        assert_eq!(db.pinned_keys().len(), 10);
+    }
+}
+
+#[test]
+fn sealed_segments_get_hint_files_that_are_rebuilt_when_stale() {
+    in_scratch_dir("hints", || {
+        {
+            let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+            for i in 0..25 {
+                db.set(&format!("k{i}"), &i.to_string()).unwrap();
+            }
+            db.delete("k3").unwrap();
+        }
+        assert!(fs::exists("db/000001.hint").unwrap());
+        assert!(fs::exists("db/000002.hint").unwrap());
+        assert!(!fs::exists("db/000003.hint").unwrap());
+
+        // missing and garbled hints are rebuilt from their segments
+        fs::remove_file("db/000001.hint").unwrap();
+        fs::write("db/000002.hint", "garbage").unwrap();
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert_eq!(db.get("k0").unwrap().as_deref(), Some("0"));
+        assert_eq!(db.get("k3").unwrap(), None);
+        assert_eq!(db.get("k24").unwrap().as_deref(), Some("24"));
+        assert_eq!(db.digest().unwrap().0, 24);
+        assert_eq!(db.stats(true).last_recovery.unwrap().records, 26);
+        assert!(fs::read("db/000002.hint").unwrap() != b"garbage");
+        drop(db);
+
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert_eq!(db.get("k12").unwrap().as_deref(), Some("12"));
+        assert_eq!(db.digest().unwrap().0, 24);
+    });
+}
+
+#[test]
+fn advice_about_dead_records_can_be_applied() {
+    in_scratch_dir("advise", || {
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        assert!(db.advise().iter().all(|a| a.change.is_none()));
+
+        for round in 0..40 {
+            for i in 0..5 {
+                db.set(&format!("k{i}"), &format!("{round}")).unwrap();
+            }
+        }
+        let advice = db.advise();
+        assert!(
+            advice
+                .iter()
+                .any(|a| matches!(a.change, Some(Tuning::CompactAtDeadRatio(_))))
+        );
+        assert!(
+            advice
+                .iter()
+                .any(|a| matches!(a.change, Some(Tuning::SegmentSize(_))))
+        );
+
+        db.apply_advice(&advice).unwrap();
+        assert!(db.advise().iter().all(|a| a.change.is_none()));
+        drop(db);
+
+        let config = fs::read_to_string("deebee.toml").unwrap();
+        assert!(config.contains("segment_size"), "{config}");
+        assert!(config.contains("dead_ratio"), "{config}");
+    });
+}
+
+#[test]
+fn prefix_scans_return_matching_keys_in_order() {
+    let mut db = TempDatabase::builder()
+        .records([
+            ("user:2:name", "bo"),
+            ("user:1:name", "al"),
+            ("user:1:email", "al@example.com"),
+            ("users", "not a user"),
+            ("team:1", "ops"),
+        ])
+        .open()
+        .unwrap();
+    db.delete("user:2:name").unwrap();
+
+    let users: Vec<(String, String)> = db.scan_prefix("user:").map(Result::unwrap).collect();
+    assert_eq!(
+        users,
+        [
+            ("user:1:email".to_string(), "al@example.com".to_string()),
+            ("user:1:name".to_string(), "al".to_string()),
+        ]
+    );
+    assert_eq!(db.scan_prefix("").count(), 4);
+    assert_eq!(db.scan_prefix("nobody").count(), 0);
+}
+
+#[test]
+fn set_many_writes_a_batch_across_segments() {
+    let mut db = TempDatabase::new().unwrap();
+    let config = db.dir().join("deebee.toml");
+    let original = fs::read_to_string(&config).unwrap();
+    fs::write(&config, original + "key_rules = { max_length = 8 }\n").unwrap();
+    db.reopen().unwrap();
+    let pairs: Vec<(String, String)> = (0..25).map(|i| (format!("k{i}"), i.to_string())).collect();
+    assert_eq!(db.set_many(pairs).unwrap(), 25);
+    assert_eq!(db.set_many([("k0", "again"), ("k0", "latest")]).unwrap(), 2);
+    assert!(db.set_many([("ok", "1"), ("much-too-long", "1")]).is_err());
+    assert!(!db.contains_key("ok"));
+
+    db.reopen().unwrap();
+    assert_eq!(db.get("k0").unwrap().as_deref(), Some("latest"));
+    assert_eq!(db.get("k24").unwrap().as_deref(), Some("24"));
+    assert_eq!(db.digest().unwrap().0, 25);
+    assert!(fs::exists(db.dir().join("test/000003.log")).unwrap());
+}
+
+#[test]
+fn export_server_requires_the_token_and_filters_by_prefix() {
+    let db = TempDatabase::builder()
+        .records([("app:b", "2"), ("app:a", "1"), ("other", "3")])
+        .open()
+        .unwrap();
+    let server = ExportServer::bind("127.0.0.1:0", "s3cret").unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let fetch = move |request: &'static str| {
+        std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        })
+    };
+
+    let client = fetch("GET /export.jsonl HTTP/1.1\r\nHost: x\r\n\r\n");
+    server.serve_one(&db).unwrap();
+    let response = client.join().unwrap();
+    assert!(response.starts_with("HTTP/1.1 401"), "{response}");
+
+    let client =
+        fetch("GET /export.jsonl?prefix=app%3A HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\r\n");
+    server.serve_one(&db).unwrap();
+    let response = client.join().unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+    assert!(head.contains("Transfer-Encoding: chunked"), "{head}");
+    // one chunk, then the empty one that says the export is complete
+    let (size, rest) = body.split_once("\r\n").unwrap();
+    let size = usize::from_str_radix(size, 16).unwrap();
+    assert_eq!(
+        &rest[..size],
+        "{\"key\":\"app:a\",\"value\":\"1\"}\n{\"key\":\"app:b\",\"value\":\"2\"}\n"
+    );
+    assert_eq!(&rest[size..], "\r\n0\r\n\r\n");
+
+    // names are decoded like the values
+    let client =
+        fetch("GET /export.jsonl?%66rom=app%3Ab HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\r\n");
+    server.serve_one(&db).unwrap();
+    let response = client.join().unwrap();
+    assert!(
+        response.ends_with(
+            "{\"key\":\"app:b\",\"value\":\"2\"}\n{\"key\":\"other\",\"value\":\"3\"}\n\r\n0\r\n\r\n"
+        ),
+        "{response}"
+    );
+}
+
+#[test]
+fn exports_redact_sensitive_keys_unless_asked_for_raw_values() {
+    in_scratch_dir("export-redacted", || {
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        db.set("user:1", "ada").unwrap();
+        db.set("token:1", "s3cret").unwrap();
+        drop(db);
+        let config = fs::read_to_string("deebee.toml").unwrap();
+        let config = config.replacen(
+            "name = \"db\"\n",
+            "name = \"db\"\nsensitive_keys = [\"token:*\"]\n",
+            1,
+        );
+        fs::write("deebee.toml", config).unwrap();
+
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        let values = |records: Vec<ExportRecord>| -> Vec<String> {
+            records.into_iter().map(|record| record.value).collect()
+        };
+        let all = KeyFilter::default();
+        assert_eq!(values(db.export(&all).unwrap()), ["<redacted>", "ada"]);
+        let streamed: Vec<ExportRecord> = db.export_iter(&all).map(Result::unwrap).collect();
+        assert_eq!(values(streamed), ["<redacted>", "ada"]);
+        assert_eq!(values(db.export_raw(&all).unwrap()), ["s3cret", "ada"]);
+        let streamed: Vec<ExportRecord> = db.export_iter_raw(&all).map(Result::unwrap).collect();
+        assert_eq!(values(streamed), ["s3cret", "ada"]);
+
+        let mut view = db.view().unwrap();
+        assert_eq!(values(view.export(&all).unwrap()), ["<redacted>", "ada"]);
+        assert_eq!(values(view.export_raw(&all).unwrap()), ["s3cret", "ada"]);
+    });
+}
+
+#[test]
+fn get_many_returns_values_in_the_order_asked() {
+    let pairs: Vec<(String, String)> = (0..25).map(|i| (format!("k{i}"), i.to_string())).collect();
+    let mut db = TempDatabase::builder().records(pairs).open().unwrap();
+    db.delete("k3").unwrap();
+
+    let values = db.get_many(&["k24", "k3", "missing", "k0", "k24"]).unwrap();
+    assert_eq!(
+        values,
+        [
+            Some("24".into()),
+            None,
+            None,
+            Some("0".into()),
+            Some("24".into())
+        ]
+    );
+    assert!(db.get_many::<&str>(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn fencing_rejects_writes_from_older_epochs() {
+    let mut stale = TempDatabase::builder()
+        .options(
+            // stands in for a primary on another host, where the lock file
+            // wouldn't keep the two apart
+            DatabaseOptions::new()
+                .sync(SyncPolicy::Never)
+                .epoch(1)
+                .lock(false),
+        )
+        .record("leader", "old")
+        .open()
+        .unwrap();
+
+    let options = DatabaseOptions::new().root(stale.dir()).lock(false);
+    let mut primary = Database::open("test", &options.clone().epoch(2)).unwrap();
+    primary.fence(2).unwrap();
+    primary.set("leader", "new").unwrap();
+    assert!(primary.fence(1).is_err());
+
+    assert!(matches!(
+        stale.set("leader", "split brain"),
+        Err(DeebeeError::Write(WriteError::Fenced {
+            epoch: Some(1),
+            fence: 2
+        }))
+    ));
+    let mut untagged = Database::open("test", &options).unwrap();
+    assert!(untagged.delete("leader").is_err());
+    assert_eq!(untagged.get("leader").unwrap().as_deref(), Some("new"));
+}
+
+#[test]
+fn open_verifies_segments_as_deep_as_asked() {
+    in_scratch_dir("verify", || {
+        {
+            let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+            for i in 0..25 {
+                db.set(&format!("k{i}"), &i.to_string()).unwrap();
+            }
+        }
+        let open =
+            |level| Database::open("db", &DatabaseOptions::new().read_only(true).verify(level));
+
+        // damage the first record of the first segment
+        let mut segment = fs::read("db/000001.log").unwrap();
+        let at = segment.windows(2).position(|w| w == b"k0").unwrap();
+        segment[at + 1] = b'X';
+        fs::write("db/000001.log", segment).unwrap();
+        assert!(open(VerifyLevel::None).is_ok());
+        assert!(open(VerifyLevel::FootersOnly).is_ok());
+        assert!(matches!(
+            open(VerifyLevel::FullChecksum),
+            Err(DeebeeError::Corruption(_))
+        ));
+
+        // and the checksum of the last record of the second one
+        let mut segment = fs::read("db/000002.log").unwrap();
+        *segment.last_mut().unwrap() ^= 0xff;
+        fs::write("db/000002.log", segment).unwrap();
+        assert!(matches!(
+            open(VerifyLevel::FootersOnly),
+            Err(DeebeeError::Corruption(_))
+        ));
+    });
+}
+
+#[test]
+fn value_cache_answers_repeated_gets() {
+    in_scratch_dir("cache", || {
+        drop(Database::open("db", &DatabaseOptions::new()).unwrap());
+        let config = fs::read_to_string("deebee.toml").unwrap();
+        fs::write("deebee.toml", config + "cache_bytes = 16\n").unwrap();
+
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        db.set("a", "1234").unwrap();
+        db.set("b", "5678").unwrap();
+        db.set("big", "far too big to be cached").unwrap();
+        for _ in 0..3 {
+            assert_eq!(db.get("a").unwrap().as_deref(), Some("1234"));
+        }
+        let stats = db.stats(true);
+        assert_eq!((stats.cache_hits, stats.cache_misses), (2, 1));
+
+        // a write replaces what the cache had
+        db.set("a", "new").unwrap();
+        assert_eq!(db.get("a").unwrap().as_deref(), Some("new"));
+        assert_eq!(db.get("a").unwrap().as_deref(), Some("new"));
+        db.get("big").unwrap();
+        db.get("big").unwrap();
+        let stats = db.stats(true);
+        assert_eq!((stats.cache_hits, stats.cache_misses), (3, 4));
+    });
+}
+
+#[test]
+fn hot_keys_warm_the_cache_of_the_next_open() {
+    in_scratch_dir("warm-cache", || {
+        drop(Database::open("db", &DatabaseOptions::new()).unwrap());
+        let config = fs::read_to_string("deebee.toml").unwrap();
+        fs::write(
+            "deebee.toml",
+            config + "cache_bytes = 1024\nwarm_cache = true\n",
+        )
+        .unwrap();
+
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        for key in ["a", "b", "cold"] {
+            db.set(key, "v").unwrap();
+        }
+        db.get("a").unwrap();
+        db.get("b").unwrap();
+        drop(db);
+        assert_eq!(fs::read_to_string("db/HOT").unwrap(), r#"["b","a"]"#);
+
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        let started = std::time::Instant::now();
+        while !db
+            .stats(true)
+            .background_tasks
+            .iter()
+            .any(|task| task.name == "warmup" && task.runs == 1)
+        {
+            assert!(started.elapsed().as_secs() < 10, "never warmed up");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        for key in ["a", "b", "cold"] {
+            assert_eq!(db.get(key).unwrap().as_deref(), Some("v"));
+        }
+        let stats = db.stats(true);
+        assert_eq!((stats.cache_hits, stats.cache_misses), (2, 1));
+    });
+}
+
+#[test]
+fn views_export_databases_as_of_one_point_in_time() {
+    in_scratch_dir("views", || {
+        let mut manager = DatabaseManager::new();
+        let options = DatabaseOptions::new();
+        manager
+            .open("orders", &options)
+            .unwrap()
+            .set("o1", "open")
+            .unwrap();
+        manager
+            .open("items", &options)
+            .unwrap()
+            .set("o1/a", "1")
+            .unwrap();
+
+        let mut views = manager.view(&["orders", "items"], &options).unwrap();
+        let orders = manager.open("orders", &options).unwrap();
+        orders.set("o1", "shipped").unwrap();
+        orders.set("o2", "open").unwrap();
+        orders.compact_segments().unwrap();
+        manager
+            .open("items", &options)
+            .unwrap()
+            .delete("o1/a")
+            .unwrap();
+
+        let all = KeyFilter::default();
+        let exported: Vec<Vec<(String, String)>> = views
+            .iter_mut()
+            .map(|view| {
+                let records = view.export(&all).unwrap();
+                records.into_iter().map(|r| (r.key, r.value)).collect()
+            })
+            .collect();
+        assert_eq!(
+            exported,
+            [
+                vec![("o1".to_string(), "open".to_string())],
+                vec![("o1/a".to_string(), "1".to_string())]
+            ]
+        );
+        assert_eq!(views[0].db_name(), "orders");
+    });
+}
+
+// orders `major.minor.patch` keys numerically
+struct Semver;
+
+impl Semver {
+    fn parts(key: &str) -> Option<Vec<u64>> {
+        let parts: Vec<u64> = key
+            .split('.')
+            .map(|part| part.parse().ok())
+            .collect::<Option<_>>()?;
+        (parts.len() == 3).then_some(parts)
+    }
+}
+
+impl KeyCodec for Semver {
+    fn name(&self) -> &str {
+        "semver"
+    }
+
+    fn compare(&self, a: &str, b: &str) -> std::cmp::Ordering {
+        Semver::parts(a).cmp(&Semver::parts(b))
+    }
+
+    fn check(&self, key: &str) -> Result<(), String> {
+        Semver::parts(key)
+            .map(|_| ())
+            .ok_or_else(|| "not major.minor.patch".to_string())
+    }
+}
+
+#[test]
+fn key_codecs_order_keys_and_stay_registered() {
+    in_scratch_dir("codec", || {
+        let options = DatabaseOptions::new().key_codec(Semver);
+        {
+            let mut db = Database::open("db", &options).unwrap();
+            for key in ["1.10.0", "1.2.0", "1.9.1"] {
+                db.set(key, "x").unwrap();
+            }
+            assert!(matches!(
+                db.set("latest", "x"),
+                Err(DeebeeError::InvalidKey(KeyError::Rejected { .. }))
+            ));
+            let keys: Vec<String> = db
+                .export(&KeyFilter::default())
+                .unwrap()
+                .into_iter()
+                .map(|record| record.key)
+                .collect();
+            assert_eq!(keys, ["1.2.0", "1.9.1", "1.10.0"]);
+            assert_eq!(db.keys_matching("1.*"), ["1.2.0", "1.9.1", "1.10.0"]);
+            let listed: Vec<&[u8]> = db.iter_keys().collect();
+            assert_eq!(listed, [&b"1.2.0"[..], b"1.9.1", b"1.10.0"]);
+
+            // ranges are in the same order, byte order would put 1.10.0 first
+            let range = KeyFilter {
+                from: Some("1.9.0".into()),
+                to: Some("1.11.0".into()),
+                ..Default::default()
+            };
+            let keys: Vec<String> = db
+                .export(&range)
+                .unwrap()
+                .into_iter()
+                .map(|record| record.key)
+                .collect();
+            assert_eq!(keys, ["1.9.1", "1.10.0"]);
+        }
+        let manifest = fs::read_to_string("db/MANIFEST").unwrap();
+        assert!(manifest.contains("key_codec = \"semver\""), "{manifest}");
+        assert!(
+            !fs::read_to_string("deebee.toml")
+                .unwrap()
+                .contains("semver")
+        );
+
+        // where older builds recorded it
+        fs::write(
+            "db/MANIFEST",
+            manifest.replace("key_codec = \"semver\"", ""),
+        )
+        .unwrap();
+        let config = fs::read_to_string("deebee.toml").unwrap();
+        fs::write(
+            "deebee.toml",
+            config.replace("name = \"db\"", "name = \"db\"\nkey_codec = \"semver\""),
+        )
+        .unwrap();
+        assert!(Database::open("db", &DatabaseOptions::new()).is_err());
+        drop(Database::open("db", &options).unwrap());
+        assert_eq!(fs::read_to_string("db/MANIFEST").unwrap(), manifest);
+        let config = fs::read_to_string("deebee.toml").unwrap();
+        assert!(!config.contains("semver"));
+
+        assert!(matches!(
+            Database::open("db", &DatabaseOptions::new()),
+            Err(DeebeeError::Config(_))
+        ));
+
+        // a codec can't be registered over keys it rejects
+        let mut plain = Database::open("plain", &DatabaseOptions::new()).unwrap();
+        plain.set("latest", "x").unwrap();
+        drop(plain);
+        assert!(Database::open("plain", &options).is_err());
+    });
+}
+
+#[test]
+fn pinned_keys_are_served_from_memory() {
+    let mut db = TempDatabase::builder()
+        .records([("config:mode", "fast"), ("other", "x")])
+        .open()
+        .unwrap();
+    assert!(db.pin("config:mode").unwrap());
+    assert!(!db.pin("config:mode").unwrap());
+    assert!(db.pin("config:missing").unwrap());
+
+    // the segment going away doesn't matter to pinned reads
+    db.reopen().unwrap();
+    assert_eq!(db.pinned_keys(), ["config:missing", "config:mode"]);
+    fs::write(db.dir().join("test/000001.log"), "").unwrap();
+    assert_eq!(db.get("config:mode").unwrap().as_deref(), Some("fast"));
+    assert_eq!(db.get("config:missing").unwrap(), None);
+
+    db.set("config:missing", "now here").unwrap();
+    assert_eq!(
+        db.get("config:missing").unwrap().as_deref(),
+        Some("now here")
+    );
+    db.delete("config:mode").unwrap();
+    assert_eq!(db.get("config:mode").unwrap(), None);
+
+    assert!(db.unpin("config:missing").unwrap());
+    db.reopen().unwrap();
+    assert_eq!(db.pinned_keys(), ["config:mode"]);
+}
+
+#[test]
+fn server_answers_line_commands_from_several_clients() {
+    let mut db = TempDatabase::new().unwrap();
+    let server = Server::bind("127.0.0.1:0", Protocol::Line).unwrap();
+    let addr = server.local_addr();
+
+    let client = move |lines: &'static str| {
+        std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(lines.as_bytes()).unwrap();
+            let mut replies = String::new();
+            stream.read_to_string(&mut replies).unwrap();
+            replies
+        })
+    };
+
+    let writer = client("SET greeting hello world\nget greeting\nDEL nope\nQUIT\n");
+    for _ in 0..3 {
+        server.serve_one(&mut db).unwrap();
+    }
+    assert_eq!(writer.join().unwrap(), "OK\nVALUE hello world\nNIL\n");
+
+    let reader = client("GET greeting\nDEL greeting\nGET greeting\nFROB x\nQUIT\n");
+    for _ in 0..3 {
+        server.serve_one(&mut db).unwrap();
+    }
+    assert_eq!(
+        reader.join().unwrap(),
+        "VALUE hello world\nOK\nNIL\nERR unknown command FROB\n"
+    );
+    assert!(!db.contains_key("greeting"));
+}
+
+#[test]
+fn server_counts_what_each_client_and_namespace_did() {
+    let mut db = TempDatabase::new().unwrap();
+    let server = Server::bind("127.0.0.1:0", Protocol::Line).unwrap();
+    let addr = server.local_addr();
+
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"SET user:1 al\nGET user:1\nGET nope\nFROB x\nSET order:9 x\nADMIN CLIENTS\nQUIT\n")
+            .unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+        replies
+    });
+    for _ in 0..5 {
+        server.serve_one(&mut db).unwrap();
+    }
+    let replies = client.join().unwrap();
+    let (_, clients) = replies.split_once("ARRAY 1\n").unwrap();
+    // the unknown command is the error, the miss isn't
+    assert!(clients.starts_with("VALUE id="), "{replies}");
+    assert!(
+        clients.contains("s commands=6 ops=4 bytes=28 errors=1\n"),
+        "{replies}"
+    );
+
+    let namespaces = db.stats(true).namespaces;
+    let op = |ops, bytes| OpStats {
+        ops,
+        bytes,
+        errors: 0,
+    };
+    assert_eq!(namespaces["user"], op(2, 16));
+    assert_eq!(namespaces["order"], op(1, 8));
+    assert_eq!(namespaces[""], op(1, 4));
+}
+
+#[test]
+fn server_scans_ship_only_the_records_passing_their_filter() {
+    let mut db = TempDatabase::builder()
+        .record("user:1", r#"{"name":"al","age":3}"#)
+        .record("user:2", r#"{"name":"bo","tags":{"admin":true}}"#)
+        .record("order:1", "for al")
+        .open()
+        .unwrap();
+    let server = Server::bind("127.0.0.1:0", Protocol::Line).unwrap();
+    let addr = server.local_addr();
+
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"SCAN GLOB user:* CONTAINS al\n\
+                  scan field tags.admin true\n\
+                  SCAN FIELD age 3 LIMIT 1\n\
+                  SCAN PREFIX order: CONTAINS al\n\
+                  SCAN FIELD name\n\
+                  QUIT\n",
+            )
+            .unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+        replies
+    });
+    for _ in 0..4 {
+        server.serve_one(&mut db).unwrap();
+    }
+    assert_eq!(
+        client.join().unwrap(),
+        "ARRAY 2\nVALUE user:1\nVALUE {\"name\":\"al\",\"age\":3}\n\
+         ARRAY 2\nVALUE user:2\nVALUE {\"name\":\"bo\",\"tags\":{\"admin\":true}}\n\
+         ARRAY 2\nVALUE user:1\nVALUE {\"name\":\"al\",\"age\":3}\n\
+         ARRAY 2\nVALUE order:1\nVALUE for al\n\
+         ERR SCAN FIELD needs a value\n"
+    );
+}
+
+#[test]
+fn retried_writes_with_an_idempotency_key_run_once() {
+    let mut db = TempDatabase::new().unwrap();
+    let server = Server::bind("127.0.0.1:0", Protocol::Line).unwrap();
+    let addr = server.local_addr();
+
+    let client = move |lines: &'static str| {
+        std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(lines.as_bytes()).unwrap();
+            let mut replies = String::new();
+            stream.read_to_string(&mut replies).unwrap();
+            replies
+        })
+    };
+
+    let first = client("IDEM t1 SET a 1\nQUIT\n");
+    server.serve_one(&mut db).unwrap();
+    assert_eq!(first.join().unwrap(), "OK\n");
+    let other = client("SET a 2\nQUIT\n");
+    server.serve_one(&mut db).unwrap();
+    other.join().unwrap();
+
+    // the retry doesn't undo the write that came after it
+    let retry = client(
+        "IDEM t1 SET a 1\nidem t1 SET a 3\nIDEM t2 DEL nope\nIDEM t2 DEL nope\nIDEM t3\nQUIT\n",
+    );
+    for _ in 0..4 {
+        server.serve_one(&mut db).unwrap();
+    }
+    assert_eq!(
+        retry.join().unwrap(),
+        "OK\nERR idempotency key t1 was used for a different request\nNIL\nNIL\n\
+         ERR IDEM takes a token and a command\n"
+    );
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("2"));
+}
+
+#[test]
+fn cached_clients_drop_what_other_clients_change() {
+    let mut db = TempDatabase::builder().record("a", "1").open().unwrap();
+    let server = Server::bind("127.0.0.1:0", Protocol::Line).unwrap();
+    let addr = server.local_addr();
+
+    let client = std::thread::spawn(move || {
+        let mut cached = CachedClient::connect(addr).unwrap();
+        assert_eq!(cached.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(cached.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(cached.hits(), 1);
+
+        let mut other = TcpStream::connect(addr).unwrap();
+        other.write_all(b"SET a 2\nQUIT\n").unwrap();
+        other.read_to_string(&mut String::new()).unwrap();
+        let started = std::time::Instant::now();
+        while cached.cached() > 0 {
+            assert!(started.elapsed().as_secs() < 10, "never invalidated");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(cached.get("a").unwrap().as_deref(), Some("2"));
+
+        cached.set("b", "x").unwrap();
+        assert_eq!(cached.get("b").unwrap().as_deref(), Some("x"));
+        assert!(cached.delete("b").unwrap());
+        assert!(matches!(
+            cached.get("has space"),
+            Err(DeebeeError::InvalidKey(KeyError::InvalidChar(' ')))
+        ));
+        cached.hits()
+    });
+    for _ in 0..6 {
+        server.serve_one(&mut db).unwrap();
+    }
+    assert_eq!(client.join().unwrap(), 1);
+    assert!(!db.contains_key("b"));
+}
+
+#[test]
+fn import_policies_decide_which_records_win() {
+    let mut db = TempDatabase::builder().record("a", "kept").open().unwrap();
+    let records = || {
+        [("a", "new"), ("b", "first"), ("b", "second")].map(|(key, value)| ExportRecord {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    };
+    let import = |db: &mut Database, on_conflict, dedup| {
+        let options = ImportOptions { on_conflict, dedup };
+        db.import_with(records(), &KeyFilter::default(), &options)
+    };
+
+    assert!(import(&mut db, OnConflict::Fail, Dedup::Last).is_err());
+    assert!(!db.contains_key("b"));
+
+    let report = import(&mut db, OnConflict::Skip, Dedup::First).unwrap();
+    assert_eq!(
+        (report.imported, report.skipped, report.duplicates),
+        (1, 1, 1)
+    );
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("kept"));
+    assert_eq!(db.get("b").unwrap().as_deref(), Some("first"));
+
+    let report = import(&mut db, OnConflict::Overwrite, Dedup::Last).unwrap();
+    assert_eq!(report.imported, 2);
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("new"));
+    assert_eq!(db.get("b").unwrap().as_deref(), Some("second"));
+}
+
+#[test]
+fn resp_server_speaks_to_redis_clients() {
+    let mut db = TempDatabase::builder()
+        .record("user:1", "al")
+        .open()
+        .unwrap();
+    let server = Server::bind("127.0.0.1:0", Protocol::Resp).unwrap();
+    let addr = server.local_addr();
+
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"*3\r\n$3\r\nSET\r\n$6\r\nuser:2\r\n$8\r\nbo\r\nbob!\r\n\
+                  *2\r\n$3\r\nget\r\n$6\r\nuser:2\r\n\
+                  EXISTS user:1 nope user:2\r\n\
+                  *2\r\n$4\r\nKEYS\r\n$5\r\nuser*\r\n\
+                  DEL user:1 nope\r\n\
+                  GET nope\r\n\
+                  PING\r\n\
+                  SET only-a-key\r\n\
+                  QUIT\r\n",
+            )
+            .unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+        replies
+    });
+    // one per command that touches the database
+    for _ in 0..6 {
+        server.serve_one(&mut db).unwrap();
+    }
+    assert_eq!(
+        client.join().unwrap(),
+        "+OK\r\n$8\r\nbo\r\nbob!\r\n:2\r\n*2\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n:1\r\n$-1\r\n\
+         +PONG\r\n-ERR wrong number of arguments for 'set' command\r\n+OK\r\n"
+    );
+    assert!(!db.contains_key("user:1"));
+}
+
+#[test]
+fn http_server_maps_keys_to_rest_endpoints() {
+    let mut db = TempDatabase::builder()
+        .record("user:1", "al")
+        .open()
+        .unwrap();
+    let server = Server::bind("127.0.0.1:0", Protocol::Http).unwrap();
+    let addr = server.local_addr();
+
+    let request = |db: &mut Database, request: String| {
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        server.serve_one(db).unwrap();
+        let response = client.join().unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse::<u16>().unwrap();
+        (status, body.trim_end().to_string())
+    };
+    let put = |key: &str, value: &str, extra: &str| {
+        format!(
+            "PUT /keys/{key} HTTP/1.1\r\nContent-Length: {}\r\n{extra}\r\n{value}",
+            value.len()
+        )
+    };
+
+    assert_eq!(
+        request(&mut db, "GET /keys/user%3A1 HTTP/1.1\r\n\r\n".into()),
+        (
+            200,
+            r#"{"crc32":"793b656a","key":"user:1","value":"al"}"#.into()
+        )
+    );
+    assert_eq!(
+        request(&mut db, "GET /keys/nope HTTP/1.1\r\n\r\n".into()).0,
+        404
+    );
+    assert_eq!(request(&mut db, put("user:2", "bo b", "")).0, 204);
+    assert_eq!(
+        request(&mut db, put("user:2", "again", "If-None-Match: *\r\n")).0,
+        409
+    );
+    assert_eq!(
+        request(
+            &mut db,
+            "GET /keys?prefix=user:&limit=5 HTTP/1.1\r\n\r\n".into()
+        ),
+        (
+            200,
+            r#"[{"key":"user:1","value":"al"},{"key":"user:2","value":"bo b"}]"#.into()
+        )
+    );
+    assert_eq!(
+        request(
+            &mut db,
+            "GET /keys?glob=*:2&contains=o+b HTTP/1.1\r\n\r\n".into()
+        ),
+        (200, r#"[{"key":"user:2","value":"bo b"}]"#.into())
+    );
+    // `+` is a space only in the query string
+    assert_eq!(request(&mut db, put("c++", "lang", "")).0, 204);
+    assert_eq!(
+        request(&mut db, "GET /keys/c%2B%2B HTTP/1.1\r\n\r\n".into()),
+        (
+            200,
+            r#"{"crc32":"31098462","key":"c++","value":"lang"}"#.into()
+        )
+    );
+    // a body that doesn't match its checksum isn't written
+    let checksum = |crc: &str| format!("X-Checksum-CRC32: {crc}\r\n");
+    assert_eq!(
+        request(&mut db, put("c++", "langs", &checksum("31098462"))).0,
+        400
+    );
+    // a malformed one is refused before it gets to the database
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(put("c++", "lang", &checksum("nope")).as_bytes())
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 400 "), "{response}");
+    assert_eq!(
+        request(&mut db, put("c++", "lang", &checksum("31098462"))).0,
+        204
+    );
+    assert_eq!(
+        request(&mut db, "GET /keys?prefix=c+ HTTP/1.1\r\n\r\n".into()),
+        (200, "[]".into())
+    );
+    assert_eq!(
+        request(&mut db, "DELETE /keys/user:1 HTTP/1.1\r\n\r\n".into()).0,
+        204
+    );
+    assert_eq!(
+        request(&mut db, "DELETE /keys/user:1 HTTP/1.1\r\n\r\n".into()).0,
+        404
+    );
+    let (status, stats) = request(&mut db, "GET /stats HTTP/1.1\r\n\r\n".into());
+    assert_eq!(status, 200);
+    assert!(stats.contains("\"total_writes\""), "{stats}");
+
+    let (status, page) = request(&mut db, "GET /status HTTP/1.1\r\n\r\n".into());
+    assert_eq!(status, 200);
+    assert!(page.contains("<td>000001.log</td>"), "{page}");
+    // the one asking is connected while it's rendered
+    assert!(page.contains("<h2>clients (1)</h2>"), "{page}");
+
+    // big bodies are gzipped for clients that take it
+    let big = "compressible ".repeat(1000);
+    assert_eq!(request(&mut db, put("big", &big, "")).0, 204);
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /keys/big HTTP/1.1\r\nAccept-Encoding: gzip;q=0.5\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        response
+    });
+    server.serve_one(&mut db).unwrap();
+    let response = client.join().unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let (head, body) = (
+        String::from_utf8_lossy(&response[..split]),
+        &response[split + 4..],
+    );
+    assert!(head.contains("Content-Encoding: gzip"), "{head}");
+    assert_eq!(body[..2], [0x1f, 0x8b]);
+    assert!(body.len() < big.len() / 10);
+    // the trailer ends with the uncompressed size
+    let crc32 = crc32fast::hash(big.as_bytes());
+    let plain = format!("{{\"crc32\":\"{crc32:08x}\",\"key\":\"big\",\"value\":\"{big}\"}}\n");
+    assert_eq!(body[body.len() - 4..], (plain.len() as u32).to_le_bytes());
+}
+
+#[test]
+fn transforms_reshape_records_in_order() {
+    let transforms: Vec<Transform> = [
+        "trim",
+        "strip-prefix=old:",
+        "lowercase-keys",
+        "add-prefix=new:",
+        "select=name,id",
+    ]
+    .iter()
+    .map(|spec| spec.parse().unwrap())
+    .collect();
+    let record = ExportRecord {
+        key: " old:User1 ".to_string(),
+        value: r#" {"id": 1, "name": "al", "password": "x"} "#.to_string(),
+    };
+    assert_eq!(
+        Transform::apply_all(&transforms, record).unwrap(),
+        ExportRecord {
+            key: "new:user1".to_string(),
+            value: r#"{"id":1,"name":"al"}"#.to_string(),
+        }
+    );
+
+    let select = "select=name".parse::<Transform>().unwrap();
+    let plain = ExportRecord {
+        key: "k".to_string(),
+        value: "not json".to_string(),
+    };
+    assert!(matches!(
+        select.apply(plain),
+        Err(DeebeeError::InvalidValue(_))
+    ));
+    assert!("add-prefix".parse::<Transform>().is_err());
+    assert!("shout".parse::<Transform>().is_err());
+}
+
+#[test]
+fn shared_database_gets_run_side_by_side() {
+    fn shareable<T: Send + Sync>() {}
+    shareable::<Database>();
+
+    in_scratch_dir("shared", || {
+        Database::open("db", &DatabaseOptions::new()).unwrap();
+        // with the cache on, the gets share it
+        let config = fs::read_to_string("deebee.toml").unwrap();
+        fs::write("deebee.toml", config + "cache_bytes = 1024\n").unwrap();
+        let shared = SharedDatabase::open("db", &DatabaseOptions::new()).unwrap();
+        shared.set("k", "v").unwrap();
+
+        // a read held open on this thread doesn't hold up gets on the others
+        let held = shared.read();
+        held.get("k").unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        for _ in 0..4 {
+            let (shared, tx) = (shared.clone(), tx.clone());
+            std::thread::spawn(move || tx.send(shared.get("k").unwrap()).unwrap());
+        }
+        for _ in 0..4 {
+            let value = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(value.as_deref(), Some("v"));
+        }
+        assert_eq!(held.stats(true).cache_hits, 4);
+        drop(held);
+
+        let writers: Vec<_> = (0..4)
+            .map(|i| {
+                let shared = shared.clone();
+                std::thread::spawn(move || shared.set(&format!("w{i}"), "x").unwrap())
+            })
+            .collect();
+        writers
+            .into_iter()
+            .for_each(|writer| writer.join().unwrap());
+        assert_eq!(shared.read().digest().unwrap().0, 5);
+    });
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_database_runs_off_the_calling_thread() {
+    in_scratch_dir("async", || {
+        block_on(async {
+            let db = deebee::AsyncDatabase::open("db", &DatabaseOptions::new())
+                .await
+                .unwrap();
+            db.set("k", "v").await.unwrap();
+            assert_eq!(db.get("k").await.unwrap().as_deref(), Some("v"));
+            assert!(db.delete("k").await.unwrap());
+            assert_eq!(db.get("k").await.unwrap(), None);
+
+            // the same few workers take every operation, clones included
+            for i in 0..100 {
+                db.clone().set(&format!("k{i}"), "v").await.unwrap();
+            }
+            assert_eq!(db.shared().read().digest().unwrap().0, 100);
+        });
+    });
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mapped_segments_follow_compaction_and_restores() {
+    in_scratch_dir("mmap", || {
+        drop(Database::open("db", &DatabaseOptions::new()).unwrap());
+        let config = fs::read_to_string("deebee.toml").unwrap();
+        fs::write("deebee.toml", config + "segment_size = 4\n").unwrap();
+
+        let mut db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        for i in 0..10 {
+            db.set(&format!("k{i}"), &format!("v{i}")).unwrap();
+        }
+        db.create_snapshot("ten").unwrap();
+        assert_eq!(db.get("k1").unwrap().as_deref(), Some("v1"));
+
+        for i in 0..10 {
+            db.set(&format!("k{i}"), "new").unwrap();
+        }
+        db.compact_segments().unwrap();
+        assert_eq!(db.get("k1").unwrap().as_deref(), Some("new"));
+
+        db.restore_snapshot("ten").unwrap();
+        for i in 0..10 {
+            assert_eq!(db.get(&format!("k{i}")).unwrap(), Some(format!("v{i}")));
+        }
+    });
+}
+
+#[test]
+fn burn_after_read_keys_are_read_once() {
+    let mut db = TempDatabase::builder().record("plain", "p").open().unwrap();
+    db.set_burn_after_read("secret", "s3cr3t").unwrap();
+    db.reopen().unwrap();
+
+    // reads that can't delete it don't get to see it
+    assert!(matches!(
+        db.get("secret"),
+        Err(DeebeeError::InvalidArgument(_))
+    ));
+    let scanned: Vec<_> = db.scan_prefix("").map(Result::unwrap).collect();
+    assert_eq!(scanned, [("plain".to_string(), "p".to_string())]);
+    assert_eq!(db.export(&KeyFilter::default()).unwrap().len(), 1);
+
+    assert_eq!(db.get_and_burn("plain").unwrap().as_deref(), Some("p"));
+    assert_eq!(
+        db.get_and_burn("secret").unwrap().as_deref(),
+        Some("s3cr3t")
+    );
+    assert_eq!(db.get_and_burn("secret").unwrap(), None);
+    assert!(!db.burns_after_read("secret"));
+    db.reopen().unwrap();
+    assert!(!db.contains_key("secret"));
+    assert!(
+        !fs::read_to_string(db.dir().join("deebee.toml"))
+            .unwrap()
+            .contains("secret")
+    );
+
+    // of several threads racing for it, one gets the value
+    let shared = SharedDatabase::open("raced", &DatabaseOptions::new().root(db.dir())).unwrap();
+    shared.write().set_burn_after_read("token", "once").unwrap();
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let shared = shared.clone();
+            std::thread::spawn(move || shared.get("token").unwrap())
+        })
+        .collect();
+    let seen: Vec<_> = readers
+        .into_iter()
+        .filter_map(|reader| reader.join().unwrap())
+        .collect();
+    assert_eq!(seen, ["once"]);
+}
+
+#[test]
+fn burn_after_read_flags_live_in_the_records() {
+    struct DropTmp;
+    impl CompactionFilter for DropTmp {
+        fn filter(&self, key: &str, _value: &str) -> FilterDecision {
+            match key.starts_with("tmp:") {
+                true => FilterDecision::Drop,
+                false => FilterDecision::Keep,
+            }
+        }
+    }
+
+    let mut db = TempDatabase::builder()
+        .options(
+            DatabaseOptions::new()
+                .segment_size(2)
+                .compaction_filter(DropTmp),
+        )
+        .open()
+        .unwrap();
+    db.set_burn_after_read("kept", "k").unwrap();
+    db.set_burn_after_read("overwritten", "o").unwrap();
+    db.set_burn_after_read("tmp:dropped", "d").unwrap();
+    db.set("overwritten", "plain").unwrap();
+    db.set("active", "x").unwrap();
+    let config = db.dir().join("deebee.toml");
+    assert!(
+        !fs::read_to_string(&config)
+            .unwrap()
+            .contains("burn_after_read")
+    );
+    assert_eq!(db.get("overwritten").unwrap().as_deref(), Some("plain"));
+
+    // the merged segment and its hint keep the flag
+    db.compact_segments().unwrap();
+    db.reopen().unwrap();
+    assert!(db.burns_after_read("kept"));
+    assert!(!db.burns_after_read("overwritten"));
+    assert!(!db.burns_after_read("tmp:dropped"));
+    db.set("tmp:dropped", "again").unwrap();
+    db.reopen().unwrap();
+    assert_eq!(db.get("tmp:dropped").unwrap().as_deref(), Some("again"));
+    assert_eq!(db.get_and_burn("kept").unwrap().as_deref(), Some("k"));
+    db.set("kept", "plain").unwrap();
+    assert_eq!(db.get("kept").unwrap().as_deref(), Some("plain"));
+
+    // what deebee.toml listed before moves into the records
+    db.set("legacy", "l").unwrap();
+    fs::write(
+        &config,
+        fs::read_to_string(&config).unwrap() + "burn_after_read = [\"legacy\", \"gone\"]\n",
+    )
+    .unwrap();
+    db.reopen().unwrap();
+    assert!(db.burns_after_read("legacy"));
+    assert!(
+        !fs::read_to_string(&config)
+            .unwrap()
+            .contains("burn_after_read")
+    );
+    db.reopen().unwrap();
+    assert!(db.burns_after_read("legacy"));
+    assert!(!db.burns_after_read("gone"));
+}
+
+#[test]
+fn merge_operands_fold_on_reads_and_compactions() {
+    struct Add;
+    impl MergeOperator for Add {
+        fn merge(&self, _key: &str, existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+            let number =
+                |bytes: &[u8]| -> i64 { std::str::from_utf8(bytes).unwrap().parse().unwrap() };
+            (existing.map_or(0, number) + number(operand))
+                .to_string()
+                .into_bytes()
+        }
+    }
+    let operands = |db: &Database| -> Vec<String> {
+        db.segments()
+            .unwrap()
+            .iter()
+            .flat_map(|segment| {
+                let content = fs::read(db.dir().join(&segment.name)).unwrap();
+                segment_records(&content, RecordEncoding::for_format(FORMAT_VERSION))
+                    .filter(|(_, record)| record.flags.contains(RecordFlags::OPERAND))
+                    .map(|(_, record)| record.key.into_owned())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    };
+
+    let mut db = TempDatabase::builder()
+        .options(DatabaseOptions::new().segment_size(2).merge_operator(Add))
+        .record("hits", "10")
+        .open()
+        .unwrap();
+    db.merge("hits", b"1").unwrap();
+    db.merge("hits", b"2").unwrap();
+    db.merge("fresh", b"5").unwrap();
+    db.set("active", "x").unwrap();
+    assert_eq!(db.get("hits").unwrap().as_deref(), Some("13"));
+    assert_eq!(db.get("fresh").unwrap().as_deref(), Some("5"));
+    assert_eq!(operands(&db), ["hits", "hits", "fresh"]);
+    let exported = db.view().unwrap().export(&KeyFilter::default()).unwrap();
+    assert_eq!(exported[1].value, "5");
+    assert_eq!(exported[2].value, "13");
+
+    // the hints keep every operand after a key's value
+    db.reopen().unwrap();
+    assert_eq!(db.get("hits").unwrap().as_deref(), Some("13"));
+
+    db.compact_segments().unwrap();
+    assert!(operands(&db).is_empty());
+    db.reopen().unwrap();
+    assert_eq!(db.get("hits").unwrap().as_deref(), Some("13"));
+    assert_eq!(db.get("fresh").unwrap().as_deref(), Some("5"));
+
+    // a set or a delete starts the key over
+    db.merge("hits", b"1").unwrap();
+    db.set("hits", "0").unwrap();
+    db.merge("hits", b"4").unwrap();
+    assert_eq!(db.get("hits").unwrap().as_deref(), Some("4"));
+    db.merge("fresh", b"1").unwrap();
+    assert!(db.delete("fresh").unwrap());
+    db.reopen().unwrap();
+    assert_eq!(db.get("hits").unwrap().as_deref(), Some("4"));
+    assert_eq!(db.get("fresh").unwrap(), None);
+
+    let mut plain = Database::open("plain", &DatabaseOptions::new().root(db.dir())).unwrap();
+    assert!(matches!(
+        plain.merge("hits", b"1"),
+        Err(DeebeeError::Config(_))
+    ));
+}
+
+#[test]
+fn values_are_lent_out_without_a_string_each() {
+    for options in [
+        DatabaseOptions::new(),
+        DatabaseOptions::new().cache_bytes(1024),
+    ] {
+        let mut db = TempDatabase::builder()
+            .options(options.segment_size(2))
+            .records([("a", "1"), ("b", "22"), ("c", "333")])
+            .open()
+            .unwrap();
+        db.set_bytes("raw", &[0xff]).unwrap();
+        db.set_burn_after_read("secret", "x").unwrap();
+
+        // sealed segments and the active one, twice for the cache
+        for _ in 0..2 {
+            let value = db.get_ref("b").unwrap().unwrap();
+            assert_eq!(&*value, b"22");
+            assert_eq!(value.as_str().unwrap(), "22");
+            assert_eq!(&*db.get_ref("raw").unwrap().unwrap(), [0xff]);
+        }
+        assert!(db.get_ref("raw").unwrap().unwrap().as_str().is_err());
+        assert!(db.get_ref("nope").unwrap().is_none());
+        assert!(db.get_ref("secret").is_err());
+
+        let mut long: Vec<(String, usize)> = db
+            .iter_with(|key, value| (value.len() > 1).then(|| (key.to_string(), value.len())))
+            .unwrap();
+        long.sort();
+        assert_eq!(long, [("b".to_string(), 2), ("c".to_string(), 3)]);
+    }
+}
+
+#[test]
+fn client_checksums_are_checked_and_kept_with_the_value() {
+    let checksummed = |db: &Database| -> Vec<String> {
+        db.segments()
+            .unwrap()
+            .iter()
+            .flat_map(|segment| {
+                let content = fs::read(db.dir().join(&segment.name)).unwrap();
+                segment_records(&content, RecordEncoding::for_format(FORMAT_VERSION))
+                    .filter(|(_, record)| record.flags.contains(RecordFlags::CHECKSUM))
+                    .map(|(_, record)| record.key.into_owned())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    };
+    let mut db = TempDatabase::builder().open().unwrap();
+    assert!(matches!(
+        db.set_checked("a", "sent", crc32fast::hash(b"mangled")),
+        Err(DeebeeError::InvalidValue(_))
+    ));
+    assert!(!db.contains_key("a"));
+    db.set_checked("a", "sent", crc32fast::hash(b"sent"))
+        .unwrap();
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("sent"));
+    assert_eq!(checksummed(&db), ["a"]);
+
+    db.reopen().unwrap();
+    db.compact_segments().unwrap();
+    assert_eq!(checksummed(&db), ["a"]);
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("sent"));
+
+    let server = Server::bind("127.0.0.1:0", Protocol::Line).unwrap();
+    let addr = server.local_addr();
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"SETCRC b 00000000 x\nSETCRC b 8cdc1683 x\nGETCRC b\nQUIT\n")
+            .unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+        replies
+    });
+    for _ in 0..3 {
+        server.serve_one(&mut db).unwrap();
+    }
+    let replies = client.join().unwrap();
+    let lines: Vec<&str> = replies.lines().collect();
+    assert!(lines[0].starts_with("ERR "), "{replies}");
+    assert_eq!(lines[1..], ["OK", "CHECKED 8cdc1683 x"]);
+}
+
+#[test]
+fn mget_reads_keys_across_namespaces_in_one_command() {
+    let mut db = TempDatabase::builder()
+        .records([("user:1", "al"), ("order:9", "x"), ("plain", "p")])
+        .open()
+        .unwrap();
+    let exchange = |db: &mut Database, protocol: Protocol, request: &'static [u8]| {
+        let server = Server::bind("127.0.0.1:0", protocol).unwrap();
+        let addr = server.local_addr();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request).unwrap();
+            let mut replies = String::new();
+            stream.read_to_string(&mut replies).unwrap();
+            replies
+        });
+        server.serve_one(db).unwrap();
+        client.join().unwrap()
+    };
+
+    assert_eq!(
+        exchange(&mut db, Protocol::Line, b"MGET user:1 nope order:9\nQUIT\n"),
+        "ARRAY 3\nVALUE al\nNIL\nVALUE x\n"
+    );
+    assert_eq!(
+        exchange(
+            &mut db,
+            Protocol::Resp,
+            b"*3\r\n$4\r\nMGET\r\n$5\r\nplain\r\n$4\r\nnope\r\nQUIT\r\n"
+        ),
+        "*2\r\n$1\r\np\r\n$-1\r\n+OK\r\n"
+    );
+    let response = exchange(
+        &mut db,
+        Protocol::Http,
+        b"GET /mget?key=order%3A9&key=nope&key=user:1 HTTP/1.1\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(
+        response.trim_end().ends_with(
+            r#"[{"key":"order:9","value":"x"},{"key":"nope","value":null},{"key":"user:1","value":"al"}]"#
+        ),
+        "{response}"
+    );
+
+    // each key counts toward its own namespace
+    let namespaces = db.stats(true).namespaces;
+    assert_eq!(namespaces["user"].ops, 2);
+    assert_eq!(namespaces["order"].ops, 2);
+    assert_eq!(namespaces[""].ops, 4);
+}
+
+#[test]
+fn roles_limit_server_clients_to_their_key_patterns() {
+    let mut db = TempDatabase::builder()
+        .records([("app1:a", "1"), ("app2:b", "2")])
+        .open()
+        .unwrap();
+    let config = db.dir().join("deebee.toml");
+    let original = fs::read_to_string(&config).unwrap();
+    let roles = concat!(
+        "[[databases.roles]]\nname = \"app1\"\ntoken = \"t1\"\nkeys = [\"app1:*\"]\n",
+        "[[databases.roles]]\nname = \"audit\"\ntoken = \"t2\"\nkeys = [\"*\"]\nread_only = true\n",
+    );
+    fs::write(&config, original + roles).unwrap();
+    db.reopen().unwrap();
+    let exchange = |db: &mut Database, protocol: Protocol, request: &'static [u8], jobs| {
+        let server = Server::bind("127.0.0.1:0", protocol).unwrap();
+        let addr = server.local_addr();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request).unwrap();
+            let mut replies = String::new();
+            stream.read_to_string(&mut replies).unwrap();
+            replies
+        });
+        for _ in 0..jobs {
+            server.serve_one(db).unwrap();
+        }
+        client.join().unwrap()
+    };
+
+    let replies = exchange(
+        &mut db,
+        Protocol::Line,
+        b"GET app1:a\nAUTH nope\nAUTH t1\nGET app1:a\nGET app2:b\nSET app2:b x\nMGET app1:a app2:b\nKEYS *\nSCAN\nADMIN CLIENTS\nQUIT\n",
+        10,
+    );
+    let lines: Vec<&str> = replies.lines().collect();
+    assert!(lines[0].starts_with("ERR authenticate"), "{replies}");
+    assert_eq!(lines[1..4], ["ERR wrong token", "OK", "VALUE 1"]);
+    for denied in &lines[4..7] {
+        assert!(denied.starts_with("ERR role app1 may not"), "{replies}");
+    }
+    assert_eq!(
+        lines[7..12],
+        [
+            "ARRAY 1",
+            "VALUE app1:a",
+            "ARRAY 2",
+            "VALUE app1:a",
+            "VALUE 1"
+        ]
+    );
+    assert!(
+        lines[12].starts_with("ERR role app1 only sees"),
+        "{replies}"
+    );
+
+    // a batch with one key out of bounds doesn't run at all
+    let replies = exchange(
+        &mut db,
+        Protocol::Resp,
+        b"AUTH t2\r\nDEL app1:a app2:b\r\nGET app2:b\r\nQUIT\r\n",
+        3,
+    );
+    assert_eq!(
+        replies,
+        "+OK\r\n-ERR role audit may not write app1:a\r\n$1\r\n2\r\n+OK\r\n"
+    );
+    assert!(db.contains_key("app1:a"));
+
+    let status = |db: &mut Database, request: &'static [u8]| {
+        let response = exchange(db, Protocol::Http, request, 1);
+        response.split(' ').nth(1).unwrap().to_string()
+    };
+    assert_eq!(status(&mut db, b"GET /keys/app2:b HTTP/1.1\r\n\r\n"), "401");
+    assert_eq!(
+        status(
+            &mut db,
+            b"GET /keys/app2:b HTTP/1.1\r\nAuthorization: Bearer t1\r\n\r\n"
+        ),
+        "403"
+    );
+    assert_eq!(
+        status(
+            &mut db,
+            b"GET /keys/app2:b HTTP/1.1\r\nAuthorization: Bearer t2\r\n\r\n"
+        ),
+        "200"
+    );
+    assert_eq!(
+        status(
+            &mut db,
+            b"GET /stats HTTP/1.1\r\nAuthorization: Bearer t1\r\n\r\n"
+        ),
+        "403"
+    );
+}
+
+#[test]
+fn compaction_filters_drop_and_rewrite_records() {
+    // drops `tmp:` keys and the `email` field of user records
+    struct Retention;
+    impl CompactionFilter for Retention {
+        fn filter(&self, key: &str, value: &str) -> FilterDecision {
+            if key.starts_with("tmp:") {
+                return FilterDecision::Drop;
+            }
+            match serde_json::from_str::<serde_json::Value>(value) {
+                Ok(mut user) if user.get("email").is_some() => {
+                    user.as_object_mut().unwrap().remove("email");
+                    FilterDecision::Rewrite(user.to_string())
+                }
+                _ => FilterDecision::Keep,
+            }
+        }
+    }
+
+    let mut db = TempDatabase::builder()
+        .options(
+            DatabaseOptions::new()
+                .segment_size(2)
+                .compaction_filter(Retention),
+        )
+        .records([
+            ("tmp:a", "1"),
+            ("user:1", r#"{"name":"ada","email":"ada@example.com"}"#),
+            ("plain", "kept"),
+            ("tmp:b", "2"),
+            ("active", "x"),
+        ])
+        .open()
+        .unwrap();
+    db.pin("user:1").unwrap();
+
+    let report = db.compact_segments().unwrap();
+    assert_eq!(
+        (
+            report.records_kept,
+            report.records_dropped,
+            report.records_rewritten
+        ),
+        (2, 2, 1)
+    );
+    assert_eq!(db.get("tmp:a").unwrap(), None);
+    assert_eq!(
+        db.get("user:1").unwrap().as_deref(),
+        Some(r#"{"name":"ada"}"#)
+    );
+    assert_eq!(db.get("plain").unwrap().as_deref(), Some("kept"));
+    // the active segment isn't merged
+    assert_eq!(db.get("active").unwrap().as_deref(), Some("x"));
+    db.reopen().unwrap();
+    assert!(!db.contains_key("tmp:b"));
+}
+
+#[test]
+fn compaction_stores_repeated_values_once() {
+    let shared = r#"{"theme":"dark","notifications":true}"#;
+    let mut db = TempDatabase::builder()
+        .options(DatabaseOptions::new().segment_size(2))
+        .records([("a", shared), ("b", shared), ("c", shared), ("d", "short")])
+        .record("e", "active")
+        .open()
+        .unwrap();
+    let config = db.dir().join("deebee.toml");
+    fs::write(
+        &config,
+        fs::read_to_string(&config).unwrap() + "dedup_min_bytes = 8\n",
+    )
+    .unwrap();
+    db.reopen().unwrap();
+
+    let report = db.compact_segments().unwrap();
+    assert_eq!(report.shared_values, 3);
+    let blobs = db.dir().join("test/blobs");
+    assert_eq!(
+        fs::read_dir(&blobs).unwrap().count(),
+        2,
+        "one blob and REFS"
+    );
+    assert!(
+        fs::read_to_string(blobs.join("REFS"))
+            .unwrap()
+            .contains("= 3")
+    );
+    for key in ["a", "b", "c"] {
+        assert_eq!(db.get(key).unwrap().as_deref(), Some(shared));
+    }
+    assert_eq!(db.export(&KeyFilter::default()).unwrap()[0].value, shared);
+    // records flag their references, a value can look like one
+    db.set("x", "\0blob:0123").unwrap();
+    assert_eq!(db.get("x").unwrap().as_deref(), Some("\0blob:0123"));
+
+    // the last reference going takes the blob with it, a snapshot keeps its own
+    db.create_snapshot("shared").unwrap();
+    for key in ["a", "b", "c", "f", "g"] {
+        db.set(key, "new").unwrap();
+    }
+    assert_eq!(db.compact_segments().unwrap().shared_values, 0);
+    assert_eq!(fs::read_dir(&blobs).unwrap().count(), 1);
+    db.restore_snapshot("shared").unwrap();
+    assert_eq!(db.get("b").unwrap().as_deref(), Some(shared));
+}
+
+#[test]
+fn compaction_compresses_sealed_segments_with_deflate() {
+    let value = |i: usize| {
+        format!(
+            r#"{{"id":{i},"theme":"dark","tags":"{}"}}"#,
+            "ab".repeat(i % 50)
+        )
+    };
+    let mut db = TempDatabase::builder()
+        .options(DatabaseOptions::new().segment_size(100))
+        .open()
+        .unwrap();
+    for i in 0..450 {
+        db.set(&format!("k{i:03}"), &value(i)).unwrap();
+    }
+    let config = db.dir().join("deebee.toml");
+    fs::write(
+        &config,
+        fs::read_to_string(&config).unwrap() + "compression = \"deflate\"\n",
+    )
+    .unwrap();
+    db.reopen().unwrap();
+    let digest = db.digest().unwrap();
+
+    let report = db.compact_segments().unwrap();
+    assert!(report.bytes_after * 2 < report.bytes_before, "{report:?}");
+    let compacted = db.dir().join("test").join(&db.segments().unwrap()[0].name);
+    assert!(fs::read(&compacted).unwrap().starts_with(b"\xffDEEBEEZ"));
+
+    // every read inflates the block its record is in
+    assert_eq!(db.get("k007").unwrap(), Some(value(7)));
+    assert_eq!(
+        &*db.get_ref("k321").unwrap().unwrap(),
+        value(321).as_bytes()
+    );
+    assert_eq!(
+        db.get_many(&["k000", "k399", "nope"]).unwrap(),
+        [Some(value(0)), Some(value(399)), None]
+    );
+    assert_eq!(
+        db.view().unwrap().get_many(&["k123", "k449"]).unwrap(),
+        [Some(value(123)), Some(value(449))]
+    );
+    assert_eq!(db.iter_with(|_, _| Some(())).unwrap().len(), 450);
+    assert_eq!(db.digest().unwrap(), digest);
+    db.reopen().unwrap();
+    assert_eq!(db.digest().unwrap(), digest);
+    assert_eq!(db.get("k200").unwrap(), Some(value(200)));
+
+    // older formats don't have compressed segments
+    let manifest = db.dir().join("test/MANIFEST");
+    fs::write(
+        &manifest,
+        fs::read_to_string(&manifest).unwrap().replace(
+            &format!("format_version = {FORMAT_VERSION}"),
+            "format_version = 5",
+        ),
+    )
+    .unwrap();
+    db.reopen().unwrap();
+    db.set("k000", "new").unwrap();
+    assert!(matches!(
+        db.compact_segments(),
+        Err(DeebeeError::Write(WriteError::FormatTooOld {
+            needed: 6,
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn background_tasks_run_on_the_pool_and_can_be_disabled() {
+    let mut db = TempDatabase::builder()
+        .options(DatabaseOptions::new().sync(SyncPolicy::EverySec))
+        .open()
+        .unwrap();
+    db.set("a", "1").unwrap();
+    // the write left itself for the flush task to fsync
+    let flushed = |db: &TempDatabase| {
+        db.stats(true)
+            .background_tasks
+            .iter()
+            .any(|task| task.name == "flush" && task.runs > 0)
+    };
+    let started = std::time::Instant::now();
+    while !flushed(&db) {
+        assert!(started.elapsed().as_secs() < 10, "flush never ran");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    let config = db.dir().join("deebee.toml");
+    let original = fs::read_to_string(&config).unwrap();
+    fs::write(
+        &config,
+        original.clone() + "background = { threads = 1, disabled = [\"compaction\"] }\n",
+    )
+    .unwrap();
+    db.reopen().unwrap();
+    let tasks = db.stats(true).background_tasks;
+    let enabled: Vec<(&str, bool)> = tasks
+        .iter()
+        .map(|task| (task.name.as_str(), task.enabled))
+        .collect();
+    assert_eq!(
+        enabled,
+        [("compaction", false), ("flush", true), ("warmup", true)]
+    );
+
+    fs::write(
+        &config,
+        original + "background = { disabled = [\"scrub\"] }\n",
+    )
+    .unwrap();
+    assert!(matches!(db.reopen(), Err(DeebeeError::Config(_))));
+}
+
+#[test]
+fn background_merges_pause_for_foreground_writes() {
+    let mut db = TempDatabase::builder()
+        .options(DatabaseOptions::new().segment_size(4))
+        .open()
+        .unwrap();
+    let config = db.dir().join("deebee.toml");
+    let original = fs::read_to_string(&config).unwrap();
+    fs::write(
+        &config,
+        original
+            + "background = { yield_ms = 50 }\n\n[databases.compaction]\nsealed_segments = 2\n",
+    )
+    .unwrap();
+    db.reopen().unwrap();
+
+    let paused = |db: &TempDatabase| {
+        db.stats(true)
+            .background_tasks
+            .iter()
+            .any(|task| task.name == "compaction" && task.pauses > 0)
+    };
+    let started = std::time::Instant::now();
+    let mut round = 0;
+    // every merge the writes start has one running right before it
+    while !paused(&db) {
+        assert!(started.elapsed().as_secs() < 10, "compaction never paused");
+        db.set(&format!("k{}", round % 10), &round.to_string())
+            .unwrap();
+        round += 1;
+    }
+    // and got on with it afterwards
+    db.reopen().unwrap();
+    let last = round - 1;
+    assert_eq!(
+        db.get(&format!("k{}", last % 10)).unwrap(),
+        Some(last.to_string())
+    );
+}
+
+#[test]
+fn patches_apply_in_whole_batches_once() {
+    let mut db = TempDatabase::builder()
+        .records([("a", "1"), ("b", "2")])
+        .open()
+        .unwrap();
+    let config = db.dir().join("deebee.toml");
+    let original = fs::read_to_string(&config).unwrap();
+    fs::write(&config, original + "key_rules = { max_length = 8 }\n").unwrap();
+    db.reopen().unwrap();
+    let batch = vec![
+        PatchOp::Set {
+            key: "c".to_string(),
+            value: "3".to_string(),
+        },
+        PatchOp::Delete {
+            key: "a".to_string(),
+        },
+    ];
+    assert!(db.apply_batch("primary", 1, batch.clone()).unwrap());
+    assert!(!db.apply_batch("primary", 1, batch).unwrap());
+    assert_eq!(db.get("c").unwrap().as_deref(), Some("3"));
+    assert!(!db.contains_key("a"));
+    assert!(matches!(
+        db.apply_batch("primary", 3, Vec::new()),
+        Err(DeebeeError::InvalidArgument(_))
+    ));
+
+    // one bad op and none of the batch lands
+    let bad = vec![
+        PatchOp::Set {
+            key: "d".to_string(),
+            value: "4".to_string(),
+        },
+        PatchOp::Set {
+            key: "much-too-long".to_string(),
+            value: "5".to_string(),
+        },
+    ];
+    assert!(db.apply_batch("primary", 2, bad).is_err());
+    assert!(!db.contains_key("d"));
+    assert_eq!(db.applied_batches("primary").unwrap(), 1);
+
+    // a journaled batch the writer didn't finish lands on the next open
+    let dir = db.dir().join("test");
+    db.create_snapshot("before").unwrap();
+    fs::write(
+        dir.join("APPLY"),
+        r#"{"source":"primary","batch":2,"ops":[{"op":"delete","key":"b"}]}"#,
+    )
+    .unwrap();
+    db.reopen().unwrap();
+    assert!(!db.contains_key("b"));
+    assert!(!fs::exists(dir.join("APPLY")).unwrap());
+    assert_eq!(db.applied_batches("primary").unwrap(), 2);
+
+    // the counts go back with the data
+    db.restore_snapshot("before").unwrap();
+    assert_eq!(db.applied_batches("primary").unwrap(), 1);
+}
+
+#[test]
+fn operations_stop_at_their_deadline_or_cancel_token() {
+    let mut db = TempDatabase::builder()
+        .options(DatabaseOptions::new().segment_size(2))
+        .records([("a", "1"), ("b", "2"), ("c", "3"), ("d", "4"), ("e", "5")])
+        .open()
+        .unwrap();
+    let late = std::time::Instant::now() - Duration::from_millis(1);
+    assert!(matches!(
+        db.get_with("a", &GetOptions::new().deadline(late)),
+        Err(DeebeeError::Cancelled(_))
+    ));
+    assert!(matches!(
+        db.set_with("a", "x", &WriteOptions::new().deadline(late)),
+        Err(DeebeeError::Cancelled(_))
+    ));
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("1"));
+    let roomy = WriteOptions::new().timeout(Duration::from_secs(60));
+    assert!(db.delete_with("e", &roomy).unwrap());
+
+    // cancelled halfway, the scan reports it once and ends
+    let token = CancelToken::new();
+    let options = GetOptions::new().cancel_token(token.clone());
+    let mut scan = db.scan_prefix_with("", &options);
+    assert_eq!(
+        scan.next().unwrap().unwrap(),
+        ("a".to_string(), "1".to_string())
+    );
+    token.cancel();
+    assert!(matches!(scan.next(), Some(Err(DeebeeError::Cancelled(_)))));
+    assert!(scan.next().is_none());
+    drop(scan);
+
+    let segments = db.segments().unwrap().len();
+    let cancelled = WriteOptions::new().cancel_token(token);
+    assert!(matches!(
+        db.compact_segments_with(&cancelled),
+        Err(DeebeeError::Cancelled(_))
+    ));
+    assert_eq!(db.segments().unwrap().len(), segments);
+    assert!(db.compact_segments_with(&roomy).unwrap().segments > 0);
+
+    // commands that wait past the server's timeout aren't run
+    let server = Server::bind("127.0.0.1:0", Protocol::Line).unwrap();
+    server.set_timeout(Some(Duration::ZERO));
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream.write_all(b"SET a 9\nQUIT\n").unwrap();
+    server.serve_one(&mut db).unwrap();
+    let mut replies = String::new();
+    stream.read_to_string(&mut replies).unwrap();
+    assert_eq!(replies, "ERR the operation ran past its deadline\n");
+    assert_eq!(db.get("a").unwrap().as_deref(), Some("1"));
+}
+
+#[test]
+fn collations_order_keys_everywhere_and_stay_recorded() {
+    in_scratch_dir("collation", || {
+        let options = DatabaseOptions::new()
+            .segment_size(2)
+            .collation(Collation::AsciiCaseInsensitive);
+        let mut db = Database::open("db", &options).unwrap();
+        for key in ["b", "a", "B", "A", "c"] {
+            db.set(key, "x").unwrap();
+        }
+        let keys: Vec<&[u8]> = db.iter_keys().collect();
+        assert_eq!(keys, [&b"A"[..], b"a", b"B", b"b", b"c"]);
+        let range = KeyFilter {
+            from: Some("a".into()),
+            to: Some("c".into()),
+            ..Default::default()
+        };
+        let keys: Vec<String> = db
+            .export(&range)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.key)
+            .collect();
+        assert_eq!(keys, ["a", "B", "b"]);
+
+        // compacted segments are written in the same order
+        db.compact_segments().unwrap();
+        let compacted = &db.segments().unwrap()[0];
+        let content = fs::read(db.dir().join(&compacted.name)).unwrap();
+        let encoding = RecordEncoding::for_format(FORMAT_VERSION);
+        let keys: Vec<String> = segment_records(&content, encoding)
+            .map(|(_, record)| record.key.into_owned())
+            .collect();
+        assert_eq!(keys, ["A", "a", "B", "b"]);
+        drop(db);
+
+        // recorded, so it opens without being named again but not as another
+        let db = Database::open("db", &DatabaseOptions::new()).unwrap();
+        let scanned: Vec<String> = db.scan_prefix("").map(|r| r.unwrap().0).collect();
+        assert_eq!(scanned, ["A", "a", "B", "b", "c"]);
+        drop(db);
+        let bytewise = DatabaseOptions::new().collation(Collation::Bytewise);
+        assert!(matches!(
+            Database::open("db", &bytewise),
+            Err(DeebeeError::Config(_))
+        ));
+    });
+}